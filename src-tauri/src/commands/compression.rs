@@ -1,7 +1,12 @@
 use crate::commands::CommandError;
 use crate::database::DatabaseManager;
-use crate::domain::compression::{CompressionSummary, run_compression};
-use crate::domain::{CompressionLevel, validate_image_file};
+use crate::domain::compression::{
+    AutoBestOptions, CompressionOptions, CompressionSummary, ResponsiveOptions, run_compression,
+};
+use crate::domain::{
+    CompressionLevel, DecodeLimits, EncoderOptions, HeifImages, MetadataPolicy, PerceptualTarget,
+    RawDecodeMode, ResizeOptions, validate_image_file,
+};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 use std::sync::Arc;
@@ -14,6 +19,18 @@ pub struct CompressImageRequest {
     pub quality: Option<u8>,
     pub format: Option<String>,
     pub level: Option<CompressionLevel>,
+    pub metadata_policy: Option<MetadataPolicy>,
+    pub convert_to_srgb: Option<bool>,
+    pub resize: Option<ResizeOptions>,
+    pub target_size: Option<u64>,
+    pub perceptual_target: Option<PerceptualTarget>,
+    pub decode_limits: Option<DecodeLimits>,
+    pub raw_mode: Option<RawDecodeMode>,
+    pub reduce_to_8bit: Option<bool>,
+    pub heif_images: Option<HeifImages>,
+    pub encoder_options: Option<EncoderOptions>,
+    pub auto_best: Option<AutoBestOptions>,
+    pub responsive: Option<ResponsiveOptions>,
 }
 
 /// Bounds how many CPU-bound compressions run at once. The frontend fires one
@@ -46,7 +63,7 @@ impl Default for CompressionLimiter {
 }
 
 /// Thin adapter: validate the input, delegate the orchestration to
-/// `run_compression`, persist the stat best-effort, and return the summary.
+/// `run_compression`, persist the stats best-effort, and return the summary.
 /// Business failures surface as `Err(CommandError)` — there is no `success:false`
 /// payload channel.
 #[tauri::command]
@@ -74,6 +91,20 @@ pub async fn compress_image(
     let format = request.format.clone();
     let quality = request.quality;
     let level = request.level.unwrap_or(CompressionLevel::Balanced);
+    let options = CompressionOptions {
+        metadata_policy: request.metadata_policy.unwrap_or_default(),
+        convert_to_srgb: request.convert_to_srgb.unwrap_or(false),
+        resize: request.resize.unwrap_or_default(),
+        target_size: request.target_size,
+        perceptual_target: request.perceptual_target,
+        decode_limits: request.decode_limits.unwrap_or_default(),
+        raw_mode: request.raw_mode.unwrap_or_default(),
+        reduce_to_8bit: request.reduce_to_8bit.unwrap_or(false),
+        heif_images: request.heif_images.unwrap_or_default(),
+        encoder: request.encoder_options.unwrap_or_default(),
+        auto_best: request.auto_best.unwrap_or_default(),
+        responsive: request.responsive,
+    };
     let outcome = tauri::async_runtime::spawn_blocking(move || {
         run_compression(
             Path::new(&file_path),
@@ -81,6 +112,7 @@ pub async fn compress_image(
             format.as_deref(),
             quality,
             level,
+            &options,
         )
     })
     .await
@@ -92,8 +124,10 @@ pub async fn compress_image(
 
     // Stats are backend-only and best-effort: a DB failure must not fail the
     // compression the user just obtained.
-    if let Err(e) = db.save_compression_stat(&outcome.stat) {
-        log::warn!("Failed to save compression stat: {e}");
+    for stat in &outcome.stats {
+        if let Err(e) = db.save_compression_stat(stat) {
+            log::warn!("Failed to save compression stat: {e}");
+        }
     }
 
     Ok(outcome.summary)
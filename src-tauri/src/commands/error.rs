@@ -78,7 +78,12 @@ impl From<FileError> for CommandError {
 impl From<CompressionError> for CommandError {
     fn from(error: CompressionError) -> Self {
         let kind = match error {
-            CompressionError::InvalidSettings(_) => ErrorKind::Validation,
+            // An unreachable target is a setting the user can relax; an oversized
+            // image is a rejected input, not a failure of the engine
+            CompressionError::InvalidSettings(_)
+            | CompressionError::TargetSizeUnreachable { .. }
+            | CompressionError::PerceptualTargetUnreachable { .. }
+            | CompressionError::DecodeLimitExceeded(_) => ErrorKind::Validation,
             CompressionError::UnsupportedFormat(_) => ErrorKind::Unsupported,
             CompressionError::IoError(_) => ErrorKind::Io,
             CompressionError::ProcessingError(_) | CompressionError::InsufficientCompression(_) => {
@@ -40,6 +40,10 @@ pub struct FileInfo {
     pub size: u64,
     pub extension: Option<String>,
     pub is_image: bool,
+    /// Format named by the file's signature, whatever its extension says
+    pub detected_format: Option<String>,
+    /// The extension and the content disagree (e.g. a PNG saved as `.jpg`)
+    pub extension_mismatch: bool,
 }
 
 #[tauri::command]
@@ -49,10 +53,12 @@ pub async fn get_file_information(file_path: String) -> Result<FileInfo, Command
 
     Ok(FileInfo {
         path: file_path,
+        extension_mismatch: metadata.has_mismatched_extension(),
         name: metadata.name,
         size: metadata.size,
         extension: metadata.extension,
         is_image: metadata.is_image,
+        detected_format: metadata.detected_format,
     })
 }
 
@@ -86,6 +86,8 @@ impl DatabaseManager {
             let min_quality = (i32::from(query.quality_setting) - quality_range).max(1) as u8;
             let max_quality = (i32::from(query.quality_setting) + quality_range).min(100) as u8;
 
+            // The query describes a compression at full size: resized rows, whose
+            // output holds fewer pixels than the source, would inflate the estimate
             let mut stmt = conn.prepare(
                 "SELECT
                     AVG(size_reduction_percent) as avg_reduction,
@@ -95,7 +97,9 @@ impl DatabaseManager {
                 WHERE input_format = ?1
                 AND output_format = ?2
                 AND quality_setting BETWEEN ?3 AND ?4
-                AND lossy_mode = ?5",
+                AND lossy_mode = ?5
+                AND (output_width IS NULL OR pixel_count IS NULL
+                     OR output_width * output_height = pixel_count)",
             )?;
 
             let row = stmt
@@ -133,15 +137,18 @@ impl DatabaseManager {
                     })
                 }
                 _ => {
+                    let mut settings = CompressionSettings::new(
+                        query.quality_setting,
+                        OutputFormat::from_string(&query.output_format)
+                            .unwrap_or(OutputFormat::WebP),
+                    );
+                    // A lossy PNG run is a palette one
+                    settings.encoder.png.quantize = query.lossy_mode;
                     let fallback = estimate_compression(
                         &query.input_format,
                         &query.output_format,
                         query.original_size,
-                        &CompressionSettings::new(
-                            query.quality_setting,
-                            OutputFormat::from_string(&query.output_format)
-                                .unwrap_or(OutputFormat::WebP),
-                        ),
+                        &settings,
                     );
                     Ok(fallback)
                 }
@@ -156,8 +163,9 @@ impl DatabaseManager {
                 "INSERT INTO compression_stats (
                     input_format, output_format, input_size_range, quality_setting,
                     lossy_mode, size_reduction_percent, original_size, compressed_size,
-                    pixel_count, compression_time_ms, timestamp
-                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                    pixel_count, compression_time_ms, timestamp, output_width, output_height,
+                    ssim, psnr, max_error
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16)",
                 rusqlite::params![
                     stat.input_format,
                     stat.output_format,
@@ -170,6 +178,11 @@ impl DatabaseManager {
                     stat.pixel_count,
                     stat.compression_time_ms,
                     stat.timestamp,
+                    stat.output_width,
+                    stat.output_height,
+                    stat.ssim,
+                    stat.psnr,
+                    stat.max_error,
                 ],
             )?;
             Ok(conn.last_insert_rowid())
@@ -306,6 +319,11 @@ impl DatabaseManager {
                 pixel_count: Some(*pixels),
                 compression_time_ms: Some(*time_ms),
                 timestamp: timestamp.clone(),
+                output_width: None,
+                output_height: None,
+                psnr: None,
+                ssim: None,
+                max_error: None,
             };
             match self.save_compression_stat(&stat) {
                 Ok(_) => inserted += 1,
@@ -14,7 +14,12 @@ pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
             compressed_size INTEGER NOT NULL,
             pixel_count INTEGER,
             compression_time_ms INTEGER,
-            timestamp TEXT NOT NULL
+            timestamp TEXT NOT NULL,
+            output_width INTEGER,
+            output_height INTEGER,
+            ssim REAL,
+            psnr REAL,
+            max_error INTEGER
         )",
         [],
     )?;
@@ -25,6 +30,26 @@ pub fn create_tables(conn: &Connection) -> rusqlite::Result<()> {
         [],
     );
 
+    // Add output dimension columns if upgrading from a schema without resizing
+    let _ = conn.execute(
+        "ALTER TABLE compression_stats ADD COLUMN output_width INTEGER",
+        [],
+    );
+    let _ = conn.execute(
+        "ALTER TABLE compression_stats ADD COLUMN output_height INTEGER",
+        [],
+    );
+
+    // Add the SSIM column if upgrading from a schema without perceptual scores
+    let _ = conn.execute("ALTER TABLE compression_stats ADD COLUMN ssim REAL", []);
+
+    // Add the remaining quality metric columns if upgrading from an older schema
+    let _ = conn.execute("ALTER TABLE compression_stats ADD COLUMN psnr REAL", []);
+    let _ = conn.execute(
+        "ALTER TABLE compression_stats ADD COLUMN max_error INTEGER",
+        [],
+    );
+
     conn.execute(
         "CREATE INDEX IF NOT EXISTS idx_compression_formats
          ON compression_stats(input_format, output_format, quality_setting)",
@@ -0,0 +1,378 @@
+//! Animated sources: GIF and animated WebP decoded into full-canvas frames, and
+//! frames encoded back into an animated WebP.
+//!
+//! Both decoders composite each frame onto the canvas, applying the source's
+//! disposal and blending, so a frame here is exactly what a viewer shows at that
+//! point. libwebp's animation encoder then works out its own sub-rectangles,
+//! disposal and blending for the output: the frames, their timing and the loop
+//! count survive, whatever encoding the source used to get there.
+
+use image::{DynamicImage, RgbaImage};
+
+use crate::domain::compression::{
+    engine::{decoding_error, image_limits},
+    error::{CompressionError, CompressionResult},
+    settings::DecodeLimits,
+};
+
+/// One frame of an animation, as shown on the canvas
+#[derive(Debug, Clone)]
+pub struct Frame {
+    pub image: RgbaImage,
+    /// How long the frame stays on screen
+    pub delay_ms: u32,
+}
+
+/// A decoded animation of at least two frames, all the size of the canvas
+#[derive(Debug, Clone)]
+pub struct Animation {
+    pub frames: Vec<Frame>,
+    /// How many times the animation plays, 0 for forever (WebP's convention)
+    pub loop_count: u16,
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl Animation {
+    /// Canvas width and height
+    pub fn dimensions(&self) -> (u32, u32) {
+        self.frames
+            .first()
+            .map_or((0, 0), |frame| frame.image.dimensions())
+    }
+}
+
+/// Decode every frame of an animated GIF or WebP. A still image, including a
+/// single-frame GIF or animation, yields `None` and takes the still-image path.
+/// The frames held in memory together count against `limits`.
+pub fn decode_animation(
+    input_data: &[u8],
+    input_format: &str,
+    limits: &DecodeLimits,
+) -> CompressionResult<Option<Animation>> {
+    let animation = match input_format {
+        "gif" => decode_gif(input_data, limits)?,
+        "webp" => decode_webp(input_data, limits)?,
+        _ => return Ok(None),
+    };
+    Ok(animation.filter(|animation| animation.frames.len() > 1))
+}
+
+fn decode_gif(input_data: &[u8], limits: &DecodeLimits) -> CompressionResult<Option<Animation>> {
+    use image::codecs::gif::GifDecoder;
+    use image::{AnimationDecoder, ImageDecoder};
+
+    // The NETSCAPE2.0 loop extension precedes the first frame; `image` does not
+    // expose it
+    let repeat = gif::Decoder::new(std::io::Cursor::new(input_data))
+        .map_err(|e| CompressionError::ProcessingError(format!("GIF decoding failed: {e}")))?
+        .repeat();
+    // The extension counts repeats after the first play, and a GIF without it
+    // plays once
+    let loop_count = match repeat {
+        gif::Repeat::Infinite => 0,
+        gif::Repeat::Finite(repeats) => repeats.saturating_add(1),
+    };
+
+    let mut decoder = GifDecoder::new(std::io::Cursor::new(input_data)).map_err(decoding_error)?;
+    let (width, height) = decoder.dimensions();
+    let frame_bytes = u64::from(width) * u64::from(height) * 4;
+    limits.check(width, height, frame_bytes)?;
+    decoder
+        .set_limits(image_limits(limits))
+        .map_err(decoding_error)?;
+    let icc_profile = decoder.icc_profile().ok().flatten();
+
+    let mut frames = Vec::new();
+    for frame in decoder.into_frames() {
+        limits.check(width, height, frame_bytes * (frames.len() as u64 + 1))?;
+        let frame = frame.map_err(decoding_error)?;
+        let (numerator, denominator) = frame.delay().numer_denom_ms();
+        frames.push(Frame {
+            delay_ms: numerator / denominator.max(1),
+            image: frame.into_buffer(),
+        });
+    }
+
+    Ok(Some(Animation {
+        frames,
+        loop_count,
+        icc_profile,
+    }))
+}
+
+fn decode_webp(input_data: &[u8], limits: &DecodeLimits) -> CompressionResult<Option<Animation>> {
+    use image_webp::{LoopCount, WebPDecoder};
+
+    let decoding_error = |e: image_webp::DecodingError| {
+        CompressionError::ProcessingError(format!("WebP decoding failed: {e}"))
+    };
+
+    let mut decoder = WebPDecoder::new(std::io::Cursor::new(input_data)).map_err(decoding_error)?;
+    if !decoder.is_animated() {
+        return Ok(None);
+    }
+    let (width, height) = decoder.dimensions();
+    let frame_bytes = u64::from(width) * u64::from(height) * 4;
+    limits.check(width, height, frame_bytes)?;
+    decoder.set_memory_limit(usize::try_from(limits.max_alloc_bytes).unwrap_or(usize::MAX));
+
+    let loop_count = match decoder.loop_count() {
+        LoopCount::Forever => 0,
+        LoopCount::Times(times) => times.get(),
+    };
+    let icc_profile = decoder.icc_profile().ok().flatten();
+
+    let has_alpha = decoder.has_alpha();
+    let mut buffer = vec![0; decoder.output_buffer_size().unwrap_or_default()];
+    let mut frames = Vec::new();
+    for _ in 0..decoder.num_frames() {
+        limits.check(width, height, frame_bytes * (frames.len() as u64 + 1))?;
+        let delay_ms = decoder.read_frame(&mut buffer).map_err(decoding_error)?;
+        let image = if has_alpha {
+            RgbaImage::from_raw(width, height, buffer.clone())
+        } else {
+            image::RgbImage::from_raw(width, height, buffer.clone())
+                .map(|rgb| DynamicImage::ImageRgb8(rgb).to_rgba8())
+        }
+        .ok_or_else(|| {
+            CompressionError::ProcessingError("WebP frame has the wrong size".to_string())
+        })?;
+        frames.push(Frame { image, delay_ms });
+    }
+
+    Ok(Some(Animation {
+        frames,
+        loop_count,
+        icc_profile,
+    }))
+}
+
+/// Owns a libwebp animation encoder, releasing it on drop
+struct AnimEncoderSession(*mut libwebp_sys::WebPAnimEncoder);
+
+impl AnimEncoderSession {
+    /// The encoder's last error, as a compression error
+    fn error(&self, step: &str) -> CompressionError {
+        let reason = unsafe {
+            let message = libwebp_sys::WebPAnimEncoderGetError(self.0);
+            if message.is_null() {
+                String::new()
+            } else {
+                std::ffi::CStr::from_ptr(message)
+                    .to_string_lossy()
+                    .into_owned()
+            }
+        };
+        CompressionError::ProcessingError(format!("Animated WebP encoding failed: {step} {reason}"))
+    }
+}
+
+impl Drop for AnimEncoderSession {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { libwebp_sys::WebPAnimEncoderDelete(self.0) };
+        }
+    }
+}
+
+/// Encode `animation` as an animated WebP, every frame with `config`. Frames
+/// are added at their cumulative timestamps and the stream is closed at the end
+/// of the last one, so it keeps its own duration rather than libwebp's guess.
+pub fn encode_animated_webp(
+    animation: &Animation,
+    config: &libwebp_sys::WebPConfig,
+) -> CompressionResult<Vec<u8>> {
+    use libwebp_sys::{
+        WebPAnimEncoderAdd, WebPAnimEncoderAssemble, WebPAnimEncoderNewInternal,
+        WebPAnimEncoderOptionsInitInternal, WebPData, WebPDataClear, WebPGetMuxABIVersion,
+        WebPPicture, WebPPictureFree, WebPPictureImportRGBA,
+    };
+
+    let too_large =
+        || CompressionError::ProcessingError("Animation too large for WebP".to_string());
+    let (width, height) = animation.dimensions();
+    let width = i32::try_from(width).map_err(|_| too_large())?;
+    let height = i32::try_from(height).map_err(|_| too_large())?;
+    let stride = width.checked_mul(4).ok_or_else(too_large)?;
+
+    unsafe {
+        let abi_version = WebPGetMuxABIVersion();
+        let mut options = std::mem::MaybeUninit::uninit();
+        if WebPAnimEncoderOptionsInitInternal(options.as_mut_ptr(), abi_version) == 0 {
+            return Err(CompressionError::ProcessingError(
+                "Failed to create the WebP animation encoder".to_string(),
+            ));
+        }
+        let mut options = options.assume_init();
+        options.anim_params.loop_count = i32::from(animation.loop_count);
+
+        let session = AnimEncoderSession(WebPAnimEncoderNewInternal(
+            width,
+            height,
+            &raw const options,
+            abi_version,
+        ));
+        if session.0.is_null() {
+            return Err(CompressionError::ProcessingError(
+                "Failed to create the WebP animation encoder".to_string(),
+            ));
+        }
+
+        let mut timestamp: i32 = 0;
+        for frame in &animation.frames {
+            let mut picture = WebPPicture::new().map_err(|()| {
+                CompressionError::ProcessingError("Failed to create WebPPicture".to_string())
+            })?;
+            picture.use_argb = 1;
+            picture.width = width;
+            picture.height = height;
+            if WebPPictureImportRGBA(&raw mut picture, frame.image.as_ptr(), stride) == 0 {
+                WebPPictureFree(&raw mut picture);
+                return Err(session.error("frame import"));
+            }
+            let added = WebPAnimEncoderAdd(session.0, &raw mut picture, timestamp, config);
+            WebPPictureFree(&raw mut picture);
+            if added == 0 {
+                return Err(session.error("frame"));
+            }
+            timestamp = i32::try_from(frame.delay_ms)
+                .ok()
+                .and_then(|delay| timestamp.checked_add(delay))
+                .ok_or_else(too_large)?;
+        }
+        // A final empty frame marks where the last one ends
+        if WebPAnimEncoderAdd(session.0, std::ptr::null_mut(), timestamp, std::ptr::null()) == 0 {
+            return Err(session.error("end of stream"));
+        }
+
+        let mut data = WebPData::default();
+        if WebPAnimEncoderAssemble(session.0, &raw mut data) == 0 {
+            return Err(session.error("assembly"));
+        }
+        let output = std::slice::from_raw_parts(data.bytes, data.size).to_vec();
+        WebPDataClear(&mut data);
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn animation(delays: &[u32], loop_count: u16) -> Animation {
+        let frames = delays
+            .iter()
+            .enumerate()
+            .map(|(i, &delay_ms)| {
+                let shade = u8::try_from(i * 60).unwrap();
+                Frame {
+                    image: RgbaImage::from_fn(16, 12, |x, _| {
+                        image::Rgba([shade, 255 - shade, 40, if x < 8 { 255 } else { 128 }])
+                    }),
+                    delay_ms,
+                }
+            })
+            .collect();
+        Animation {
+            frames,
+            loop_count,
+            icc_profile: None,
+        }
+    }
+
+    fn lossless_config() -> libwebp_sys::WebPConfig {
+        let mut config = libwebp_sys::WebPConfig::new().unwrap();
+        config.lossless = 1;
+        config
+    }
+
+    #[test]
+    fn test_webp_round_trip_keeps_frames_timing_and_loop_count() {
+        let source = animation(&[40, 120, 70], 3);
+        let webp = encode_animated_webp(&source, &lossless_config()).unwrap();
+
+        let decoded = decode_animation(&webp, "webp", &DecodeLimits::default())
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.loop_count, 3);
+        assert_eq!(decoded.dimensions(), (16, 12));
+        let delays: Vec<u32> = decoded.frames.iter().map(|frame| frame.delay_ms).collect();
+        // The last frame keeps its own duration, not the average of the others
+        assert_eq!(delays, [40, 120, 70]);
+        for (decoded, source) in decoded.frames.iter().zip(&source.frames) {
+            assert_eq!(decoded.image, source.image);
+        }
+    }
+
+    #[test]
+    fn test_gif_loop_extension_becomes_a_play_count() {
+        use image::codecs::gif::{GifEncoder, Repeat};
+
+        let encode = |repeat: Option<Repeat>| {
+            let mut gif = Vec::new();
+            {
+                let mut encoder = GifEncoder::new(&mut gif);
+                if let Some(repeat) = repeat {
+                    encoder.set_repeat(repeat).unwrap();
+                }
+                let frames = animation(&[30, 50], 0).frames.into_iter().map(|frame| {
+                    image::Frame::from_parts(
+                        frame.image,
+                        0,
+                        0,
+                        image::Delay::from_numer_denom_ms(frame.delay_ms, 1),
+                    )
+                });
+                encoder.encode_frames(frames).unwrap();
+            }
+            decode_animation(&gif, "gif", &DecodeLimits::default())
+                .unwrap()
+                .unwrap()
+        };
+
+        let forever = encode(Some(Repeat::Infinite));
+        assert_eq!(forever.loop_count, 0);
+        assert_eq!(forever.frames.len(), 2);
+        assert_eq!(
+            forever
+                .frames
+                .iter()
+                .map(|f| f.delay_ms)
+                .collect::<Vec<_>>(),
+            [30, 50]
+        );
+        assert_eq!(encode(Some(Repeat::Finite(2))).loop_count, 3);
+        assert_eq!(encode(None).loop_count, 1);
+    }
+
+    #[test]
+    fn test_still_images_are_not_animations() {
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(RgbaImage::new(4, 4))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let still_webp = webp::Encoder::from_rgba(&[0; 64], 4, 4).encode_lossless();
+
+        let limits = DecodeLimits::default();
+        assert!(decode_animation(&png, "png", &limits).unwrap().is_none());
+        assert!(
+            decode_animation(&still_webp, "webp", &limits)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_all_frames_together_count_against_the_limits() {
+        let webp = encode_animated_webp(&animation(&[10, 10, 10], 0), &lossless_config()).unwrap();
+        // Room for two 16x12 RGBA frames, not three
+        let limits = DecodeLimits {
+            max_alloc_bytes: 16 * 12 * 4 * 2,
+            ..DecodeLimits::default()
+        };
+        assert!(matches!(
+            decode_animation(&webp, "webp", &limits),
+            Err(CompressionError::DecodeLimitExceeded(_))
+        ));
+    }
+}
@@ -0,0 +1,198 @@
+//! Colour management: pixels described by an embedded ICC profile are converted
+//! to sRGB with Little CMS, after which the profile can be dropped.
+
+use image::{DynamicImage, ImageBuffer};
+use lcms2::{ColorSpaceSignature, Flags, Intent, PixelFormat, Profile, Transform};
+
+use crate::domain::compression::error::{CompressionError, CompressionResult};
+
+/// Convert `img` from the colour space of `icc_profile` to sRGB, keeping alpha
+/// and 16-bit precision. The result is meant to be written without a profile:
+/// untagged pixels are read as sRGB everywhere.
+///
+/// Relative colorimetric with black point compensation, the usual "convert to
+/// sRGB" for web export: in-gamut colours stay exact, out-of-gamut ones clip.
+pub fn convert_to_srgb(img: &DynamicImage, icc_profile: &[u8]) -> CompressionResult<DynamicImage> {
+    let source = Profile::new_icc(icc_profile)
+        .map_err(|e| CompressionError::ProcessingError(format!("Invalid ICC profile: {e}")))?;
+    let is_gray = match source.color_space() {
+        ColorSpaceSignature::RgbData => false,
+        ColorSpaceSignature::GrayData => true,
+        other => {
+            return Err(CompressionError::UnsupportedFormat(format!(
+                "Cannot convert {other:?} pixels to sRGB"
+            )));
+        }
+    };
+    let has_alpha = img.color().has_alpha();
+    // More than one byte per channel: 16-bit or float, both handled as 16-bit
+    let is_wide = img.color().bytes_per_pixel() > img.color().channel_count();
+
+    // Source pixels in the layout the profile describes
+    let (pixels, in_format) = match (is_gray, has_alpha, is_wide) {
+        (true, false, false) => (
+            DynamicImage::ImageLuma8(img.to_luma8()),
+            PixelFormat::GRAY_8,
+        ),
+        (true, true, false) => (
+            DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+            PixelFormat::GRAYA_8,
+        ),
+        (true, false, true) => (
+            DynamicImage::ImageLuma16(img.to_luma16()),
+            PixelFormat::GRAY_16,
+        ),
+        (true, true, true) => (
+            DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+            PixelFormat::GRAYA_16,
+        ),
+        (false, false, false) => (DynamicImage::ImageRgb8(img.to_rgb8()), PixelFormat::RGB_8),
+        (false, true, false) => (
+            DynamicImage::ImageRgba8(img.to_rgba8()),
+            PixelFormat::RGBA_8,
+        ),
+        (false, false, true) => (
+            DynamicImage::ImageRgb16(img.to_rgb16()),
+            PixelFormat::RGB_16,
+        ),
+        (false, true, true) => (
+            DynamicImage::ImageRgba16(img.to_rgba16()),
+            PixelFormat::RGBA_16,
+        ),
+    };
+    let out_format = match (has_alpha, is_wide) {
+        (false, false) => PixelFormat::RGB_8,
+        (true, false) => PixelFormat::RGBA_8,
+        (false, true) => PixelFormat::RGB_16,
+        (true, true) => PixelFormat::RGBA_16,
+    };
+
+    let transform: Transform<u8, u8> = Transform::new_flags(
+        &source,
+        in_format,
+        &Profile::new_srgb(),
+        out_format,
+        Intent::RelativeColorimetric,
+        Flags::BLACKPOINT_COMPENSATION | Flags::COPY_ALPHA,
+    )
+    .map_err(|e| CompressionError::ProcessingError(format!("Colour transform failed: {e}")))?;
+
+    let (width, height) = (img.width(), img.height());
+    let pixel_count = width as usize * height as usize;
+    let mut converted = vec![0u8; pixel_count * out_format.bytes_per_pixel()];
+    transform.transform_pixels(pixels.as_bytes(), &mut converted);
+
+    let output = if is_wide {
+        // 16-bit samples come back in native byte order, as lcms2 read them
+        let samples: Vec<u16> = converted
+            .chunks_exact(2)
+            .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+            .collect();
+        if has_alpha {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+        } else {
+            ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16)
+        }
+    } else if has_alpha {
+        ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgba8)
+    } else {
+        ImageBuffer::from_raw(width, height, converted).map(DynamicImage::ImageRgb8)
+    };
+    output.ok_or_else(|| {
+        CompressionError::ProcessingError("Colour transform produced a short buffer".to_string())
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// A Display P3 profile built by lcms2: sRGB's transfer curve, P3 primaries
+    pub(crate) fn display_p3_profile() -> Vec<u8> {
+        use lcms2::{CIExyY, CIExyYTRIPLE, ToneCurve};
+
+        let white = CIExyY {
+            x: 0.3127,
+            y: 0.3290,
+            Y: 1.0,
+        };
+        let primaries = CIExyYTRIPLE {
+            Red: CIExyY {
+                x: 0.680,
+                y: 0.320,
+                Y: 1.0,
+            },
+            Green: CIExyY {
+                x: 0.265,
+                y: 0.690,
+                Y: 1.0,
+            },
+            Blue: CIExyY {
+                x: 0.150,
+                y: 0.060,
+                Y: 1.0,
+            },
+        };
+        let curve =
+            ToneCurve::new_parametric(4, &[2.4, 1.0 / 1.055, 0.055 / 1.055, 1.0 / 12.92, 0.04045])
+                .unwrap();
+        Profile::new_rgb(&white, &primaries, &[&curve, &curve, &curve])
+            .unwrap()
+            .icc()
+            .unwrap()
+    }
+
+    #[test]
+    fn test_display_p3_red_is_clipped_into_srgb() {
+        let img =
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(2, 2, image::Rgb([255, 0, 0])));
+
+        let converted = convert_to_srgb(&img, &display_p3_profile())
+            .unwrap()
+            .to_rgb8();
+
+        // P3's red lies outside sRGB: it lands on sRGB's own most saturated red
+        let [r, g, b] = converted.get_pixel(0, 0).0;
+        assert!(r >= 250 && g <= 10 && b <= 10, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn test_display_p3_midtone_shifts_and_alpha_survives() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+            2,
+            2,
+            image::Rgba([180, 120, 60, 77]),
+        ));
+
+        let converted = convert_to_srgb(&img, &display_p3_profile()).unwrap();
+
+        let image::Rgba([r, g, b, a]) = *converted.to_rgba8().get_pixel(1, 1);
+        assert_eq!(a, 77);
+        // The same numbers mean a more saturated colour in P3: sRGB needs more red
+        assert!(r > 180 && g < 125 && b < 60, "{:?}", [r, g, b]);
+    }
+
+    #[test]
+    fn test_srgb_profile_is_an_identity_and_16_bit_is_kept() {
+        let srgb = Profile::new_srgb().icc().unwrap();
+        let img = DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(
+            2,
+            2,
+            image::Rgb([40_000u16, 20_000, 1_000]),
+        ));
+
+        let converted = convert_to_srgb(&img, &srgb).unwrap();
+
+        let DynamicImage::ImageRgb16(pixels) = converted else {
+            panic!("expected a 16-bit RGB image");
+        };
+        let [r, g, b] = pixels.get_pixel(0, 0).0;
+        assert!(r.abs_diff(40_000) < 64 && g.abs_diff(20_000) < 64 && b.abs_diff(1_000) < 64);
+    }
+
+    #[test]
+    fn test_invalid_profile_is_rejected() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(1, 1));
+        assert!(convert_to_srgb(&img, b"not an icc profile").is_err());
+    }
+}
@@ -11,41 +11,124 @@
 )]
 
 use crate::domain::compression::{
+    animation::{Animation, decode_animation, encode_animated_webp},
+    color::convert_to_srgb,
     error::{CompressionError, CompressionResult},
     formats::OutputFormat,
-    settings::CompressionSettings,
+    metadata::{
+        ImageMetadata, extract_from_heic, extract_metadata, rewrite_jpeg_metadata,
+        rewrite_png_metadata,
+    },
+    metrics::{QualityMetrics, Unmeasured, measure, ssim},
+    raw::decode_raw,
+    settings::{
+        CompressionSettings, DecodeLimits, JpegOptions, MetadataPolicy, PerceptualTarget,
+        PngOptions, ResizeOptions, WebpMode, WebpPreset,
+    },
 };
+use crate::domain::file::{is_camera_raw, resolve_format, sniff_image_format};
 use image::DynamicImage;
 use std::path::Path;
 
-/// Decode a HEIC/HEIF file into a DynamicImage + optional ICC profile using libheif-rs
-fn decode_heic(input_data: &[u8]) -> CompressionResult<(DynamicImage, Option<Vec<u8>>)> {
-    let lib_heif = libheif_rs::LibHeif::new();
+/// Parse a HEIC/HEIF container with libheif, under `limits`
+fn read_heic<'a>(
+    input_data: &'a [u8],
+    limits: &DecodeLimits,
+) -> CompressionResult<libheif_rs::HeifContext<'a>> {
+    let read_error = |e: libheif_rs::HeifError| {
+        if matches!(
+            e.sub_code,
+            libheif_rs::HeifErrorSubCode::SecurityLimitExceeded
+        ) {
+            CompressionError::DecodeLimitExceeded(e.message)
+        } else {
+            CompressionError::ProcessingError(format!("Failed to read HEIC context: {e}"))
+        }
+    };
 
-    let ctx = libheif_rs::HeifContext::read_from_bytes(input_data).map_err(|e| {
-        CompressionError::ProcessingError(format!("Failed to read HEIC context: {e}"))
-    })?;
+    // libheif enforces the same ceilings inside its own parser and decoder,
+    // which covers tiled and derived images whose size the image handle hides
+    let mut ctx = libheif_rs::HeifContext::new().map_err(read_error)?;
+    let mut security_limits = ctx.security_limits();
+    security_limits.set_max_image_size_pixels(limits.max_pixels);
+    security_limits.set_max_memory_block_size(limits.max_alloc_bytes);
+    ctx.set_security_limits(&security_limits)
+        .map_err(read_error)?;
+    ctx.read_bytes(input_data).map_err(read_error)?;
+    Ok(ctx)
+}
 
-    let handle = ctx.primary_image_handle().map_err(|e| {
-        CompressionError::ProcessingError(format!("Failed to get HEIC primary image: {e}"))
-    })?;
+/// Number of top-level images in a HEIC/HEIF file: a burst or a live photo
+/// holds several, besides the auxiliary alpha, depth and gain maps that belong
+/// to one of them
+pub fn heic_top_level_image_count<P: AsRef<Path>>(
+    input_path: P,
+    limits: &DecodeLimits,
+) -> CompressionResult<usize> {
+    let input_data = std::fs::read(input_path)
+        .map_err(|e| CompressionError::IoError(format!("Failed to read input file: {e}")))?;
+    Ok(read_heic(&input_data, limits)?.number_of_top_level_images())
+}
+
+/// Decode a HEIC/HEIF file into a DynamicImage + optional ICC profile using libheif-rs,
+/// along with the EXIF and XMP of the same image, read under the same limits.
+/// `image` picks a top-level image by position, `None` the primary one. An
+/// image coded with more than 8 bits per sample (10-bit HDR photos) comes back
+/// with 16 bits, and one with an alpha auxiliary image comes back as RGBA,
+/// straight (not premultiplied).
+fn decode_heic(
+    input_data: &[u8],
+    limits: &DecodeLimits,
+    image: Option<usize>,
+) -> CompressionResult<(DynamicImage, Option<Vec<u8>>, ImageMetadata)> {
+    let lib_heif = libheif_rs::LibHeif::new();
+    let ctx = read_heic(input_data, limits)?;
+
+    let handle = match image {
+        None => ctx.primary_image_handle().map_err(|e| {
+            CompressionError::ProcessingError(format!("Failed to get HEIC primary image: {e}"))
+        })?,
+        Some(index) => ctx
+            .top_level_image_handles()
+            .into_iter()
+            .nth(index)
+            .ok_or_else(|| {
+                CompressionError::InvalidSettings(format!(
+                    "The HEIC file has no image number {}",
+                    index + 1
+                ))
+            })?,
+    };
+    let (width, height) = (handle.width(), handle.height());
+    let bits = handle.luma_bits_per_pixel();
+    let high_bit_depth = bits > 8;
+    let has_alpha = handle.has_alpha_channel();
+    let channels: usize = if has_alpha { 4 } else { 3 };
+    let sample_bytes: usize = if high_bit_depth { 2 } else { 1 };
+    limits.check(
+        width,
+        height,
+        u64::from(width) * u64::from(height) * (channels * sample_bytes) as u64,
+    )?;
 
     // Extract ICC profile BEFORE decode (original, untransformed profile)
     let icc_profile = handle.color_profile_raw().map(|p| p.data);
 
+    // Decoding options left at their defaults apply the `irot`/`imir`
+    // transforms, so the pixels come out upright; an interleaved chroma with
+    // alpha makes libheif fill it from the alpha auxiliary image
+    let chroma = match (high_bit_depth, has_alpha) {
+        (false, false) => libheif_rs::RgbChroma::Rgb,
+        (false, true) => libheif_rs::RgbChroma::Rgba,
+        (true, false) => libheif_rs::RgbChroma::HdrRgbLe,
+        (true, true) => libheif_rs::RgbChroma::HdrRgbaLe,
+    };
     let heif_image = lib_heif
-        .decode(
-            &handle,
-            libheif_rs::ColorSpace::Rgb(libheif_rs::RgbChroma::Rgba),
-            None,
-        )
+        .decode(&handle, libheif_rs::ColorSpace::Rgb(chroma), None)
         .map_err(|e| {
             CompressionError::ProcessingError(format!("Failed to decode HEIC image: {e}"))
         })?;
 
-    let width = handle.width();
-    let height = handle.height();
-
     let planes = heif_image.planes();
     let plane = planes.interleaved.ok_or_else(|| {
         CompressionError::ProcessingError("HEIC image has no interleaved plane data".to_string())
@@ -53,75 +136,614 @@ fn decode_heic(input_data: &[u8]) -> CompressionResult<(DynamicImage, Option<Vec
 
     let stride = plane.stride;
     let plane_data = plane.data;
+    let row_bytes = width as usize * channels * sample_bytes;
 
-    // Copy pixel data row by row (stride may differ from width * 4)
-    let mut rgba_data = Vec::with_capacity((width * height * 4) as usize);
+    // Copy pixel data row by row (stride may differ from the row length)
+    let mut data = Vec::with_capacity(row_bytes * height as usize);
     for y in 0..height as usize {
         let row_start = y * stride;
-        let row_end = row_start + (width as usize * 4);
-        rgba_data.extend_from_slice(&plane_data[row_start..row_end]);
+        data.extend_from_slice(&plane_data[row_start..row_start + row_bytes]);
     }
 
-    let img_buf: image::RgbaImage = image::ImageBuffer::from_raw(width, height, rgba_data)
-        .ok_or_else(|| {
-            CompressionError::ProcessingError(
-                "Failed to create image buffer from HEIC data".to_string(),
-            )
-        })?;
+    let mut img = heic_pixels(
+        width,
+        height,
+        has_alpha,
+        high_bit_depth.then_some(bits),
+        data,
+    )
+    .ok_or_else(|| {
+        CompressionError::ProcessingError(
+            "Failed to create image buffer from HEIC data".to_string(),
+        )
+    })?;
+    if has_alpha && heif_image.is_premultiplied_alpha() {
+        img = unpremultiply(&img);
+    }
+
+    Ok((img, icc_profile, extract_from_heic(&handle)))
+}
+
+/// Wrap interleaved RGB(A) rows as an image: 8-bit samples as they are, or
+/// little-endian samples of `bits` significant bits widened to 16
+fn heic_pixels(
+    width: u32,
+    height: u32,
+    has_alpha: bool,
+    bits: Option<u8>,
+    data: Vec<u8>,
+) -> Option<DynamicImage> {
+    use image::ImageBuffer;
+
+    let Some(bits) = bits else {
+        return if has_alpha {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgba8)
+        } else {
+            ImageBuffer::from_raw(width, height, data).map(DynamicImage::ImageRgb8)
+        };
+    };
+    let samples: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|sample| widen_to_16_bits(u16::from_le_bytes([sample[0], sample[1]]), bits))
+        .collect();
+    if has_alpha {
+        ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16)
+    } else {
+        ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16)
+    }
+}
+
+/// Divide the colour of premultiplied RGBA pixels back by their alpha, keeping
+/// the sample depth
+fn unpremultiply(img: &DynamicImage) -> DynamicImage {
+    let mut straight = img.to_rgba32f();
+    divide_by_alpha(&mut straight);
+    let straight = DynamicImage::ImageRgba32F(straight);
+    if is_high_bit_depth(img.color()) {
+        DynamicImage::ImageRgba16(straight.to_rgba16())
+    } else {
+        DynamicImage::ImageRgba8(straight.to_rgba8())
+    }
+}
+
+/// Stretch a sample of `bits` significant bits (9 to 16) over the full 16-bit
+/// range, repeating its top bits in the low ones so the maximum maps to 65535
+fn widen_to_16_bits(sample: u16, bits: u8) -> u16 {
+    let bits = u32::from(bits.clamp(9, 16));
+    // Nothing to repeat at 16 bits, where the shift would overflow
+    (sample << (16 - bits)) | sample.checked_shr(2 * bits - 16).unwrap_or(0)
+}
+
+/// Owns a libjxl decoder and its thread pool, releasing both on drop so every
+/// early return in `decode_jxl` cleans up.
+struct JxlDecoderSession {
+    decoder: *mut jpegxl_sys::decode::JxlDecoder,
+    runner: *mut std::ffi::c_void,
+}
+
+impl JxlDecoderSession {
+    fn new() -> CompressionResult<Self> {
+        use jpegxl_sys::decode::{JxlDecoderCreate, JxlDecoderSetParallelRunner, JxlDecoderStatus};
+        use jpegxl_sys::threads::thread_parallel_runner::{
+            JxlThreadParallelRunner, JxlThreadParallelRunnerCreate,
+            JxlThreadParallelRunnerDefaultNumWorkerThreads,
+        };
+
+        unsafe {
+            let session = Self {
+                decoder: JxlDecoderCreate(std::ptr::null()),
+                runner: JxlThreadParallelRunnerCreate(
+                    std::ptr::null(),
+                    JxlThreadParallelRunnerDefaultNumWorkerThreads(),
+                ),
+            };
+            if session.decoder.is_null()
+                || session.runner.is_null()
+                || JxlDecoderSetParallelRunner(
+                    session.decoder,
+                    JxlThreadParallelRunner,
+                    session.runner,
+                ) != JxlDecoderStatus::Success
+            {
+                return Err(CompressionError::ProcessingError(
+                    "Failed to create JPEG XL decoder".to_string(),
+                ));
+            }
+            Ok(session)
+        }
+    }
+}
+
+impl Drop for JxlDecoderSession {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.decoder.is_null() {
+                jpegxl_sys::decode::JxlDecoderDestroy(self.decoder);
+            }
+            if !self.runner.is_null() {
+                jpegxl_sys::threads::thread_parallel_runner::JxlThreadParallelRunnerDestroy(
+                    self.runner,
+                );
+            }
+        }
+    }
+}
+
+/// Decode a JPEG XL file into a DynamicImage + optional ICC profile using libjxl.
+/// Pixels come back with orientation already applied, 16-bit when the file
+/// stores more than 8 bits per sample and 8-bit otherwise; the profile is the
+/// one describing those pixels.
+fn decode_jxl(
+    input_data: &[u8],
+    limits: &DecodeLimits,
+) -> CompressionResult<(DynamicImage, Option<Vec<u8>>)> {
+    use jpegxl_sys::common::types::{JxlDataType, JxlEndianness, JxlPixelFormat};
+    use jpegxl_sys::decode::{
+        JxlColorProfileTarget, JxlDecoderCloseInput, JxlDecoderGetBasicInfo,
+        JxlDecoderGetColorAsICCProfile, JxlDecoderGetICCProfileSize, JxlDecoderImageOutBufferSize,
+        JxlDecoderProcessInput, JxlDecoderSetImageOutBuffer, JxlDecoderSetInput, JxlDecoderStatus,
+        JxlDecoderSubscribeEvents,
+    };
+
+    let decode_error =
+        |step: &str| CompressionError::ProcessingError(format!("JPEG XL decoding failed: {step}"));
+
+    let session = JxlDecoderSession::new()?;
+    let dec = session.decoder;
+
+    let mut width = 0;
+    let mut height = 0;
+    let mut format = JxlPixelFormat {
+        num_channels: 4,
+        data_type: JxlDataType::Uint8,
+        endianness: JxlEndianness::Native,
+        align: 0,
+    };
+    let mut icc_profile = None;
+    let mut pixels = Vec::new();
+
+    unsafe {
+        let events = JxlDecoderStatus::BasicInfo as i32
+            | JxlDecoderStatus::ColorEncoding as i32
+            | JxlDecoderStatus::FullImage as i32;
+        if JxlDecoderSubscribeEvents(dec, events) != JxlDecoderStatus::Success
+            || JxlDecoderSetInput(dec, input_data.as_ptr(), input_data.len())
+                != JxlDecoderStatus::Success
+        {
+            return Err(decode_error("invalid input"));
+        }
+        JxlDecoderCloseInput(dec);
+
+        loop {
+            match JxlDecoderProcessInput(dec) {
+                JxlDecoderStatus::BasicInfo => {
+                    let mut info = std::mem::MaybeUninit::uninit();
+                    if JxlDecoderGetBasicInfo(dec, info.as_mut_ptr()) != JxlDecoderStatus::Success {
+                        return Err(decode_error("unreadable header"));
+                    }
+                    let info = info.assume_init();
+                    width = info.xsize;
+                    height = info.ysize;
+                    let color = if info.num_color_channels == 1 { 1 } else { 3 };
+                    format.num_channels = color + u32::from(info.alpha_bits > 0);
+                    let sample_bytes = if info.bits_per_sample > 8 {
+                        format.data_type = JxlDataType::Uint16;
+                        2
+                    } else {
+                        1
+                    };
+                    let pixels = u64::from(width) * u64::from(height);
+                    let bytes = pixels * u64::from(format.num_channels) * sample_bytes;
+                    limits.check(width, height, bytes)?;
+                }
+                JxlDecoderStatus::ColorEncoding => {
+                    let mut size = 0;
+                    if JxlDecoderGetICCProfileSize(dec, JxlColorProfileTarget::Data, &raw mut size)
+                        == JxlDecoderStatus::Success
+                        && size > 0
+                    {
+                        let mut icc = vec![0u8; size];
+                        if JxlDecoderGetColorAsICCProfile(
+                            dec,
+                            JxlColorProfileTarget::Data,
+                            icc.as_mut_ptr(),
+                            size,
+                        ) == JxlDecoderStatus::Success
+                        {
+                            icc_profile = Some(icc);
+                        }
+                    }
+                }
+                JxlDecoderStatus::NeedImageOutBuffer => {
+                    let mut size = 0;
+                    if JxlDecoderImageOutBufferSize(dec, &raw const format, &raw mut size)
+                        != JxlDecoderStatus::Success
+                    {
+                        return Err(decode_error("unsupported pixel layout"));
+                    }
+                    pixels.resize(size, 0);
+                    if JxlDecoderSetImageOutBuffer(
+                        dec,
+                        &raw const format,
+                        pixels.as_mut_ptr().cast(),
+                        size,
+                    ) != JxlDecoderStatus::Success
+                    {
+                        return Err(decode_error("unsupported pixel layout"));
+                    }
+                }
+                JxlDecoderStatus::FullImage => {}
+                JxlDecoderStatus::Success => break,
+                _ => return Err(decode_error("corrupt or truncated file")),
+            }
+        }
+    }
+
+    let img = jxl_image_from_raw(width, height, &format, pixels)
+        .ok_or_else(|| decode_error("pixel buffer does not match the image size"))?;
 
-    Ok((DynamicImage::ImageRgba8(img_buf), icc_profile))
+    Ok((img, icc_profile))
 }
 
-/// Decode any supported image format, returning the image + optional ICC profile
+/// Wrap the buffer libjxl filled in the `DynamicImage` matching its layout.
+fn jxl_image_from_raw(
+    width: u32,
+    height: u32,
+    format: &jpegxl_sys::common::types::JxlPixelFormat,
+    pixels: Vec<u8>,
+) -> Option<DynamicImage> {
+    use jpegxl_sys::common::types::JxlDataType;
+
+    if format.data_type == JxlDataType::Uint16 {
+        use image::ImageBuffer;
+        // Native-endian samples spanning the full 16-bit range
+        let samples: Vec<u16> = pixels
+            .chunks_exact(2)
+            .map(|sample| u16::from_ne_bytes([sample[0], sample[1]]))
+            .collect();
+        match format.num_channels {
+            1 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLuma16),
+            2 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageLumaA16),
+            3 => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgb16),
+            _ => ImageBuffer::from_raw(width, height, samples).map(DynamicImage::ImageRgba16),
+        }
+    } else {
+        match format.num_channels {
+            1 => image::GrayImage::from_raw(width, height, pixels).map(DynamicImage::ImageLuma8),
+            2 => image::GrayAlphaImage::from_raw(width, height, pixels)
+                .map(DynamicImage::ImageLumaA8),
+            3 => image::RgbImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgb8),
+            _ => image::RgbaImage::from_raw(width, height, pixels).map(DynamicImage::ImageRgba8),
+        }
+    }
+}
+
+/// Decode any supported image format, returning the image + optional ICC profile.
+/// Every decoder checks the dimensions in the headers against the settings'
+/// decode limits before allocating the pixels; the settings also pick how a
+/// camera RAW is rendered and which image of a HEIF container is read.
 fn decode_image_with_icc(
     input_data: &[u8],
     input_format: &str,
+    settings: &CompressionSettings,
 ) -> CompressionResult<(DynamicImage, Option<Vec<u8>>)> {
-    use image::ImageFormat;
+    use image::codecs::{
+        bmp::BmpDecoder, gif::GifDecoder, jpeg::JpegDecoder, png::PngDecoder, tiff::TiffDecoder,
+        webp::WebPDecoder,
+    };
+
+    let cursor = std::io::Cursor::new(input_data);
+    let limits = &settings.decode_limits;
 
-    match input_format.to_lowercase().as_str() {
-        "heic" | "heif" => decode_heic(input_data),
-        "png" => {
-            let img =
-                image::load_from_memory_with_format(input_data, ImageFormat::Png).map_err(|e| {
-                    CompressionError::ProcessingError(format!("Image decoding failed: {e}"))
-                })?;
-            Ok((img, None))
+    // A mislabelled source is decoded as what it really is
+    let format = sniff_image_format(input_data).map_or(input_format, |sniffed| {
+        resolve_format(sniffed, input_format)
+    });
+    match format.to_lowercase().as_str() {
+        "heic" | "heif" => {
+            decode_heic(input_data, limits, settings.heif_image).map(|(img, icc, _)| (img, icc))
         }
+        // Developed to sRGB, or the camera's own rendering: no profile to carry
+        raw if is_camera_raw(raw) => {
+            decode_raw(input_data, settings.raw_mode, limits).map(|img| (img, None))
+        }
+        "jxl" => decode_jxl(input_data, limits),
+        "png" => decode_with_icc(
+            PngDecoder::with_limits(cursor, image_limits(limits)).map_err(decoding_error)?,
+            limits,
+        ),
         "jpg" | "jpeg" => {
-            let img = image::load_from_memory_with_format(input_data, ImageFormat::Jpeg).map_err(
-                |e| CompressionError::ProcessingError(format!("Image decoding failed: {e}")),
-            )?;
-            Ok((img, None))
+            decode_with_icc(JpegDecoder::new(cursor).map_err(decoding_error)?, limits)
         }
-        "webp" => {
-            let img = image::load_from_memory_with_format(input_data, ImageFormat::WebP).map_err(
-                |e| CompressionError::ProcessingError(format!("Image decoding failed: {e}")),
-            )?;
-            Ok((img, None))
+        "webp" => decode_with_icc(WebPDecoder::new(cursor).map_err(decoding_error)?, limits),
+        // An animation decodes to its first frame for still output
+        "gif" => decode_with_icc(GifDecoder::new(cursor).map_err(decoding_error)?, limits),
+        // The first page of a multi-page TIFF; 16-bit samples stay 16-bit
+        "tiff" | "tif" => {
+            decode_with_icc(TiffDecoder::new(cursor).map_err(decoding_error)?, limits)
         }
+        "bmp" => decode_with_icc(BmpDecoder::new(cursor).map_err(decoding_error)?, limits),
         _ => Err(CompressionError::UnsupportedFormat(format!(
             "Unsupported format: {input_format}"
         ))),
     }
 }
 
+/// The ICC profile of a PNG, JPEG or WebP, read from its headers without
+/// decoding the pixels
+fn read_icc_profile(input_data: &[u8], input_format: &str) -> Option<Vec<u8>> {
+    use image::ImageDecoder;
+    use image::codecs::{jpeg::JpegDecoder, png::PngDecoder, webp::WebPDecoder};
+
+    let cursor = std::io::Cursor::new(input_data);
+    let icc_profile = match input_format.to_lowercase().as_str() {
+        "png" => PngDecoder::new(cursor).ok()?.icc_profile(),
+        "jpg" | "jpeg" => JpegDecoder::new(cursor).ok()?.icc_profile(),
+        "webp" => WebPDecoder::new(cursor).ok()?.icc_profile(),
+        _ => return None,
+    };
+    icc_profile.ok().flatten()
+}
+
+/// Stored dimensions of a PNG, JPEG or WebP, read from its headers
+fn read_dimensions(input_data: &[u8], input_format: &str) -> Option<(u32, u32)> {
+    let format = image::ImageFormat::from_extension(input_format)?;
+    image::ImageReader::with_format(std::io::Cursor::new(input_data), format)
+        .into_dimensions()
+        .ok()
+}
+
+/// Stored colour type of a PNG, JPEG or WebP, read from its headers
+fn read_color_type(input_data: &[u8], input_format: &str) -> Option<image::ColorType> {
+    use image::ImageDecoder;
+
+    let format = image::ImageFormat::from_extension(input_format)?;
+    image::ImageReader::with_format(std::io::Cursor::new(input_data), format)
+        .into_decoder()
+        .ok()
+        .map(|decoder| decoder.color_type())
+}
+
+/// Whether the pixels will be converted to sRGB, resized or reduced to 8 bits,
+/// any of which rules out the lossless passthroughs that never decode them
+fn alters_pixels(input_data: &[u8], input_format: &str, settings: &CompressionSettings) -> bool {
+    let converts_to_srgb =
+        settings.convert_to_srgb && read_icc_profile(input_data, input_format).is_some();
+    let reduces_depth = settings.reduce_to_8bit
+        && read_color_type(input_data, input_format).is_some_and(is_high_bit_depth);
+    converts_to_srgb || reduces_depth || resizes(input_data, input_format, &settings.resize)
+}
+
+/// More than one byte per channel: 16-bit or float samples
+fn is_high_bit_depth(color: image::ColorType) -> bool {
+    color.bytes_per_pixel() > color.channel_count()
+}
+
+/// The same pixels with 8 bits per channel, keeping grey and alpha as they are
+fn reduce_to_8bit(img: DynamicImage) -> DynamicImage {
+    if !is_high_bit_depth(img.color()) {
+        return img;
+    }
+    match (img.color().has_color(), img.color().has_alpha()) {
+        (false, false) => DynamicImage::ImageLuma8(img.to_luma8()),
+        (false, true) => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        (true, false) => DynamicImage::ImageRgb8(img.to_rgb8()),
+        (true, true) => DynamicImage::ImageRgba8(img.to_rgba8()),
+    }
+}
+
+/// Whether `resize` shrinks the image. The bounds apply to the upright image, so
+/// a 90° EXIF rotation swaps the stored sides first.
+fn resizes(input_data: &[u8], input_format: &str, resize: &ResizeOptions) -> bool {
+    use image::metadata::Orientation;
+
+    if *resize == ResizeOptions::default() {
+        return false;
+    }
+    // Unreadable headers: let the decode path find out
+    let Some((width, height)) = read_dimensions(input_data, input_format) else {
+        return true;
+    };
+    let rotated = matches!(
+        extract_metadata(input_data, input_format).orientation(),
+        Some(
+            Orientation::Rotate90
+                | Orientation::Rotate270
+                | Orientation::Rotate90FlipH
+                | Orientation::Rotate270FlipH
+        )
+    );
+    let upright = if rotated {
+        (height, width)
+    } else {
+        (width, height)
+    };
+    resize.target_dimensions(upright.0, upright.1) != upright
+}
+
+/// Lanczos3 downscale in premultiplied alpha, so the colour of fully
+/// transparent pixels cannot bleed into the visible edge. Alpha images come back
+/// as 8- or 16-bit RGBA, depending on the source depth.
+fn resize_image(img: &DynamicImage, width: u32, height: u32) -> DynamicImage {
+    use image::imageops::FilterType;
+
+    if !img.color().has_alpha() {
+        return img.resize_exact(width, height, FilterType::Lanczos3);
+    }
+
+    let mut premultiplied = img.to_rgba32f();
+    for pixel in premultiplied.pixels_mut() {
+        let alpha = pixel[3];
+        for channel in &mut pixel.0[..3] {
+            *channel *= alpha;
+        }
+    }
+    let mut resized = image::imageops::resize(&premultiplied, width, height, FilterType::Lanczos3);
+    divide_by_alpha(&mut resized);
+
+    let resized = DynamicImage::ImageRgba32F(resized);
+    if is_high_bit_depth(img.color()) {
+        DynamicImage::ImageRgba16(resized.to_rgba16())
+    } else {
+        DynamicImage::ImageRgba8(resized.to_rgba8())
+    }
+}
+
+/// Turn premultiplied float RGBA back into straight alpha. Lanczos overshoots
+/// around hard edges, so alpha and colour are clamped before and after.
+fn divide_by_alpha(pixels: &mut image::Rgba32FImage) {
+    for pixel in pixels.pixels_mut() {
+        let alpha = pixel[3].clamp(0.0, 1.0);
+        pixel[3] = alpha;
+        for channel in &mut pixel.0[..3] {
+            *channel = if alpha > 0.0 {
+                (*channel / alpha).clamp(0.0, 1.0)
+            } else {
+                0.0
+            };
+        }
+    }
+}
+
+/// Decode through an `image` decoder, keeping the ICC profile it found in the
+/// container (PNG `iCCP`, JPEG APP2 `ICC_PROFILE` markers, WebP `ICCP` chunk,
+/// TIFF `InterColorProfile` tag)
+fn decode_with_icc(
+    mut decoder: impl image::ImageDecoder,
+    limits: &DecodeLimits,
+) -> CompressionResult<(DynamicImage, Option<Vec<u8>>)> {
+    let (width, height) = decoder.dimensions();
+    limits.check(width, height, decoder.total_bytes())?;
+
+    // A malformed profile is dropped rather than failing the whole decode. Read
+    // before the limits are set: the TIFF decoder loses its tags when they are.
+    let icc_profile = decoder.icc_profile().ok().flatten();
+
+    // Also bounds what the decoder allocates besides the pixels
+    decoder
+        .set_limits(image_limits(limits))
+        .map_err(decoding_error)?;
+    let img = DynamicImage::from_decoder(decoder).map_err(decoding_error)?;
+    Ok((img, icc_profile))
+}
+
+/// `limits` as the `image` crate expresses them
+pub(super) fn image_limits(limits: &DecodeLimits) -> image::Limits {
+    let mut image_limits = image::Limits::default();
+    image_limits.max_alloc = Some(limits.max_alloc_bytes);
+    image_limits
+}
+
+pub(super) fn decoding_error(e: image::ImageError) -> CompressionError {
+    match e {
+        image::ImageError::Limits(e) => CompressionError::DecodeLimitExceeded(e.to_string()),
+        e => CompressionError::ProcessingError(format!("Image decoding failed: {e}")),
+    }
+}
+
+/// Decode for re-encoding: the pixels are turned upright according to the EXIF
+/// orientation, which is then reset to 1 in the metadata written back with them,
+/// and downscaled per `settings.resize`. With `convert_to_srgb` the pixels are
+/// moved to sRGB and the profile dropped; the ICC profile and metadata returned
+/// are those the policy lets through.
+fn decode_upright(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(DynamicImage, Option<Vec<u8>>, ImageMetadata)> {
+    let policy = settings.metadata_policy;
+    let format = input_format.to_lowercase();
+    // HEIF metadata comes from the context the decoder opened
+    let (mut img, icc_profile, mut metadata) = if matches!(format.as_str(), "heic" | "heif") {
+        decode_heic(input_data, &settings.decode_limits, settings.heif_image)?
+    } else {
+        let (img, icc_profile) = decode_image_with_icc(input_data, input_format, settings)?;
+        (img, icc_profile, extract_metadata(input_data, input_format))
+    };
+
+    let icc_profile = match icc_profile {
+        Some(icc) if settings.convert_to_srgb => match convert_to_srgb(&img, &icc) {
+            Ok(converted) => {
+                img = converted;
+                None
+            }
+            // Unconvertible (CMYK, broken profile): the tagged source is still right
+            Err(e) => {
+                log::warn!("Keeping the ICC profile, sRGB conversion failed: {e}");
+                Some(icc)
+            }
+        },
+        icc_profile => icc_profile,
+    };
+
+    // libheif (irot/imir), libjxl (codestream orientation) and the RAW decoder
+    // (TIFF tag) already decode upright; the EXIF tag of those containers is
+    // informative only
+    let decoder_applies_orientation =
+        matches!(format.as_str(), "heic" | "heif" | "jxl") || is_camera_raw(&format);
+    if !decoder_applies_orientation && let Some(orientation) = metadata.orientation() {
+        img.apply_orientation(orientation);
+    }
+    metadata.reset_orientation();
+
+    let (width, height) = settings.resize.target_dimensions(img.width(), img.height());
+    if (width, height) != (img.width(), img.height()) {
+        img = resize_image(&img, width, height);
+    }
+    if settings.reduce_to_8bit {
+        img = reduce_to_8bit(img);
+    }
+
+    Ok((
+        img,
+        icc_profile.filter(|_| policy.keeps_icc()),
+        metadata.for_policy(policy),
+    ))
+}
+
+/// How a target-size or perceptual-target compression found its quality: the
+/// quality it settled on and how many encodes the search took
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct QualitySearch {
+    pub quality: u8,
+    pub passes: u32,
+    /// SSIM the output reached, for a perceptual target
+    pub ssim: Option<f64>,
+}
+
 /// Result of a compression operation
 #[derive(Debug, Clone)]
 pub struct CompressionOutput {
-    pub output_path: std::path::PathBuf,
+    /// Where the output was written; `None` for an in-memory compression
+    pub output_path: Option<std::path::PathBuf>,
     pub original_size: u64,
     pub compressed_size: u64,
     pub format: OutputFormat,
     pub savings_percent: f64,
+    /// Width and height of the written image, when known
+    pub dimensions: Option<(u32, u32)>,
+    /// Set when the quality was searched for rather than taken from the settings
+    pub quality_search: Option<QualitySearch>,
+    /// Fidelity of the output to the source, when it could be measured
+    pub metrics: Option<QualityMetrics>,
+    /// Why `metrics` is `None`
+    pub unmeasured: Option<Unmeasured>,
+    /// Whether the written pixels differ from those that were encoded: never for
+    /// a passthrough, and not when a lossy encoder's output decodes back intact
+    pub lossy: bool,
+    /// Allowed formats `auto-best` did not let compete: AVIF when the
+    /// candidates are held to a fidelity floor, JPEG for a transparent source
+    /// when transparency is kept, every format but WebP for an animation
+    pub excluded_formats: Vec<OutputFormat>,
 }
 
 impl CompressionOutput {
     pub fn new(
-        output_path: std::path::PathBuf,
+        output_path: Option<std::path::PathBuf>,
         original_size: u64,
         compressed_size: u64,
         format: OutputFormat,
+        dimensions: Option<(u32, u32)>,
     ) -> Self {
         let savings_percent = if original_size > 0 {
             ((original_size as f64 - compressed_size as f64) / original_size as f64) * 100.0
@@ -135,6 +757,12 @@ impl CompressionOutput {
             compressed_size,
             format,
             savings_percent,
+            dimensions,
+            quality_search: None,
+            metrics: None,
+            unmeasured: None,
+            lossy: false,
+            excluded_formats: Vec::new(),
         }
     }
 }
@@ -150,549 +778,4497 @@ pub fn compress_file_to_file<P: AsRef<Path>>(
     let input_path = input_path.as_ref();
     let output_path = output_path.as_ref();
 
-    // Get original file size
-    let original_size = std::fs::metadata(input_path)
-        .map_err(|e| CompressionError::IoError(format!("Failed to get file metadata: {e}")))?
-        .len();
-
-    // Determine input format from extension
+    // Only a hint: the content's signature decides
     let input_format = input_path
         .extension()
         .and_then(|ext| ext.to_str())
-        .ok_or_else(|| CompressionError::UnsupportedFormat("No file extension".to_string()))?;
-
-    // Route to appropriate compression function based on target format
-    match settings.format {
-        OutputFormat::WebP => {
-            compress_to_webp_file(input_path, output_path, input_format, settings)?;
-        }
-        OutputFormat::Png => compress_to_png_file(input_path, output_path, input_format, settings)?,
-        OutputFormat::Jpeg => {
-            compress_to_jpeg_file(input_path, output_path, input_format, settings)?;
-        }
-    }
-
-    // Get compressed file size
-    let compressed_size = std::fs::metadata(output_path)
-        .map_err(|e| CompressionError::IoError(format!("Failed to get output file metadata: {e}")))?
-        .len();
-
-    Ok(CompressionOutput::new(
-        output_path.to_path_buf(),
-        original_size,
-        compressed_size,
-        settings.format,
-    ))
-}
+        .unwrap_or_default();
 
-// Private compression functions for each format (file-to-file)
-
-fn compress_to_webp_file(
-    input_path: &Path,
-    output_path: &Path,
-    input_format: &str,
-    settings: &CompressionSettings,
-) -> CompressionResult<()> {
     let input_data = std::fs::read(input_path)
         .map_err(|e| CompressionError::IoError(format!("Failed to read input file: {e}")))?;
-
-    let (img, icc_profile) = decode_image_with_icc(&input_data, input_format)?;
-
-    // Encode to WebP using the webp crate + tuned WebPConfig
-    let has_alpha = img.color().has_alpha();
-
-    let encoded = if has_alpha {
-        let rgba_img = img.to_rgba8();
-        let (width, height) = rgba_img.dimensions();
-        let encoder = webp::Encoder::from_rgba(rgba_img.as_raw(), width, height);
-        encode_webp_advanced(&encoder, settings)?
-    } else {
-        let rgb_img = img.to_rgb8();
-        let (width, height) = rgb_img.dimensions();
-        let encoder = webp::Encoder::from_rgb(rgb_img.as_raw(), width, height);
-        encode_webp_advanced(&encoder, settings)?
-    };
-
-    // Inject ICC profile into WebP RIFF container if present
-    let output_data = match icc_profile {
-        Some(ref icc) => inject_icc_into_webp(&encoded, icc),
-        None => encoded.to_vec(),
-    };
+    let (output_data, output) = compress_bytes(&input_data, input_format, settings)?;
 
     std::fs::write(output_path, &output_data)
         .map_err(|e| CompressionError::IoError(format!("Failed to write output file: {e}")))?;
 
-    Ok(())
+    Ok(CompressionOutput {
+        output_path: Some(output_path.to_path_buf()),
+        ..output
+    })
 }
 
-fn compress_to_png_file(
-    input_path: &Path,
-    output_path: &Path,
+/// Compress an encoded image held in memory. `input_format` is the extension
+/// the source would have on disk (`"png"`, `"jpg"`, ...), used only when its
+/// signature is not recognised. Nothing touches the filesystem, so the
+/// returned output has no `output_path`.
+pub fn compress_bytes(
+    input: &[u8],
     input_format: &str,
-    _settings: &CompressionSettings,
-) -> CompressionResult<()> {
-    if input_format.to_lowercase().as_str() == "png" {
-        // PNG -> PNG goes straight through oxipng, which preserves existing ICC chunks
-        let options = oxipng::Options::from_preset(3);
-        let input_data = std::fs::read(input_path)
-            .map_err(|e| CompressionError::IoError(format!("Failed to read PNG file: {e}")))?;
-        match oxipng::optimize_from_memory(&input_data, &options) {
-            Ok(optimized_data) => {
-                std::fs::write(output_path, optimized_data).map_err(|e| {
-                    CompressionError::IoError(format!("Failed to write optimized PNG: {e}"))
-                })?;
-            }
-            Err(_) => {
-                std::fs::copy(input_path, output_path).map_err(|e| {
-                    CompressionError::IoError(format!("Failed to copy PNG file: {e}"))
-                })?;
-            }
-        }
-        Ok(())
-    } else {
-        let input_data = std::fs::read(input_path)
-            .map_err(|e| CompressionError::IoError(format!("Failed to read input file: {e}")))?;
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, CompressionOutput)> {
+    validate_settings(settings)?;
+    let input_format = resolve_input_format(input, input_format)?;
+
+    // Route to appropriate compression function based on target format
+    let (output_data, written, quality_search) =
+        if settings.target_size.is_some() || settings.perceptual_target.is_some() {
+            let (output_data, written, search) =
+                compress_with_quality_search(input, input_format, settings)?;
+            (output_data, written, Some(search))
+        } else {
+            let (output_data, written) = match settings.format {
+                OutputFormat::WebP => compress_to_webp(input, input_format, settings)?,
+                OutputFormat::Png => compress_to_png(input, input_format, settings)?,
+                OutputFormat::Jpeg => compress_to_jpeg(input, input_format, settings)?,
+                OutputFormat::Avif => compress_to_avif(input, input_format, settings)?,
+                OutputFormat::Jxl => compress_to_jxl(input, input_format, settings)?,
+            };
+            (output_data, written, None)
+        };
 
-        let (img, icc_profile) = decode_image_with_icc(&input_data, input_format)?;
+    let output = describe_output(
+        input.len() as u64,
+        &output_data,
+        &written,
+        settings,
+        quality_search,
+    );
+    Ok((output_data, output))
+}
 
-        // Encode PNG with ICC profile using PngEncoder
-        encode_png_with_icc(&img, output_path, icc_profile.as_deref())?;
+/// What a compression of an `original_size`-byte source to `output_data`
+/// reports, measured against the pixels it was encoded from
+fn describe_output(
+    original_size: u64,
+    output_data: &[u8],
+    written: &Written,
+    settings: &CompressionSettings,
+    quality_search: Option<QualitySearch>,
+) -> CompressionOutput {
+    let measured = measure_written(written, output_data, settings.format);
+    let quality = quality_search.map_or(settings.quality, |search| search.quality);
+    CompressionOutput {
+        quality_search,
+        lossy: written.is_lossy(measured.as_ref().ok(), settings, quality),
+        metrics: measured.ok(),
+        unmeasured: measured.err(),
+        ..CompressionOutput::new(
+            None,
+            original_size,
+            output_data.len() as u64,
+            settings.format,
+            written.dimensions(),
+        )
+    }
+}
 
-        // Optimize with oxipng (preserves iCCP chunks by default)
-        let options = oxipng::Options::from_preset(3);
-        if let Ok(png_data) = std::fs::read(output_path)
-            && let Ok(optimized_data) = oxipng::optimize_from_memory(&png_data, &options)
-        {
-            let _ = std::fs::write(output_path, optimized_data);
-        }
+/// A still source decoded once to be encoded several times, as the variants of
+/// a responsive set are
+pub struct DecodedImage {
+    img: DynamicImage,
+    icc_profile: Option<Vec<u8>>,
+    metadata: ImageMetadata,
+    original_size: u64,
+}
 
-        Ok(())
+impl DecodedImage {
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.img.width(), self.img.height())
     }
 }
 
-fn compress_to_jpeg_file(
-    input_path: &Path,
-    output_path: &Path,
+/// Decode `input` as [`compress_bytes`] does before encoding it: upright,
+/// bounded by `settings.resize`, with the profile and metadata the policy keeps.
+/// An animation yields its first frame.
+pub fn decode_still(
+    input: &[u8],
     input_format: &str,
     settings: &CompressionSettings,
-) -> CompressionResult<()> {
-    let input_data = std::fs::read(input_path)
-        .map_err(|e| CompressionError::IoError(format!("Failed to read input file: {e}")))?;
-
-    let (img, icc_profile) = decode_image_with_icc(&input_data, input_format)?;
-
-    // Convert to RGB (JPEG does not support transparency)
-    let rgb_img = img.to_rgb8();
-    let (width, height) = rgb_img.dimensions();
-    let pixels = rgb_img.as_raw();
+) -> CompressionResult<DecodedImage> {
+    validate_settings(settings)?;
+    let input_format = resolve_input_format(input, input_format)?;
+    let (img, icc_profile, metadata) = decode_upright(input, input_format, settings)?;
+    Ok(DecodedImage {
+        img,
+        icc_profile,
+        metadata,
+        original_size: input.len() as u64,
+    })
+}
 
-    let jpeg_data = encode_jpeg_mozjpeg(
-        pixels,
-        width,
-        height,
-        settings.quality,
-        icc_profile.as_deref(),
-    )?;
+/// Encode a decoded image to `settings.format`, downscaled by
+/// `settings.resize` on top of what decoding applied. Every output is a fresh
+/// encode, so lossless JPEG, which rewrites the source itself, is refused.
+pub fn compress_decoded(
+    source: &DecodedImage,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, CompressionOutput)> {
+    validate_settings(settings)?;
+    if settings.format == OutputFormat::Jpeg && settings.encoder.jpeg.lossless {
+        return Err(CompressionError::InvalidSettings(
+            "Lossless JPEG output needs a JPEG source whose pixels are left unchanged".to_string(),
+        ));
+    }
 
-    std::fs::write(output_path, &jpeg_data)
-        .map_err(|e| CompressionError::IoError(format!("Failed to write JPEG file: {e}")))?;
+    let (width, height) = settings
+        .resize
+        .target_dimensions(source.img.width(), source.img.height());
+    let img = if (width, height) == source.dimensions() {
+        source.img.clone()
+    } else {
+        resize_image(&source.img, width, height)
+    };
+    let icc_profile = source.icc_profile.as_deref();
+    let (data, quality_search) =
+        if settings.target_size.is_some() || settings.perceptual_target.is_some() {
+            check_quality_search(settings)?;
+            let (data, search) = search_quality(&img, icc_profile, &source.metadata, settings)?;
+            (data, Some(search))
+        } else {
+            (
+                encode_still(&img, icc_profile, &source.metadata, settings)?,
+                None,
+            )
+        };
 
-    Ok(())
+    let written = Written::Encoded(img);
+    let output = describe_output(
+        source.original_size,
+        &data,
+        &written,
+        settings,
+        quality_search,
+    );
+    Ok((data, output))
 }
 
-/// Encode a DynamicImage to PNG with optional ICC profile
-fn encode_png_with_icc(
-    img: &DynamicImage,
-    output_path: &Path,
-    icc_profile: Option<&[u8]>,
-) -> CompressionResult<()> {
-    use image::ImageEncoder;
+/// How many `auto-best` candidates encode at once. They share one decoded
+/// image, but each holds its own encoder state and output, and AVIF alone keeps
+/// a core busy for long.
+const SMALLEST_OUTPUT_WORKERS: usize = 2;
 
-    let output_file = std::fs::File::create(output_path)
-        .map_err(|e| CompressionError::IoError(format!("Failed to create output file: {e}")))?;
-    let writer = std::io::BufWriter::new(output_file);
+/// Compress `input` to each of `formats`, [`SMALLEST_OUTPUT_WORKERS`] at a time,
+/// and keep the smallest output. The source is decoded once, as an animation or
+/// a still image, and every candidate is encoded from it, so only lossless JPEG
+/// still rewrites the source itself. An animation only competes as WebP, the one output that keeps its
+/// frames, and `keep_transparency` leaves JPEG out for a source with
+/// transparent pixels.
+///
+/// The same quality means different fidelity to different codecs, so unless a
+/// target size bounds them all, every candidate is held to one SSIM floor: the
+/// perceptual target, else what a JPEG reaches at `settings.quality`. A lossy
+/// candidate searches for its smallest output above it, a lossless one (PNG,
+/// lossless WebP) must clear it as encoded, and AVIF, which cannot be decoded to
+/// be scored, does not compete; the output lists the formats left out this way.
+/// A candidate that fails (a target size for PNG,
+/// a floor out of reach, ...) drops out; when none is left, the first error is
+/// returned.
+pub fn compress_bytes_to_smallest(
+    input: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+    formats: &[OutputFormat],
+    keep_transparency: bool,
+) -> CompressionResult<(Vec<u8>, CompressionOutput)> {
+    validate_settings(settings)?;
+    let input_format = resolve_input_format(input, input_format)?;
+    let no_candidate = || {
+        CompressionError::InvalidSettings(
+            "None of the allowed formats can hold this image".to_string(),
+        )
+    };
 
-    let mut encoder = image::codecs::png::PngEncoder::new(writer);
+    if let Some(animation) =
+        decode_animation(input, &input_format.to_lowercase(), &settings.decode_limits)?
+    {
+        if !formats.contains(&OutputFormat::WebP) {
+            return Err(no_candidate());
+        }
+        return compress_animation_to_webp(input, input_format, animation, settings, formats);
+    }
 
-    if let Some(icc) = icc_profile {
-        let _ = encoder.set_icc_profile(icc.to_vec());
+    let (img, icc_profile, metadata) = decode_upright(input, input_format, settings)?;
+    let is_transparent = keep_transparency && has_transparency(&img);
+    let fidelity_floor = match settings.perceptual_target {
+        _ if settings.target_size.is_some() => None,
+        Some(target) => Some(target.min_ssim()),
+        None => Some(jpeg_fidelity(&img, settings)?),
+    };
+    let candidates: Vec<OutputFormat> = formats
+        .iter()
+        .copied()
+        .filter(|&format| !is_transparent || format != OutputFormat::Jpeg)
+        .filter(|&format| fidelity_floor.is_none() || format != OutputFormat::Avif)
+        .collect();
+    if candidates.is_empty() {
+        return Err(no_candidate());
     }
+    let excluded_formats = formats
+        .iter()
+        .copied()
+        .filter(|format| !candidates.contains(format))
+        .collect();
 
-    let rgba = img.to_rgba8();
-    let (width, height) = rgba.dimensions();
-    encoder
-        .write_image(
-            rgba.as_raw(),
-            width,
-            height,
-            image::ExtendedColorType::Rgba8,
+    let results = encode_candidates(&candidates, |format| {
+        let settings = candidate_settings(settings, format, fidelity_floor);
+        if format == OutputFormat::Jpeg && settings.encoder.jpeg.lossless {
+            // Rewrites the source's coefficients: nothing is lost to check
+            let (data, written) = compress_to_jpeg(input, input_format, &settings)?;
+            return Ok((data, Some(written), None));
+        }
+        let (data, quality_search) = encode_candidate(
+            &img,
+            icc_profile.as_deref(),
+            &metadata,
+            &settings,
+            fidelity_floor,
+        )?;
+        Ok((data, None, quality_search))
+    });
+
+    let mut smallest: Option<(OutputFormat, SmallestCandidate)> = None;
+    let mut first_error = None;
+    for (&format, result) in candidates.iter().zip(results) {
+        match result {
+            // Ties go to the format listed first
+            Ok(candidate)
+                if smallest
+                    .as_ref()
+                    .is_none_or(|(_, (data, ..))| candidate.0.len() < data.len()) =>
+            {
+                smallest = Some((format, candidate));
+            }
+            Ok(_) => {}
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
+        }
+    }
+    let Some((format, (data, written, quality_search))) = smallest else {
+        return Err(first_error.unwrap_or_else(no_candidate));
+    };
+    // Only the winner is measured
+    let written = written.unwrap_or(Written::Encoded(img));
+    let settings = candidate_settings(settings, format, fidelity_floor);
+    let output = CompressionOutput {
+        excluded_formats,
+        ..describe_output(
+            input.len() as u64,
+            &data,
+            &written,
+            &settings,
+            quality_search,
+        )
+    };
+    Ok((data, output))
+}
+
+/// The `auto-best` output of an animation, already decoded: animated WebP, the
+/// one output that keeps its frames, as [`compress_bytes`] would write it
+fn compress_animation_to_webp(
+    input: &[u8],
+    input_format: &str,
+    animation: Animation,
+    settings: &CompressionSettings,
+    formats: &[OutputFormat],
+) -> CompressionResult<(Vec<u8>, CompressionOutput)> {
+    let settings = CompressionSettings {
+        format: OutputFormat::WebP,
+        ..settings.clone()
+    };
+    let (animation, metadata) =
+        prepare_animation_for_webp(animation, input, input_format, &settings);
+    let (data, written, quality_search) =
+        if settings.target_size.is_some() || settings.perceptual_target.is_some() {
+            check_quality_search(&settings)?;
+            let (data, written, search) = search_animation(&animation, &metadata, &settings)?;
+            (data, written, Some(search))
+        } else {
+            let data = encode_animation(&animation, &metadata, &settings)?;
+            (data, Written::Animated(animation.dimensions()), None)
+        };
+
+    let output = CompressionOutput {
+        excluded_formats: formats
+            .iter()
+            .copied()
+            .filter(|&format| format != OutputFormat::WebP)
+            .collect(),
+        ..describe_output(
+            input.len() as u64,
+            &data,
+            &written,
+            &settings,
+            quality_search,
+        )
+    };
+    Ok((data, output))
+}
+
+/// Encode one `auto-best` candidate from the shared decoded image: searched
+/// for when its settings carry a target, else encoded once and checked against
+/// the fidelity floor
+fn encode_candidate(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
+    fidelity_floor: Option<f64>,
+) -> CompressionResult<(Vec<u8>, Option<QualitySearch>)> {
+    if settings.perceptual_target.is_some() || settings.target_size.is_some() {
+        check_quality_search(settings)?;
+        let (data, search) = search_quality(img, icc_profile, metadata, settings)?;
+        return Ok((data, Some(search)));
+    }
+    let data = encode_still(img, icc_profile, metadata, settings)?;
+    if let Some(min_ssim) = fidelity_floor {
+        let best_ssim = ssim(img, &decode_output(&data, settings.format)?)?;
+        if best_ssim < min_ssim {
+            return Err(CompressionError::PerceptualTargetUnreachable {
+                min_ssim,
+                best_ssim,
+            });
+        }
+    }
+    Ok((data, None))
+}
+
+/// An `auto-best` candidate's output, with what it was written from when that
+/// is not the shared decoded image, and how its quality was searched for
+type SmallestCandidate = (Vec<u8>, Option<Written>, Option<QualitySearch>);
+
+/// Run `encode` for every one of `formats` on [`SMALLEST_OUTPUT_WORKERS`]
+/// threads, each taking the next format as it finishes one, and return the
+/// results in the order of `formats`. An encoder that panics fails its format
+/// alone.
+fn encode_candidates<T: Send>(
+    formats: &[OutputFormat],
+    encode: impl Fn(OutputFormat) -> CompressionResult<T> + Sync,
+) -> Vec<CompressionResult<T>> {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    let next = AtomicUsize::new(0);
+    let encode_one = |format| {
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| encode(format))).unwrap_or_else(
+            |_| {
+                Err(CompressionError::ProcessingError(
+                    "An encoder panicked".to_string(),
+                ))
+            },
         )
-        .map_err(|e| CompressionError::ProcessingError(format!("PNG encoding failed: {e}")))?;
+    };
+    let mut done: Vec<(usize, CompressionResult<T>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..SMALLEST_OUTPUT_WORKERS.min(formats.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut done = Vec::new();
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(&format) = formats.get(index) else {
+                            return done;
+                        };
+                        done.push((index, encode_one(format)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap_or_default())
+            .collect()
+    });
+    done.sort_by_key(|(index, _)| *index);
+    done.into_iter().map(|(_, result)| result).collect()
+}
+
+/// The settings `format` competes with for the smallest output: the fidelity
+/// floor becomes its perceptual target when its encoder has a quality to search
+fn candidate_settings(
+    settings: &CompressionSettings,
+    format: OutputFormat,
+    fidelity_floor: Option<f64>,
+) -> CompressionSettings {
+    let searchable = match format {
+        OutputFormat::WebP => settings.encoder.webp.mode == WebpMode::Lossy,
+        OutputFormat::Jpeg => !settings.encoder.jpeg.lossless,
+        OutputFormat::Jxl => true,
+        OutputFormat::Png | OutputFormat::Avif => false,
+    };
+    CompressionSettings {
+        format,
+        perceptual_target: fidelity_floor
+            .filter(|_| searchable)
+            .map(PerceptualTarget::MinSsim),
+        ..settings.clone()
+    }
+}
+
+/// Largest width or height libjpeg encodes
+const JPEG_MAX_DIMENSION: u32 = 65_500;
+
+/// The SSIM a JPEG at `settings.quality` reaches on `img`: what the quality
+/// stands for when formats compete, whichever of them win. An image too large
+/// for JPEG has no such yardstick and is held to a visually lossless output.
+fn jpeg_fidelity(img: &DynamicImage, settings: &CompressionSettings) -> CompressionResult<f64> {
+    if img.width().max(img.height()) > JPEG_MAX_DIMENSION {
+        return Ok(PerceptualTarget::VisuallyLossless.min_ssim());
+    }
+    let jpeg = encode_jpeg(
+        img,
+        None,
+        &ImageMetadata::default(),
+        settings.quality,
+        settings.encoder.jpeg,
+    )?;
+    ssim(img, &decode_output(&jpeg, OutputFormat::Jpeg)?)
+}
+
+/// Whether any pixel of `img` is less than fully opaque
+fn has_transparency(img: &DynamicImage) -> bool {
+    img.color().has_alpha() && img.to_rgba8().pixels().any(|pixel| pixel[3] < u8::MAX)
+}
+
+/// Format of `input`: the one its signature names, else the declared one (which
+/// also tells a TIFF-based camera RAW from a plain TIFF)
+fn resolve_input_format<'a>(input: &[u8], declared: &'a str) -> CompressionResult<&'a str> {
+    match sniff_image_format(input) {
+        Some(sniffed) => Ok(resolve_format(sniffed, declared)),
+        None if declared.is_empty() => Err(CompressionError::UnsupportedFormat(
+            "Unrecognized image content and no file extension".to_string(),
+        )),
+        None => Ok(declared),
+    }
+}
+
+/// What a format writer reports about the image it wrote
+enum Written {
+    /// Encoded from these pixels, decoded from the source
+    Encoded(DynamicImage),
+    /// Passed through losslessly without decoding, with its stored dimensions
+    /// when the headers gave them
+    Untouched(Option<(u32, u32)>),
+    /// Encoded as an animation on a canvas of these dimensions
+    Animated((u32, u32)),
+}
+
+impl Written {
+    fn dimensions(&self) -> Option<(u32, u32)> {
+        match self {
+            Written::Encoded(img) => Some((img.width(), img.height())),
+            Written::Untouched(dimensions) => *dimensions,
+            Written::Animated(dimensions) => Some(*dimensions),
+        }
+    }
+
+    /// Whether what was written lost anything. A passthrough never does. An
+    /// encode does when its encoder can (a palette, lossy WebP, any JPEG
+    /// re-encode, AVIF, JPEG XL below 100) and, when the output was measured,
+    /// it did not decode back identical: a palette that fell back to lossless,
+    /// say. Lossless WebP may rewrite the colour of invisible pixels, so its
+    /// measure is not consulted. `quality` is the one encoded at, which a
+    /// search may have lowered.
+    fn is_lossy(
+        &self,
+        metrics: Option<&QualityMetrics>,
+        settings: &CompressionSettings,
+        quality: u8,
+    ) -> bool {
+        let encoder_is_lossy = match settings.format {
+            OutputFormat::Png => settings.encoder.png.quantize && quality < 100,
+            OutputFormat::WebP => settings.encoder.webp.mode != WebpMode::Lossless,
+            OutputFormat::Jpeg | OutputFormat::Avif => true,
+            OutputFormat::Jxl => quality < 100,
+        };
+        match self {
+            Written::Untouched(_) => false,
+            Written::Animated(_) => encoder_is_lossy,
+            Written::Encoded(_) => encoder_is_lossy && metrics.is_none_or(|m| m.max_error > 0),
+        }
+    }
+}
+
+/// Fidelity of the written output to the pixels it was encoded from. A lossless
+/// passthrough is identical by construction; an encoded output is decoded
+/// again, except AVIF, for which there is no decoder, and an animation, which
+/// has no single reference image. Measuring is diagnostic, so a failure is
+/// logged and reported as [`Unmeasured::Failed`] rather than failing the
+/// compression.
+fn measure_written(
+    written: &Written,
+    output_data: &[u8],
+    format: OutputFormat,
+) -> Result<QualityMetrics, Unmeasured> {
+    let reference = match written {
+        Written::Untouched(_) => return Ok(QualityMetrics::IDENTICAL),
+        Written::Animated(_) => return Err(Unmeasured::Animation),
+        Written::Encoded(_) if format == OutputFormat::Avif => return Err(Unmeasured::AvifOutput),
+        Written::Encoded(img) => img,
+    };
+    decode_output(output_data, format)
+        .and_then(|decoded| measure(reference, &decoded))
+        .map_err(|e| {
+            log::warn!("Could not measure the output quality: {e}");
+            Unmeasured::Failed
+        })
+}
+
+/// Decode an output this engine wrote, to compare it with its source. It is no
+/// larger than the source, which has already been held to the decode limits.
+fn decode_output(data: &[u8], format: OutputFormat) -> CompressionResult<DynamicImage> {
+    const UNLIMITED: DecodeLimits = DecodeLimits {
+        max_pixels: u64::MAX,
+        max_alloc_bytes: u64::MAX,
+    };
+    match format {
+        OutputFormat::Jxl => decode_jxl(data, &UNLIMITED).map(|(img, _)| img),
+        _ => image::load_from_memory(data)
+            .map_err(|e| CompressionError::ProcessingError(format!("Output decoding failed: {e}"))),
+    }
+}
+
+// Private compression functions for each format (bytes-to-bytes)
+
+fn compress_to_webp(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written)> {
+    if let Some((animation, metadata)) =
+        decode_animation_for_webp(input_data, input_format, settings)?
+    {
+        let output_data = encode_animation(&animation, &metadata, settings)?;
+        return Ok((output_data, Written::Animated(animation.dimensions())));
+    }
+
+    let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+    let output_data = encode_webp(&img, icc_profile.as_deref(), &metadata, settings)?;
+
+    Ok((output_data, Written::Encoded(img)))
+}
+
+/// Decode an animated GIF or WebP for animated WebP output, with every frame
+/// moved to sRGB and downscaled as [`decode_upright`] does for a still image.
+/// A still source yields `None`.
+fn decode_animation_for_webp(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<Option<(Animation, ImageMetadata)>> {
+    let animation = decode_animation(
+        input_data,
+        &input_format.to_lowercase(),
+        &settings.decode_limits,
+    )?;
+    Ok(animation
+        .map(|animation| prepare_animation_for_webp(animation, input_data, input_format, settings)))
+}
+
+/// Move every frame of a decoded animation to sRGB and downscale it, as
+/// [`decode_upright`] does for a still image, and read the metadata the policy
+/// keeps
+fn prepare_animation_for_webp(
+    mut animation: Animation,
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> (Animation, ImageMetadata) {
+    if settings.convert_to_srgb
+        && let Some(icc) = &animation.icc_profile
+    {
+        let converted: CompressionResult<Vec<_>> = animation
+            .frames
+            .iter()
+            .map(|frame| {
+                convert_to_srgb(&DynamicImage::ImageRgba8(frame.image.clone()), icc)
+                    .map(DynamicImage::into_rgba8)
+            })
+            .collect();
+        match converted {
+            Ok(images) => {
+                for (frame, image) in animation.frames.iter_mut().zip(images) {
+                    frame.image = image;
+                }
+                animation.icc_profile = None;
+            }
+            Err(e) => log::warn!("Keeping the ICC profile, sRGB conversion failed: {e}"),
+        }
+    }
+
+    let (width, height) = animation.dimensions();
+    let (target_width, target_height) = settings.resize.target_dimensions(width, height);
+    if (target_width, target_height) != (width, height) {
+        for frame in &mut animation.frames {
+            let image = DynamicImage::ImageRgba8(std::mem::take(&mut frame.image));
+            frame.image = resize_image(&image, target_width, target_height).into_rgba8();
+        }
+    }
+
+    let policy = settings.metadata_policy;
+    if !policy.keeps_icc() {
+        animation.icc_profile = None;
+    }
+    let metadata = extract_metadata(input_data, input_format).for_policy(policy);
+    (animation, metadata)
+}
+
+/// Encode an animation to WebP with the still-image configuration, then add
+/// its ICC profile and the metadata chunks
+fn encode_animation(
+    animation: &Animation,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
+) -> CompressionResult<Vec<u8>> {
+    let encoded = encode_animated_webp(animation, &webp_config(settings)?)?;
+    let output_data = match &animation.icc_profile {
+        Some(icc) => inject_icc_into_webp(&encoded, icc),
+        None => encoded,
+    };
+    Ok(inject_metadata_into_webp(&output_data, metadata))
+}
+
+/// Encode to WebP using the webp crate + tuned WebPConfig, then add the ICC
+/// profile and metadata chunks to the RIFF container
+fn encode_webp(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
+) -> CompressionResult<Vec<u8>> {
+    let encoded = if img.color().has_alpha() {
+        let rgba_img = img.to_rgba8();
+        let (width, height) = rgba_img.dimensions();
+        let encoder = webp::Encoder::from_rgba(rgba_img.as_raw(), width, height);
+        encode_webp_advanced(&encoder, settings)?
+    } else {
+        let rgb_img = img.to_rgb8();
+        let (width, height) = rgb_img.dimensions();
+        let encoder = webp::Encoder::from_rgb(rgb_img.as_raw(), width, height);
+        encode_webp_advanced(&encoder, settings)?
+    };
+
+    let output_data = match icc_profile {
+        Some(icc) => inject_icc_into_webp(&encoded, icc),
+        None => encoded.to_vec(),
+    };
+    Ok(inject_metadata_into_webp(&output_data, metadata))
+}
+
+/// Encode at a searched quality rather than `settings.quality`: the highest one
+/// whose output fits `settings.target_size`, or the lowest one whose output
+/// meets `settings.perceptual_target`. The source is decoded once and every
+/// candidate is a full re-encode, so the lossless JPEG pass never applies.
+fn compress_with_quality_search(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written, QualitySearch)> {
+    check_quality_search(settings)?;
+    if settings.format == OutputFormat::WebP
+        && let Some((animation, metadata)) =
+            decode_animation_for_webp(input_data, input_format, settings)?
+    {
+        return search_animation(&animation, &metadata, settings);
+    }
+
+    let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+    let (output_data, search) = search_quality(&img, icc_profile.as_deref(), &metadata, settings)?;
+    Ok((output_data, Written::Encoded(img), search))
+}
 
+/// Refuse a target size or perceptual target for an output whose encoder has no
+/// quality to search
+fn check_quality_search(settings: &CompressionSettings) -> CompressionResult<()> {
+    let (target, supported) = if settings.target_size.is_some() {
+        (
+            "target size",
+            matches!(
+                settings.format,
+                OutputFormat::WebP | OutputFormat::Jpeg | OutputFormat::Avif
+            ),
+        )
+    } else {
+        // Candidates are decoded to be scored, and there is no AVIF decoder
+        (
+            "perceptual target",
+            matches!(
+                settings.format,
+                OutputFormat::WebP | OutputFormat::Jpeg | OutputFormat::Jxl
+            ),
+        )
+    };
+    if !supported {
+        return Err(CompressionError::InvalidSettings(format!(
+            "A {target} does not apply to {} output",
+            settings.format.extension()
+        )));
+    }
+    // Lossless WebP and JPEG do not read the quality the search varies
+    if settings.format == OutputFormat::WebP && settings.encoder.webp.mode != WebpMode::Lossy {
+        return Err(CompressionError::InvalidSettings(format!(
+            "A {target} does not apply to lossless WebP output"
+        )));
+    }
+    if settings.format == OutputFormat::Jpeg && settings.encoder.jpeg.lossless {
+        return Err(CompressionError::InvalidSettings(format!(
+            "A {target} does not apply to lossless JPEG output"
+        )));
+    }
     Ok(())
 }
 
-/// Encode RGB pixels to JPEG using mozjpeg with optional ICC profile
-fn encode_jpeg_mozjpeg(
-    pixels: &[u8],
-    width: u32,
-    height: u32,
-    quality: u8,
+/// Encode a decoded still image at the quality [`compress_with_quality_search`]
+/// looks for, once [`check_quality_search`] has accepted the settings
+fn search_quality(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, QualitySearch)> {
+    let mut passes = 0;
+    let mut encode = |quality: u8| {
+        passes += 1;
+        let settings = CompressionSettings {
+            quality,
+            ..settings.clone()
+        };
+        encode_still(img, icc_profile, metadata, &settings)
+    };
+
+    let (quality, output_data, ssim) = match (settings.target_size, settings.perceptual_target) {
+        (Some(target_bytes), _) => {
+            let (quality, data) = search_target_size(settings.quality, target_bytes, &mut encode)?;
+            (quality, data, None)
+        }
+        (None, Some(target)) => {
+            let (quality, data, score) =
+                search_perceptual_target(img, settings.format, target.min_ssim(), &mut encode)?;
+            (quality, data, Some(score))
+        }
+        (None, None) => (settings.quality, encode(settings.quality)?, None),
+    };
+
+    Ok((
+        output_data,
+        QualitySearch {
+            quality,
+            passes,
+            ssim,
+        },
+    ))
+}
+
+/// Encode a decoded still image to `settings.format` at `settings.quality`, as
+/// the format writers do once they have decoded the source
+fn encode_still(
+    img: &DynamicImage,
     icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
 ) -> CompressionResult<Vec<u8>> {
-    unsafe {
-        let mut cinfo: mozjpeg_sys::jpeg_compress_struct = std::mem::zeroed();
-        let mut jerr: mozjpeg_sys::jpeg_error_mgr = std::mem::zeroed();
+    match settings.format {
+        OutputFormat::WebP => encode_webp(img, icc_profile, metadata, settings),
+        OutputFormat::Png => {
+            if settings.encoder.png.quantize
+                && settings.quality < 100
+                && let Some(png_data) = quantize_png(img, icc_profile, metadata, settings)?
+            {
+                return Ok(png_data);
+            }
+            encode_png(img, icc_profile, metadata, settings.encoder.png)
+        }
+        OutputFormat::Jpeg => encode_jpeg(
+            img,
+            icc_profile,
+            metadata,
+            settings.quality,
+            settings.encoder.jpeg,
+        ),
+        OutputFormat::Avif => encode_avif_with_icc(img, icc_profile, metadata, settings.quality),
+        OutputFormat::Jxl => encode_jxl(img, settings.quality, icc_profile, metadata),
+    }
+}
 
-        cinfo.common.err = mozjpeg_sys::jpeg_std_error(&mut jerr);
-        mozjpeg_sys::jpeg_CreateCompress(
-            &raw mut cinfo,
-            mozjpeg_sys::JPEG_LIB_VERSION,
-            std::mem::size_of::<mozjpeg_sys::jpeg_compress_struct>(),
-        );
+/// Target-size search for an animated WebP. A perceptual target is refused:
+/// there is no single image to score the frames against.
+fn search_animation(
+    animation: &Animation,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written, QualitySearch)> {
+    let Some(target_bytes) = settings.target_size else {
+        return Err(CompressionError::InvalidSettings(
+            "A perceptual target does not apply to an animation".to_string(),
+        ));
+    };
 
-        // Setup memory destination
-        let mut buf_ptr: *mut u8 = std::ptr::null_mut();
-        let mut buf_size: std::ffi::c_ulong = 0;
-        mozjpeg_sys::jpeg_mem_dest(&mut cinfo, &raw mut buf_ptr, &raw mut buf_size);
+    let mut passes = 0;
+    let (quality, output_data) =
+        search_target_size(settings.quality, target_bytes, &mut |quality| {
+            passes += 1;
+            let settings = CompressionSettings {
+                quality,
+                ..settings.clone()
+            };
+            encode_animation(animation, metadata, &settings)
+        })?;
 
-        cinfo.image_width = width;
-        cinfo.image_height = height;
-        cinfo.input_components = 3;
-        cinfo.in_color_space = mozjpeg_sys::J_COLOR_SPACE::JCS_RGB;
+    Ok((
+        output_data,
+        Written::Animated(animation.dimensions()),
+        QualitySearch {
+            quality,
+            passes,
+            ssim: None,
+        },
+    ))
+}
 
-        mozjpeg_sys::jpeg_set_defaults(&mut cinfo);
-        mozjpeg_sys::jpeg_set_quality(&mut cinfo, i32::from(quality), i32::from(true));
+/// The highest quality up to `ceiling` whose output fits in `target_bytes`, by
+/// bisection: output size falls steadily with quality for the lossy encoders.
+fn search_target_size(
+    ceiling: u8,
+    target_bytes: u64,
+    encode: &mut impl FnMut(u8) -> CompressionResult<Vec<u8>>,
+) -> CompressionResult<(u8, Vec<u8>)> {
+    let fits = |data: &[u8]| data.len() as u64 <= target_bytes;
 
-        mozjpeg_sys::jpeg_start_compress(&mut cinfo, i32::from(true));
+    // The requested quality is the ceiling, and often fits outright
+    let first = encode(ceiling)?;
+    if fits(&first) {
+        return Ok((ceiling, first));
+    }
+    let mut smallest_bytes = first.len() as u64;
+    let mut best = None;
+    let (mut low, mut high) = (1, ceiling - 1);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let candidate = encode(quality)?;
+        if fits(&candidate) {
+            best = Some((quality, candidate));
+            low = quality + 1;
+        } else {
+            smallest_bytes = smallest_bytes.min(candidate.len() as u64);
+            high = quality - 1;
+        }
+    }
 
-        // Write ICC profile after start_compress, before scanlines
-        if let Some(icc) = icc_profile {
-            mozjpeg_sys::jpeg_write_icc_profile(
-                &mut cinfo,
-                icc.as_ptr(),
-                icc.len() as std::ffi::c_uint,
-            );
+    best.ok_or(CompressionError::TargetSizeUnreachable {
+        target_bytes,
+        smallest_bytes,
+    })
+}
+
+/// The lowest quality whose output, decoded again, scores at least `min_ssim`
+/// against `reference`, by bisection over 1..=100: the smallest output that
+/// meets the target. Returns the quality, the output and its score.
+fn search_perceptual_target(
+    reference: &DynamicImage,
+    format: OutputFormat,
+    min_ssim: f64,
+    encode: &mut impl FnMut(u8) -> CompressionResult<Vec<u8>>,
+) -> CompressionResult<(u8, Vec<u8>, f64)> {
+    let score = |data: &[u8]| ssim(reference, &decode_output(data, format)?);
+
+    let mut best = None;
+    let mut best_ssim: f64 = 0.0;
+    let (mut low, mut high) = (1, 100);
+    while low <= high {
+        let quality = low + (high - low) / 2;
+        let candidate = encode(quality)?;
+        let candidate_ssim = score(&candidate)?;
+        if candidate_ssim >= min_ssim {
+            best = Some((quality, candidate, candidate_ssim));
+            high = quality - 1;
+        } else {
+            best_ssim = best_ssim.max(candidate_ssim);
+            low = quality + 1;
+        }
+    }
+
+    best.ok_or(CompressionError::PerceptualTargetUnreachable {
+        min_ssim,
+        best_ssim,
+    })
+}
+
+fn compress_to_png(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written)> {
+    let is_png_input = input_format.to_lowercase().as_str() == "png";
+    let policy = settings.metadata_policy;
+
+    if settings.encoder.png.quantize && settings.quality < 100 {
+        let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+
+        // `None` means the palette cannot reach the quality floor: stay lossless
+        if let Some(png_data) = quantize_png(&img, icc_profile.as_deref(), &metadata, settings)? {
+            return Ok((png_data, Written::Encoded(img)));
         }
+    }
+
+    // PNG -> PNG goes straight through oxipng, which preserves existing ICC and
+    // metadata chunks; the pixels are untouched, so the orientation tag stays
+    // valid. Any other policy rewrites the chunks first.
+    let passthrough = match policy {
+        _ if !is_png_input || alters_pixels(input_data, input_format, settings) => None,
+        MetadataPolicy::KeepAll => Some(input_data.to_vec()),
+        _ => rewrite_png_metadata(
+            input_data,
+            &extract_metadata(input_data, input_format).for_policy(policy),
+            policy.keeps_icc(),
+        ),
+    };
+
+    if let Some(png_data) = passthrough {
+        let dimensions = read_dimensions(&png_data, input_format);
+        let options = oxipng_options(settings.encoder.png);
+        let output_data = oxipng::optimize_from_memory(&png_data, &options).unwrap_or(png_data);
+        Ok((output_data, Written::Untouched(dimensions)))
+    } else {
+        let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+
+        let png_data = encode_png(
+            &img,
+            icc_profile.as_deref(),
+            &metadata,
+            settings.encoder.png,
+        )?;
+        Ok((png_data, Written::Encoded(img)))
+    }
+}
+
+/// libimagequant speed (1 = best palette, 10 = fastest). 4 is pngquant's default.
+const PNG_QUANTIZATION_SPEED: i32 = 4;
+
+/// Reduce an image to a ≤256-colour palette (libimagequant, the pngquant engine)
+/// and write it as an oxipng-optimized indexed PNG. `settings.quality` is the
+/// floor the palette must reach; when it cannot, this returns `None` and the
+/// caller keeps the image lossless instead of shipping a visibly degraded one.
+fn quantize_png(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    settings: &CompressionSettings,
+) -> CompressionResult<Option<Vec<u8>>> {
+    let quantization_error = |e: imagequant::Error| {
+        CompressionError::ProcessingError(format!("PNG quantization failed: {e}"))
+    };
+
+    let rgba = img.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    let pixels: Vec<imagequant::RGBA> = rgba
+        .pixels()
+        .map(|p| imagequant::RGBA::new(p[0], p[1], p[2], p[3]))
+        .collect();
+
+    let mut attributes = imagequant::new();
+    attributes
+        .set_speed(PNG_QUANTIZATION_SPEED)
+        .map_err(quantization_error)?;
+    attributes
+        .set_quality(settings.quality, 100)
+        .map_err(quantization_error)?;
+
+    let mut image = attributes
+        .new_image(pixels, width as usize, height as usize, 0.0)
+        .map_err(quantization_error)?;
+    let mut result = match attributes.quantize(&mut image) {
+        Ok(result) => result,
+        Err(imagequant::Error::QualityTooLow) => return Ok(None),
+        Err(e) => return Err(quantization_error(e)),
+    };
+    result
+        .set_dithering_level(f32::from(settings.encoder.png.dithering) / 100.0)
+        .map_err(quantization_error)?;
+    let (palette, indices) = result.remapped(&mut image).map_err(quantization_error)?;
+
+    let raw = oxipng::RawImage::new(
+        width,
+        height,
+        oxipng::ColorType::Indexed { palette },
+        oxipng::BitDepth::Eight,
+        indices,
+    )
+    .map_err(|e| CompressionError::ProcessingError(format!("PNG encoding failed: {e}")))?;
+
+    optimize_raw_png(raw, icc_profile, metadata, settings.encoder.png).map(Some)
+}
+
+fn compress_to_jpeg(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written)> {
+    let is_jpeg_input = matches!(input_format.to_lowercase().as_str(), "jpg" | "jpeg");
+    let lossless = is_jpeg_input && !alters_pixels(input_data, input_format, settings);
+    if settings.encoder.jpeg.lossless && !lossless {
+        return Err(CompressionError::InvalidSettings(
+            "Lossless JPEG output needs a JPEG source whose pixels are left unchanged".to_string(),
+        ));
+    }
+    if lossless && (settings.quality == 100 || settings.encoder.jpeg.lossless) {
+        // JPEG -> JPEG at quality 100, or asked for lossless, is rewritten at the
+        // coefficient level, the counterpart of the oxipng pass for PNG -> PNG.
+        // The coefficients are not rotated, so the orientation tag is kept as-is
+        let policy = settings.metadata_policy;
+        let dimensions = read_dimensions(input_data, input_format);
+        let metadata = extract_metadata(input_data, input_format).for_policy(policy);
+        let output_data = match optimize_jpeg_lossless(input_data, &metadata, policy.keeps_icc()) {
+            Ok(optimized) => optimized,
+            // Falling back to the untouched source is only acceptable when it
+            // would not leak metadata the policy removes
+            Err(_) if policy == MetadataPolicy::KeepAll => input_data.to_vec(),
+            Err(e) => return Err(e),
+        };
+        return Ok((output_data, Written::Untouched(dimensions)));
+    }
+
+    let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+    let jpeg_data = encode_jpeg(
+        &img,
+        icc_profile.as_deref(),
+        &metadata,
+        settings.quality,
+        settings.encoder.jpeg,
+    )?;
+
+    Ok((jpeg_data, Written::Encoded(img)))
+}
+
+/// Encode to JPEG with mozjpeg, flattening transparency (JPEG has none)
+fn encode_jpeg(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    quality: u8,
+    options: JpegOptions,
+) -> CompressionResult<Vec<u8>> {
+    let rgb_img = img.to_rgb8();
+    let (width, height) = rgb_img.dimensions();
+    encode_jpeg_mozjpeg(
+        rgb_img.as_raw(),
+        width,
+        height,
+        quality,
+        options,
+        icc_profile,
+        metadata,
+    )
+}
+
+fn compress_to_avif(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written)> {
+    let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+    let output_data =
+        encode_avif_with_icc(&img, icc_profile.as_deref(), &metadata, settings.quality)?;
+
+    Ok((output_data, Written::Encoded(img)))
+}
+
+/// Encoder speed handed to rav1e (1 = slowest/smallest, 10 = fastest). rav1e is far
+/// slower than libwebp; 6 gives up a few percent of size against the default 4
+/// for a much shorter encode.
+const AVIF_ENCODER_SPEED: u8 = 6;
+
+/// Encode to AVIF. ravif writes neither a profile nor metadata: the profile is
+/// added afterwards as a `colr` (prof) property of the primary item, EXIF and
+/// XMP as metadata items.
+fn encode_avif_with_icc(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    quality: u8,
+) -> CompressionResult<Vec<u8>> {
+    let encoded = encode_avif(img, quality)?;
+    let encoded = match icc_profile {
+        Some(icc) => inject_icc_into_avif(&encoded, icc),
+        None => encoded,
+    };
+    Ok(inject_metadata_into_avif(&encoded, metadata))
+}
+
+/// Encode a DynamicImage to AVIF (ravif/rav1e), keeping alpha when present
+fn encode_avif(img: &DynamicImage, quality: u8) -> CompressionResult<Vec<u8>> {
+    use image::ImageEncoder;
+
+    let mut buffer = Vec::new();
+    let encoder = image::codecs::avif::AvifEncoder::new_with_speed_quality(
+        &mut buffer,
+        AVIF_ENCODER_SPEED,
+        quality,
+    );
+
+    let result = if img.color().has_alpha() {
+        let rgba = img.to_rgba8();
+        let (width, height) = rgba.dimensions();
+        encoder.write_image(
+            rgba.as_raw(),
+            width,
+            height,
+            image::ExtendedColorType::Rgba8,
+        )
+    } else {
+        let rgb = img.to_rgb8();
+        let (width, height) = rgb.dimensions();
+        encoder.write_image(rgb.as_raw(), width, height, image::ExtendedColorType::Rgb8)
+    };
+    result.map_err(|e| CompressionError::ProcessingError(format!("AVIF encoding failed: {e}")))?;
+
+    Ok(buffer)
+}
+
+fn compress_to_jxl(
+    input_data: &[u8],
+    input_format: &str,
+    settings: &CompressionSettings,
+) -> CompressionResult<(Vec<u8>, Written)> {
+    let is_jpeg_input = matches!(input_format.to_lowercase().as_str(), "jpg" | "jpeg");
+    let policy = settings.metadata_policy;
+    // The `jbrd` box rebuilds every source marker, so the JPEG handed to libjxl
+    // has been through the policy already
+    let recompressible = match policy {
+        _ if !is_jpeg_input
+            || settings.quality < 100
+            || alters_pixels(input_data, input_format, settings) =>
+        {
+            None
+        }
+        MetadataPolicy::KeepAll => Some(input_data.to_vec()),
+        _ => rewrite_jpeg_metadata(
+            input_data,
+            &extract_metadata(input_data, input_format).for_policy(policy),
+            policy.keeps_icc(),
+        ),
+    };
+
+    if let Some(jpeg) = recompressible {
+        // Lossless transcode: the JPEG stays bit-reconstructible
+        Ok((
+            recompress_jpeg_to_jxl(&jpeg)?,
+            Written::Untouched(read_dimensions(&jpeg, input_format)),
+        ))
+    } else {
+        let (img, icc_profile, metadata) = decode_upright(input_data, input_format, settings)?;
+        Ok((
+            encode_jxl(&img, settings.quality, icc_profile.as_deref(), &metadata)?,
+            Written::Encoded(img),
+        ))
+    }
+}
+
+/// Owns a libjxl encoder and its thread pool, releasing both on drop
+struct JxlEncoderSession {
+    encoder: *mut jpegxl_sys::encoder::encode::JxlEncoder,
+    runner: *mut std::ffi::c_void,
+}
+
+impl JxlEncoderSession {
+    fn new() -> CompressionResult<Self> {
+        use jpegxl_sys::encoder::encode::{
+            JxlEncoderCreate, JxlEncoderSetParallelRunner, JxlEncoderStatus,
+        };
+        use jpegxl_sys::threads::thread_parallel_runner::{
+            JxlThreadParallelRunner, JxlThreadParallelRunnerCreate,
+            JxlThreadParallelRunnerDefaultNumWorkerThreads,
+        };
+
+        unsafe {
+            let session = Self {
+                encoder: JxlEncoderCreate(std::ptr::null()),
+                runner: JxlThreadParallelRunnerCreate(
+                    std::ptr::null(),
+                    JxlThreadParallelRunnerDefaultNumWorkerThreads(),
+                ),
+            };
+            if session.encoder.is_null()
+                || session.runner.is_null()
+                || JxlEncoderSetParallelRunner(
+                    session.encoder,
+                    JxlThreadParallelRunner,
+                    session.runner,
+                ) != JxlEncoderStatus::Success
+            {
+                return Err(CompressionError::ProcessingError(
+                    "Failed to create JPEG XL encoder".to_string(),
+                ));
+            }
+            Ok(session)
+        }
+    }
+
+    /// Frame settings owned by the encoder (freed with it)
+    fn frame_settings(
+        &self,
+    ) -> CompressionResult<*mut jpegxl_sys::encoder::encode::JxlEncoderFrameSettings> {
+        let frame_settings = unsafe {
+            jpegxl_sys::encoder::encode::JxlEncoderFrameSettingsCreate(
+                self.encoder,
+                std::ptr::null(),
+            )
+        };
+        if frame_settings.is_null() {
+            return Err(jxl_encode_error("frame settings"));
+        }
+        Ok(frame_settings)
+    }
+
+    /// Store EXIF and XMP in `Exif` and `xml ` boxes, which switches the output
+    /// to the container format. Must come before any frame is added.
+    fn add_metadata_boxes(&self, metadata: &ImageMetadata) -> CompressionResult<()> {
+        use jpegxl_sys::common::types::{JxlBool, JxlBoxType};
+        use jpegxl_sys::encoder::encode::{
+            JxlEncoderAddBox, JxlEncoderStatus, JxlEncoderUseBoxes, JxlEncoderUseContainer,
+        };
+
+        if metadata.is_empty() {
+            return Ok(());
+        }
+        let exif = metadata.isobmff_exif();
+        let boxes = [
+            exif.as_deref().map(|exif| (b"Exif", exif)),
+            metadata.xmp.as_deref().map(|xmp| (b"xml ", xmp)),
+        ];
+
+        unsafe {
+            if JxlEncoderUseContainer(self.encoder, JxlBool::True) != JxlEncoderStatus::Success
+                || JxlEncoderUseBoxes(self.encoder) != JxlEncoderStatus::Success
+            {
+                return Err(jxl_encode_error("metadata boxes"));
+            }
+            for (box_type, contents) in boxes.into_iter().flatten() {
+                let box_type = JxlBoxType(box_type.map(u8::cast_signed));
+                if JxlEncoderAddBox(
+                    self.encoder,
+                    &raw const box_type,
+                    contents.as_ptr(),
+                    contents.len(),
+                    JxlBool::False,
+                ) != JxlEncoderStatus::Success
+                {
+                    return Err(jxl_encode_error("metadata boxes"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the input and drain the encoded codestream/container
+    fn finish(self) -> CompressionResult<Vec<u8>> {
+        use jpegxl_sys::encoder::encode::{
+            JxlEncoderCloseInput, JxlEncoderProcessOutput, JxlEncoderStatus,
+        };
+
+        let mut output = vec![0u8; 64 * 1024];
+        let mut written = 0;
+
+        unsafe {
+            JxlEncoderCloseInput(self.encoder);
+
+            loop {
+                let mut next_out = output.as_mut_ptr().add(written);
+                let mut avail_out = output.len() - written;
+                let status =
+                    JxlEncoderProcessOutput(self.encoder, &raw mut next_out, &raw mut avail_out);
+                written = output.len() - avail_out;
+
+                match status {
+                    JxlEncoderStatus::Success => break,
+                    JxlEncoderStatus::NeedMoreOutput => output.resize(output.len() * 2, 0),
+                    JxlEncoderStatus::Error => return Err(jxl_encode_error("codestream")),
+                }
+            }
+        }
+
+        output.truncate(written);
+        Ok(output)
+    }
+}
+
+impl Drop for JxlEncoderSession {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.encoder.is_null() {
+                jpegxl_sys::encoder::encode::JxlEncoderDestroy(self.encoder);
+            }
+            if !self.runner.is_null() {
+                jpegxl_sys::threads::thread_parallel_runner::JxlThreadParallelRunnerDestroy(
+                    self.runner,
+                );
+            }
+        }
+    }
+}
+
+fn jxl_encode_error(step: &str) -> CompressionError {
+    CompressionError::ProcessingError(format!("JPEG XL encoding failed: {step}"))
+}
+
+/// Encode a DynamicImage to JPEG XL with libjxl. Quality 100 is mathematically
+/// lossless (modular mode); below that, quality maps to a butteraugli distance
+/// the way libjxl's own tools do. The ICC profile, when present, is embedded;
+/// otherwise the pixels are tagged sRGB. EXIF and XMP go in `Exif` and `xml `
+/// boxes of the container.
+fn encode_jxl(
+    img: &DynamicImage,
+    quality: u8,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+) -> CompressionResult<Vec<u8>> {
+    use jpegxl_sys::color::color_encoding::JxlColorEncoding;
+    use jpegxl_sys::common::types::{JxlBool, JxlDataType, JxlEndianness, JxlPixelFormat};
+    use jpegxl_sys::encoder::encode::{
+        JxlColorEncodingSetToSRGB, JxlEncoderAddImageFrame, JxlEncoderDistanceFromQuality,
+        JxlEncoderInitBasicInfo, JxlEncoderSetBasicInfo, JxlEncoderSetColorEncoding,
+        JxlEncoderSetFrameDistance, JxlEncoderSetFrameLossless, JxlEncoderSetICCProfile,
+        JxlEncoderStatus,
+    };
+
+    let lossless = quality == 100;
+    let has_alpha = img.color().has_alpha();
+    let (width, height) = (img.width(), img.height());
+    let pixels = if has_alpha {
+        img.to_rgba8().into_raw()
+    } else {
+        img.to_rgb8().into_raw()
+    };
+    let format = JxlPixelFormat {
+        num_channels: if has_alpha { 4 } else { 3 },
+        data_type: JxlDataType::Uint8,
+        endianness: JxlEndianness::Native,
+        align: 0,
+    };
+
+    let session = JxlEncoderSession::new()?;
+    session.add_metadata_boxes(metadata)?;
+    let enc = session.encoder;
+
+    unsafe {
+        let mut info = std::mem::MaybeUninit::uninit();
+        JxlEncoderInitBasicInfo(info.as_mut_ptr());
+        let mut info = info.assume_init();
+        info.xsize = width;
+        info.ysize = height;
+        info.bits_per_sample = 8;
+        info.num_color_channels = 3;
+        if has_alpha {
+            info.num_extra_channels = 1;
+            info.alpha_bits = 8;
+        }
+        // Lossless must keep the source colour space; lossy goes through XYB
+        info.uses_original_profile = JxlBool::from(lossless);
+        if JxlEncoderSetBasicInfo(enc, &raw const info) != JxlEncoderStatus::Success {
+            return Err(jxl_encode_error("basic info"));
+        }
+
+        // libjxl rejects malformed profiles; fall back to sRGB rather than fail
+        let icc_accepted = icc_profile.is_some_and(|icc| {
+            JxlEncoderSetICCProfile(enc, icc.as_ptr(), icc.len()) == JxlEncoderStatus::Success
+        });
+        if !icc_accepted {
+            let mut srgb = std::mem::MaybeUninit::<JxlColorEncoding>::uninit();
+            JxlColorEncodingSetToSRGB(srgb.as_mut_ptr(), JxlBool::False);
+            if JxlEncoderSetColorEncoding(enc, srgb.as_ptr()) != JxlEncoderStatus::Success {
+                return Err(jxl_encode_error("colour encoding"));
+            }
+        }
+
+        let frame_settings = session.frame_settings()?;
+        let status = if lossless {
+            JxlEncoderSetFrameLossless(frame_settings, JxlBool::True)
+        } else {
+            JxlEncoderSetFrameDistance(
+                frame_settings,
+                JxlEncoderDistanceFromQuality(f32::from(quality)),
+            )
+        };
+        if status != JxlEncoderStatus::Success {
+            return Err(jxl_encode_error("quality"));
+        }
+
+        if JxlEncoderAddImageFrame(
+            frame_settings,
+            &raw const format,
+            pixels.as_ptr().cast(),
+            pixels.len(),
+        ) != JxlEncoderStatus::Success
+        {
+            return Err(jxl_encode_error("image frame"));
+        }
+    }
+
+    session.finish()
+}
+
+/// Losslessly recompress a JPEG into JPEG XL. libjxl keeps the DCT coefficients
+/// and stores a `jbrd` reconstruction box in the container, so the original JPEG
+/// can be rebuilt byte for byte.
+fn recompress_jpeg_to_jxl(jpeg_data: &[u8]) -> CompressionResult<Vec<u8>> {
+    use jpegxl_sys::common::types::JxlBool;
+    use jpegxl_sys::encoder::encode::{
+        JxlEncoderAddJPEGFrame, JxlEncoderStatus, JxlEncoderStoreJPEGMetadata,
+        JxlEncoderUseContainer,
+    };
+
+    let session = JxlEncoderSession::new()?;
+    let enc = session.encoder;
+
+    unsafe {
+        if JxlEncoderUseContainer(enc, JxlBool::True) != JxlEncoderStatus::Success
+            || JxlEncoderStoreJPEGMetadata(enc, JxlBool::True) != JxlEncoderStatus::Success
+        {
+            return Err(jxl_encode_error("JPEG reconstruction data"));
+        }
+
+        let frame_settings = session.frame_settings()?;
+        if JxlEncoderAddJPEGFrame(frame_settings, jpeg_data.as_ptr(), jpeg_data.len())
+            != JxlEncoderStatus::Success
+        {
+            return Err(jxl_encode_error("unsupported JPEG"));
+        }
+    }
+
+    session.finish()
+}
+
+/// Encode a DynamicImage to an oxipng-optimized PNG with optional ICC profile
+/// and metadata. 16-bit and float sources are written with 16 bits per channel;
+/// oxipng narrows them to 8 only when that loses nothing.
+fn encode_png(
+    img: &DynamicImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    options: PngOptions,
+) -> CompressionResult<Vec<u8>> {
+    let (bit_depth, data) = if is_high_bit_depth(img.color()) {
+        // PNG stores 16-bit samples big-endian
+        let data = img
+            .to_rgba16()
+            .into_raw()
+            .into_iter()
+            .flat_map(u16::to_be_bytes)
+            .collect();
+        (oxipng::BitDepth::Sixteen, data)
+    } else {
+        (oxipng::BitDepth::Eight, img.to_rgba8().into_raw())
+    };
+    let raw = oxipng::RawImage::new(
+        img.width(),
+        img.height(),
+        oxipng::ColorType::RGBA,
+        bit_depth,
+        data,
+    )
+    .map_err(|e| CompressionError::ProcessingError(format!("PNG encoding failed: {e}")))?;
+
+    optimize_raw_png(raw, icc_profile, metadata, options)
+}
+
+/// Attach the ICC profile (iCCP) and metadata chunks (eXIf, iTXt), then let
+/// oxipng pick the smallest colour type, filters and deflate stream
+fn optimize_raw_png(
+    mut raw: oxipng::RawImage,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+    options: PngOptions,
+) -> CompressionResult<Vec<u8>> {
+    if let Some(icc) = icc_profile {
+        raw.add_icc_profile(icc);
+    }
+    for (name, data) in metadata.png_chunks() {
+        raw.add_png_chunk(name, data);
+    }
+
+    raw.create_optimized_png(&oxipng_options(options))
+        .map_err(|e| CompressionError::ProcessingError(format!("PNG optimization failed: {e}")))
+}
+
+/// Zopfli passes over each deflate block, oxipng's own default
+const ZOPFLI_ITERATIONS: std::num::NonZeroU8 = match std::num::NonZeroU8::new(15) {
+    Some(iterations) => iterations,
+    None => std::num::NonZeroU8::MIN,
+};
+
+/// oxipng options for `options`. Interlacing is always set, so an interlaced
+/// source loses it unless it was asked for
+fn oxipng_options(options: PngOptions) -> oxipng::Options {
+    let mut oxipng_options = oxipng::Options::from_preset(options.level);
+    if options.zopfli {
+        oxipng_options.deflate = oxipng::Deflaters::Zopfli {
+            iterations: ZOPFLI_ITERATIONS,
+        };
+    }
+    if options.interlace {
+        oxipng_options.interlace = Some(oxipng::Interlacing::Adam7);
+    }
+    oxipng_options
+}
+
+/// Encode RGB pixels to JPEG using mozjpeg with optional ICC profile and
+/// metadata. A libjpeg error (an image past its 65,500-pixel limit, say) is
+/// returned rather than ending the process.
+fn encode_jpeg_mozjpeg(
+    pixels: &[u8],
+    width: u32,
+    height: u32,
+    quality: u8,
+    options: JpegOptions,
+    icc_profile: Option<&[u8]>,
+    metadata: &ImageMetadata,
+) -> CompressionResult<Vec<u8>> {
+    unsafe {
+        let mut cinfo: mozjpeg_sys::jpeg_compress_struct = std::mem::zeroed();
+        let mut jerr: mozjpeg_sys::jpeg_error_mgr = std::mem::zeroed();
+
+        let err = mozjpeg_sys::jpeg_std_error(&mut jerr);
+        err.error_exit = Some(unwind_on_jpeg_error);
+        cinfo.common.err = err;
+        mozjpeg_sys::jpeg_CreateCompress(
+            &raw mut cinfo,
+            mozjpeg_sys::JPEG_LIB_VERSION,
+            std::mem::size_of::<mozjpeg_sys::jpeg_compress_struct>(),
+        );
+
+        let mut buf_ptr: *mut u8 = std::ptr::null_mut();
+        let mut buf_size: std::ffi::c_ulong = 0;
+
+        let encoded = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            // Setup memory destination
+            mozjpeg_sys::jpeg_mem_dest(&mut cinfo, &raw mut buf_ptr, &raw mut buf_size);
+
+            cinfo.image_width = width;
+            cinfo.image_height = height;
+            cinfo.input_components = 3;
+            cinfo.in_color_space = mozjpeg_sys::J_COLOR_SPACE::JCS_RGB;
+
+            mozjpeg_sys::jpeg_set_defaults(&mut cinfo);
+            // The table is picked by index when the quality scales it, so it goes first
+            mozjpeg_sys::jpeg_c_set_int_param(
+                &mut cinfo,
+                mozjpeg_sys::J_INT_PARAM::JINT_BASE_QUANT_TBL_IDX,
+                i32::from(options.quant_table),
+            );
+            mozjpeg_sys::jpeg_set_quality(&mut cinfo, i32::from(quality), i32::from(true));
+
+            for param in [
+                mozjpeg_sys::J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT,
+                mozjpeg_sys::J_BOOLEAN_PARAM::JBOOLEAN_TRELLIS_QUANT_DC,
+            ] {
+                mozjpeg_sys::jpeg_c_set_bool_param(&mut cinfo, param, i32::from(options.trellis));
+            }
+            if !options.progressive {
+                // jpeg_set_defaults installed mozjpeg's progressive scan script
+                mozjpeg_sys::jpeg_c_set_bool_param(
+                    &mut cinfo,
+                    mozjpeg_sys::J_BOOLEAN_PARAM::JBOOLEAN_OPTIMIZE_SCANS,
+                    i32::from(false),
+                );
+                cinfo.num_scans = 0;
+                cinfo.scan_info = std::ptr::null();
+            }
+            // Chroma keeps a factor of 1: the luma factors set the subsampling
+            let (h_factor, v_factor) = options.chroma_subsampling.luma_factors();
+            let luma = &mut *cinfo.comp_info;
+            luma.h_samp_factor = h_factor;
+            luma.v_samp_factor = v_factor;
+
+            mozjpeg_sys::jpeg_start_compress(&mut cinfo, i32::from(true));
+
+            // Markers go after start_compress, before scanlines: EXIF/XMP (APP1) first
+            write_jpeg_app1_markers(&mut cinfo, metadata);
+            if let Some(icc) = icc_profile {
+                mozjpeg_sys::jpeg_write_icc_profile(
+                    &mut cinfo,
+                    icc.as_ptr(),
+                    icc.len() as std::ffi::c_uint,
+                );
+            }
+
+            let row_stride = width as usize * 3;
+            while cinfo.next_scanline < cinfo.image_height {
+                let row_offset = cinfo.next_scanline as usize * row_stride;
+                let row_ptr = pixels.as_ptr().add(row_offset);
+                let mut row_array = [row_ptr];
+                mozjpeg_sys::jpeg_write_scanlines(&mut cinfo, row_array.as_mut_ptr(), 1);
+            }
+
+            mozjpeg_sys::jpeg_finish_compress(&mut cinfo);
+        }));
+
+        mozjpeg_sys::jpeg_destroy_compress(&mut cinfo);
+
+        // Copy the buffer allocated by jpeg_mem_dest before freeing it
+        let result = match encoded {
+            Ok(()) if !buf_ptr.is_null() && buf_size > 0 => {
+                Ok(std::slice::from_raw_parts(buf_ptr, buf_size as usize).to_vec())
+            }
+            Ok(()) => Err(CompressionError::ProcessingError(
+                "mozjpeg produced empty output".to_string(),
+            )),
+            Err(_) => Err(CompressionError::ProcessingError(format!(
+                "JPEG encoding failed for a {width}x{height} image"
+            ))),
+        };
+
+        if !buf_ptr.is_null() {
+            libc_free(buf_ptr.cast::<std::ffi::c_void>());
+        }
+
+        result
+    }
+}
+
+/// Marker code of the APP1 segments that carry EXIF and XMP
+const JPEG_APP1_MARKER: i32 = 0xE1;
+
+/// Marker code of the APP2 segments that carry an ICC profile
+const JPEG_APP2_MARKER: i32 = 0xE2;
+
+/// libjpeg's default `error_exit` calls `exit()`, which would take the whole app
+/// down on a corrupt file. mozjpeg-sys is built with unwinding support, so unwind
+/// instead and let the caller turn it into an error.
+extern "C-unwind" fn unwind_on_jpeg_error(_cinfo: &mut mozjpeg_sys::jpeg_common_struct) {
+    std::panic::resume_unwind(Box::new("libjpeg error"));
+}
+
+/// Write EXIF and XMP as APP1 markers (must be called between
+/// `jpeg_start_compress`/`jpeg_write_coefficients` and the first scanline)
+fn write_jpeg_app1_markers(
+    cinfo: &mut mozjpeg_sys::jpeg_compress_struct,
+    metadata: &ImageMetadata,
+) {
+    for payload in metadata.jpeg_app1_payloads() {
+        unsafe {
+            mozjpeg_sys::jpeg_write_marker(
+                cinfo,
+                JPEG_APP1_MARKER,
+                payload.as_ptr(),
+                payload.len() as std::ffi::c_uint,
+            );
+        }
+    }
+}
+
+/// Losslessly optimize a JPEG without decoding it (what `jpegtran -optimize
+/// -progressive -copy all` does, minus the clutter): the DCT coefficients are
+/// copied as-is, Huffman tables are rebuilt and scans are rewritten as
+/// progressive. Only `metadata` (as APP1) and, if `keep_icc`, the source ICC
+/// profile are written; comments, other APP segments and vendor markers are
+/// dropped. Pixels decode identically to the original.
+fn optimize_jpeg_lossless(
+    jpeg_data: &[u8],
+    metadata: &ImageMetadata,
+    keep_icc: bool,
+) -> CompressionResult<Vec<u8>> {
+    unsafe {
+        let mut srcinfo: mozjpeg_sys::jpeg_decompress_struct = std::mem::zeroed();
+        let mut dstinfo: mozjpeg_sys::jpeg_compress_struct = std::mem::zeroed();
+        let mut src_err: mozjpeg_sys::jpeg_error_mgr = std::mem::zeroed();
+        let mut dst_err: mozjpeg_sys::jpeg_error_mgr = std::mem::zeroed();
+
+        for (common, err) in [
+            (&mut srcinfo.common, &mut src_err),
+            (&mut dstinfo.common, &mut dst_err),
+        ] {
+            let err = mozjpeg_sys::jpeg_std_error(err);
+            err.error_exit = Some(unwind_on_jpeg_error);
+            common.err = err;
+        }
+
+        mozjpeg_sys::jpeg_CreateDecompress(
+            &raw mut srcinfo,
+            mozjpeg_sys::JPEG_LIB_VERSION,
+            std::mem::size_of::<mozjpeg_sys::jpeg_decompress_struct>(),
+        );
+        mozjpeg_sys::jpeg_CreateCompress(
+            &raw mut dstinfo,
+            mozjpeg_sys::JPEG_LIB_VERSION,
+            std::mem::size_of::<mozjpeg_sys::jpeg_compress_struct>(),
+        );
+
+        let mut buf_ptr: *mut u8 = std::ptr::null_mut();
+        let mut buf_size: std::ffi::c_ulong = 0;
+
+        let transcoded = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            mozjpeg_sys::jpeg_mem_src(
+                &mut srcinfo,
+                jpeg_data.as_ptr(),
+                jpeg_data.len() as std::ffi::c_ulong,
+            );
+            // Only the ICC profile needs libjpeg's marker store; EXIF and XMP are
+            // lifted from the raw bytes like for every other writer
+            if keep_icc {
+                mozjpeg_sys::jpeg_save_markers(&mut srcinfo, JPEG_APP2_MARKER, 0xFFFF);
+            }
+            mozjpeg_sys::jpeg_read_header(&mut srcinfo, i32::from(true));
+
+            let coefficients = mozjpeg_sys::jpeg_read_coefficients(&mut srcinfo);
+
+            mozjpeg_sys::jpeg_copy_critical_parameters(&srcinfo, &mut dstinfo);
+            dstinfo.optimize_coding = i32::from(true);
+            mozjpeg_sys::jpeg_simple_progression(&mut dstinfo);
+
+            mozjpeg_sys::jpeg_mem_dest(&mut dstinfo, &raw mut buf_ptr, &raw mut buf_size);
+            mozjpeg_sys::jpeg_write_coefficients(&mut dstinfo, coefficients);
+            write_jpeg_app1_markers(&mut dstinfo, metadata);
+
+            let mut icc_ptr: *mut u8 = std::ptr::null_mut();
+            let mut icc_len: std::ffi::c_uint = 0;
+            if mozjpeg_sys::jpeg_read_icc_profile(&mut srcinfo, &raw mut icc_ptr, &raw mut icc_len)
+                != 0
+            {
+                mozjpeg_sys::jpeg_write_icc_profile(&mut dstinfo, icc_ptr, icc_len);
+                libc_free(icc_ptr.cast::<std::ffi::c_void>());
+            }
+
+            mozjpeg_sys::jpeg_finish_compress(&mut dstinfo);
+            mozjpeg_sys::jpeg_finish_decompress(&mut srcinfo);
+        }));
+
+        mozjpeg_sys::jpeg_destroy_compress(&mut dstinfo);
+        mozjpeg_sys::jpeg_destroy_decompress(&mut srcinfo);
+
+        let result = match transcoded {
+            Ok(()) if !buf_ptr.is_null() && buf_size > 0 => {
+                Ok(std::slice::from_raw_parts(buf_ptr, buf_size as usize).to_vec())
+            }
+            _ => Err(CompressionError::ProcessingError(
+                "Lossless JPEG optimization failed".to_string(),
+            )),
+        };
+
+        if !buf_ptr.is_null() {
+            libc_free(buf_ptr.cast::<std::ffi::c_void>());
+        }
+
+        result
+    }
+}
+
+unsafe extern "C" {
+    fn free(ptr: *mut std::ffi::c_void);
+}
+
+unsafe fn libc_free(ptr: *mut std::ffi::c_void) {
+    unsafe { free(ptr) };
+}
+
+/// Inject an ICC profile into a WebP RIFF container.
+///
+/// WebP files use the RIFF container format. To embed ICC, we need the
+/// extended format (VP8X header) with an ICCP chunk.
+///
+/// Layout: RIFF [size] WEBP VP8X [flags] ICCP [icc_data] VP8/VP8L [payload]
+fn inject_icc_into_webp(webp_data: &[u8], icc_data: &[u8]) -> Vec<u8> {
+    // Minimum valid WebP: "RIFF" + size(4) + "WEBP" + chunk = 12+ bytes
+    if webp_data.len() < 12 {
+        return webp_data.to_vec();
+    }
+
+    // Parse existing RIFF header
+    let fourcc = &webp_data[8..12];
+    if fourcc != b"WEBP" {
+        return webp_data.to_vec();
+    }
+
+    // Identify the payload chunk type starting at offset 12
+    let chunk_type = &webp_data[12..16];
+
+    // Build padded ICCP chunk (RIFF chunks must be even-aligned)
+    let icc_chunk = build_riff_chunk(b"ICCP", icc_data);
+
+    if chunk_type == b"VP8X" {
+        // Already extended format: inject ICCP flag + chunk after VP8X
+        inject_icc_into_extended_webp(webp_data, &icc_chunk)
+    } else {
+        // Simple format (VP8 or VP8L): wrap in VP8X + ICCP
+        wrap_simple_webp(webp_data, WEBP_ICC_FLAG, &icc_chunk, chunk_type)
+    }
+}
+
+/// VP8X feature flags (byte 20 of an extended WebP)
+const WEBP_ICC_FLAG: u8 = 0b0010_0000;
+const WEBP_ALPHA_FLAG: u8 = 0b0001_0000;
+const WEBP_EXIF_FLAG: u8 = 0b0000_1000;
+const WEBP_XMP_FLAG: u8 = 0b0000_0100;
+
+/// Append EXIF and XMP chunks to a WebP, converting it to the extended format
+/// if needed. The container spec places them after the image data.
+fn inject_metadata_into_webp(webp_data: &[u8], metadata: &ImageMetadata) -> Vec<u8> {
+    if metadata.is_empty() || webp_data.len() < 16 || &webp_data[8..12] != b"WEBP" {
+        return webp_data.to_vec();
+    }
+
+    let chunk_type = &webp_data[12..16];
+    let mut result = if chunk_type == b"VP8X" {
+        webp_data.to_vec()
+    } else {
+        wrap_simple_webp(webp_data, 0, &[], chunk_type)
+    };
+    if result.len() < 30 {
+        return webp_data.to_vec();
+    }
+
+    if let Some(exif) = &metadata.exif {
+        result[20] |= WEBP_EXIF_FLAG;
+        result.extend_from_slice(&build_riff_chunk(b"EXIF", exif));
+    }
+    if let Some(xmp) = &metadata.xmp {
+        result[20] |= WEBP_XMP_FLAG;
+        result.extend_from_slice(&build_riff_chunk(b"XMP ", xmp));
+    }
+
+    let riff_size = (result.len() - 8) as u32;
+    result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    result
+}
+
+/// Build a RIFF chunk: FourCC + LE32 size + data + optional padding byte
+// Callers pass `b"ICCP"`-style literals, which are naturally `&[u8; 4]`; taking
+// the FourCC by value would force a `*b"ICCP"` deref at every call site.
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn build_riff_chunk(fourcc: &[u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(8 + data.len() + 1);
+    chunk.extend_from_slice(fourcc);
+    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    chunk.extend_from_slice(data);
+    if !data.len().is_multiple_of(2) {
+        chunk.push(0); // RIFF padding
+    }
+    chunk
+}
+
+/// Inject ICCP into an already-extended WebP (has VP8X)
+fn inject_icc_into_extended_webp(webp_data: &[u8], icc_chunk: &[u8]) -> Vec<u8> {
+    // VP8X chunk is at offset 12, payload at 20, flags at byte 20
+    // VP8X payload is always 10 bytes
+    let vp8x_end = 12 + 8 + 10; // 30
+
+    if webp_data.len() < vp8x_end {
+        return webp_data.to_vec();
+    }
+
+    // Set ICC flag (bit 5 of flags byte at offset 20)
+    let flags_byte = webp_data[20] | WEBP_ICC_FLAG;
+
+    let mut result = Vec::with_capacity(webp_data.len() + icc_chunk.len());
+    // RIFF header placeholder (we'll fix the size later)
+    result.extend_from_slice(&webp_data[..20]);
+    result.push(flags_byte);
+    result.extend_from_slice(&webp_data[21..vp8x_end]);
+    // Insert ICCP chunk right after VP8X
+    result.extend_from_slice(icc_chunk);
+    // Rest of the file (VP8/VP8L/ALPH etc.)
+    result.extend_from_slice(&webp_data[vp8x_end..]);
+
+    // Fix RIFF file size (bytes 4..8 = total size - 8)
+    let riff_size = (result.len() - 8) as u32;
+    result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+
+    result
+}
+
+/// Wrap a simple WebP (VP8/VP8L) into extended format: VP8X carrying `flags`,
+/// then `leading_chunk` (e.g. ICCP, which must precede the image data)
+fn wrap_simple_webp(
+    webp_data: &[u8],
+    flags: u8,
+    leading_chunk: &[u8],
+    chunk_type: &[u8],
+) -> Vec<u8> {
+    // Read canvas dimensions from the bitstream
+    let (canvas_w, canvas_h) = read_webp_dimensions(webp_data, chunk_type);
+
+    let mut vp8x_payload = [0u8; 10];
+    vp8x_payload[0] = flags;
+
+    // If source is VP8L (lossless), also set the alpha flag since VP8L may contain alpha
+    if chunk_type == b"VP8L" {
+        vp8x_payload[0] |= WEBP_ALPHA_FLAG;
+    }
+
+    // Canvas size is stored as (width-1) and (height-1) in 24-bit LE
+    let w_minus_1 = canvas_w.saturating_sub(1);
+    let h_minus_1 = canvas_h.saturating_sub(1);
+    vp8x_payload[4] = (w_minus_1 & 0xFF) as u8;
+    vp8x_payload[5] = ((w_minus_1 >> 8) & 0xFF) as u8;
+    vp8x_payload[6] = ((w_minus_1 >> 16) & 0xFF) as u8;
+    vp8x_payload[7] = (h_minus_1 & 0xFF) as u8;
+    vp8x_payload[8] = ((h_minus_1 >> 8) & 0xFF) as u8;
+    vp8x_payload[9] = ((h_minus_1 >> 16) & 0xFF) as u8;
+
+    let vp8x_chunk = build_riff_chunk(b"VP8X", &vp8x_payload);
+
+    // Payload = everything after "RIFF....WEBP" (offset 12)
+    let payload = &webp_data[12..];
+
+    let total_size = 4 + vp8x_chunk.len() + leading_chunk.len() + payload.len(); // "WEBP" + chunks
+    let mut result = Vec::with_capacity(8 + total_size);
+    result.extend_from_slice(b"RIFF");
+    result.extend_from_slice(&(total_size as u32).to_le_bytes());
+    result.extend_from_slice(b"WEBP");
+    result.extend_from_slice(&vp8x_chunk);
+    result.extend_from_slice(leading_chunk);
+    result.extend_from_slice(payload);
+
+    result
+}
+
+/// Read canvas dimensions from a WebP bitstream
+fn read_webp_dimensions(webp_data: &[u8], chunk_type: &[u8]) -> (u32, u32) {
+    // Default fallback
+    let default = (1, 1);
+
+    if webp_data.len() < 30 {
+        return default;
+    }
+
+    // Chunk data starts at offset 20 (12 RIFF header + 4 FourCC + 4 size)
+    let chunk_data_offset = 20;
+
+    if chunk_type == b"VP8 " {
+        // VP8 lossy: dimensions at bytes 6-9 of frame data (after 3-byte frame tag)
+        // Frame header: [frame_tag(3)] [start_code(3): 0x9D 0x01 0x2A] [width(2)] [height(2)]
+        let offset = chunk_data_offset;
+        if webp_data.len() < offset + 10 {
+            return default;
+        }
+        let width = u16::from_le_bytes([webp_data[offset + 6], webp_data[offset + 7]]) & 0x3FFF;
+        let height = u16::from_le_bytes([webp_data[offset + 8], webp_data[offset + 9]]) & 0x3FFF;
+        (u32::from(width), u32::from(height))
+    } else if chunk_type == b"VP8L" {
+        // VP8L lossless: signature byte (0x2F) then 32-bit LE with packed w/h
+        let offset = chunk_data_offset;
+        if webp_data.len() < offset + 5 {
+            return default;
+        }
+        let bits = u32::from_le_bytes([
+            webp_data[offset + 1],
+            webp_data[offset + 2],
+            webp_data[offset + 3],
+            webp_data[offset + 4],
+        ]);
+        let width = (bits & 0x3FFF) + 1;
+        let height = ((bits >> 14) & 0x3FFF) + 1;
+        (width, height)
+    } else {
+        default
+    }
+}
+
+/// A box located in an ISOBMFF (HEIF/AVIF) byte stream. `start..end` covers the
+/// whole box, `body` is where its payload begins (after size + FourCC).
+struct IsoBox {
+    fourcc: [u8; 4],
+    start: usize,
+    body: usize,
+    end: usize,
+}
+
+/// Split `data[range]` into its sibling boxes. Returns `None` on any truncated
+/// or inconsistent size field.
+fn parse_iso_boxes(data: &[u8], range: std::ops::Range<usize>) -> Option<Vec<IsoBox>> {
+    let mut boxes = Vec::new();
+    let mut pos = range.start;
+
+    while pos < range.end {
+        let header = data.get(pos..pos + 8)?;
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+        let fourcc = [header[4], header[5], header[6], header[7]];
+
+        let (body, end) = match size {
+            // Box extends to the end of the enclosing range
+            0 => (pos + 8, range.end),
+            // 64-bit "largesize" follows the FourCC
+            1 => {
+                let large = read_be_uint(data, pos + 8, 8)? as usize;
+                (pos + 16, pos.checked_add(large)?)
+            }
+            _ => (pos + 8, pos.checked_add(size as usize)?),
+        };
+
+        if end > range.end || body > end {
+            return None;
+        }
+        boxes.push(IsoBox {
+            fourcc,
+            start: pos,
+            body,
+            end,
+        });
+        pos = end;
+    }
+
+    Some(boxes)
+}
+
+// Same rationale as `build_riff_chunk`: callers pass `b"iprp"`-style literals.
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn find_iso_box<'a>(boxes: &'a [IsoBox], fourcc: &[u8; 4]) -> Option<&'a IsoBox> {
+    boxes.iter().find(|b| &b.fourcc == fourcc)
+}
+
+/// Build a compact ISOBMFF box: BE32 size + FourCC + payload
+#[allow(clippy::trivially_copy_pass_by_ref)]
+fn build_iso_box(fourcc: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut iso_box = Vec::with_capacity(8 + payload.len());
+    iso_box.extend_from_slice(&((8 + payload.len()) as u32).to_be_bytes());
+    iso_box.extend_from_slice(fourcc);
+    iso_box.extend_from_slice(payload);
+    iso_box
+}
+
+/// Rewrite the compact size field of a box after its payload changed
+fn fix_iso_box_size(iso_box: &mut [u8]) -> Option<()> {
+    let size = u32::try_from(iso_box.len()).ok()?;
+    iso_box.get_mut(0..4)?.copy_from_slice(&size.to_be_bytes());
+    Some(())
+}
+
+/// Read a big-endian unsigned integer of `size` bytes (0, 2, 4 or 8)
+fn read_be_uint(data: &[u8], pos: usize, size: usize) -> Option<u64> {
+    let bytes = data.get(pos..pos + size)?;
+    Some(bytes.iter().fold(0u64, |acc, &b| (acc << 8) | u64::from(b)))
+}
+
+fn write_be_uint(data: &mut [u8], pos: usize, size: usize, value: u64) -> Option<()> {
+    let bytes = data.get_mut(pos..pos + size)?;
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (value >> (8 * (size - 1 - i))) as u8;
+    }
+    Some(())
+}
+
+/// Inject an ICC profile into an AVIF (HEIF) container.
+///
+/// ravif writes a minimal container without colour-profile support. The profile
+/// goes into a `colr` box of type `prof`, appended to the property container
+/// (`ipco`) and associated with the primary item in `ipma`. Growing `meta` moves
+/// the `mdat` payload, so the `iloc` offsets are shifted by the same amount.
+///
+/// Layout: ftyp meta[hdlr pitm iloc iinf iprp[ipco ipma]] mdat
+///
+/// Returns the input unchanged if the container is not laid out as expected.
+fn inject_icc_into_avif(avif_data: &[u8], icc_data: &[u8]) -> Vec<u8> {
+    try_inject_icc_into_avif(avif_data, icc_data).unwrap_or_else(|| avif_data.to_vec())
+}
+
+fn try_inject_icc_into_avif(avif_data: &[u8], icc_data: &[u8]) -> Option<Vec<u8>> {
+    let top = parse_iso_boxes(avif_data, 0..avif_data.len())?;
+    let meta = find_iso_box(&top, b"meta")?;
+    // `meta` is a FullBox: version + flags (4 bytes) precede its children
+    let meta_children_start = meta.body + 4;
+    let meta_children = parse_iso_boxes(avif_data, meta_children_start..meta.end)?;
+
+    let pitm = find_iso_box(&meta_children, b"pitm")?;
+    let item_id_size = if *avif_data.get(pitm.body)? == 0 {
+        2
+    } else {
+        4
+    };
+    let primary_item = read_be_uint(avif_data, pitm.body + 4, item_id_size)?;
+
+    let iprp = find_iso_box(&meta_children, b"iprp")?;
+    let iprp_children = parse_iso_boxes(avif_data, iprp.body..iprp.end)?;
+    let ipco = find_iso_box(&iprp_children, b"ipco")?;
+    let ipma = find_iso_box(&iprp_children, b"ipma")?;
+
+    // Only compact (32-bit size) boxes are rewritten
+    if [meta, iprp, ipco].iter().any(|b| b.body - b.start != 8) {
+        return None;
+    }
+
+    // Property indices are 1-based, in `ipco` order
+    let property_index = parse_iso_boxes(avif_data, ipco.body..ipco.end)?.len() + 1;
+
+    let mut colr_payload = Vec::with_capacity(4 + icc_data.len());
+    colr_payload.extend_from_slice(b"prof");
+    colr_payload.extend_from_slice(icc_data);
+
+    let mut new_ipco = avif_data[ipco.start..ipco.end].to_vec();
+    new_ipco.extend_from_slice(&build_iso_box(b"colr", &colr_payload));
+    fix_iso_box_size(&mut new_ipco)?;
+
+    let new_ipma = add_ipma_association(
+        &avif_data[ipma.start..ipma.end],
+        primary_item,
+        property_index,
+    )?;
+
+    let mut new_iprp = avif_data[iprp.start..iprp.body].to_vec();
+    for child in &iprp_children {
+        match &child.fourcc {
+            b"ipco" => new_iprp.extend_from_slice(&new_ipco),
+            b"ipma" => new_iprp.extend_from_slice(&new_ipma),
+            _ => new_iprp.extend_from_slice(&avif_data[child.start..child.end]),
+        }
+    }
+    fix_iso_box_size(&mut new_iprp)?;
+
+    let mut new_meta = avif_data[meta.start..meta_children_start].to_vec();
+    let mut iloc_range = None;
+    for child in &meta_children {
+        match &child.fourcc {
+            b"iprp" => new_meta.extend_from_slice(&new_iprp),
+            fourcc => {
+                if fourcc == b"iloc" {
+                    iloc_range = Some(new_meta.len()..new_meta.len() + (child.end - child.start));
+                }
+                new_meta.extend_from_slice(&avif_data[child.start..child.end]);
+            }
+        }
+    }
+    fix_iso_box_size(&mut new_meta)?;
+
+    // Everything stored after the old `meta` moves by the size it gained
+    let delta = (new_meta.len() - (meta.end - meta.start)) as u64;
+    shift_iloc_offsets(&mut new_meta[iloc_range?], meta.end as u64, delta)?;
+
+    let mut result = Vec::with_capacity(avif_data.len() + new_meta.len());
+    result.extend_from_slice(&avif_data[..meta.start]);
+    result.extend_from_slice(&new_meta);
+    result.extend_from_slice(&avif_data[meta.end..]);
+
+    Some(result)
+}
+
+/// MIME type of the item an AVIF stores its XMP packet in
+const AVIF_XMP_CONTENT_TYPE: &[u8] = b"application/rdf+xml";
+
+/// Add EXIF and XMP to an AVIF (HEIF) container as metadata items.
+///
+/// ravif writes no metadata. Each block becomes an item (`Exif`, or `mime` of
+/// type `application/rdf+xml` for XMP): an `infe` entry in `iinf`, an `iloc`
+/// extent pointing into an `mdat` appended to the file, and a `cdsc` reference
+/// in `iref` tying it to the primary item. Growing `meta` moves the coded
+/// data, so the existing `iloc` offsets are shifted as for the ICC profile.
+///
+/// Returns the input unchanged if the container is not laid out as expected.
+fn inject_metadata_into_avif(avif_data: &[u8], metadata: &ImageMetadata) -> Vec<u8> {
+    if metadata.is_empty() {
+        return avif_data.to_vec();
+    }
+    try_inject_metadata_into_avif(avif_data, metadata).unwrap_or_else(|| {
+        log::warn!("Could not add EXIF/XMP items to the AVIF container");
+        avif_data.to_vec()
+    })
+}
+
+/// A metadata block stored as an AVIF item
+struct AvifMetadataItem {
+    item_type: &'static [u8; 4],
+    /// MIME type of a `mime` item
+    content_type: Option<&'static [u8]>,
+    payload: Vec<u8>,
+}
+
+fn try_inject_metadata_into_avif(avif_data: &[u8], metadata: &ImageMetadata) -> Option<Vec<u8>> {
+    let mut items = Vec::new();
+    if let Some(exif) = metadata.isobmff_exif() {
+        items.push(AvifMetadataItem {
+            item_type: b"Exif",
+            content_type: None,
+            payload: exif,
+        });
+    }
+    if let Some(xmp) = &metadata.xmp {
+        items.push(AvifMetadataItem {
+            item_type: b"mime",
+            content_type: Some(AVIF_XMP_CONTENT_TYPE),
+            payload: xmp.clone(),
+        });
+    }
+
+    let top = parse_iso_boxes(avif_data, 0..avif_data.len())?;
+    // The payloads go after the last box, which must therefore state its size
+    if read_be_uint(avif_data, top.last()?.start, 4)? == 0 {
+        return None;
+    }
+    let meta = find_iso_box(&top, b"meta")?;
+    let meta_children_start = meta.body + 4;
+    let meta_children = parse_iso_boxes(avif_data, meta_children_start..meta.end)?;
+
+    let pitm = find_iso_box(&meta_children, b"pitm")?;
+    let pitm_id_size = if *avif_data.get(pitm.body)? == 0 {
+        2
+    } else {
+        4
+    };
+    let primary_item = u16::try_from(read_be_uint(avif_data, pitm.body + 4, pitm_id_size)?).ok()?;
+    let iinf = find_iso_box(&meta_children, b"iinf")?;
+    let iloc = find_iso_box(&meta_children, b"iloc")?;
+    let iref = find_iso_box(&meta_children, b"iref");
+
+    // Only compact (32-bit size) boxes are rewritten
+    if [meta, iinf, iloc]
+        .into_iter()
+        .chain(iref)
+        .any(|b| b.body - b.start != 8)
+    {
+        return None;
+    }
+
+    let (new_iinf, item_ids) = add_iinf_entries(&avif_data[iinf.start..iinf.end], &items)?;
+    let (mut new_iloc, extent_offsets, offset_size) =
+        add_iloc_items(&avif_data[iloc.start..iloc.end], &items, &item_ids)?;
+    let new_iref = add_iref_descriptions(
+        iref.map(|iref| &avif_data[iref.start..iref.end]),
+        &item_ids,
+        primary_item,
+    )?;
+
+    let mut new_meta = avif_data[meta.start..meta_children_start].to_vec();
+    let mut iloc_at = None;
+    for child in &meta_children {
+        match &child.fourcc {
+            b"iinf" => new_meta.extend_from_slice(&new_iinf),
+            b"iref" => new_meta.extend_from_slice(&new_iref),
+            b"iloc" => {
+                iloc_at = Some(new_meta.len());
+                new_meta.extend_from_slice(&new_iloc);
+            }
+            _ => new_meta.extend_from_slice(&avif_data[child.start..child.end]),
+        }
+    }
+    if iref.is_none() {
+        new_meta.extend_from_slice(&new_iref);
+    }
+    fix_iso_box_size(&mut new_meta)?;
+
+    // Everything stored after the old `meta` moves by the size it gained; the
+    // new extents, still at offset 0, are left alone
+    let iloc_at = iloc_at?;
+    let delta = (new_meta.len() - (meta.end - meta.start)) as u64;
+    shift_iloc_offsets(&mut new_iloc, meta.end as u64, delta)?;
+
+    // The payloads follow everything else, in an `mdat` of their own
+    let mut position = meta.start + new_meta.len() + (avif_data.len() - meta.end) + 8;
+    let mut mdat_payload = Vec::new();
+    for (item, &extent_at) in items.iter().zip(&extent_offsets) {
+        if offset_size < 8 && u32::try_from(position).is_err() {
+            return None;
+        }
+        write_be_uint(&mut new_iloc, extent_at, offset_size, position as u64)?;
+        mdat_payload.extend_from_slice(&item.payload);
+        position += item.payload.len();
+    }
+    new_meta[iloc_at..iloc_at + new_iloc.len()].copy_from_slice(&new_iloc);
+
+    let mut result = Vec::with_capacity(avif_data.len() + new_meta.len() + mdat_payload.len());
+    result.extend_from_slice(&avif_data[..meta.start]);
+    result.extend_from_slice(&new_meta);
+    result.extend_from_slice(&avif_data[meta.end..]);
+    result.extend_from_slice(&build_iso_box(b"mdat", &mdat_payload));
+    Some(result)
+}
+
+/// Rebuild an `iinf` box with an `infe` entry per item, numbered after the
+/// highest existing item. Ids stay 16-bit so every box keeps the version
+/// ravif wrote.
+fn add_iinf_entries(iinf: &[u8], items: &[AvifMetadataItem]) -> Option<(Vec<u8>, Vec<u16>)> {
+    let count_size = if *iinf.get(8)? == 0 { 2 } else { 4 };
+    let entry_count = read_be_uint(iinf, 12, count_size)?;
+    let mut first_id = 1;
+    for infe in parse_iso_boxes(iinf, 12 + count_size..iinf.len())? {
+        let id_size = if *iinf.get(infe.body)? < 3 { 2 } else { 4 };
+        first_id = first_id.max(read_be_uint(iinf, infe.body + 4, id_size)? + 1);
+    }
+    let item_ids: Vec<u16> = (first_id..first_id + items.len() as u64)
+        .map(|id| u16::try_from(id).ok())
+        .collect::<Option<_>>()?;
+
+    let mut new_iinf = iinf.to_vec();
+    write_be_uint(
+        &mut new_iinf,
+        12,
+        count_size,
+        entry_count + items.len() as u64,
+    )?;
+    for (item, id) in items.iter().zip(&item_ids) {
+        // Version 2: id, protection index, type, empty name[, content type]
+        let mut infe = vec![2, 0, 0, 0];
+        infe.extend_from_slice(&id.to_be_bytes());
+        infe.extend_from_slice(&[0, 0]);
+        infe.extend_from_slice(item.item_type);
+        infe.push(0);
+        if let Some(content_type) = item.content_type {
+            infe.extend_from_slice(content_type);
+            infe.push(0);
+        }
+        new_iinf.extend_from_slice(&build_iso_box(b"infe", &infe));
+    }
+    fix_iso_box_size(&mut new_iinf)?;
+    Some((new_iinf, item_ids))
+}
+
+/// Rebuild (or create) an `iref` box with a `cdsc` reference from each item
+/// to the one it describes.
+fn add_iref_descriptions(iref: Option<&[u8]>, item_ids: &[u16], described: u16) -> Option<Vec<u8>> {
+    let id_size = match iref {
+        Some(iref) if *iref.get(8)? != 0 => 4,
+        _ => 2,
+    };
+    let mut references = Vec::new();
+    for &id in item_ids {
+        // from_item_ID, reference_count, to_item_ID
+        let mut cdsc = vec![0; 2 * id_size + 2];
+        write_be_uint(&mut cdsc, 0, id_size, u64::from(id))?;
+        write_be_uint(&mut cdsc, id_size, 2, 1)?;
+        write_be_uint(&mut cdsc, id_size + 2, id_size, u64::from(described))?;
+        references.extend_from_slice(&build_iso_box(b"cdsc", &cdsc));
+    }
+    match iref {
+        Some(iref) => {
+            let mut new_iref = [iref, &references].concat();
+            fix_iso_box_size(&mut new_iref)?;
+            Some(new_iref)
+        }
+        None => Some(build_iso_box(
+            b"iref",
+            &[&[0, 0, 0, 0][..], &references].concat(),
+        )),
+    }
+}
+
+/// Rebuild an `iloc` box with one entry per item, each a single extent whose
+/// offset is left at 0. Returns the box, where each extent offset sits in it
+/// and the width of those offsets.
+fn add_iloc_items(
+    iloc: &[u8],
+    items: &[AvifMetadataItem],
+    item_ids: &[u16],
+) -> Option<(Vec<u8>, Vec<usize>, usize)> {
+    let version = *iloc.get(8)?;
+    let sizes = *iloc.get(12)?;
+    let offset_size = usize::from(sizes >> 4);
+    let length_size = usize::from(sizes & 0x0F);
+    let sizes = *iloc.get(13)?;
+    let base_offset_size = usize::from(sizes >> 4);
+    let index_size = if version >= 1 {
+        usize::from(sizes & 0x0F)
+    } else {
+        0
+    };
+    // A payload needs a real offset and length to point at
+    if offset_size < 4 || length_size < 4 {
+        return None;
+    }
+
+    let (count_size, id_size) = if version < 2 { (2, 2) } else { (4, 4) };
+    let item_count = read_be_uint(iloc, 14, count_size)?;
+    let mut new_iloc = iloc.to_vec();
+    write_be_uint(
+        &mut new_iloc,
+        14,
+        count_size,
+        item_count + items.len() as u64,
+    )?;
+
+    let mut extent_offsets = Vec::with_capacity(items.len());
+    for (item, &id) in items.iter().zip(item_ids) {
+        let id_at = new_iloc.len();
+        new_iloc.resize(id_at + id_size, 0);
+        write_be_uint(&mut new_iloc, id_at, id_size, u64::from(id))?;
+        if version >= 1 {
+            new_iloc.extend_from_slice(&[0, 0]); // construction method 0: file offset
+        }
+        new_iloc.extend_from_slice(&[0, 0]); // data_reference_index
+        new_iloc.resize(new_iloc.len() + base_offset_size, 0);
+        new_iloc.extend_from_slice(&1u16.to_be_bytes()); // extent_count
+        new_iloc.resize(new_iloc.len() + index_size, 0);
+        extent_offsets.push(new_iloc.len());
+        new_iloc.resize(new_iloc.len() + offset_size, 0);
+        let length_at = new_iloc.len();
+        new_iloc.resize(length_at + length_size, 0);
+        write_be_uint(
+            &mut new_iloc,
+            length_at,
+            length_size,
+            item.payload.len() as u64,
+        )?;
+    }
+    fix_iso_box_size(&mut new_iloc)?;
+    Some((new_iloc, extent_offsets, offset_size))
+}
+
+/// Rebuild an `ipma` box with one more (non-essential) property associated to
+/// `item_id`.
+fn add_ipma_association(ipma: &[u8], item_id: u64, property_index: usize) -> Option<Vec<u8>> {
+    let version = *ipma.get(8)?;
+    let large_indices = ipma.get(11)? & 1 == 1;
+    let item_id_size = if version < 1 { 2 } else { 4 };
+    let association_size = if large_indices { 2 } else { 1 };
+
+    // The essential flag takes the top bit, the index the remaining 7 or 15
+    let max_index = if large_indices { 0x7FFF } else { 0x7F };
+    if property_index > max_index {
+        return None;
+    }
+
+    let entry_count = read_be_uint(ipma, 12, 4)?;
+    let mut result = ipma.get(..16)?.to_vec();
+    let mut pos = 16;
+    let mut associated = false;
+
+    for _ in 0..entry_count {
+        let entry_item = read_be_uint(ipma, pos, item_id_size)?;
+        let count = *ipma.get(pos + item_id_size)?;
+        let associations_end = pos + item_id_size + 1 + usize::from(count) * association_size;
+
+        if entry_item == item_id {
+            result.extend_from_slice(ipma.get(pos..pos + item_id_size)?);
+            result.push(count.checked_add(1)?);
+            result.extend_from_slice(ipma.get(pos + item_id_size + 1..associations_end)?);
+            let index_at = result.len();
+            result.resize(index_at + association_size, 0);
+            write_be_uint(
+                &mut result,
+                index_at,
+                association_size,
+                property_index as u64,
+            )?;
+            associated = true;
+        } else {
+            result.extend_from_slice(ipma.get(pos..associations_end)?);
+        }
+        pos = associations_end;
+    }
+
+    if !associated {
+        return None;
+    }
+    fix_iso_box_size(&mut result)?;
+    Some(result)
+}
+
+/// Move every `iloc` extent that points at or past `moved_from` by `delta` bytes.
+/// Only file-offset extents (construction method 0) are touched.
+fn shift_iloc_offsets(iloc: &mut [u8], moved_from: u64, delta: u64) -> Option<()> {
+    let version = *iloc.get(8)?;
+    let sizes = *iloc.get(12)?;
+    let offset_size = usize::from(sizes >> 4);
+    let length_size = usize::from(sizes & 0x0F);
+    let sizes = *iloc.get(13)?;
+    let base_offset_size = usize::from(sizes >> 4);
+    let index_size = if version >= 1 {
+        usize::from(sizes & 0x0F)
+    } else {
+        0
+    };
+
+    let (item_count, mut pos) = if version < 2 {
+        (read_be_uint(iloc, 14, 2)?, 16)
+    } else {
+        (read_be_uint(iloc, 14, 4)?, 18)
+    };
+    let item_id_size = if version < 2 { 2 } else { 4 };
+
+    for _ in 0..item_count {
+        pos += item_id_size;
+        let construction_method = if version >= 1 {
+            let method = read_be_uint(iloc, pos, 2)? & 0x0F;
+            pos += 2;
+            method
+        } else {
+            0
+        };
+        pos += 2; // data_reference_index
+
+        let base_offset_at = pos;
+        let base_offset = read_be_uint(iloc, pos, base_offset_size)?;
+        pos += base_offset_size;
+
+        let extent_count = read_be_uint(iloc, pos, 2)?;
+        pos += 2;
+
+        let shift = |value: u64| {
+            if construction_method == 0 && value >= moved_from {
+                value + delta
+            } else {
+                value
+            }
+        };
+
+        // A non-zero base offset carries the position; extents are then relative
+        let extents_are_relative = base_offset_size > 0 && base_offset > 0;
+        if extents_are_relative {
+            write_be_uint(iloc, base_offset_at, base_offset_size, shift(base_offset))?;
+        }
+
+        for _ in 0..extent_count {
+            pos += index_size;
+            if !extents_are_relative && offset_size > 0 {
+                let extent_offset = read_be_uint(iloc, pos, offset_size)?;
+                write_be_uint(iloc, pos, offset_size, shift(extent_offset))?;
+            }
+            pos += offset_size + length_size;
+        }
+    }
+
+    Some(())
+}
+
+// Helper functions
+
+fn encode_webp_advanced(
+    encoder: &webp::Encoder,
+    settings: &CompressionSettings,
+) -> CompressionResult<webp::WebPMemory> {
+    encoder
+        .encode_advanced(&webp_config(settings)?)
+        .map_err(|e| CompressionError::ProcessingError(format!("WebP encoding failed: {e:?}")))
+}
+
+/// libwebp configuration for `settings`, shared by still and animated output
+fn webp_config(settings: &CompressionSettings) -> CompressionResult<webp::WebPConfig> {
+    let options = settings.encoder.webp;
+    let preset = match options.preset {
+        None => libwebp_sys::WebPPreset::WEBP_PRESET_DEFAULT,
+        Some(WebpPreset::Photo) => libwebp_sys::WebPPreset::WEBP_PRESET_PHOTO,
+        Some(WebpPreset::Drawing) => libwebp_sys::WebPPreset::WEBP_PRESET_DRAWING,
+        Some(WebpPreset::Icon) => libwebp_sys::WebPPreset::WEBP_PRESET_ICON,
+    };
+    let mut config = webp::WebPConfig::new_with_preset(preset, 75.0).map_err(|()| {
+        CompressionError::ProcessingError("Failed to create WebPConfig".to_string())
+    })?;
+    config.method = i32::from(options.method);
+
+    match options.mode {
+        WebpMode::Lossy => {
+            config.lossless = 0;
+            config.quality = f32::from(settings.quality);
+            config.use_sharp_yuv = 1; // Precise RGB→YUV, fixes color desaturation
+            config.alpha_quality = i32::from(options.alpha_quality);
+            config.autofilter = 1; // Auto deblocking filter
+        }
+        WebpMode::NearLossless | WebpMode::Lossless => {
+            config.lossless = 1;
+            // Lossless coding reads `quality` as the effort
+            config.quality = f32::from(options.lossless_effort);
+            config.alpha_compression = 0;
+            if options.mode == WebpMode::NearLossless {
+                config.near_lossless = i32::from(options.near_lossless);
+            }
+        }
+    }
+    Ok(config)
+}
+
+fn validate_settings(settings: &CompressionSettings) -> CompressionResult<()> {
+    if !settings.is_valid() {
+        return Err(CompressionError::InvalidSettings(format!(
+            "Invalid settings: quality {}, resize {:?}",
+            settings.quality, settings.resize
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+// Assertions compare against exact float literals produced by the same code path,
+// so bit-equality is the intended check.
+#[allow(clippy::float_cmp)]
+mod tests {
+    use super::*;
+    use crate::domain::compression::settings::{
+        ChromaSubsampling, PerceptualTarget, RawDecodeMode,
+    };
+
+    #[test]
+    fn test_compression_output_creation() {
+        let output_path = std::path::PathBuf::from("/tmp/test.webp");
+        let output =
+            CompressionOutput::new(Some(output_path.clone()), 1000, 5, OutputFormat::WebP, None);
+
+        assert_eq!(output.output_path, Some(output_path));
+        assert_eq!(output.original_size, 1000);
+        assert_eq!(output.compressed_size, 5);
+        assert!(output.savings_percent > 99.0); // 995/1000 * 100
+    }
+
+    #[test]
+    fn test_settings_validation() {
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        assert!(validate_settings(&settings).is_ok());
+
+        let mut invalid_settings = CompressionSettings::new(80, OutputFormat::WebP);
+        invalid_settings.quality = 200; // Invalid quality
+        assert!(validate_settings(&invalid_settings).is_err());
+    }
+
+    #[test]
+    fn test_build_riff_chunk() {
+        let data = b"test data";
+        let chunk = build_riff_chunk(b"ICCP", data);
+        assert_eq!(&chunk[0..4], b"ICCP");
+        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
+        assert_eq!(size, 9);
+        assert_eq!(&chunk[8..17], data);
+        // Odd size → padding byte
+        assert_eq!(chunk.len(), 18);
+        assert_eq!(chunk[17], 0);
+    }
+
+    #[test]
+    fn test_build_riff_chunk_even() {
+        let data = b"test";
+        let chunk = build_riff_chunk(b"ICCP", data);
+        // Even size → no padding
+        assert_eq!(chunk.len(), 12);
+    }
+
+    #[test]
+    fn test_inject_icc_into_webp_too_small() {
+        let small = vec![0u8; 5];
+        let icc = vec![1, 2, 3];
+        let result = inject_icc_into_webp(&small, &icc);
+        assert_eq!(result, small);
+    }
+
+    #[test]
+    fn test_inject_icc_into_non_webp_riff() {
+        // RIFF header with "WAVE" instead of "WEBP" — should return unchanged
+        let mut wave = Vec::new();
+        wave.extend_from_slice(b"RIFF");
+        wave.extend_from_slice(&100u32.to_le_bytes());
+        wave.extend_from_slice(b"WAVE");
+        wave.extend(vec![0u8; 100]);
+        let icc = vec![1, 2, 3];
+        let result = inject_icc_into_webp(&wave, &icc);
+        assert_eq!(result, wave);
+    }
+
+    #[test]
+    fn test_compression_output_zero_size() {
+        let output = CompressionOutput::new(
+            Some(std::path::PathBuf::from("/tmp/test.webp")),
+            0,
+            0,
+            OutputFormat::WebP,
+            None,
+        );
+        assert_eq!(output.savings_percent, 0.0);
+    }
+
+    /// Bytes of every `iloc` extent, in file order (test helper: version 0,
+    /// no base offset — the layout ravif writes)
+    fn avif_item_payloads(avif: &[u8]) -> Vec<Vec<u8>> {
+        let top = parse_iso_boxes(avif, 0..avif.len()).unwrap();
+        let meta = find_iso_box(&top, b"meta").unwrap();
+        let children = parse_iso_boxes(avif, meta.body + 4..meta.end).unwrap();
+        let iloc = find_iso_box(&children, b"iloc").unwrap();
+        let offset_size = usize::from(avif[iloc.body + 4] >> 4);
+        let length_size = usize::from(avif[iloc.body + 4] & 0x0F);
+        let item_count = read_be_uint(avif, iloc.body + 6, 2).unwrap();
+
+        let mut pos = iloc.body + 8;
+        let mut payloads = Vec::new();
+        for _ in 0..item_count {
+            pos += 4; // item_ID + data_reference_index
+            let extent_count = read_be_uint(avif, pos, 2).unwrap();
+            pos += 2;
+            for _ in 0..extent_count {
+                let offset = read_be_uint(avif, pos, offset_size).unwrap() as usize;
+                let length = read_be_uint(avif, pos + offset_size, length_size).unwrap() as usize;
+                payloads.push(avif[offset..offset + length].to_vec());
+                pos += offset_size + length_size;
+            }
+        }
+        payloads
+    }
+
+    /// EXIF and XMP stored as AVIF metadata items, found through `iinf` and
+    /// `iloc` (test helper: the same layout as `avif_item_payloads`)
+    fn avif_metadata(avif: &[u8]) -> ImageMetadata {
+        let top = parse_iso_boxes(avif, 0..avif.len()).unwrap();
+        let meta = find_iso_box(&top, b"meta").unwrap();
+        let children = parse_iso_boxes(avif, meta.body + 4..meta.end).unwrap();
+        let iinf = find_iso_box(&children, b"iinf").unwrap();
+        let iloc = find_iso_box(&children, b"iloc").unwrap();
+
+        let mut ids = (None, None);
+        for infe in parse_iso_boxes(avif, iinf.body + 6..iinf.end).unwrap() {
+            let id = read_be_uint(avif, infe.body + 4, 2).unwrap();
+            match &avif[infe.body + 8..infe.body + 12] {
+                b"Exif" => ids.0 = Some(id),
+                b"mime" if avif[infe.body..infe.end].ends_with(b"application/rdf+xml\0") => {
+                    ids.1 = Some(id);
+                }
+                _ => {}
+            }
+        }
+
+        let offset_size = usize::from(avif[iloc.body + 4] >> 4);
+        let length_size = usize::from(avif[iloc.body + 4] & 0x0F);
+        let item_count = read_be_uint(avif, iloc.body + 6, 2).unwrap();
+        let mut pos = iloc.body + 8;
+        let mut metadata = ImageMetadata::default();
+        for _ in 0..item_count {
+            let id = read_be_uint(avif, pos, 2).unwrap();
+            pos += 4;
+            let extent_count = read_be_uint(avif, pos, 2).unwrap();
+            pos += 2;
+            for _ in 0..extent_count {
+                let offset = read_be_uint(avif, pos, offset_size).unwrap() as usize;
+                let length = read_be_uint(avif, pos + offset_size, length_size).unwrap() as usize;
+                let payload = &avif[offset..offset + length];
+                if Some(id) == ids.0 {
+                    metadata.exif = Some(payload[4..].to_vec());
+                } else if Some(id) == ids.1 {
+                    metadata.xmp = Some(payload.to_vec());
+                }
+                pos += offset_size + length_size;
+            }
+        }
+        metadata
+    }
+
+    /// EXIF and XMP stored in the `Exif` and `xml ` boxes of a JPEG XL container
+    fn jxl_metadata(jxl: &[u8]) -> ImageMetadata {
+        let mut metadata = ImageMetadata::default();
+        if !jxl.starts_with(&[0, 0, 0, 12, b'J', b'X', b'L', b' ']) {
+            return metadata;
+        }
+        for jxl_box in parse_iso_boxes(jxl, 0..jxl.len()).unwrap() {
+            match &jxl_box.fourcc {
+                b"Exif" => metadata.exif = Some(jxl[jxl_box.body + 4..jxl_box.end].to_vec()),
+                b"xml " => metadata.xmp = Some(jxl[jxl_box.body..jxl_box.end].to_vec()),
+                _ => {}
+            }
+        }
+        metadata
+    }
+
+    fn sample_avif(with_alpha: bool) -> Vec<u8> {
+        let img = if with_alpha {
+            DynamicImage::ImageRgba8(image::RgbaImage::from_pixel(
+                16,
+                16,
+                image::Rgba([200, 40, 40, 128]),
+            ))
+        } else {
+            DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+                16,
+                16,
+                image::Rgb([200, 40, 40]),
+            ))
+        };
+        encode_avif(&img, 60).unwrap()
+    }
+
+    #[test]
+    fn test_encode_avif_writes_an_avif_container() {
+        let avif = sample_avif(false);
+        assert_eq!(&avif[4..8], b"ftyp");
+        assert_eq!(&avif[8..12], b"avif");
+    }
+
+    #[test]
+    fn test_inject_icc_into_avif_adds_prof_colr_and_keeps_payloads() {
+        let icc = vec![0xAB; 301];
+
+        for with_alpha in [false, true] {
+            let avif = sample_avif(with_alpha);
+            let result = inject_icc_into_avif(&avif, &icc);
+
+            // colr header + "prof" + profile, plus one ipma association byte
+            assert_eq!(result.len(), avif.len() + 8 + 4 + icc.len() + 1);
+
+            let top = parse_iso_boxes(&result, 0..result.len()).unwrap();
+            let meta = find_iso_box(&top, b"meta").unwrap();
+            let children = parse_iso_boxes(&result, meta.body + 4..meta.end).unwrap();
+            let iprp = find_iso_box(&children, b"iprp").unwrap();
+            let iprp_children = parse_iso_boxes(&result, iprp.body..iprp.end).unwrap();
+            let ipco = find_iso_box(&iprp_children, b"ipco").unwrap();
+            let props = parse_iso_boxes(&result, ipco.body..ipco.end).unwrap();
+            let colr = props.last().unwrap();
+            assert_eq!(&colr.fourcc, b"colr");
+            assert_eq!(&result[colr.body..colr.body + 4], b"prof");
+            assert_eq!(&result[colr.body + 4..colr.end], icc.as_slice());
+
+            // The primary item (id 1) now references the new property
+            let ipma = find_iso_box(&iprp_children, b"ipma").unwrap();
+            let ipma_box = &result[ipma.start..ipma.end];
+            let expected_index = u8::try_from(props.len()).unwrap();
+            assert_eq!(read_be_uint(ipma_box, 16, 2), Some(1));
+            let count = usize::from(ipma_box[18]);
+            assert_eq!(ipma_box[18 + count], expected_index);
+
+            // Coded data still resolves through the shifted `iloc` offsets
+            assert_eq!(avif_item_payloads(&result), avif_item_payloads(&avif));
+        }
+    }
+
+    #[test]
+    fn test_inject_metadata_into_avif_adds_items_for_the_primary_image() {
+        let metadata = ImageMetadata {
+            exif: Some(b"MM\x00\x2a\x00\x00\x00\x08\x00\x00".to_vec()),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+        };
+
+        for with_alpha in [false, true] {
+            let avif = sample_avif(with_alpha);
+            let result = inject_metadata_into_avif(&avif, &metadata);
+
+            assert_eq!(avif_metadata(&result), metadata);
+            // The coded data still resolves, the new items coming last
+            let payloads = avif_item_payloads(&result);
+            assert_eq!(payloads[..payloads.len() - 2], avif_item_payloads(&avif));
+
+            // Both items describe the primary one (id 1)
+            let top = parse_iso_boxes(&result, 0..result.len()).unwrap();
+            let meta = find_iso_box(&top, b"meta").unwrap();
+            let children = parse_iso_boxes(&result, meta.body + 4..meta.end).unwrap();
+            let iref = find_iso_box(&children, b"iref").unwrap();
+            let references = parse_iso_boxes(&result, iref.body + 4..iref.end).unwrap();
+            let described: Vec<_> = references
+                .iter()
+                .filter(|reference| &reference.fourcc == b"cdsc")
+                .map(|cdsc| read_be_uint(&result, cdsc.body + 4, 2).unwrap())
+                .collect();
+            assert_eq!(described, [1, 1]);
+        }
+    }
+
+    #[test]
+    fn test_inject_icc_into_avif_leaves_unknown_data_unchanged() {
+        let not_avif = b"RIFF\x10\x00\x00\x00WEBPVP8 ".to_vec();
+        assert_eq!(inject_icc_into_avif(&not_avif, &[1, 2, 3]), not_avif);
+    }
+
+    #[test]
+    fn test_jxl_lossless_round_trip_keeps_pixels_and_alpha() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(24, 16, |x, y| {
+            image::Rgba([(x * 10) as u8, (y * 15) as u8, 90, (x + y) as u8 * 5])
+        }));
+
+        let encoded = encode_jxl(&img, 100, None, &ImageMetadata::default()).unwrap();
+        assert_eq!(&encoded[..2], &[0xFF, 0x0A]); // bare codestream signature
+
+        let (decoded, _) = decode_jxl(&encoded, &DecodeLimits::default()).unwrap();
+        assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+    }
+
+    #[test]
+    fn test_jxl_lossy_encode_keeps_dimensions() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            40,
+            30,
+            image::Rgb([10, 120, 200]),
+        ));
+
+        let (decoded, icc) = decode_jxl(
+            &encode_jxl(&img, 70, None, &ImageMetadata::default()).unwrap(),
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (40, 30));
+        assert!(!decoded.color().has_alpha());
+        assert!(icc.is_some()); // profile describing the decoded sRGB pixels
+    }
+
+    #[test]
+    fn test_recompress_jpeg_to_jxl_stores_reconstruction_data() {
+        let img =
+            image::RgbImage::from_fn(32, 32, |x, y| image::Rgb([x as u8 * 8, y as u8 * 8, 64]));
+        let jpeg = encode_jpeg_mozjpeg(
+            img.as_raw(),
+            32,
+            32,
+            85,
+            JpegOptions::default(),
+            None,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+
+        let jxl = recompress_jpeg_to_jxl(&jpeg).unwrap();
+        let boxes = parse_iso_boxes(&jxl, 0..jxl.len()).unwrap();
+        assert_eq!(&boxes[0].fourcc, b"JXL ");
+        assert!(find_iso_box(&boxes, b"jbrd").is_some());
+
+        let (decoded, _) = decode_jxl(&jxl, &DecodeLimits::default()).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (32, 32));
+    }
+
+    #[test]
+    fn test_decode_jxl_rejects_garbage() {
+        assert!(decode_jxl(b"not a jpeg xl file", &DecodeLimits::default()).is_err());
+    }
+
+    /// Offset of the first JPEG segment with the given marker and payload prefix
+    fn find_jpeg_segment(jpeg: &[u8], marker: u8, prefix: &[u8]) -> Option<usize> {
+        (2..jpeg.len().saturating_sub(4 + prefix.len())).find(|&i| {
+            jpeg[i] == 0xFF && jpeg[i + 1] == marker && jpeg[i + 4..].starts_with(prefix)
+        })
+    }
+
+    #[test]
+    fn test_optimize_jpeg_lossless_keeps_pixels_icc_and_exif() {
+        let img = image::RgbImage::from_fn(48, 40, |x, y| {
+            image::Rgb([(x * 5) as u8, (y * 6) as u8, ((x + y) * 3) as u8])
+        });
+        let icc = vec![0x42; 200];
+        let baseline = encode_jpeg_mozjpeg(
+            img.as_raw(),
+            48,
+            40,
+            90,
+            JpegOptions::default(),
+            Some(&icc),
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+
+        // Splice an EXIF APP1 and a COM segment in right after SOI
+        let mut jpeg = baseline[..2].to_vec();
+        jpeg.extend_from_slice(&[0xFF, 0xE1, 0x00, 0x0E]);
+        jpeg.extend_from_slice(b"Exif\0\0MM\0*\0\0");
+        jpeg.extend_from_slice(&[0xFF, 0xFE, 0x00, 0x07]);
+        jpeg.extend_from_slice(b"hello");
+        jpeg.extend_from_slice(&baseline[2..]);
+
+        let optimized =
+            optimize_jpeg_lossless(&jpeg, &extract_metadata(&jpeg, "jpeg"), true).unwrap();
+
+        assert_eq!(
+            extract_metadata(&optimized, "jpeg").exif.as_deref(),
+            Some(&b"MM\0*\0\0"[..])
+        );
+        assert!(find_jpeg_segment(&optimized, 0xFE, b"hello").is_none());
+        let icc_at = find_jpeg_segment(&optimized, 0xE2, b"ICC_PROFILE\0").unwrap();
+        assert_eq!(
+            &optimized[icc_at + 18..icc_at + 18 + icc.len()],
+            icc.as_slice()
+        );
+        // SOF2: progressive DCT
+        assert!(optimized.windows(2).any(|w| w == [0xFF, 0xC2]));
+
+        let decode = |data: &[u8]| {
+            image::load_from_memory_with_format(data, image::ImageFormat::Jpeg)
+                .unwrap()
+                .to_rgb8()
+        };
+        assert_eq!(decode(&optimized), decode(&jpeg));
+    }
+
+    #[test]
+    fn test_optimize_jpeg_lossless_rejects_corrupt_input() {
+        assert!(
+            optimize_jpeg_lossless(
+                b"\xFF\xD8\xFF\xE0 not really a jpeg",
+                &ImageMetadata::default(),
+                true
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    fn test_jpeg_lossless_option_keeps_pixels_at_any_quality() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(48, 40, |x, y| {
+            image::Rgb([(x * 5) as u8, (y * 6) as u8, ((x + y) * 3) as u8])
+        }));
+        let jpeg = encode_jpeg(
+            &img,
+            None,
+            &ImageMetadata::default(),
+            85,
+            JpegOptions::default(),
+        )
+        .unwrap();
+        let mut settings = CompressionSettings::new(60, OutputFormat::Jpeg);
+        settings.encoder.jpeg.lossless = true;
+
+        let (output, _) = compress_bytes(&jpeg, "jpeg", &settings).unwrap();
+        let decode = |data: &[u8]| image::load_from_memory(data).unwrap().to_rgb8();
+        assert_eq!(decode(&output), decode(&jpeg));
+
+        // Neither a PNG source nor a resize can keep the coefficients
+        let png = encode_source(&img);
+        assert!(matches!(
+            compress_bytes(&png, "png", &settings),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+        settings.resize.max_width = Some(24);
+        assert!(matches!(
+            compress_bytes(&jpeg, "jpeg", &settings),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn test_quantize_png_writes_an_indexed_png_with_icc() {
+        // A smooth gradient: far more than 256 colours going in
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(64, 64, |x, y| {
+            image::Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255])
+        }));
+        let icc = vec![0x11; 128];
+        let settings = CompressionSettings::new(40, OutputFormat::Png);
+
+        let png = quantize_png(&img, Some(&icc), &ImageMetadata::default(), &settings)
+            .unwrap()
+            .unwrap();
+
+        assert!(png.windows(4).any(|w| w == b"PLTE"));
+        assert!(png.windows(4).any(|w| w == b"iCCP"));
+        let decoded = image::load_from_memory(&png).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (64, 64));
+    }
+
+    #[test]
+    fn test_quantize_png_declines_when_quality_floor_is_unreachable() {
+        // Random-ish noise cannot be represented by 256 colours at quality 99
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            let v = x
+                .wrapping_mul(2_654_435_761)
+                .wrapping_add(y.wrapping_mul(40_503));
+            image::Rgb([v as u8, (v >> 8) as u8, (v >> 16) as u8])
+        }));
+        let settings = CompressionSettings::new(99, OutputFormat::Png);
+
+        assert!(
+            quantize_png(&img, None, &ImageMetadata::default(), &settings)
+                .unwrap()
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn test_png_below_quality_100_is_quantized_only_when_asked() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, ((x + y) * 2) as u8])
+        }));
+        let png = encode_source(&img);
+        let mut settings = CompressionSettings::new(60, OutputFormat::Png);
+
+        let (lossless, _) = compress_bytes(&png, "png", &settings).unwrap();
+        assert!(!lossless.windows(4).any(|w| w == b"PLTE"));
+        assert_eq!(
+            decode_output(&lossless, OutputFormat::Png)
+                .unwrap()
+                .to_rgb8(),
+            img.to_rgb8()
+        );
+
+        settings.encoder.png.quantize = true;
+        let (palette, _) = compress_bytes(&png, "png", &settings).unwrap();
+        assert!(palette.windows(4).any(|w| w == b"PLTE"));
+    }
+
+    fn sample_metadata() -> ImageMetadata {
+        ImageMetadata {
+            exif: Some(b"II*\0\x08\0\0\0\0\0".to_vec()),
+            xmp: Some(b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>".to_vec()),
+        }
+    }
+
+    #[test]
+    fn test_encode_jpeg_writes_exif_and_xmp() {
+        let img = image::RgbImage::from_pixel(16, 16, image::Rgb([90, 30, 200]));
+        let jpeg = encode_jpeg_mozjpeg(
+            img.as_raw(),
+            16,
+            16,
+            80,
+            JpegOptions::default(),
+            None,
+            &sample_metadata(),
+        )
+        .unwrap();
+
+        assert_eq!(extract_metadata(&jpeg, "jpeg"), sample_metadata());
+        assert!(image::load_from_memory(&jpeg).is_ok());
+    }
+
+    #[test]
+    fn test_encode_png_writes_exif_and_xmp() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            16,
+            16,
+            image::Rgb([90, 30, 200]),
+        ));
+        let png = encode_png(&img, None, &sample_metadata(), PngOptions::default()).unwrap();
+
+        assert_eq!(extract_metadata(&png, "png"), sample_metadata());
+        assert!(image::load_from_memory(&png).is_ok());
+    }
+
+    #[test]
+    fn test_decode_upright_applies_and_resets_exif_orientation() {
+        // Orientation 6: stored 4x2, displayed rotated 90° clockwise (2x4)
+        let exif = b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0".to_vec();
+        let metadata = ImageMetadata {
+            exif: Some(exif),
+            xmp: None,
+        };
+        let img = image::RgbImage::from_pixel(4, 2, image::Rgb([200, 80, 10]));
+        let jpeg = encode_jpeg_mozjpeg(
+            img.as_raw(),
+            4,
+            2,
+            90,
+            JpegOptions::default(),
+            None,
+            &metadata,
+        )
+        .unwrap();
+
+        let (decoded, _, metadata) = decode_upright(
+            &jpeg,
+            "jpeg",
+            &CompressionSettings::new(80, OutputFormat::Png),
+        )
+        .unwrap();
+
+        assert_eq!((decoded.width(), decoded.height()), (2, 4));
+        assert_eq!(
+            metadata.orientation(),
+            Some(image::metadata::Orientation::NoTransforms)
+        );
+    }
+
+    #[test]
+    fn test_inject_metadata_into_simple_webp() {
+        let img = image::RgbImage::from_pixel(16, 16, image::Rgb([90, 30, 200]));
+        let webp = webp::Encoder::from_rgb(img.as_raw(), 16, 16).encode(75.0);
+        assert_eq!(&webp[12..16], b"VP8 ");
+
+        let result = inject_metadata_into_webp(&webp, &sample_metadata());
+
+        assert_eq!(&result[12..16], b"VP8X");
+        assert_eq!(
+            result[20] & (WEBP_EXIF_FLAG | WEBP_XMP_FLAG),
+            WEBP_EXIF_FLAG | WEBP_XMP_FLAG
+        );
+        assert_eq!(
+            u32::from_le_bytes([result[4], result[5], result[6], result[7]]) as usize,
+            result.len() - 8
+        );
+        assert_eq!(extract_metadata(&result, "webp"), sample_metadata());
+        let decoded = image::load_from_memory(&result).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (16, 16));
+    }
+
+    #[test]
+    fn test_inject_metadata_keeps_existing_icc_chunk() {
+        let img = image::RgbImage::from_pixel(16, 16, image::Rgb([90, 30, 200]));
+        let webp = webp::Encoder::from_rgb(img.as_raw(), 16, 16).encode(75.0);
+        let with_icc = inject_icc_into_webp(&webp, &[0x42; 64]);
+
+        let result = inject_metadata_into_webp(&with_icc, &sample_metadata());
+
+        assert_eq!(result[20] & WEBP_ICC_FLAG, WEBP_ICC_FLAG);
+        assert!(result.windows(4).any(|w| w == b"ICCP"));
+        assert_eq!(extract_metadata(&result, "webp"), sample_metadata());
+    }
+
+    #[test]
+    fn test_decode_image_with_icc_reads_png_jpeg_and_webp_profiles() {
+        let img = image::RgbImage::from_pixel(8, 8, image::Rgb([30, 60, 90]));
+        let icc = vec![0x42; 300];
+        let none = ImageMetadata::default();
+
+        let png = encode_png(
+            &DynamicImage::ImageRgb8(img.clone()),
+            Some(&icc),
+            &none,
+            PngOptions::default(),
+        )
+        .unwrap();
+        let jpeg = encode_jpeg_mozjpeg(
+            img.as_raw(),
+            8,
+            8,
+            90,
+            JpegOptions::default(),
+            Some(&icc),
+            &none,
+        )
+        .unwrap();
+        let webp = webp::Encoder::from_rgb(img.as_raw(), 8, 8).encode(80.0);
+        let webp = inject_icc_into_webp(&webp, &icc);
+
+        for (data, format) in [(&png, "png"), (&jpeg, "JPG"), (&webp, "webp")] {
+            let (decoded, found) = decode_image_with_icc(
+                data,
+                format,
+                &CompressionSettings::new(100, OutputFormat::Png),
+            )
+            .unwrap();
+            assert_eq!((decoded.width(), decoded.height()), (8, 8), "{format}");
+            assert_eq!(found.as_deref(), Some(icc.as_slice()), "{format}");
+        }
+    }
+
+    #[test]
+    fn test_convert_to_srgb_drops_the_profile_and_moves_the_pixels() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let p3 = crate::domain::compression::color::tests::display_p3_profile();
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            8,
+            8,
+            image::Rgb([180, 120, 60]),
+        ));
+        let input = dir.path().join("wide.png");
+        std::fs::write(
+            &input,
+            encode_png(
+                &img,
+                Some(&p3),
+                &ImageMetadata::default(),
+                PngOptions::default(),
+            )
+            .unwrap(),
+        )
+        .unwrap();
+
+        // Quality 100 on both: PNG -> PNG must leave its passthrough to convert
+        for format in [OutputFormat::Png, OutputFormat::WebP] {
+            let output = dir.path().join(format!("out.{}", format.extension()));
+            let settings = CompressionSettings {
+                convert_to_srgb: true,
+                ..CompressionSettings::new(100, format)
+            };
+            compress_file_to_file(&input, &output, &settings).unwrap();
+
+            let data = std::fs::read(&output).unwrap();
+            assert_eq!(output_icc(&data, format, &p3), None, "{format}");
+            let image::Rgb([r, g, b]) = *image::load_from_memory(&data)
+                .unwrap()
+                .to_rgb8()
+                .get_pixel(4, 4);
+            assert!(r > 180 && g < 125 && b < 60, "{format}: {:?}", [r, g, b]);
+        }
+    }
+
+    #[test]
+    fn test_resize_applies_to_the_upright_image_and_skips_passthroughs() {
+        let dir = tempfile::TempDir::new().unwrap();
+        // Orientation 6: stored 40x20, displayed 20x40
+        let exif = b"MM\0*\0\0\0\x08\0\x01\x01\x12\0\x03\0\0\0\x01\0\x06\0\0\0\0\0\0".to_vec();
+        let metadata = ImageMetadata {
+            exif: Some(exif),
+            xmp: None,
+        };
+        let img = image::RgbImage::from_pixel(40, 20, image::Rgb([200, 80, 10]));
+        let input = dir.path().join("rotated.jpg");
+        std::fs::write(
+            &input,
+            encode_jpeg_mozjpeg(
+                img.as_raw(),
+                40,
+                20,
+                90,
+                JpegOptions::default(),
+                None,
+                &metadata,
+            )
+            .unwrap(),
+        )
+        .unwrap();
+        let resize = ResizeOptions {
+            max_height: Some(20),
+            ..ResizeOptions::default()
+        };
+
+        // Quality 100 JPEG -> JPEG and JPEG -> JXL would otherwise keep every pixel
+        for format in [OutputFormat::Jpeg, OutputFormat::Jxl, OutputFormat::WebP] {
+            let output = dir.path().join(format!("out.{}", format.extension()));
+            let settings = CompressionSettings {
+                resize,
+                ..CompressionSettings::new(100, format)
+            };
+            let result = compress_file_to_file(&input, &output, &settings).unwrap();
+            assert_eq!(result.dimensions, Some((10, 20)), "{format}");
+        }
+
+        // Bounds the upright image already meets leave the passthrough in place
+        let output = dir.path().join("same.jpg");
+        let settings = CompressionSettings {
+            resize: ResizeOptions {
+                max_width: Some(20),
+                ..ResizeOptions::default()
+            },
+            ..CompressionSettings::new(100, OutputFormat::Jpeg)
+        };
+        let result = compress_file_to_file(&input, &output, &settings).unwrap();
+        assert_eq!(result.dimensions, Some((40, 20)));
+    }
+
+    #[test]
+    fn test_resize_image_keeps_transparent_colour_out_of_the_edge() {
+        // Left half opaque white, right half transparent pure red
+        let img = image::RgbaImage::from_fn(16, 16, |x, _| {
+            if x < 8 {
+                image::Rgba([255, 255, 255, 255])
+            } else {
+                image::Rgba([255, 0, 0, 0])
+            }
+        });
+
+        let resized = resize_image(&DynamicImage::ImageRgba8(img), 8, 8).to_rgba8();
+
+        let image::Rgba([r, g, b, a]) = *resized.get_pixel(4, 4);
+        assert!(a > 0 && a < 255, "{a}");
+        assert!(r > 240 && g > 240 && b > 240, "{:?}", [r, g, b]);
+    }
+
+    /// A busy 64x64 PNG, so quality has a large effect on output size
+    fn write_noisy_png(dir: &Path) -> std::path::PathBuf {
+        let img = image::RgbImage::from_fn(64, 64, |x, y| {
+            let v = (x * 7919 + y * 104_729) ^ (x * y * 31);
+            image::Rgb([(v % 251) as u8, (v / 7 % 241) as u8, (v / 13 % 239) as u8])
+        });
+        let input = dir.join("noisy.png");
+        img.save(&input).unwrap();
+        input
+    }
+
+    #[test]
+    fn test_target_size_picks_the_highest_quality_that_fits() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+        let img = image::open(&input).unwrap();
+        let none = ImageMetadata::default();
+
+        for format in [OutputFormat::WebP, OutputFormat::Jpeg] {
+            let size_at = |quality: u8| match format {
+                OutputFormat::WebP => encode_webp(
+                    &img,
+                    None,
+                    &none,
+                    &CompressionSettings::new(quality, format),
+                )
+                .unwrap()
+                .len() as u64,
+                _ => encode_jpeg(&img, None, &none, quality, JpegOptions::default())
+                    .unwrap()
+                    .len() as u64,
+            };
+            let target = size_at(50);
+            let output = dir.path().join(format!("out.{}", format.extension()));
+            let settings = CompressionSettings {
+                target_size: Some(target),
+                ..CompressionSettings::new(90, format)
+            };
+
+            let result = compress_file_to_file(&input, &output, &settings).unwrap();
+
+            let search = result.quality_search.unwrap();
+            assert!(result.compressed_size <= target, "{format}");
+            assert!(search.quality >= 50 && search.quality < 90, "{format}");
+            assert!(size_at(search.quality + 1) > target, "{format}");
+            // The ceiling, then a bisection of 1..=89
+            assert!(search.passes > 1 && search.passes <= 8, "{format}");
+        }
+    }
+
+    #[test]
+    fn test_target_size_reports_a_fitting_ceiling_in_one_pass() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+        let output = dir.path().join("out.jpg");
+        let settings = CompressionSettings {
+            target_size: Some(10_000_000),
+            ..CompressionSettings::new(85, OutputFormat::Jpeg)
+        };
+
+        let result = compress_file_to_file(&input, &output, &settings).unwrap();
+
+        assert_eq!(
+            result.quality_search,
+            Some(QualitySearch {
+                quality: 85,
+                passes: 1,
+                ssim: None,
+            })
+        );
+    }
+
+    #[test]
+    fn test_target_size_fails_with_the_smallest_size_when_nothing_fits() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+        let output = dir.path().join("out.webp");
+        let settings = CompressionSettings {
+            target_size: Some(100),
+            ..CompressionSettings::new(80, OutputFormat::WebP)
+        };
+
+        let error = compress_file_to_file(&input, &output, &settings).unwrap_err();
+
+        let CompressionError::TargetSizeUnreachable {
+            target_bytes,
+            smallest_bytes,
+        } = error
+        else {
+            panic!("unexpected error: {error}");
+        };
+        assert_eq!(target_bytes, 100);
+        assert!(smallest_bytes > 100);
+        assert!(!output.exists());
+
+        let png_settings = CompressionSettings {
+            format: OutputFormat::Png,
+            ..settings
+        };
+        assert!(matches!(
+            compress_file_to_file(&input, &output, &png_settings),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn test_perceptual_target_picks_the_lowest_quality_that_meets_it() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+        let img = image::open(&input).unwrap();
+
+        for format in [OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Jxl] {
+            let output = dir.path().join(format!("out.{}", format.extension()));
+            let settings = CompressionSettings {
+                perceptual_target: Some(PerceptualTarget::MinSsim(0.95)),
+                ..CompressionSettings::new(80, format)
+            };
+
+            let result = compress_file_to_file(&input, &output, &settings).unwrap();
+
+            let search = result.quality_search.unwrap();
+            let reached = search.ssim.unwrap();
+            assert!(reached >= 0.95, "{format}: {reached}");
+            // Bisection of 1..=100 takes at most 7 encodes
+            assert!(search.passes <= 7, "{format}");
+            let data = std::fs::read(&output).unwrap();
+            let decoded = match format {
+                OutputFormat::Jxl => decode_jxl(&data, &DecodeLimits::default()).unwrap().0,
+                _ => image::load_from_memory(&data).unwrap(),
+            };
+            assert!((ssim(&img, &decoded).unwrap() - reached).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn test_perceptual_target_fails_when_even_quality_100_falls_short() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+        let output = dir.path().join("out.jpg");
+        let settings = CompressionSettings {
+            perceptual_target: Some(PerceptualTarget::MinSsim(1.0)),
+            ..CompressionSettings::new(80, OutputFormat::Jpeg)
+        };
+
+        let error = compress_file_to_file(&input, &output, &settings).unwrap_err();
+
+        let CompressionError::PerceptualTargetUnreachable { best_ssim, .. } = error else {
+            panic!("unexpected error: {error}");
+        };
+        assert!(best_ssim > 0.9 && best_ssim < 1.0, "{best_ssim}");
+
+        // There is no AVIF decoder to score candidates with
+        let avif_settings = CompressionSettings {
+            format: OutputFormat::Avif,
+            ..settings
+        };
+        let output = dir.path().join("out.avif");
+        assert!(matches!(
+            compress_file_to_file(&input, &output, &avif_settings),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn test_every_compression_measures_its_output() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+
+        let measured = |settings: &CompressionSettings| {
+            let output = dir.path().join(format!(
+                "out{}.{}",
+                settings.quality,
+                settings.format.extension()
+            ));
+            compress_file_to_file(&input, &output, settings)
+                .unwrap()
+                .metrics
+        };
+
+        // Lossless: PNG -> PNG passthrough, WebP lossless, JPEG XL modular, and
+        // a palette that cannot reach quality 60 on noise
+        let mut palette = CompressionSettings::new(60, OutputFormat::Png);
+        palette.encoder.png.quantize = true;
+        for settings in [
+            CompressionSettings::new(100, OutputFormat::Png),
+            lossless_webp(),
+            CompressionSettings::new(100, OutputFormat::Jxl),
+            palette,
+        ] {
+            assert_eq!(
+                measured(&settings),
+                Some(QualityMetrics::IDENTICAL),
+                "{}",
+                settings.format
+            );
+        }
+        for format in [OutputFormat::WebP, OutputFormat::Jpeg] {
+            let metrics = measured(&CompressionSettings::new(60, format)).unwrap();
+            let psnr = metrics.psnr.unwrap();
+            // Noise is the worst case for a lossy encoder, hence the low floor
+            assert!(psnr > 5.0 && psnr < 60.0, "{format}: {psnr}");
+            assert!(metrics.ssim < 1.0 && metrics.max_error > 0, "{format}");
+        }
+        assert_eq!(
+            measured(&CompressionSettings::new(60, OutputFormat::Avif)),
+            None
+        );
+    }
+
+    #[test]
+    fn test_lossy_flag_follows_what_was_written() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let png = std::fs::read(write_noisy_png(dir.path())).unwrap();
+        let img = image::load_from_memory(&png).unwrap();
+        let jpeg = encode_jpeg(
+            &img,
+            None,
+            &ImageMetadata::default(),
+            90,
+            JpegOptions::default(),
+        )
+        .unwrap();
+        let written = |source: &[u8], format: &str, settings: &CompressionSettings| {
+            compress_bytes(source, format, settings).unwrap().1
+        };
+
+        // A palette that cannot reach quality 60 on noise falls back to lossless
+        let mut palette = CompressionSettings::new(60, OutputFormat::Png);
+        palette.encoder.png.quantize = true;
+        assert!(!written(&png, "png", &palette).lossy);
+        assert!(!written(&png, "png", &lossless_webp()).lossy);
+        assert!(
+            written(
+                &png,
+                "png",
+                &CompressionSettings::new(60, OutputFormat::WebP)
+            )
+            .lossy
+        );
+
+        // JPEG -> JPEG at quality 100 keeps the coefficients, unless the pixels
+        // change and the image is encoded again
+        let jpeg_100 = CompressionSettings::new(100, OutputFormat::Jpeg);
+        assert!(!written(&jpeg, "jpg", &jpeg_100).lossy);
+        let resized = CompressionSettings {
+            resize: ResizeOptions {
+                max_width: Some(32),
+                ..ResizeOptions::default()
+            },
+            ..jpeg_100
+        };
+        let output = written(&jpeg, "jpg", &resized);
+        assert_eq!(output.dimensions, Some((32, 32)));
+        assert!(output.lossy);
+
+        // AVIF is always lossy, and says why it was not measured
+        let avif = written(
+            &png,
+            "png",
+            &CompressionSettings::new(60, OutputFormat::Avif),
+        );
+        assert!(avif.lossy);
+        assert_eq!(
+            (avif.metrics, avif.unmeasured),
+            (None, Some(Unmeasured::AvifOutput))
+        );
+    }
+
+    #[test]
+    fn test_compress_bytes_works_without_the_filesystem() {
+        let mut gradient = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::from_fn(48, 32, |x, y| {
+            image::Rgb([(x * 5) as u8, (y * 7) as u8, 128])
+        }))
+        .write_to(
+            &mut std::io::Cursor::new(&mut gradient),
+            image::ImageFormat::Png,
+        )
+        .unwrap();
+
+        for format in [
+            OutputFormat::WebP,
+            OutputFormat::Png,
+            OutputFormat::Jpeg,
+            OutputFormat::Avif,
+            OutputFormat::Jxl,
+        ] {
+            let (data, output) =
+                compress_bytes(&gradient, "png", &CompressionSettings::new(80, format)).unwrap();
+
+            assert_eq!(output.output_path, None, "{format}");
+            assert_eq!(output.original_size, gradient.len() as u64, "{format}");
+            assert_eq!(output.compressed_size, data.len() as u64, "{format}");
+            assert_eq!(output.dimensions, Some((48, 32)), "{format}");
+            if format != OutputFormat::Avif {
+                let decoded = decode_output(&data, format).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (48, 32), "{format}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_the_content_decides_the_input_format() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let png = std::fs::read(write_noisy_png(dir.path())).unwrap();
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+
+        // A PNG declared as JPEG, or saved without an extension
+        let (_, output) = compress_bytes(&png, "jpg", &settings).unwrap();
+        assert_eq!(output.dimensions, Some((64, 64)));
+        let bare = dir.path().join("IMG_0001");
+        std::fs::write(&bare, &png).unwrap();
+        let output = dir.path().join("out.webp");
+        assert!(compress_file_to_file(&bare, &output, &settings).is_ok());
+
+        assert!(matches!(
+            compress_bytes(b"not an image", "", &settings),
+            Err(CompressionError::UnsupportedFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_a_header_declaring_a_huge_image_is_refused_before_decoding() {
+        let img = DynamicImage::ImageRgb8(image::RgbImage::new(8, 8));
+        let mut jpeg = encode_jpeg(
+            &img,
+            None,
+            &ImageMetadata::default(),
+            80,
+            JpegOptions::default(),
+        )
+        .unwrap();
+        // Rewrite the frame header (SOF0 or, from mozjpeg, SOF2) to 60000x60000
+        let sof = jpeg
+            .windows(2)
+            .position(|w| w == [0xff, 0xc0] || w == [0xff, 0xc2])
+            .unwrap();
+        jpeg[sof + 5..sof + 9].copy_from_slice(&[0xea, 0x60, 0xea, 0x60]);
+
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        let Err(CompressionError::DecodeLimitExceeded(reason)) =
+            compress_bytes(&jpeg, "jpg", &settings)
+        else {
+            panic!("a 60000x60000 header was decoded");
+        };
+        assert!(reason.contains("60000x60000"), "{reason}");
+    }
+
+    #[test]
+    fn test_decode_limits_apply_to_every_decoder() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let png = std::fs::read(write_noisy_png(dir.path())).unwrap();
+        let jxl = encode_jxl(
+            &image::load_from_memory(&png).unwrap(),
+            90,
+            None,
+            &ImageMetadata::default(),
+        )
+        .unwrap();
+        let webp = compress_bytes(
+            &png,
+            "png",
+            &CompressionSettings::new(80, OutputFormat::WebP),
+        )
+        .unwrap()
+        .0;
+
+        // 64x64 is 4096 pixels and 12 KiB of RGB
+        for limits in [
+            DecodeLimits {
+                max_pixels: 4095,
+                ..DecodeLimits::default()
+            },
+            DecodeLimits {
+                max_alloc_bytes: 12 * 1024 - 1,
+                ..DecodeLimits::default()
+            },
+        ] {
+            let settings = CompressionSettings {
+                decode_limits: limits,
+                ..CompressionSettings::new(80, OutputFormat::Jpeg)
+            };
+            for (data, format) in [(&png, "png"), (&jxl, "jxl"), (&webp, "webp")] {
+                assert!(
+                    matches!(
+                        compress_bytes(data, format, &settings),
+                        Err(CompressionError::DecodeLimitExceeded(_))
+                    ),
+                    "{format} {limits:?}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compress_file_to_file_writes_what_compress_bytes_returns() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = write_noisy_png(dir.path());
+        let source = std::fs::read(&input).unwrap();
+
+        // PNG -> PNG also covers the oxipng pass over the in-memory passthrough
+        for format in [OutputFormat::Png, OutputFormat::WebP, OutputFormat::Jpeg] {
+            let settings = CompressionSettings::new(100, format);
+            let path = dir.path().join(format!("out.{}", format.extension()));
+            let written = compress_file_to_file(&input, &path, &settings).unwrap();
+            let (data, in_memory) = compress_bytes(&source, "png", &settings).unwrap();
+
+            assert_eq!(std::fs::read(&path).unwrap(), data, "{format}");
+            assert_eq!(written.output_path.as_deref(), Some(path.as_path()));
+            assert_eq!(written.compressed_size, in_memory.compressed_size);
+            assert_eq!(written.metrics, in_memory.metrics);
+        }
+    }
+
+    /// ICC profile of an encoded output as a reader sees it. JPEG XL is left
+    /// out: libjxl always reports a profile, synthesized when none is stored.
+    fn output_icc(data: &[u8], format: OutputFormat, icc: &[u8]) -> Option<Vec<u8>> {
+        use image::ImageDecoder;
+        let cursor = std::io::Cursor::new(data);
+        match format {
+            OutputFormat::Png => image::codecs::png::PngDecoder::new(cursor)
+                .unwrap()
+                .icc_profile()
+                .unwrap(),
+            OutputFormat::Jpeg => image::codecs::jpeg::JpegDecoder::new(cursor)
+                .unwrap()
+                .icc_profile()
+                .unwrap(),
+            OutputFormat::WebP => image::codecs::webp::WebPDecoder::new(cursor)
+                .unwrap()
+                .icc_profile()
+                .unwrap(),
+            // Stored uncompressed in the `colr` box
+            OutputFormat::Avif => data
+                .windows(icc.len())
+                .any(|w| w == icc)
+                .then(|| icc.to_vec()),
+            OutputFormat::Jxl => None,
+        }
+    }
+
+    #[test]
+    fn test_metadata_policy_leaves_exactly_the_allowed_tags_in_every_output() {
+        use crate::domain::compression::metadata::tests::{
+            LATITUDE, OWNER, SENSITIVE_XMP, SERIAL, TAG_ARTIST, TAG_EXIF_IFD, TAG_MAKE, contains,
+            ifd0_tags, sensitive_exif,
+        };
+        const TAG_GPS_IFD: u16 = 0x8825;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let img = DynamicImage::ImageRgb8(image::RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 128])
+        }));
+        // A real profile: libjxl refuses to recompress a JPEG with a bogus one
+        let (_, icc) = decode_jxl(
+            &encode_jxl(&img, 90, None, &ImageMetadata::default()).unwrap(),
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+        let icc = icc.unwrap();
+        let metadata = ImageMetadata {
+            exif: Some(sensitive_exif()),
+            xmp: Some(SENSITIVE_XMP.to_vec()),
+        };
+        let sources = [
+            (
+                "jpg",
+                encode_jpeg(&img, Some(&icc), &metadata, 90, JpegOptions::default()).unwrap(),
+            ),
+            (
+                "png",
+                encode_png(&img, Some(&icc), &metadata, PngOptions::default()).unwrap(),
+            ),
+        ];
+        let policies = [
+            MetadataPolicy::KeepAll,
+            MetadataPolicy::KeepAllExceptLocation,
+            MetadataPolicy::IccOnly,
+            MetadataPolicy::StripAll,
+        ];
+
+        for (extension, source) in &sources {
+            let input = dir.path().join(format!("source.{extension}"));
+            std::fs::write(&input, source).unwrap();
+
+            for format in OutputFormat::ALL {
+                for quality in [80, 100] {
+                    for policy in policies {
+                        let case = format!("{extension} -> {format} q{quality} {policy:?}");
+                        let output = dir.path().join(format!("out.{}", format.extension()));
+                        let settings = CompressionSettings {
+                            metadata_policy: policy,
+                            ..CompressionSettings::new(quality, format)
+                        };
+                        compress_file_to_file(&input, &output, &settings).unwrap();
+                        let data = std::fs::read(&output).unwrap();
+
+                        if policy != MetadataPolicy::KeepAll {
+                            assert!(!contains(&data, LATITUDE), "{case}");
+                            assert!(!contains(&data, b"GPSLatitude"), "{case}");
+                        }
+                        if matches!(policy, MetadataPolicy::IccOnly | MetadataPolicy::StripAll) {
+                            assert!(!contains(&data, OWNER), "{case}");
+                            assert!(!contains(&data, SERIAL), "{case}");
+                        }
+                        if format != OutputFormat::Jxl {
+                            let kept_icc = output_icc(&data, format, &icc);
+                            let expected_icc = policy.keeps_icc().then(|| icc.clone());
+                            assert_eq!(kept_icc, expected_icc, "{case}");
+                        }
+
+                        let kept = match format {
+                            OutputFormat::Avif => avif_metadata(&data),
+                            OutputFormat::Jxl => jxl_metadata(&data),
+                            _ => extract_metadata(&data, format.extension()),
+                        };
+                        let tags = kept.exif.as_deref().map(ifd0_tags);
+                        match policy {
+                            MetadataPolicy::KeepAll => {
+                                assert_eq!(
+                                    tags,
+                                    Some(vec![TAG_MAKE, TAG_ARTIST, TAG_EXIF_IFD, TAG_GPS_IFD]),
+                                    "{case}"
+                                );
+                                assert_eq!(kept.xmp.as_deref(), Some(SENSITIVE_XMP), "{case}");
+                            }
+                            MetadataPolicy::KeepAllExceptLocation => {
+                                assert_eq!(
+                                    tags,
+                                    Some(vec![TAG_MAKE, TAG_ARTIST, TAG_EXIF_IFD]),
+                                    "{case}"
+                                );
+                                assert!(contains(&data, SERIAL), "{case}");
+                                assert_eq!(kept.xmp, None, "{case}");
+                            }
+                            MetadataPolicy::IccOnly | MetadataPolicy::StripAll => {
+                                assert!(kept.is_empty(), "{case}");
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// A 3-frame GIF that loops twice after its first play, each frame drawing
+    /// a band over the previous one with a different delay
+    fn animated_gif() -> Vec<u8> {
+        use image::codecs::gif::{GifEncoder, Repeat};
+
+        let mut gif = Vec::new();
+        {
+            let mut encoder = GifEncoder::new(&mut gif);
+            encoder.set_repeat(Repeat::Finite(2)).unwrap();
+            let frames = [20u32, 90, 250].into_iter().enumerate().map(|(i, delay)| {
+                let band = i as u32 * 8;
+                let canvas = image::RgbaImage::from_fn(24, 24, |x, _| {
+                    if x >= band && x < band + 8 {
+                        image::Rgba([255, 40, 0, 255])
+                    } else {
+                        image::Rgba([0, 80, 200, 255])
+                    }
+                });
+                image::Frame::from_parts(canvas, 0, 0, image::Delay::from_numer_denom_ms(delay, 1))
+            });
+            encoder.encode_frames(frames).unwrap();
+        }
+        gif
+    }
+
+    fn frame_timing(webp: &[u8]) -> (u16, Vec<u32>) {
+        let animation = decode_animation(webp, "webp", &DecodeLimits::default())
+            .unwrap()
+            .unwrap();
+        let delays = animation
+            .frames
+            .iter()
+            .map(|frame| frame.delay_ms)
+            .collect();
+        (animation.loop_count, delays)
+    }
+
+    #[test]
+    fn test_gif_becomes_an_animated_webp_with_its_timing() {
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        let (webp, output) = compress_bytes(&animated_gif(), "gif", &settings).unwrap();
+
+        assert_eq!(output.dimensions, Some((24, 24)));
+        assert_eq!(output.metrics, None);
+        assert_eq!(output.unmeasured, Some(Unmeasured::Animation));
+        assert_eq!(frame_timing(&webp), (3, vec![20, 90, 250]));
+    }
+
+    #[test]
+    fn test_animated_webp_recompression_keeps_every_frame() {
+        let (animated, _) = compress_bytes(&animated_gif(), "gif", &lossless_webp()).unwrap();
+
+        let mut settings = CompressionSettings::new(60, OutputFormat::WebP);
+        settings.resize = ResizeOptions {
+            max_width: Some(12),
+            ..ResizeOptions::default()
+        };
+        let (webp, output) = compress_bytes(&animated, "webp", &settings).unwrap();
+
+        assert_eq!(output.dimensions, Some((12, 12)));
+        assert_eq!(frame_timing(&webp), (3, vec![20, 90, 250]));
+    }
+
+    #[test]
+    fn test_animations_take_their_first_frame_for_still_output() {
+        let settings = CompressionSettings::new(80, OutputFormat::Png);
+        let (png, output) = compress_bytes(&animated_gif(), "gif", &settings).unwrap();
+
+        assert_eq!(output.dimensions, Some((24, 24)));
+        let first = decode_output(&png, OutputFormat::Png).unwrap().to_rgba8();
+        assert_eq!(first.get_pixel(0, 0), &image::Rgba([255, 40, 0, 255]));
+        assert_eq!(first.get_pixel(12, 0), &image::Rgba([0, 80, 200, 255]));
+    }
+
+    #[test]
+    fn test_animations_search_a_target_size_but_not_a_perceptual_target() {
+        let gif = animated_gif();
+        let mut settings = CompressionSettings::new(90, OutputFormat::WebP);
+        settings.target_size = Some(100_000);
+        let (webp, output) = compress_bytes(&gif, "gif", &settings).unwrap();
+        assert_eq!(output.quality_search.map(|search| search.quality), Some(90));
+        assert_eq!(frame_timing(&webp).1.len(), 3);
+
+        settings.target_size = None;
+        settings.perceptual_target = Some(PerceptualTarget::MinSsim(0.95));
+        assert!(matches!(
+            compress_bytes(&gif, "gif", &settings),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+    }
+
+    /// A TIFF of one page per entry: 16-bit RGB, LZW-compressed, carrying `icc`
+    /// on every page
+    fn tiff_pages(pages: &[(u32, u32, [u16; 3])], icc: &[u8]) -> Vec<u8> {
+        use tiff::encoder::{TiffEncoder, TiffValue, colortype::RGB16, compression::Lzw};
+        use tiff::tags::{Tag, Type};
+
+        /// The profile as the TIFF spec types it: UNDEFINED bytes, not BYTE
+        struct Profile<'a>(&'a [u8]);
+        impl TiffValue for Profile<'_> {
+            const BYTE_LEN: u8 = 1;
+            const FIELD_TYPE: Type = Type::UNDEFINED;
+            fn count(&self) -> usize {
+                self.0.len()
+            }
+            fn data(&self) -> std::borrow::Cow<'_, [u8]> {
+                self.0.into()
+            }
+        }
+
+        let mut data = std::io::Cursor::new(Vec::new());
+        let mut encoder = TiffEncoder::new(&mut data).unwrap();
+        for &(width, height, colour) in pages {
+            let samples: Vec<u16> = colour
+                .iter()
+                .copied()
+                .cycle()
+                .take((width * height * 3) as usize)
+                .collect();
+            let mut page = encoder
+                .new_image_with_compression::<RGB16, _>(width, height, Lzw)
+                .unwrap();
+            page.encoder()
+                .write_tag(Tag::Unknown(34675), Profile(icc))
+                .unwrap();
+            page.write_data(&samples).unwrap();
+        }
+        data.into_inner()
+    }
+
+    #[test]
+    fn test_tiff_decodes_its_first_page_with_16_bits_and_its_profile() {
+        let icc = vec![0x42; 300];
+        let tiff = tiff_pages(&[(20, 10, [65535, 32896, 0]), (8, 8, [0, 0, 65535])], &icc);
+
+        let (img, found) = decode_image_with_icc(
+            &tiff,
+            "tiff",
+            &CompressionSettings::new(100, OutputFormat::Png),
+        )
+        .unwrap();
+
+        assert_eq!(img.color(), image::ColorType::Rgb16);
+        assert_eq!((img.width(), img.height()), (20, 10));
+        assert_eq!(
+            img.to_rgb16().get_pixel(3, 3),
+            &image::Rgb([65535, 32896, 0])
+        );
+        assert_eq!(found, Some(icc));
+    }
+
+    #[test]
+    fn test_tiff_and_bmp_compress_to_webp_and_jpeg() {
+        use tiff::encoder::{TiffEncoder, colortype::RGB8, compression::Deflate};
+
+        let icc = vec![0x42; 300];
+        let lzw = tiff_pages(&[(24, 16, [51400, 12850, 2570])], &icc);
+        let mut deflate = std::io::Cursor::new(Vec::new());
+        TiffEncoder::new(&mut deflate)
+            .unwrap()
+            .write_image_with_compression::<RGB8, _>(
+                24,
+                16,
+                Deflate::default(),
+                &[200; 24 * 16 * 3],
+            )
+            .unwrap();
+        let mut bmp = Vec::new();
+        DynamicImage::ImageRgb8(image::RgbImage::from_pixel(
+            24,
+            16,
+            image::Rgb([200, 50, 10]),
+        ))
+        .write_to(&mut std::io::Cursor::new(&mut bmp), image::ImageFormat::Bmp)
+        .unwrap();
+
+        for (input, format) in [
+            (&lzw, "tif"),
+            (&deflate.into_inner(), "tiff"),
+            (&bmp, "bmp"),
+        ] {
+            for output_format in [OutputFormat::WebP, OutputFormat::Jpeg] {
+                let settings = CompressionSettings::new(80, output_format);
+                let (data, output) = compress_bytes(input, format, &settings).unwrap();
+
+                assert_eq!(
+                    output.dimensions,
+                    Some((24, 16)),
+                    "{format} {output_format}"
+                );
+                let decoded = decode_output(&data, output_format).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), (24, 16));
+            }
+        }
+
+        // The TIFF's profile travels with it
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        let (webp, _) = compress_bytes(&lzw, "tif", &settings).unwrap();
+        assert_eq!(output_icc(&webp, OutputFormat::WebP, &icc), Some(icc));
+    }
+
+    #[test]
+    fn test_a_dng_compresses_from_its_preview_or_its_sensor_data() {
+        let dng = crate::domain::compression::raw::tests::sample_dng(true, 6);
 
-        let row_stride = width as usize * 3;
-        while cinfo.next_scanline < cinfo.image_height {
-            let row_offset = cinfo.next_scanline as usize * row_stride;
-            let row_ptr = pixels.as_ptr().add(row_offset);
-            let mut row_array = [row_ptr];
-            mozjpeg_sys::jpeg_write_scanlines(&mut cinfo, row_array.as_mut_ptr(), 1);
-        }
+        for (raw_mode, dimensions) in [
+            (RawDecodeMode::Fast, (30, 40)),
+            (RawDecodeMode::Full, (12, 16)),
+        ] {
+            for output_format in [OutputFormat::Jpeg, OutputFormat::WebP] {
+                let settings = CompressionSettings {
+                    raw_mode,
+                    ..CompressionSettings::new(80, output_format)
+                };
+                // The extension tells the DNG from the plain TIFF its header claims
+                let (data, output) = compress_bytes(&dng, "DNG", &settings).unwrap();
 
-        mozjpeg_sys::jpeg_finish_compress(&mut cinfo);
+                assert_eq!(output.dimensions, Some(dimensions), "{raw_mode:?}");
+                let decoded = decode_output(&data, output_format).unwrap();
+                assert_eq!((decoded.width(), decoded.height()), dimensions);
+            }
+        }
+    }
 
-        // Copy buffer before destroying
-        let result = if !buf_ptr.is_null() && buf_size > 0 {
-            std::slice::from_raw_parts(buf_ptr, buf_size as usize).to_vec()
-        } else {
-            return Err(CompressionError::ProcessingError(
-                "mozjpeg produced empty output".to_string(),
-            ));
+    #[test]
+    fn test_png_output_keeps_16_bits_unless_reduced() {
+        let colour = image::Rgb([1000_u16, 30001, 65000]);
+        let mut png = Vec::new();
+        DynamicImage::ImageRgb16(image::ImageBuffer::from_pixel(16, 16, colour))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let resize = ResizeOptions {
+            longest_edge: Some(8),
+            ..ResizeOptions::default()
         };
 
-        mozjpeg_sys::jpeg_destroy_compress(&mut cinfo);
+        // Decoded and re-encoded (resized), or passed through to oxipng
+        for resize in [resize, ResizeOptions::default()] {
+            let settings = CompressionSettings {
+                resize,
+                ..CompressionSettings::new(100, OutputFormat::Png)
+            };
+            let (data, _) = compress_bytes(&png, "png", &settings).unwrap();
+            let decoded = decode_output(&data, OutputFormat::Png).unwrap();
+            assert_eq!(decoded.color(), image::ColorType::Rgb16, "{resize:?}");
+            let [r, g, b] = decoded.to_rgb16().get_pixel(4, 4).0;
+            assert!(r.abs_diff(1000) <= 2 && g.abs_diff(30001) <= 2 && b.abs_diff(65000) <= 2);
 
-        // Free the buffer allocated by jpeg_mem_dest
-        if !buf_ptr.is_null() {
-            libc_free(buf_ptr.cast::<std::ffi::c_void>());
+            let reduced = CompressionSettings {
+                reduce_to_8bit: true,
+                ..settings
+            };
+            let (data, _) = compress_bytes(&png, "png", &reduced).unwrap();
+            let decoded = decode_output(&data, OutputFormat::Png).unwrap();
+            assert_eq!(decoded.color(), image::ColorType::Rgb8, "{resize:?}");
         }
-
-        Ok(result)
     }
-}
 
-unsafe extern "C" {
-    fn free(ptr: *mut std::ffi::c_void);
-}
+    #[test]
+    fn test_high_bit_depth_samples_span_the_16_bit_range() {
+        assert_eq!(widen_to_16_bits(0, 10), 0);
+        assert_eq!(widen_to_16_bits(1023, 10), 65535);
+        assert_eq!(widen_to_16_bits(512, 10), 0x8020);
+        assert_eq!(widen_to_16_bits(4095, 12), 65535);
+        assert_eq!(widen_to_16_bits(0xabcd, 16), 0xabcd);
+    }
 
-unsafe fn libc_free(ptr: *mut std::ffi::c_void) {
-    unsafe { free(ptr) };
-}
+    #[test]
+    fn test_heic_pixels_keep_alpha_and_depth_only_when_present() {
+        let rgb = heic_pixels(2, 1, false, None, vec![1, 2, 3, 4, 5, 6]).unwrap();
+        assert_eq!(rgb.color(), image::ColorType::Rgb8);
 
-/// Inject an ICC profile into a WebP RIFF container.
-///
-/// WebP files use the RIFF container format. To embed ICC, we need the
-/// extended format (VP8X header) with an ICCP chunk.
-///
-/// Layout: RIFF [size] WEBP VP8X [flags] ICCP [icc_data] VP8/VP8L [payload]
-fn inject_icc_into_webp(webp_data: &[u8], icc_data: &[u8]) -> Vec<u8> {
-    // Minimum valid WebP: "RIFF" + size(4) + "WEBP" + chunk = 12+ bytes
-    if webp_data.len() < 12 {
-        return webp_data.to_vec();
-    }
+        let samples: Vec<u8> = [1023_u16, 0, 512, 1023]
+            .iter()
+            .flat_map(|sample| sample.to_le_bytes())
+            .collect();
+        let rgba = heic_pixels(1, 1, true, Some(10), samples).unwrap();
+        assert_eq!(rgba.color(), image::ColorType::Rgba16);
+        assert_eq!(
+            rgba.to_rgba16().get_pixel(0, 0).0,
+            [65535, 0, 0x8020, 65535]
+        );
 
-    // Parse existing RIFF header
-    let fourcc = &webp_data[8..12];
-    if fourcc != b"WEBP" {
-        return webp_data.to_vec();
+        // Too few samples for the dimensions
+        assert!(heic_pixels(2, 2, false, None, vec![0; 3]).is_none());
     }
 
-    // Identify the payload chunk type starting at offset 12
-    let chunk_type = &webp_data[12..16];
+    #[test]
+    fn test_premultiplied_alpha_is_straightened_at_the_same_depth() {
+        let premultiplied = DynamicImage::ImageRgba8(image::ImageBuffer::from_pixel(
+            1,
+            1,
+            image::Rgba([64, 32, 0, 128]),
+        ));
 
-    // Build padded ICCP chunk (RIFF chunks must be even-aligned)
-    let icc_chunk = build_riff_chunk(b"ICCP", icc_data);
+        let straight = unpremultiply(&premultiplied);
 
-    if chunk_type == b"VP8X" {
-        // Already extended format: inject ICCP flag + chunk after VP8X
-        inject_icc_into_extended_webp(webp_data, &icc_chunk)
-    } else {
-        // Simple format (VP8 or VP8L): wrap in VP8X + ICCP
-        wrap_simple_webp_with_icc(webp_data, &icc_chunk, chunk_type)
+        assert_eq!(straight.color(), image::ColorType::Rgba8);
+        let [r, g, b, a] = straight.to_rgba8().get_pixel(0, 0).0;
+        assert!(r.abs_diff(128) <= 1 && g.abs_diff(64) <= 1, "{r} {g}");
+        assert_eq!((b, a), (0, 128));
     }
-}
 
-/// Build a RIFF chunk: FourCC + LE32 size + data + optional padding byte
-// Callers pass `b"ICCP"`-style literals, which are naturally `&[u8; 4]`; taking
-// the FourCC by value would force a `*b"ICCP"` deref at every call site.
-#[allow(clippy::trivially_copy_pass_by_ref)]
-fn build_riff_chunk(fourcc: &[u8; 4], data: &[u8]) -> Vec<u8> {
-    let mut chunk = Vec::with_capacity(8 + data.len() + 1);
-    chunk.extend_from_slice(fourcc);
-    chunk.extend_from_slice(&(data.len() as u32).to_le_bytes());
-    chunk.extend_from_slice(data);
-    if !data.len().is_multiple_of(2) {
-        chunk.push(0); // RIFF padding
+    fn lossless_webp() -> CompressionSettings {
+        let mut settings = CompressionSettings::new(80, OutputFormat::WebP);
+        settings.encoder.webp.mode = WebpMode::Lossless;
+        settings
     }
-    chunk
-}
-
-/// Inject ICCP into an already-extended WebP (has VP8X)
-fn inject_icc_into_extended_webp(webp_data: &[u8], icc_chunk: &[u8]) -> Vec<u8> {
-    // VP8X chunk is at offset 12, payload at 20, flags at byte 20
-    // VP8X payload is always 10 bytes
-    let vp8x_end = 12 + 8 + 10; // 30
 
-    if webp_data.len() < vp8x_end {
-        return webp_data.to_vec();
-    }
+    #[test]
+    fn test_webp_lossless_is_a_mode_and_quality_100_stays_lossy() {
+        let png = textured_png(32, 32);
+        let source = image::load_from_memory(&png).unwrap().to_rgb8();
 
-    // Set ICC flag (bit 5 of flags byte at offset 20)
-    let mut flags_byte = webp_data[20];
-    flags_byte |= 0b0010_0000; // ICC profile flag
+        // The lossless bitstream is VP8L; lossy is VP8, whatever the quality
+        let (lossless, _) = compress_bytes(&png, "png", &lossless_webp()).unwrap();
+        assert_eq!(&lossless[12..16], b"VP8L");
+        assert_eq!(
+            decode_output(&lossless, OutputFormat::WebP)
+                .unwrap()
+                .to_rgb8(),
+            source
+        );
+        let lossy_100 = CompressionSettings::new(100, OutputFormat::WebP);
+        let (lossy, _) = compress_bytes(&png, "png", &lossy_100).unwrap();
+        assert_eq!(&lossy[12..16], b"VP8 ");
 
-    let mut result = Vec::with_capacity(webp_data.len() + icc_chunk.len());
-    // RIFF header placeholder (we'll fix the size later)
-    result.extend_from_slice(&webp_data[..20]);
-    result.push(flags_byte);
-    result.extend_from_slice(&webp_data[21..vp8x_end]);
-    // Insert ICCP chunk right after VP8X
-    result.extend_from_slice(icc_chunk);
-    // Rest of the file (VP8/VP8L/ALPH etc.)
-    result.extend_from_slice(&webp_data[vp8x_end..]);
+        let mut fastest = lossless_webp();
+        fastest.encoder.webp.lossless_effort = 0;
+        let (fast, _) = compress_bytes(&png, "png", &fastest).unwrap();
+        assert_eq!(&fast[12..16], b"VP8L");
+        assert_ne!(fast, lossless);
+    }
 
-    // Fix RIFF file size (bytes 4..8 = total size - 8)
-    let riff_size = (result.len() - 8) as u32;
-    result[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    fn textured_png(width: u32, height: u32) -> Vec<u8> {
+        let img = image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([
+                (x * 7 + y * 3) as u8,
+                ((x * y) % 251) as u8,
+                (x ^ y).wrapping_mul(13) as u8,
+            ])
+        });
+        encode_source(&DynamicImage::ImageRgb8(img))
+    }
 
-    result
-}
+    #[test]
+    fn test_jpeg_options_pick_the_scan_mode_and_the_chroma_sampling() {
+        let png = textured_png(32, 24);
+        let mut settings = CompressionSettings::new(85, OutputFormat::Jpeg);
 
-/// Wrap a simple WebP (VP8/VP8L) into extended format with VP8X + ICCP
-fn wrap_simple_webp_with_icc(webp_data: &[u8], icc_chunk: &[u8], chunk_type: &[u8]) -> Vec<u8> {
-    // Read canvas dimensions from the bitstream
-    let (canvas_w, canvas_h) = read_webp_dimensions(webp_data, chunk_type);
+        let (progressive, _) = compress_bytes(&png, "png", &settings).unwrap();
+        // SOF2 opens a progressive frame, and 4:2:0 samples luma at 2×2
+        let sof = find_jpeg_segment(&progressive, 0xC2, &[]).unwrap();
+        assert_eq!(progressive[sof + 11], 0x22);
 
-    // VP8X flags: bit 5 = ICC
-    let mut vp8x_payload = [0u8; 10];
-    vp8x_payload[0] = 0b0010_0000; // ICC flag
+        settings.encoder.jpeg.progressive = false;
+        settings.encoder.jpeg.chroma_subsampling = ChromaSubsampling::Yuv444;
+        let (baseline, _) = compress_bytes(&png, "png", &settings).unwrap();
+        assert!(find_jpeg_segment(&baseline, 0xC2, &[]).is_none());
+        let sof = find_jpeg_segment(&baseline, 0xC0, &[]).unwrap();
+        assert_eq!(baseline[sof + 11], 0x11);
+        assert!(decode_output(&baseline, OutputFormat::Jpeg).is_ok());
 
-    // If source is VP8L (lossless), also set the alpha flag since VP8L may contain alpha
-    if chunk_type == b"VP8L" {
-        vp8x_payload[0] |= 0b0001_0000; // Alpha flag
+        settings.encoder.jpeg.trellis = false;
+        settings.encoder.jpeg.quant_table = 0;
+        let (untuned, _) = compress_bytes(&png, "png", &settings).unwrap();
+        assert_ne!(untuned, baseline);
     }
 
-    // Canvas size is stored as (width-1) and (height-1) in 24-bit LE
-    let w_minus_1 = canvas_w.saturating_sub(1);
-    let h_minus_1 = canvas_h.saturating_sub(1);
-    vp8x_payload[4] = (w_minus_1 & 0xFF) as u8;
-    vp8x_payload[5] = ((w_minus_1 >> 8) & 0xFF) as u8;
-    vp8x_payload[6] = ((w_minus_1 >> 16) & 0xFF) as u8;
-    vp8x_payload[7] = (h_minus_1 & 0xFF) as u8;
-    vp8x_payload[8] = ((h_minus_1 >> 8) & 0xFF) as u8;
-    vp8x_payload[9] = ((h_minus_1 >> 16) & 0xFF) as u8;
+    #[test]
+    fn test_png_options_interlace_and_deflate_with_zopfli() {
+        let png = textured_png(32, 24);
+        let mut settings = CompressionSettings::new(100, OutputFormat::Png);
 
-    let vp8x_chunk = build_riff_chunk(b"VP8X", &vp8x_payload);
+        let (plain, _) = compress_bytes(&png, "png", &settings).unwrap();
+        // The interlace method is the last byte of IHDR
+        assert_eq!(plain[28], 0);
 
-    // Payload = everything after "RIFF....WEBP" (offset 12)
-    let payload = &webp_data[12..];
+        settings.encoder.png.interlace = true;
+        settings.encoder.png.zopfli = true;
+        let (interlaced, _) = compress_bytes(&png, "png", &settings).unwrap();
+        assert_eq!(interlaced[28], 1);
+        assert_eq!(
+            decode_output(&interlaced, OutputFormat::Png)
+                .unwrap()
+                .to_rgb8(),
+            decode_output(&plain, OutputFormat::Png).unwrap().to_rgb8()
+        );
+    }
 
-    let total_size = 4 + vp8x_chunk.len() + icc_chunk.len() + payload.len(); // "WEBP" + chunks
-    let mut result = Vec::with_capacity(8 + total_size);
-    result.extend_from_slice(b"RIFF");
-    result.extend_from_slice(&(total_size as u32).to_le_bytes());
-    result.extend_from_slice(b"WEBP");
-    result.extend_from_slice(&vp8x_chunk);
-    result.extend_from_slice(icc_chunk);
-    result.extend_from_slice(payload);
+    #[test]
+    fn test_webp_near_lossless_gives_up_exact_pixels() {
+        let png = textured_png(48, 48);
+        let source = image::load_from_memory(&png).unwrap().to_rgb8();
+        let mut settings = lossless_webp();
 
-    result
-}
+        let (lossless, _) = compress_bytes(&png, "png", &settings).unwrap();
+        let decoded = decode_output(&lossless, OutputFormat::WebP).unwrap();
+        assert_eq!(decoded.to_rgb8(), source);
 
-/// Read canvas dimensions from a WebP bitstream
-fn read_webp_dimensions(webp_data: &[u8], chunk_type: &[u8]) -> (u32, u32) {
-    // Default fallback
-    let default = (1, 1);
+        settings.encoder.webp.mode = WebpMode::NearLossless;
+        settings.encoder.webp.near_lossless = 0;
+        settings.encoder.webp.method = 6;
+        settings.encoder.webp.preset = Some(WebpPreset::Drawing);
+        let (near_lossless, _) = compress_bytes(&png, "png", &settings).unwrap();
+        let decoded = decode_output(&near_lossless, OutputFormat::WebP).unwrap();
+        assert_ne!(decoded.to_rgb8(), source);
+    }
 
-    if webp_data.len() < 30 {
-        return default;
+    fn encode_source(img: &DynamicImage) -> Vec<u8> {
+        let mut png = Vec::new();
+        img.write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        png
     }
 
-    // Chunk data starts at offset 20 (12 RIFF header + 4 FourCC + 4 size)
-    let chunk_data_offset = 20;
+    #[test]
+    fn test_decoded_image_is_encoded_at_each_requested_width() {
+        let png = textured_png(64, 32);
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        let source = decode_still(&png, "png", &settings).unwrap();
+        assert_eq!(source.dimensions(), (64, 32));
 
-    if chunk_type == b"VP8 " {
-        // VP8 lossy: dimensions at bytes 6-9 of frame data (after 3-byte frame tag)
-        // Frame header: [frame_tag(3)] [start_code(3): 0x9D 0x01 0x2A] [width(2)] [height(2)]
-        let offset = chunk_data_offset;
-        if webp_data.len() < offset + 10 {
-            return default;
-        }
-        let width = u16::from_le_bytes([webp_data[offset + 6], webp_data[offset + 7]]) & 0x3FFF;
-        let height = u16::from_le_bytes([webp_data[offset + 8], webp_data[offset + 9]]) & 0x3FFF;
-        (u32::from(width), u32::from(height))
-    } else if chunk_type == b"VP8L" {
-        // VP8L lossless: signature byte (0x2F) then 32-bit LE with packed w/h
-        let offset = chunk_data_offset;
-        if webp_data.len() < offset + 5 {
-            return default;
+        for (max_width, format) in [(16, OutputFormat::WebP), (64, OutputFormat::Png)] {
+            let variant = CompressionSettings {
+                resize: ResizeOptions {
+                    max_width: Some(max_width),
+                    ..ResizeOptions::default()
+                },
+                ..CompressionSettings::new(80, format)
+            };
+            let (data, output) = compress_decoded(&source, &variant).unwrap();
+            assert_eq!(output.dimensions, Some((max_width, max_width / 2)));
+            assert_eq!(output.original_size, png.len() as u64);
+            assert_eq!(decode_output(&data, format).unwrap().width(), max_width);
         }
-        let bits = u32::from_le_bytes([
-            webp_data[offset + 1],
-            webp_data[offset + 2],
-            webp_data[offset + 3],
-            webp_data[offset + 4],
-        ]);
-        let width = (bits & 0x3FFF) + 1;
-        let height = ((bits >> 14) & 0x3FFF) + 1;
-        (width, height)
-    } else {
-        default
+
+        let mut lossless = CompressionSettings::new(80, OutputFormat::Jpeg);
+        lossless.encoder.jpeg.lossless = true;
+        assert!(matches!(
+            compress_decoded(&source, &lossless),
+            Err(CompressionError::InvalidSettings(_))
+        ));
     }
-}
 
-// Helper functions
+    #[test]
+    fn test_smallest_output_wins_and_names_its_format() {
+        // Two flat colours: a palette PNG is far below any JPEG
+        let flat = encode_source(&DynamicImage::ImageRgb8(image::RgbImage::from_fn(
+            64,
+            64,
+            |x, _| image::Rgb(if x < 32 { [200, 30, 30] } else { [20, 20, 90] }),
+        )));
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        let candidates = [OutputFormat::Jpeg, OutputFormat::Png];
 
-fn encode_webp_advanced(
-    encoder: &webp::Encoder,
-    settings: &CompressionSettings,
-) -> CompressionResult<webp::WebPMemory> {
-    let mut config = webp::WebPConfig::new().map_err(|()| {
-        CompressionError::ProcessingError("Failed to create WebPConfig".to_string())
-    })?;
+        let (data, output) =
+            compress_bytes_to_smallest(&flat, "png", &settings, &candidates, false).unwrap();
 
-    if settings.quality == 100 {
-        // Lossless mode
-        config.lossless = 1;
-        config.quality = 75.0;
-        config.alpha_compression = 0;
-    } else {
-        // Lossy mode with optimized settings
-        config.lossless = 0;
-        config.quality = f32::from(settings.quality);
-        config.method = 4; // Good quality/speed tradeoff
-        config.use_sharp_yuv = 1; // Precise RGB→YUV, fixes color desaturation
-        config.alpha_quality = 100; // Don't degrade alpha channel
-        config.autofilter = 1; // Auto deblocking filter
+        assert_eq!(output.format, OutputFormat::Png);
+        assert_eq!(output.compressed_size, data.len() as u64);
+        let (jpeg, _) = compress_bytes(
+            &flat,
+            "png",
+            &CompressionSettings::new(80, OutputFormat::Jpeg),
+        )
+        .unwrap();
+        assert!(data.len() < jpeg.len());
     }
 
-    encoder
-        .encode_advanced(&config)
-        .map_err(|e| CompressionError::ProcessingError(format!("WebP encoding failed: {e:?}")))
-}
-
-fn validate_settings(settings: &CompressionSettings) -> CompressionResult<()> {
-    if !settings.is_valid() {
-        return Err(CompressionError::InvalidSettings(format!(
-            "Invalid quality setting: {}",
-            settings.quality
+    #[test]
+    fn test_smallest_output_keeps_transparency_and_animation_on_request() {
+        let transparent = encode_source(&DynamicImage::ImageRgba8(image::RgbaImage::from_fn(
+            16,
+            16,
+            |x, _| image::Rgba([255, 0, 0, if x < 8 { 255 } else { 0 }]),
         )));
-    }
-    Ok(())
-}
-
-#[cfg(test)]
-// Assertions compare against exact float literals produced by the same code path,
-// so bit-equality is the intended check.
-#[allow(clippy::float_cmp)]
-mod tests {
-    use super::*;
+        let settings = CompressionSettings::new(80, OutputFormat::WebP);
+        let jpeg_only = [OutputFormat::Jpeg];
 
-    #[test]
-    fn test_compression_output_creation() {
-        let output_path = std::path::PathBuf::from("/tmp/test.webp");
-        let output = CompressionOutput::new(output_path.clone(), 1000, 5, OutputFormat::WebP);
+        assert!(
+            compress_bytes_to_smallest(&transparent, "png", &settings, &jpeg_only, false).is_ok()
+        );
+        assert!(matches!(
+            compress_bytes_to_smallest(&transparent, "png", &settings, &jpeg_only, true),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+        let (_, output) = compress_bytes_to_smallest(
+            &transparent,
+            "png",
+            &settings,
+            &[OutputFormat::Jpeg, OutputFormat::Png],
+            true,
+        )
+        .unwrap();
+        assert_eq!(output.excluded_formats, [OutputFormat::Jpeg]);
 
-        assert_eq!(output.output_path, output_path);
-        assert_eq!(output.original_size, 1000);
-        assert_eq!(output.compressed_size, 5);
-        assert!(output.savings_percent > 99.0); // 995/1000 * 100
+        // A still output would drop the frames, so only WebP competes
+        let candidates = [OutputFormat::Png, OutputFormat::WebP];
+        let (webp, output) =
+            compress_bytes_to_smallest(&animated_gif(), "gif", &settings, &candidates, false)
+                .unwrap();
+        assert_eq!(output.format, OutputFormat::WebP);
+        assert_eq!(output.excluded_formats, [OutputFormat::Png]);
+        assert_eq!(frame_timing(&webp).0, 3);
     }
 
     #[test]
-    fn test_settings_validation() {
+    fn test_smallest_output_takes_an_image_too_large_for_jpeg() {
+        let img = image::RgbImage::from_pixel(65_600, 2, image::Rgb([40, 90, 160]));
+        let png = encode_source(&DynamicImage::ImageRgb8(img));
         let settings = CompressionSettings::new(80, OutputFormat::WebP);
-        assert!(validate_settings(&settings).is_ok());
 
-        let mut invalid_settings = CompressionSettings::new(80, OutputFormat::WebP);
-        invalid_settings.quality = 200; // Invalid quality
-        assert!(validate_settings(&invalid_settings).is_err());
-    }
+        let (_, output) = compress_bytes_to_smallest(
+            &png,
+            "png",
+            &settings,
+            &[OutputFormat::WebP, OutputFormat::Png],
+            false,
+        )
+        .unwrap();
+        assert_eq!(output.format, OutputFormat::Png);
 
-    #[test]
-    fn test_build_riff_chunk() {
-        let data = b"test data";
-        let chunk = build_riff_chunk(b"ICCP", data);
-        assert_eq!(&chunk[0..4], b"ICCP");
-        let size = u32::from_le_bytes([chunk[4], chunk[5], chunk[6], chunk[7]]);
-        assert_eq!(size, 9);
-        assert_eq!(&chunk[8..17], data);
-        // Odd size → padding byte
-        assert_eq!(chunk.len(), 18);
-        assert_eq!(chunk[17], 0);
+        // libjpeg's error ends in an error, not in exit()
+        let jpeg = CompressionSettings::new(80, OutputFormat::Jpeg);
+        assert!(matches!(
+            compress_bytes(&png, "png", &jpeg),
+            Err(CompressionError::ProcessingError(_))
+        ));
     }
 
     #[test]
-    fn test_build_riff_chunk_even() {
-        let data = b"test";
-        let chunk = build_riff_chunk(b"ICCP", data);
-        // Even size → no padding
-        assert_eq!(chunk.len(), 12);
-    }
+    fn test_smallest_output_holds_every_candidate_to_the_same_fidelity() {
+        let png = textured_png(64, 64);
+        let img = image::load_from_memory(&png).unwrap();
+        let mut palette = CompressionSettings::new(60, OutputFormat::WebP);
+        palette.encoder.png.quantize = true;
+        let candidates = [OutputFormat::WebP, OutputFormat::Jpeg, OutputFormat::Png];
+        let winner_ssim = |settings: &CompressionSettings| {
+            let (data, output) =
+                compress_bytes_to_smallest(&png, "png", settings, &candidates, false).unwrap();
+            ssim(&img, &decode_output(&data, output.format).unwrap()).unwrap()
+        };
 
-    #[test]
-    fn test_inject_icc_into_webp_too_small() {
-        let small = vec![0u8; 5];
-        let icc = vec![1, 2, 3];
-        let result = inject_icc_into_webp(&small, &icc);
-        assert_eq!(result, small);
+        let floor = CompressionSettings {
+            perceptual_target: Some(PerceptualTarget::MinSsim(0.95)),
+            ..palette.clone()
+        };
+        assert!(winner_ssim(&floor) >= 0.95);
+
+        // Without a target, the quality stands for what a JPEG reaches with it
+        let reference = jpeg_fidelity(&img, &palette).unwrap();
+        assert!(reference > 0.5 && reference < 1.0, "{reference}");
+        assert!(winner_ssim(&palette) >= reference);
+
+        // AVIF cannot be scored against the floor, and says it sat out
+        let (_, output) = compress_bytes_to_smallest(
+            &png,
+            "png",
+            &palette,
+            &[OutputFormat::Avif, OutputFormat::WebP],
+            false,
+        )
+        .unwrap();
+        assert_eq!(output.excluded_formats, [OutputFormat::Avif]);
+        assert!(matches!(
+            compress_bytes_to_smallest(&png, "png", &palette, &[OutputFormat::Avif], false),
+            Err(CompressionError::InvalidSettings(_))
+        ));
     }
 
     #[test]
-    fn test_inject_icc_into_non_webp_riff() {
-        // RIFF header with "WAVE" instead of "WEBP" — should return unchanged
-        let mut wave = Vec::new();
-        wave.extend_from_slice(b"RIFF");
-        wave.extend_from_slice(&100u32.to_le_bytes());
-        wave.extend_from_slice(b"WAVE");
-        wave.extend(vec![0u8; 100]);
-        let icc = vec![1, 2, 3];
-        let result = inject_icc_into_webp(&wave, &icc);
-        assert_eq!(result, wave);
+    fn test_smallest_output_candidates_report_in_the_order_they_were_listed() {
+        let results = encode_candidates(&OutputFormat::ALL, |format| {
+            assert_ne!(format, OutputFormat::Avif, "encoder failure");
+            Ok(format)
+        });
+
+        assert_eq!(results.len(), OutputFormat::ALL.len());
+        for (&format, result) in OutputFormat::ALL.iter().zip(results) {
+            match result {
+                Ok(encoded) => assert_eq!(encoded, format),
+                Err(e) => {
+                    assert_eq!(format, OutputFormat::Avif);
+                    assert!(matches!(e, CompressionError::ProcessingError(_)));
+                }
+            }
+        }
     }
 
     #[test]
-    fn test_compression_output_zero_size() {
-        let output = CompressionOutput::new(
-            std::path::PathBuf::from("/tmp/test.webp"),
-            0,
-            0,
-            OutputFormat::WebP,
-        );
-        assert_eq!(output.savings_percent, 0.0);
+    fn test_smallest_output_skips_formats_the_settings_do_not_apply_to() {
+        let png = textured_png(32, 32);
+        let mut settings = CompressionSettings::new(90, OutputFormat::WebP);
+        settings.target_size = Some(100_000);
+
+        // A target size does not apply to PNG output
+        let candidates = [OutputFormat::Png, OutputFormat::Jpeg];
+        let (_, output) =
+            compress_bytes_to_smallest(&png, "png", &settings, &candidates, false).unwrap();
+        assert_eq!(output.format, OutputFormat::Jpeg);
+
+        assert!(matches!(
+            compress_bytes_to_smallest(&png, "png", &settings, &[OutputFormat::Png], false),
+            Err(CompressionError::InvalidSettings(_))
+        ));
     }
 }
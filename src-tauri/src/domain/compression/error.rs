@@ -14,6 +14,18 @@ pub enum CompressionError {
     IoError(String),
     #[error("Compression ratio too low: {:.2}%", .0 * 100.0)]
     InsufficientCompression(f64),
+    #[error(
+        "No quality fits in {target_bytes} bytes, the smallest output is {smallest_bytes} bytes"
+    )]
+    TargetSizeUnreachable {
+        target_bytes: u64,
+        smallest_bytes: u64,
+    },
+    #[error("No quality reaches an SSIM of {min_ssim}, the best output scores {best_ssim:.4}")]
+    PerceptualTargetUnreachable { min_ssim: f64, best_ssim: f64 },
+    /// The headers declare an image bigger than the decode limits allow
+    #[error("Image exceeds the decode limits: {0}")]
+    DecodeLimitExceeded(String),
 }
 
 /// Errors that can occur during statistics operations
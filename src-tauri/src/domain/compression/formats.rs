@@ -6,15 +6,39 @@ pub enum OutputFormat {
     Png,
     Jpeg,
     WebP,
+    Avif,
+    Jxl,
 }
 
 impl OutputFormat {
+    /// Every output format, WebP first: the order `auto-best` breaks ties in
+    pub const ALL: [OutputFormat; 5] = [
+        OutputFormat::WebP,
+        OutputFormat::Png,
+        OutputFormat::Jpeg,
+        OutputFormat::Avif,
+        OutputFormat::Jxl,
+    ];
+
     /// Returns the file extension for this format
     pub fn extension(&self) -> &'static str {
         match self {
             OutputFormat::Png => "png",
             OutputFormat::Jpeg => "jpg",
             OutputFormat::WebP => "webp",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jxl => "jxl",
+        }
+    }
+
+    /// Returns the media type a browser serves this format as
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            OutputFormat::Png => "image/png",
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::WebP => "image/webp",
+            OutputFormat::Avif => "image/avif",
+            OutputFormat::Jxl => "image/jxl",
         }
     }
 
@@ -24,6 +48,8 @@ impl OutputFormat {
             "png" => Some(OutputFormat::Png),
             "jpeg" | "jpg" => Some(OutputFormat::Jpeg),
             "webp" => Some(OutputFormat::WebP),
+            "avif" => Some(OutputFormat::Avif),
+            "jxl" => Some(OutputFormat::Jxl),
             _ => None,
         }
     }
@@ -38,6 +64,8 @@ impl std::fmt::Display for OutputFormat {
                 OutputFormat::Png => "PNG",
                 OutputFormat::Jpeg => "JPEG",
                 OutputFormat::WebP => "WebP",
+                OutputFormat::Avif => "AVIF",
+                OutputFormat::Jxl => "JPEG XL",
             }
         )
     }
@@ -52,6 +80,8 @@ mod tests {
         assert_eq!(OutputFormat::from_string("png"), Some(OutputFormat::Png));
         assert_eq!(OutputFormat::from_string("JPG"), Some(OutputFormat::Jpeg));
         assert_eq!(OutputFormat::from_string("webp"), Some(OutputFormat::WebP));
+        assert_eq!(OutputFormat::from_string("AVIF"), Some(OutputFormat::Avif));
+        assert_eq!(OutputFormat::from_string("jxl"), Some(OutputFormat::Jxl));
         assert_eq!(OutputFormat::from_string("unknown"), None);
     }
 }
@@ -0,0 +1,839 @@
+//! Camera metadata (EXIF, XMP) carried from the source file to the output.
+//!
+//! Extraction works on the raw container bytes, next to `decode_image_with_icc`:
+//! the pixel decoders drop everything but the pixels, so the blocks are lifted
+//! here and handed back to each writer in a container-neutral form. Outputs
+//! that skip the decoder (lossless JPEG and PNG passes) are filtered in place.
+
+use crate::domain::compression::settings::MetadataPolicy;
+
+/// Signature that opens an XMP packet stored in a JPEG APP1 segment
+const JPEG_XMP_SIGNATURE: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Signature that opens an EXIF block stored in a JPEG APP1 segment
+const JPEG_EXIF_SIGNATURE: &[u8] = b"Exif\0\0";
+
+/// iTXt keyword under which PNG stores XMP
+const PNG_XMP_KEYWORD: &[u8] = b"XML:com.adobe.xmp";
+
+/// Largest payload a JPEG marker segment can hold (the 16-bit length counts itself)
+const JPEG_MAX_SEGMENT_PAYLOAD: usize = 65_533;
+
+/// TIFF tag holding the EXIF orientation (a SHORT, 1 = upright)
+const EXIF_ORIENTATION_TAG: u16 = 0x0112;
+
+/// IFD0 tag pointing at the GPS IFD
+const EXIF_GPS_IFD_TAG: u16 = 0x8825;
+
+/// XMP properties that place the image somewhere. A packet naming any of them
+/// is dropped whole: XMP has too many location vocabularies to edit safely.
+const XMP_LOCATION_MARKERS: &[&[u8]] = &[
+    b"exif:GPS",
+    b"Iptc4xmpCore:Location",
+    b"Iptc4xmpExt:LocationCreated",
+    b"Iptc4xmpExt:LocationShown",
+    b"photoshop:City",
+    b"photoshop:State",
+    b"photoshop:Country",
+];
+
+/// Ancillary PNG chunks a policy rewrite keeps besides iCCP: how the pixels
+/// render, and the frames of an APNG. Every other ancillary chunk, private or
+/// unknown ones included, may carry metadata and is dropped.
+const PNG_RENDERING_CHUNKS: [&[u8; 4]; 8] = [
+    b"tRNS", b"gAMA", b"cHRM", b"sRGB", b"pHYs", b"acTL", b"fcTL", b"fdAT",
+];
+
+/// The two ways XMP serializers write the orientation property
+const XMP_ORIENTATION_PATTERNS: [(&[u8], &[u8]); 2] = [
+    (b"tiff:Orientation=\"", b"\""),
+    (b"<tiff:Orientation>", b"</tiff:Orientation>"),
+];
+
+/// EXIF and XMP blocks lifted from a source image. `exif` is the bare TIFF
+/// structure (starting at the `II`/`MM` byte-order mark), `xmp` the XML packet.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ImageMetadata {
+    pub exif: Option<Vec<u8>>,
+    pub xmp: Option<Vec<u8>>,
+}
+
+impl ImageMetadata {
+    pub fn is_empty(&self) -> bool {
+        self.exif.is_none() && self.xmp.is_none()
+    }
+
+    /// The blocks allowed through by `policy`
+    #[must_use]
+    pub fn for_policy(mut self, policy: MetadataPolicy) -> Self {
+        match policy {
+            MetadataPolicy::KeepAll => self,
+            MetadataPolicy::KeepAllExceptLocation => {
+                // An EXIF block that cannot be parsed cannot be proven clean
+                if let Some(exif) = self.exif.as_mut()
+                    && strip_exif_gps(exif).is_none()
+                {
+                    self.exif = None;
+                }
+                if self.xmp.as_deref().is_some_and(|xmp| {
+                    XMP_LOCATION_MARKERS
+                        .iter()
+                        .any(|marker| find(xmp, marker).is_some())
+                }) {
+                    self.xmp = None;
+                }
+                self
+            }
+            MetadataPolicy::IccOnly | MetadataPolicy::StripAll => Self::default(),
+        }
+    }
+
+    /// Orientation recorded in the EXIF block, if any and valid
+    pub fn orientation(&self) -> Option<image::metadata::Orientation> {
+        let exif = self.exif.as_deref()?;
+        let (offset, big_endian) = exif_orientation_offset(exif)?;
+        let value = read_u16(exif, offset, big_endian)?;
+        image::metadata::Orientation::from_exif(u8::try_from(value).ok()?)
+    }
+
+    /// Mark the image as upright (orientation 1) in both EXIF and XMP, for
+    /// pixels that have already been rotated into place
+    pub fn reset_orientation(&mut self) {
+        if let Some(exif) = self.exif.as_mut()
+            && let Some((offset, big_endian)) = exif_orientation_offset(exif)
+        {
+            let upright = if big_endian {
+                1u16.to_be_bytes()
+            } else {
+                1u16.to_le_bytes()
+            };
+            exif[offset..offset + 2].copy_from_slice(&upright);
+        }
+
+        if let Some(xmp) = self.xmp.as_mut() {
+            for (open, close) in XMP_ORIENTATION_PATTERNS {
+                reset_xmp_orientation(xmp, open, close);
+            }
+        }
+    }
+
+    /// APP1 segment payloads (EXIF first, then XMP) for a JPEG writer. A block
+    /// too large for a single segment is skipped: extended XMP and multi-segment
+    /// EXIF are not portable enough to be worth writing.
+    pub fn jpeg_app1_payloads(&self) -> Vec<Vec<u8>> {
+        [
+            (JPEG_EXIF_SIGNATURE, self.exif.as_deref()),
+            (JPEG_XMP_SIGNATURE, self.xmp.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(signature, block)| {
+            let block = block?;
+            (signature.len() + block.len() <= JPEG_MAX_SEGMENT_PAYLOAD)
+                .then(|| [signature, block].concat())
+        })
+        .collect()
+    }
+
+    /// The payload of an ISOBMFF `Exif` item or box (AVIF, JPEG XL): the offset
+    /// of the TIFF header, here 0, then the EXIF block
+    pub fn isobmff_exif(&self) -> Option<Vec<u8>> {
+        self.exif
+            .as_ref()
+            .map(|exif| [&[0, 0, 0, 0][..], exif].concat())
+    }
+
+    /// `eXIf` and `iTXt` chunks (type, data) for a PNG writer
+    pub fn png_chunks(&self) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = Vec::new();
+        if let Some(exif) = &self.exif {
+            chunks.push((*b"eXIf", exif.clone()));
+        }
+        if let Some(xmp) = &self.xmp {
+            // keyword\0, uncompressed (flag 0, method 0), empty language and
+            // translated keyword, then the packet
+            let mut itxt = PNG_XMP_KEYWORD.to_vec();
+            itxt.extend_from_slice(&[0, 0, 0, 0, 0]);
+            itxt.extend_from_slice(xmp);
+            chunks.push((*b"iTXt", itxt));
+        }
+        chunks
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize, big_endian: bool) -> Option<u16> {
+    let bytes = [*data.get(offset)?, *data.get(offset + 1)?];
+    Some(if big_endian {
+        u16::from_be_bytes(bytes)
+    } else {
+        u16::from_le_bytes(bytes)
+    })
+}
+
+fn read_u32(data: &[u8], offset: usize, big_endian: bool) -> Option<u32> {
+    let bytes: [u8; 4] = data.get(offset..offset + 4)?.try_into().ok()?;
+    Some(if big_endian {
+        u32::from_be_bytes(bytes)
+    } else {
+        u32::from_le_bytes(bytes)
+    })
+}
+
+/// TIFF byte order: `true` for big-endian `MM`, `false` for `II`
+fn tiff_big_endian(tiff: &[u8]) -> Option<bool> {
+    match tiff.get(..2)? {
+        b"MM" => Some(true),
+        b"II" => Some(false),
+        _ => None,
+    }
+}
+
+/// Offset of IFD0 and its entry count
+fn tiff_ifd0(tiff: &[u8], big_endian: bool) -> Option<(usize, usize)> {
+    let ifd = read_u32(tiff, 4, big_endian)? as usize;
+    let entries = usize::from(read_u16(tiff, ifd, big_endian)?);
+    Some((ifd, entries))
+}
+
+/// Byte offset of the 12-byte IFD0 entry for `tag`
+fn find_ifd0_entry(tiff: &[u8], big_endian: bool, tag: u16) -> Option<usize> {
+    let (ifd, entries) = tiff_ifd0(tiff, big_endian)?;
+    (0..entries)
+        .map(|i| ifd + 2 + i * 12)
+        .find(|&entry| read_u16(tiff, entry, big_endian) == Some(tag))
+}
+
+/// Byte offset of the orientation value in IFD0 and the TIFF byte order
+fn exif_orientation_offset(tiff: &[u8]) -> Option<(usize, bool)> {
+    let big_endian = tiff_big_endian(tiff)?;
+    find_ifd0_entry(tiff, big_endian, EXIF_ORIENTATION_TAG)
+        // Tag, type and count take 8 bytes; a single SHORT sits inline after them
+        .map(|entry| entry + 8)
+        .filter(|&value| value + 2 <= tiff.len())
+        .map(|value| (value, big_endian))
+}
+
+/// Size in bytes of one value of a TIFF field type
+fn tiff_type_size(field_type: u16) -> Option<usize> {
+    match field_type {
+        1 | 2 | 6 | 7 => Some(1), // BYTE, ASCII, SBYTE, UNDEFINED
+        3 | 8 => Some(2),         // SHORT, SSHORT
+        4 | 9 | 11 => Some(4),    // LONG, SLONG, FLOAT
+        5 | 10 | 12 => Some(8),   // RATIONAL, SRATIONAL, DOUBLE
+        _ => None,
+    }
+}
+
+/// Remove the GPS IFD: its entries and out-of-line values are zeroed and the
+/// pointer is dropped from IFD0, so no coordinate survives anywhere in the
+/// block. `None` when the structure is malformed.
+fn strip_exif_gps(tiff: &mut [u8]) -> Option<()> {
+    let big_endian = tiff_big_endian(tiff)?;
+    let (ifd0, entries) = tiff_ifd0(tiff, big_endian)?;
+    let entries_end = ifd0 + 2 + entries * 12;
+    tiff.get(..entries_end + 4)?; // entries + next-IFD offset
+    let Some(entry) = find_ifd0_entry(tiff, big_endian, EXIF_GPS_IFD_TAG) else {
+        return Some(());
+    };
+
+    let gps_ifd = read_u32(tiff, entry + 8, big_endian)? as usize;
+    let gps_entries = usize::from(read_u16(tiff, gps_ifd, big_endian)?);
+    let gps_end = gps_ifd + 2 + gps_entries * 12 + 4;
+    tiff.get(gps_ifd..gps_end)?;
+    for i in 0..gps_entries {
+        let gps_entry = gps_ifd + 2 + i * 12;
+        let field_type = read_u16(tiff, gps_entry + 2, big_endian)?;
+        let count = read_u32(tiff, gps_entry + 4, big_endian)? as usize;
+        let size = tiff_type_size(field_type)?.checked_mul(count)?;
+        if size > 4 {
+            let value = read_u32(tiff, gps_entry + 8, big_endian)? as usize;
+            tiff.get_mut(value..value.checked_add(size)?)?.fill(0);
+        }
+    }
+    tiff[gps_ifd..gps_end].fill(0);
+
+    // Shift the following entries and the next-IFD offset over the pointer
+    tiff.copy_within(entry + 12..entries_end + 4, entry);
+    tiff[entries_end - 8..entries_end + 4].fill(0);
+    let remaining = u16::try_from(entries - 1).ok()?;
+    let remaining = if big_endian {
+        remaining.to_be_bytes()
+    } else {
+        remaining.to_le_bytes()
+    };
+    tiff[ifd0..ifd0 + 2].copy_from_slice(&remaining);
+
+    Some(())
+}
+
+/// Rewrite every `open`N`close` orientation value in an XMP packet to 1
+fn reset_xmp_orientation(xmp: &mut Vec<u8>, open: &[u8], close: &[u8]) {
+    let mut from = 0;
+    while let Some(start) = find(&xmp[from..], open).map(|i| from + i + open.len()) {
+        let Some(end) = find(&xmp[start..], close).map(|i| start + i) else {
+            return;
+        };
+        xmp.splice(start..end, *b"1");
+        from = start + 1;
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+/// Lift EXIF and XMP from the source container. Unknown formats and malformed
+/// containers yield empty metadata: losing metadata never fails a compression.
+/// A HEIF container is not parsed here but by the decoder, under the decode
+/// limits: see [`extract_from_heic`].
+pub fn extract_metadata(input_data: &[u8], input_format: &str) -> ImageMetadata {
+    match input_format.to_lowercase().as_str() {
+        "jpg" | "jpeg" => extract_from_jpeg(input_data),
+        "png" => extract_from_png(input_data),
+        "webp" => extract_from_webp(input_data),
+        _ => ImageMetadata::default(),
+    }
+}
+
+/// Rewrite the metadata segments of a JPEG without touching its entropy-coded
+/// data: every APPn and COM segment goes except JFIF (APP0), Adobe (APP14, it
+/// drives the colour transform) and, when `keep_icc`, the ICC profile (APP2).
+/// `metadata` is written back as APP1 right after SOI. `None` when the marker
+/// structure cannot be walked, so nothing unvetted slips through.
+pub fn rewrite_jpeg_metadata(
+    jpeg: &[u8],
+    metadata: &ImageMetadata,
+    keep_icc: bool,
+) -> Option<Vec<u8>> {
+    if !jpeg.starts_with(&[0xFF, 0xD8]) {
+        return None;
+    }
+
+    let mut kept = Vec::with_capacity(jpeg.len());
+    let mut pos = 2;
+    loop {
+        if pos + 4 > jpeg.len() || jpeg[pos] != 0xFF {
+            return None;
+        }
+        let marker = jpeg[pos + 1];
+        if marker == 0xDA {
+            kept.extend_from_slice(&jpeg[pos..]); // scans and everything after
+            break;
+        }
+        let length = usize::from(u16::from_be_bytes([jpeg[pos + 2], jpeg[pos + 3]]));
+        if length < 2 {
+            return None;
+        }
+        let segment = jpeg.get(pos..pos + 2 + length)?;
+        // APP0 (JFIF), APP14 (Adobe) and the non-APP markers fall through to `true`
+        let keep = match marker {
+            0xE2 => keep_icc && segment[4..].starts_with(b"ICC_PROFILE\0"),
+            0xE1 | 0xE3..=0xED | 0xEF | 0xFE => false,
+            _ => true,
+        };
+        if keep {
+            kept.extend_from_slice(segment);
+        }
+        pos += 2 + length;
+    }
+
+    let mut result = Vec::with_capacity(kept.len() + 2);
+    result.extend_from_slice(&[0xFF, 0xD8]);
+    for payload in metadata.jpeg_app1_payloads() {
+        let length = u16::try_from(payload.len() + 2).unwrap_or(u16::MAX);
+        result.extend_from_slice(&[0xFF, 0xE1]);
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(&payload);
+    }
+    result.extend_from_slice(&kept);
+    Some(result)
+}
+
+/// Rewrite the metadata chunks of a PNG without touching its image data: only
+/// the critical chunks, the rendering chunks and, if `keep_icc`, iCCP survive.
+/// `metadata` is written back just before the first IDAT. `None` when the
+/// chunk structure cannot be walked.
+pub fn rewrite_png_metadata(
+    png: &[u8],
+    metadata: &ImageMetadata,
+    keep_icc: bool,
+) -> Option<Vec<u8>> {
+    const SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+    if !png.starts_with(SIGNATURE) {
+        return None;
+    }
+
+    let mut result = SIGNATURE.to_vec();
+    let mut pos = SIGNATURE.len();
+    let mut inserted = false;
+    while pos < png.len() {
+        let header = png.get(pos..pos + 8)?;
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let chunk = png.get(pos..pos + 12 + length)?;
+        let name = &header[4..8];
+
+        if name == b"IDAT" && !inserted {
+            for (name, data) in metadata.png_chunks() {
+                result.extend(png_chunk(name, &data));
+            }
+            inserted = true;
+        }
+        // A critical chunk has an uppercase first letter
+        let keep = name[0].is_ascii_uppercase()
+            || PNG_RENDERING_CHUNKS.iter().any(|&m| m == name)
+            || (keep_icc && name == b"iCCP");
+        if keep {
+            result.extend_from_slice(chunk);
+        }
+        pos += 12 + length;
+    }
+
+    inserted.then_some(result)
+}
+
+/// A complete PNG chunk: length, type, data and CRC
+fn png_chunk(name: [u8; 4], data: &[u8]) -> Vec<u8> {
+    let mut chunk = Vec::with_capacity(12 + data.len());
+    chunk.extend_from_slice(&u32::try_from(data.len()).unwrap_or(u32::MAX).to_be_bytes());
+    chunk.extend_from_slice(&name);
+    chunk.extend_from_slice(data);
+    let mut crc = crc32fast::Hasher::new();
+    crc.update(&name);
+    crc.update(data);
+    chunk.extend_from_slice(&crc.finalize().to_be_bytes());
+    chunk
+}
+
+fn extract_from_jpeg(data: &[u8]) -> ImageMetadata {
+    let mut metadata = ImageMetadata::default();
+    if !data.starts_with(&[0xFF, 0xD8]) {
+        return metadata;
+    }
+
+    let mut pos = 2;
+    while pos + 4 <= data.len() && data[pos] == 0xFF {
+        let marker = data[pos + 1];
+        match marker {
+            0xFF => {
+                pos += 1; // fill byte
+                continue;
+            }
+            0xD0..=0xD8 | 0x01 => {
+                pos += 2; // standalone markers carry no length
+                continue;
+            }
+            0xD9 | 0xDA => break, // metadata never follows the start of scan
+            _ => {}
+        }
+
+        let length = usize::from(u16::from_be_bytes([data[pos + 2], data[pos + 3]]));
+        let Some(payload) = data.get(pos + 4..pos + 2 + length.max(2)) else {
+            break;
+        };
+        if marker == 0xE1 {
+            if let Some(exif) = payload.strip_prefix(JPEG_EXIF_SIGNATURE) {
+                metadata.exif.get_or_insert_with(|| exif.to_vec());
+            } else if let Some(xmp) = payload.strip_prefix(JPEG_XMP_SIGNATURE) {
+                metadata.xmp.get_or_insert_with(|| xmp.to_vec());
+            }
+        }
+        pos += 2 + length;
+    }
+
+    metadata
+}
+
+fn extract_from_png(data: &[u8]) -> ImageMetadata {
+    let mut metadata = ImageMetadata::default();
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return metadata;
+    }
+
+    let mut pos = 8;
+    while let Some(header) = data.get(pos..pos + 8) {
+        let length = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let Some(body) = data.get(pos + 8..pos + 8 + length) else {
+            break;
+        };
+        match &header[4..8] {
+            b"eXIf" => {
+                metadata.exif.get_or_insert_with(|| body.to_vec());
+            }
+            b"iTXt" => {
+                if let Some(xmp) = png_itxt_xmp(body) {
+                    metadata.xmp.get_or_insert_with(|| xmp.to_vec());
+                }
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        pos += 12 + length; // length + type + data + CRC
+    }
+
+    metadata
+}
+
+/// The XMP packet of an uncompressed `XML:com.adobe.xmp` iTXt chunk. Compressed
+/// XMP is rare (exiftool and Adobe tools write it uncompressed) and is skipped.
+fn png_itxt_xmp(body: &[u8]) -> Option<&[u8]> {
+    let rest = body.strip_prefix(PNG_XMP_KEYWORD)?.strip_prefix(&[0])?;
+    let (&compressed, rest) = rest.split_first()?;
+    if compressed != 0 {
+        return None;
+    }
+    let rest = rest.get(1..)?; // compression method
+    let language_end = rest.iter().position(|&b| b == 0)?;
+    let rest = &rest[language_end + 1..];
+    let translated_end = rest.iter().position(|&b| b == 0)?;
+    Some(&rest[translated_end + 1..])
+}
+
+fn extract_from_webp(data: &[u8]) -> ImageMetadata {
+    let mut metadata = ImageMetadata::default();
+    if data.len() < 12 || &data[..4] != b"RIFF" || &data[8..12] != b"WEBP" {
+        return metadata;
+    }
+
+    let mut pos = 12;
+    while let Some(header) = data.get(pos..pos + 8) {
+        let size = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let Some(body) = data.get(pos + 8..pos + 8 + size) else {
+            break;
+        };
+        match &header[..4] {
+            // Some writers keep the JPEG-style "Exif\0\0" prefix in the chunk
+            b"EXIF" => {
+                let exif = body.strip_prefix(JPEG_EXIF_SIGNATURE).unwrap_or(body);
+                metadata.exif.get_or_insert_with(|| exif.to_vec());
+            }
+            b"XMP " => {
+                metadata.xmp.get_or_insert_with(|| body.to_vec());
+            }
+            _ => {}
+        }
+        pos += 8 + size + (size % 2); // chunks are padded to even sizes
+    }
+
+    metadata
+}
+
+/// EXIF and XMP of one image of a HEIF container, through the handle the
+/// decoder opened (with its security limits): each image of a burst carries
+/// its own
+pub fn extract_from_heic(handle: &libheif_rs::ImageHandle) -> ImageMetadata {
+    let mut metadata = ImageMetadata::default();
+    for block in handle.all_metadata() {
+        if block.item_type.0 == *b"Exif" {
+            // HEIF prefixes the TIFF structure with a 32-bit offset to its header
+            let Some(offset) = block.raw_data.get(..4) else {
+                continue;
+            };
+            let offset = u32::from_be_bytes([offset[0], offset[1], offset[2], offset[3]]) as usize;
+            if let Some(exif) = block.raw_data.get(4 + offset..) {
+                metadata.exif.get_or_insert_with(|| exif.to_vec());
+            }
+        } else if block.content_type == "application/rdf+xml" {
+            metadata.xmp.get_or_insert(block.raw_data);
+        }
+    }
+
+    metadata
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Values planted in `sensitive_exif`, none of which may survive a privacy policy
+    pub(crate) const OWNER: &[u8] = b"Jane Owner";
+    pub(crate) const SERIAL: &[u8] = b"SN-4242";
+    /// The GPSLatitude rationals 48/1, 51/1, 30/1 as stored big-endian
+    pub(crate) const LATITUDE: &[u8] = &[0, 0, 0, 48, 0, 0, 0, 1, 0, 0, 0, 51, 0, 0, 0, 1];
+
+    pub(crate) const TAG_MAKE: u16 = 0x010F;
+    pub(crate) const TAG_ARTIST: u16 = 0x013B;
+    pub(crate) const TAG_EXIF_IFD: u16 = 0x8769;
+
+    /// Big-endian EXIF with Make, Artist, an EXIF IFD holding the body serial
+    /// number and a GPS IFD holding a latitude
+    pub(crate) fn sensitive_exif() -> Vec<u8> {
+        fn entry(tag: u16, field_type: u16, count: u32, value: u32) -> Vec<u8> {
+            [
+                &tag.to_be_bytes()[..],
+                &field_type.to_be_bytes(),
+                &count.to_be_bytes(),
+                &value.to_be_bytes(),
+            ]
+            .concat()
+        }
+
+        let mut tiff = b"MM\0*\0\0\0\x08\0\x04".to_vec();
+        tiff.extend(entry(TAG_MAKE, 2, 6, 62));
+        tiff.extend(entry(TAG_ARTIST, 2, 11, 68));
+        tiff.extend(entry(TAG_EXIF_IFD, 4, 1, 80));
+        tiff.extend(entry(EXIF_GPS_IFD_TAG, 4, 1, 106));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(b"Canon\0");
+        tiff.extend_from_slice(&[OWNER, b"\0\0"].concat()); // padded to 80
+        tiff.extend_from_slice(b"\0\x01");
+        tiff.extend(entry(0xA431, 2, 8, 98)); // BodySerialNumber
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(&[SERIAL, b"\0"].concat());
+        tiff.extend_from_slice(b"\0\x02");
+        tiff.extend(entry(0x0001, 2, 2, u32::from_be_bytes(*b"N\0\0\0")));
+        tiff.extend(entry(0x0002, 5, 3, 136));
+        tiff.extend_from_slice(&[0; 4]);
+        tiff.extend_from_slice(LATITUDE);
+        tiff.extend_from_slice(&[0, 0, 0, 30, 0, 0, 0, 1]);
+        assert_eq!(tiff.len(), 160);
+        tiff
+    }
+
+    /// XMP naming the photographer and a GPS position
+    pub(crate) const SENSITIVE_XMP: &[u8] =
+        b"<rdf:Description dc:creator=\"Jane Owner\" exif:GPSLatitude=\"48,51.5N\"/>";
+
+    /// Tags of the IFD0 entries, in file order
+    pub(crate) fn ifd0_tags(tiff: &[u8]) -> Vec<u16> {
+        let big_endian = tiff_big_endian(tiff).unwrap();
+        let (ifd, entries) = tiff_ifd0(tiff, big_endian).unwrap();
+        (0..entries)
+            .map(|i| read_u16(tiff, ifd + 2 + i * 12, big_endian).unwrap())
+            .collect()
+    }
+
+    pub(crate) fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+        find(haystack, needle).is_some()
+    }
+
+    const TIFF: &[u8] = b"MM\0*\0\0\0\x08\0\0";
+    const XMP: &[u8] = b"<x:xmpmeta xmlns:x=\"adobe:ns:meta/\"/>";
+
+    fn sample() -> ImageMetadata {
+        ImageMetadata {
+            exif: Some(TIFF.to_vec()),
+            xmp: Some(XMP.to_vec()),
+        }
+    }
+
+    fn jpeg_segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let length = u16::try_from(payload.len() + 2).unwrap();
+        [&[0xFF, marker][..], &length.to_be_bytes(), payload].concat()
+    }
+
+    #[test]
+    fn test_extract_from_jpeg_app1_segments() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(jpeg_segment(0xE0, b"JFIF\0\x01\x01"));
+        for payload in sample().jpeg_app1_payloads() {
+            jpeg.extend(jpeg_segment(0xE1, &payload));
+        }
+        jpeg.extend(jpeg_segment(0xDA, b"\0"));
+        jpeg.extend_from_slice(&[0xFF, 0xD9]);
+
+        assert_eq!(extract_metadata(&jpeg, "JPG"), sample());
+    }
+
+    #[test]
+    fn test_jpeg_payloads_skip_blocks_larger_than_a_segment() {
+        let metadata = ImageMetadata {
+            exif: Some(vec![0; JPEG_MAX_SEGMENT_PAYLOAD]),
+            xmp: Some(XMP.to_vec()),
+        };
+        let payloads = metadata.jpeg_app1_payloads();
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].starts_with(JPEG_XMP_SIGNATURE));
+    }
+
+    #[test]
+    fn test_extract_from_png_chunks() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        for (name, body) in sample().png_chunks() {
+            png.extend_from_slice(&u32::try_from(body.len()).unwrap().to_be_bytes());
+            png.extend_from_slice(&name);
+            png.extend_from_slice(&body);
+            png.extend_from_slice(&[0; 4]); // CRC is not checked
+        }
+        png.extend_from_slice(b"\0\0\0\0IEND\0\0\0\0");
+
+        assert_eq!(extract_metadata(&png, "png"), sample());
+    }
+
+    #[test]
+    fn test_extract_from_webp_chunks() {
+        let mut body = b"WEBP".to_vec();
+        for (name, data) in [
+            (b"EXIF", [JPEG_EXIF_SIGNATURE, TIFF].concat()),
+            (b"XMP ", XMP.to_vec()),
+        ] {
+            body.extend_from_slice(name);
+            body.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+            body.extend_from_slice(&data);
+            if data.len() % 2 == 1 {
+                body.push(0);
+            }
+        }
+        let mut webp = b"RIFF".to_vec();
+        webp.extend_from_slice(&u32::try_from(body.len()).unwrap().to_le_bytes());
+        webp.extend(body);
+
+        assert_eq!(extract_metadata(&webp, "webp"), sample());
+    }
+
+    /// A one-entry IFD0 holding the orientation tag
+    fn tiff_with_orientation(big_endian: bool, orientation: u16) -> Vec<u8> {
+        let (header, entry): (&[u8], Vec<u8>) = if big_endian {
+            (
+                b"MM\0*\0\0\0\x08\0\x01",
+                [
+                    &[0x01, 0x12, 0, 3, 0, 0, 0, 1][..],
+                    &orientation.to_be_bytes(),
+                    &[0, 0],
+                ]
+                .concat(),
+            )
+        } else {
+            (
+                b"II*\0\x08\0\0\0\x01\0",
+                [
+                    &[0x12, 0x01, 3, 0, 1, 0, 0, 0][..],
+                    &orientation.to_le_bytes(),
+                    &[0, 0],
+                ]
+                .concat(),
+            )
+        };
+        [header, &entry, &[0, 0, 0, 0]].concat()
+    }
+
+    #[test]
+    fn test_orientation_is_read_in_both_byte_orders() {
+        for big_endian in [true, false] {
+            let metadata = ImageMetadata {
+                exif: Some(tiff_with_orientation(big_endian, 6)),
+                xmp: None,
+            };
+            assert_eq!(
+                metadata.orientation(),
+                Some(image::metadata::Orientation::Rotate90)
+            );
+        }
+        assert_eq!(sample().orientation(), None); // IFD0 without the tag
+    }
+
+    #[test]
+    fn test_reset_orientation_rewrites_exif_and_xmp() {
+        let mut metadata = ImageMetadata {
+            exif: Some(tiff_with_orientation(false, 8)),
+            xmp: Some(
+                b"<rdf:Description tiff:Orientation=\"8\"/><tiff:Orientation>8</tiff:Orientation>"
+                    .to_vec(),
+            ),
+        };
+
+        metadata.reset_orientation();
+
+        assert_eq!(metadata.exif, Some(tiff_with_orientation(false, 1)));
+        assert_eq!(
+            metadata.xmp.as_deref(),
+            Some(
+                &b"<rdf:Description tiff:Orientation=\"1\"/><tiff:Orientation>1</tiff:Orientation>"
+                    [..]
+            )
+        );
+    }
+
+    #[test]
+    fn test_keep_all_except_location_drops_the_gps_ifd_only() {
+        let metadata = ImageMetadata {
+            exif: Some(sensitive_exif()),
+            xmp: Some(SENSITIVE_XMP.to_vec()),
+        }
+        .for_policy(MetadataPolicy::KeepAllExceptLocation);
+
+        let exif = metadata.exif.unwrap();
+        assert_eq!(ifd0_tags(&exif), [TAG_MAKE, TAG_ARTIST, TAG_EXIF_IFD]);
+        assert!(!contains(&exif, LATITUDE));
+        assert!(contains(&exif, SERIAL));
+        assert_eq!(metadata.xmp, None); // names a GPS position
+    }
+
+    #[test]
+    fn test_keep_all_except_location_keeps_xmp_without_location() {
+        let metadata = ImageMetadata {
+            exif: Some(b"not a tiff".to_vec()),
+            xmp: Some(XMP.to_vec()),
+        }
+        .for_policy(MetadataPolicy::KeepAllExceptLocation);
+
+        assert_eq!(metadata.exif, None); // unparseable, so not provably clean
+        assert_eq!(metadata.xmp.as_deref(), Some(XMP));
+    }
+
+    #[test]
+    fn test_rewrite_jpeg_metadata_keeps_only_vetted_segments() {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(jpeg_segment(0xE0, b"JFIF\0\x01\x01"));
+        jpeg.extend(jpeg_segment(
+            0xE1,
+            &[JPEG_EXIF_SIGNATURE, &sensitive_exif()].concat(),
+        ));
+        jpeg.extend(jpeg_segment(0xE2, b"ICC_PROFILE\0\x01\x01icc"));
+        jpeg.extend(jpeg_segment(0xED, b"Photoshop 3.0\0Jane Owner"));
+        jpeg.extend(jpeg_segment(0xFE, b"Jane Owner"));
+        jpeg.extend(jpeg_segment(0xDB, b"\0quant"));
+        jpeg.extend(jpeg_segment(0xDA, b"\0"));
+        jpeg.extend_from_slice(&[0x12, 0x34, 0xFF, 0xD9]);
+
+        let rewritten = rewrite_jpeg_metadata(&jpeg, &ImageMetadata::default(), true).unwrap();
+
+        assert!(!contains(&rewritten, OWNER));
+        assert!(extract_metadata(&rewritten, "jpeg").is_empty());
+        assert!(contains(&rewritten, b"ICC_PROFILE"));
+        assert!(rewritten.ends_with(&[0xFF, 0xDA, 0, 3, 0, 0x12, 0x34, 0xFF, 0xD9]));
+
+        let stripped = rewrite_jpeg_metadata(&jpeg, &sample(), false).unwrap();
+        assert!(!contains(&stripped, b"ICC_PROFILE"));
+        assert_eq!(extract_metadata(&stripped, "jpeg"), sample());
+        assert_eq!(
+            rewrite_jpeg_metadata(b"\xFF\xD8\xFF\xE1\xFF", &sample(), true),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rewrite_png_metadata_replaces_text_chunks() {
+        let mut png = b"\x89PNG\r\n\x1a\n".to_vec();
+        png.extend(png_chunk(*b"IHDR", &[0; 13]));
+        png.extend(png_chunk(*b"iCCP", b"icc\0\0data"));
+        png.extend(png_chunk(*b"tEXt", b"Author\0Jane Owner"));
+        png.extend(png_chunk(*b"eXIf", &sensitive_exif()));
+        png.extend(png_chunk(*b"gAMA", &45_455_u32.to_be_bytes()));
+        png.extend(png_chunk(*b"prVt", b"serial 0042 Jane Owner"));
+        png.extend(png_chunk(*b"IDAT", b"pixels"));
+        png.extend(png_chunk(*b"IEND", b""));
+
+        let rewritten = rewrite_png_metadata(&png, &sample(), false).unwrap();
+
+        assert!(!contains(&rewritten, OWNER));
+        assert!(!contains(&rewritten, b"prVt"));
+        assert!(!contains(&rewritten, b"iCCP"));
+        assert!(contains(&rewritten, b"gAMA"));
+        let kept_icc = rewrite_png_metadata(&png, &sample(), true).unwrap();
+        assert!(contains(&kept_icc, b"iCCP"));
+        assert!(!contains(&kept_icc, b"prVt"));
+        assert_eq!(extract_metadata(&rewritten, "png"), sample());
+        let exif_at = find(&rewritten, b"eXIf").unwrap();
+        assert!(exif_at < find(&rewritten, b"IDAT").unwrap());
+        assert_eq!(
+            rewrite_png_metadata(b"\x89PNG\r\n\x1a\n\0\0", &sample(), true),
+            None
+        );
+    }
+
+    #[test]
+    fn test_extract_from_unknown_or_malformed_input_is_empty() {
+        assert!(extract_metadata(b"\xFF\xD8\xFF\xE1\xFF", "jpeg").is_empty());
+        assert!(extract_metadata(b"not a png", "png").is_empty());
+        assert!(extract_metadata(b"anything", "bmp").is_empty());
+    }
+}
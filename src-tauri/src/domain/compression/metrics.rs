@@ -0,0 +1,282 @@
+//! Comparison of an encoded output against the pixels it was encoded from: the
+//! fidelity figures reported for every compression, and the SSIM that drives the
+//! perceptual-target search.
+
+use image::DynamicImage;
+use serde::{Deserialize, Serialize};
+
+use crate::domain::compression::error::{CompressionError, CompressionResult};
+
+/// Side of the square SSIM window, and the step between windows
+const SSIM_WINDOW: usize = 8;
+const SSIM_STRIDE: usize = 4;
+
+/// Stabilising constants of the SSIM formula for 8-bit samples:
+/// (0.01 × 255)² and (0.03 × 255)²
+const SSIM_C1: f64 = 6.5025;
+const SSIM_C2: f64 = 58.5225;
+
+/// How faithful an output is to its source
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct QualityMetrics {
+    /// Peak signal-to-noise ratio in dB; `None` for a pixel-identical output,
+    /// whose PSNR is infinite
+    pub psnr: Option<f64>,
+    /// Structural similarity, from 0 to 1 (identical); see [`ssim`]
+    pub ssim: f64,
+    /// Largest difference of any channel of any pixel, out of 255
+    pub max_error: u8,
+}
+
+/// Why an output carries no [`QualityMetrics`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unmeasured {
+    /// No AVIF decoder is built in (libheif only decodes HEVC), so the output
+    /// cannot be read back
+    AvifOutput,
+    /// An animation has no single image to compare with
+    Animation,
+    /// Decoding or comparing the output failed; the reason is logged
+    Failed,
+}
+
+impl QualityMetrics {
+    pub const IDENTICAL: Self = Self {
+        psnr: None,
+        ssim: 1.0,
+        max_error: 0,
+    };
+}
+
+/// PSNR, SSIM and maximum error of `candidate` against `reference`. As for
+/// [`ssim`], alpha counts only when the candidate kept it.
+// Sample counts are far below 2^52, exact in f64.
+#[allow(clippy::cast_precision_loss)]
+pub fn measure(
+    reference: &DynamicImage,
+    candidate: &DynamicImage,
+) -> CompressionResult<QualityMetrics> {
+    let ssim = ssim(reference, candidate)?;
+
+    let (reference, candidate) = if candidate.color().has_alpha() {
+        (
+            reference.to_rgba8().into_raw(),
+            candidate.to_rgba8().into_raw(),
+        )
+    } else {
+        (
+            reference.to_rgb8().into_raw(),
+            candidate.to_rgb8().into_raw(),
+        )
+    };
+    let (squared_error, max_error) = reference.iter().zip(&candidate).fold(
+        (0u64, 0u8),
+        |(squared_error, max_error), (&a, &b)| {
+            let error = a.abs_diff(b);
+            (
+                squared_error + u64::from(error) * u64::from(error),
+                max_error.max(error),
+            )
+        },
+    );
+
+    let psnr = (squared_error > 0).then(|| {
+        let mse = squared_error as f64 / reference.len() as f64;
+        10.0 * (255.0 * 255.0 / mse).log10()
+    });
+    Ok(QualityMetrics {
+        psnr,
+        ssim,
+        max_error,
+    })
+}
+
+/// Structural similarity of `candidate` to `reference`, from 0 to 1 (identical):
+/// the mean SSIM of 8×8 windows, four pixels apart, over the luma plane.
+///
+/// Alpha is compared only when `candidate` kept it. The luma is then weighted by
+/// alpha, so invisible pixels do not count, and the alpha plane is scored too;
+/// JPEG output is judged on its colour alone rather than on the transparency it
+/// cannot store.
+pub fn ssim(reference: &DynamicImage, candidate: &DynamicImage) -> CompressionResult<f64> {
+    let (width, height) = (reference.width(), reference.height());
+    if (width, height) != (candidate.width(), candidate.height()) {
+        return Err(CompressionError::ProcessingError(format!(
+            "Cannot compare a {}x{} output with a {width}x{height} source",
+            candidate.width(),
+            candidate.height(),
+        )));
+    }
+    let (width, height) = (width as usize, height as usize);
+
+    if candidate.color().has_alpha() {
+        let (reference, candidate) = (planes(reference), planes(candidate));
+        let luma = plane_ssim(&reference.0, &candidate.0, width, height);
+        let alpha = plane_ssim(&reference.1, &candidate.1, width, height);
+        Ok(f64::midpoint(luma, alpha))
+    } else {
+        let luma = |img: &DynamicImage| -> Vec<f64> {
+            img.to_rgb8()
+                .pixels()
+                .map(|p| luma_of(p[0], p[1], p[2]))
+                .collect()
+        };
+        Ok(plane_ssim(
+            &luma(reference),
+            &luma(candidate),
+            width,
+            height,
+        ))
+    }
+}
+
+/// BT.601 luma of an 8-bit RGB sample
+fn luma_of(r: u8, g: u8, b: u8) -> f64 {
+    0.299 * f64::from(r) + 0.587 * f64::from(g) + 0.114 * f64::from(b)
+}
+
+/// Alpha-weighted luma and alpha planes of an image
+fn planes(img: &DynamicImage) -> (Vec<f64>, Vec<f64>) {
+    img.to_rgba8()
+        .pixels()
+        .map(|p| {
+            let alpha = f64::from(p[3]);
+            (luma_of(p[0], p[1], p[2]) * alpha / 255.0, alpha)
+        })
+        .unzip()
+}
+
+/// Mean SSIM of two planes of `width` × `height` samples in 0..=255. An image
+/// smaller than a window is scored as a single window.
+// Window sample counts are at most 64, exact in f64.
+#[allow(clippy::cast_precision_loss)]
+fn plane_ssim(a: &[f64], b: &[f64], width: usize, height: usize) -> f64 {
+    let (window_width, window_height) = (SSIM_WINDOW.min(width), SSIM_WINDOW.min(height));
+    let samples = (window_width * window_height) as f64;
+
+    let mut total = 0.0;
+    let mut windows = 0usize;
+    for top in (0..=height - window_height).step_by(SSIM_STRIDE) {
+        for left in (0..=width - window_width).step_by(SSIM_STRIDE) {
+            // Sums of the samples, of their squares and of their products
+            let (mut sums, mut squares, mut products) = ((0.0, 0.0), (0.0, 0.0), 0.0);
+            for row in top..top + window_height {
+                let start = row * width + left;
+                for (&p, &q) in a[start..start + window_width]
+                    .iter()
+                    .zip(&b[start..start + window_width])
+                {
+                    sums.0 += p;
+                    sums.1 += q;
+                    squares.0 += p * p;
+                    squares.1 += q * q;
+                    products += p * q;
+                }
+            }
+            let (mean_a, mean_b) = (sums.0 / samples, sums.1 / samples);
+            let variance_a = squares.0 / samples - mean_a * mean_a;
+            let variance_b = squares.1 / samples - mean_b * mean_b;
+            let covariance = products / samples - mean_a * mean_b;
+
+            total += ((2.0 * mean_a * mean_b + SSIM_C1) * (2.0 * covariance + SSIM_C2))
+                / ((mean_a * mean_a + mean_b * mean_b + SSIM_C1)
+                    * (variance_a + variance_b + SSIM_C2));
+            windows += 1;
+        }
+    }
+
+    if windows == 0 {
+        // Empty image: nothing differs
+        1.0
+    } else {
+        total / windows as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gradient(width: u32, height: u32) -> image::RgbImage {
+        image::RgbImage::from_fn(width, height, |x, y| {
+            image::Rgb([
+                (x * 8).try_into().unwrap(),
+                (y * 8).try_into().unwrap(),
+                ((x + y) * 4).try_into().unwrap(),
+            ])
+        })
+    }
+
+    #[test]
+    fn test_identical_images_score_one() {
+        let img = DynamicImage::ImageRgb8(gradient(32, 32));
+        assert!((ssim(&img, &img).unwrap() - 1.0).abs() < 1e-12);
+
+        let tiny = DynamicImage::ImageRgb8(gradient(3, 2));
+        assert!((ssim(&tiny, &tiny).unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_score_falls_with_distortion() {
+        let reference = DynamicImage::ImageRgb8(gradient(32, 32));
+        let mut slight = gradient(32, 32);
+        let mut heavy = gradient(32, 32);
+        for (x, y, pixel) in slight.enumerate_pixels_mut() {
+            if (x + y) % 7 == 0 {
+                pixel[0] = pixel[0].saturating_add(6);
+            }
+        }
+        for (x, _, pixel) in heavy.enumerate_pixels_mut() {
+            pixel[1] = if x % 2 == 0 { 0 } else { 255 };
+        }
+
+        let slight = ssim(&reference, &DynamicImage::ImageRgb8(slight)).unwrap();
+        let heavy = ssim(&reference, &DynamicImage::ImageRgb8(heavy)).unwrap();
+
+        assert!(slight < 1.0 && heavy < slight, "{slight} {heavy}");
+    }
+
+    #[test]
+    fn test_alpha_is_scored_only_when_the_candidate_keeps_it() {
+        let opaque = image::RgbaImage::from_pixel(16, 16, image::Rgba([200, 90, 40, 255]));
+        let faded = image::RgbaImage::from_fn(16, 16, |x, _| {
+            image::Rgba([200, 90, 40, if x < 8 { 255 } else { 0 }])
+        });
+        let colour_only =
+            DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(faded.clone()).to_rgb8());
+
+        let faded = DynamicImage::ImageRgba8(faded);
+        let opaque = DynamicImage::ImageRgba8(opaque);
+        assert!(ssim(&opaque, &faded).unwrap() < 0.9);
+        assert!((ssim(&faded, &colour_only).unwrap() - 1.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_measure_reports_psnr_and_the_largest_error() {
+        let reference = DynamicImage::ImageRgb8(gradient(32, 32));
+        assert_eq!(
+            measure(&reference, &reference).unwrap(),
+            QualityMetrics::IDENTICAL
+        );
+
+        // One channel of every pixel off by 5: MSE = 25 / 3
+        let mut shifted = gradient(32, 32);
+        for pixel in shifted.pixels_mut() {
+            pixel[2] = pixel[2].saturating_add(5);
+        }
+        let metrics = measure(&reference, &DynamicImage::ImageRgb8(shifted)).unwrap();
+
+        assert_eq!(metrics.max_error, 5);
+        let expected = 10.0 * (255.0_f64 * 255.0 * 3.0 / 25.0).log10();
+        assert!((metrics.psnr.unwrap() - expected).abs() < 1e-9);
+        assert!(metrics.ssim < 1.0);
+    }
+
+    #[test]
+    fn test_mismatched_dimensions_are_rejected() {
+        let a = DynamicImage::ImageRgb8(gradient(32, 32));
+        let b = DynamicImage::ImageRgb8(gradient(32, 16));
+        assert!(ssim(&a, &b).is_err());
+    }
+}
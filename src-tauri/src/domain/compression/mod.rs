@@ -3,25 +3,44 @@
 // This module provides image compression functionality using pure functions
 // and data structures, following Rust idioms for zero-cost abstractions.
 
+pub mod animation;
+pub mod color;
 pub mod engine;
 pub mod error;
 pub mod formats;
+pub mod metadata;
+pub mod metrics;
 pub mod naming;
 pub mod pipeline;
+pub mod raw;
+pub mod responsive;
 pub mod settings;
 pub mod stats;
 
 // Re-export core types and functions for easy access
 pub use error::{CompressionError, CompressionResult, StatsError};
 pub use formats::OutputFormat;
-pub use naming::{CompressionLevel, resolve_output_path};
-pub use settings::CompressionSettings;
+pub use naming::{
+    CompressionLevel, resolve_indexed_output_path, resolve_output_path, resolve_variant_output_path,
+};
+pub use responsive::{ResponsiveManifest, ResponsiveOptions, ResponsiveVariant};
+pub use settings::{
+    ChromaSubsampling, CompressionSettings, DecodeLimits, EncoderOptions, HeifImages, JpegOptions,
+    MetadataPolicy, PerceptualTarget, PngOptions, RawDecodeMode, ResizeOptions, WebpMode,
+    WebpOptions, WebpPreset,
+};
 
 // Engine functions - core compression operations
-pub use engine::{CompressionOutput, compress_file_to_file};
+pub use engine::{
+    CompressionOutput, DecodedImage, QualitySearch, compress_bytes, compress_bytes_to_smallest,
+    compress_decoded, compress_file_to_file, decode_still,
+};
 
 // Pipeline - orchestrates a single compression (thin command adapter above it)
-pub use pipeline::{CompressionOutcome, CompressionSummary, run_compression};
+pub use pipeline::{
+    AutoBestOptions, CompressionOptions, CompressionOutcome, CompressionSummary, ExtraOutput,
+    run_compression,
+};
 
 // Statistics types and functions
 pub use stats::{
@@ -36,10 +36,37 @@ const FALLBACK_STEM: &str = "compressed";
 /// parameters produce a new file. Choosing another destination is a separate
 /// feature, tracked in the roadmap.
 pub fn resolve_output_path(input: &Path, level: CompressionLevel, extension: &str) -> PathBuf {
+    output_path_with_suffix(input, level.suffix(), extension)
+}
+
+/// Resolve where one of several outputs of the same input is written, such as
+/// each top-level image of a HEIF container: `{stem}_{level}_{index}.{extension}`,
+/// with `index` counted from 1.
+pub fn resolve_indexed_output_path(
+    input: &Path,
+    level: CompressionLevel,
+    index: usize,
+    extension: &str,
+) -> PathBuf {
+    output_path_with_suffix(input, &format!("{}_{index}", level.suffix()), extension)
+}
+
+/// Resolve where the variant of a responsive set that is `width` pixels wide
+/// is written: `{stem}_{level}_{width}w.{extension}`.
+pub fn resolve_variant_output_path(
+    input: &Path,
+    level: CompressionLevel,
+    width: u32,
+    extension: &str,
+) -> PathBuf {
+    output_path_with_suffix(input, &format!("{}_{width}w", level.suffix()), extension)
+}
+
+fn output_path_with_suffix(input: &Path, suffix: &str, extension: &str) -> PathBuf {
     let stem = get_file_stem(input).unwrap_or_else(|_| FALLBACK_STEM.to_string());
 
     let mut output = input.to_path_buf();
-    output.set_file_name(format!("{}_{}.{}", stem, level.suffix(), extension));
+    output.set_file_name(format!("{stem}_{suffix}.{extension}"));
     output
 }
 
@@ -74,6 +101,30 @@ mod tests {
         assert_eq!(first, second);
     }
 
+    #[test]
+    fn test_indexed_outputs_number_the_level_suffix() {
+        let input = Path::new("/home/user/Pictures/IMG_0001.HEIC");
+
+        assert_eq!(
+            resolve_indexed_output_path(input, CompressionLevel::Balanced, 1, "jpg"),
+            PathBuf::from("/home/user/Pictures/IMG_0001_balanced_1.jpg")
+        );
+        assert_eq!(
+            resolve_indexed_output_path(input, CompressionLevel::Light, 12, "webp"),
+            PathBuf::from("/home/user/Pictures/IMG_0001_light_12.webp")
+        );
+    }
+
+    #[test]
+    fn test_variant_outputs_carry_their_width() {
+        let input = Path::new("/home/user/Pictures/hero.png");
+
+        assert_eq!(
+            resolve_variant_output_path(input, CompressionLevel::Balanced, 640, "avif"),
+            PathBuf::from("/home/user/Pictures/hero_balanced_640w.avif")
+        );
+    }
+
     #[test]
     fn test_level_deserializes_from_frontend_values() {
         assert_eq!(
@@ -2,16 +2,28 @@
 // to overflow u64. Scoped deviation — see docs/conventions.md (pedantic-cast).
 #![allow(clippy::cast_possible_truncation)]
 
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::time::Instant;
 
 use serde::{Deserialize, Serialize};
 
-use crate::domain::compression::engine::{CompressionOutput, compress_file_to_file};
+use crate::domain::compression::engine::{
+    CompressionOutput, QualitySearch, compress_bytes_to_smallest, compress_decoded,
+    compress_file_to_file, decode_still, heic_top_level_image_count,
+};
 use crate::domain::compression::error::CompressionError;
 use crate::domain::compression::formats::OutputFormat;
-use crate::domain::compression::naming::{CompressionLevel, resolve_output_path};
-use crate::domain::compression::settings::{CompressionSettings, DEFAULT_QUALITY};
+use crate::domain::compression::metrics::{QualityMetrics, Unmeasured};
+use crate::domain::compression::naming::{
+    CompressionLevel, resolve_indexed_output_path, resolve_output_path, resolve_variant_output_path,
+};
+use crate::domain::compression::responsive::{
+    ResponsiveManifest, ResponsiveOptions, ResponsiveVariant,
+};
+use crate::domain::compression::settings::{
+    CompressionSettings, DEFAULT_QUALITY, DecodeLimits, EncoderOptions, HeifImages, MetadataPolicy,
+    PerceptualTarget, RawDecodeMode, ResizeOptions,
+};
 use crate::domain::compression::stats::{
     CompressionStat, create_stat_with_time, pixel_count_from_path,
 };
@@ -24,93 +36,471 @@ pub struct CompressionSummary {
     pub compressed_size: u64,
     pub savings_percent: f64,
     pub output_path: String,
+    /// The quality a target-size or perceptual-target compression settled on,
+    /// its encode passes and, for a perceptual target, the SSIM reached
+    pub quality_search: Option<QualitySearch>,
+    /// Fidelity of the returned file to the source, when it could be measured
+    pub metrics: Option<QualityMetrics>,
+    /// Why `metrics` is `None`: an AVIF output (there is no AVIF decoder to
+    /// read it back), an animation, or a measurement that failed
+    pub metrics_unavailable: Option<Unmeasured>,
+    /// The other files written for the same input, in order: the further
+    /// top-level images of a HEIF container when all of them are exported, or
+    /// the other variants of a responsive set
+    pub extra_outputs: Vec<ExtraOutput>,
+    /// Every variant of a responsive set and the `<picture>` serving them
+    pub responsive: Option<ResponsiveManifest>,
+    /// Allowed formats `auto-best` did not let compete (see
+    /// `CompressionOutput::excluded_formats`)
+    pub excluded_formats: Vec<OutputFormat>,
+}
+
+/// A further file written for an input whose main output is the summary's
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExtraOutput {
+    pub output_path: String,
+    pub compressed_size: u64,
+    pub dimensions: Option<(u32, u32)>,
+}
+
+/// Constraints on the `auto-best` format mode
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoBestOptions {
+    /// Formats allowed to compete, by extension; empty allows every one
+    pub formats: Vec<String>,
+    /// Leave JPEG out when the source has transparent pixels. On by default:
+    /// the fidelity a JPEG is scored on does not count the alpha it drops.
+    pub keep_transparency: bool,
+}
+
+impl Default for AutoBestOptions {
+    fn default() -> Self {
+        Self {
+            formats: Vec::new(),
+            keep_transparency: true,
+        }
+    }
+}
+
+/// How the pixels and metadata are processed on their way to the encoder. The
+/// default keeps every pixel and every metadata block.
+#[derive(Debug, Clone, Default)]
+pub struct CompressionOptions {
+    pub metadata_policy: MetadataPolicy,
+    pub convert_to_srgb: bool,
+    pub resize: ResizeOptions,
+    /// Maximum output size in bytes; the quality (the default when none is
+    /// given) becomes a ceiling the search never goes above
+    pub target_size: Option<u64>,
+    /// Perceptual quality to reach instead of a fixed quality
+    pub perceptual_target: Option<PerceptualTarget>,
+    /// Ceilings on the decoded size of an untrusted input
+    pub decode_limits: DecodeLimits,
+    /// How a camera RAW input is rendered
+    pub raw_mode: RawDecodeMode,
+    /// Drop 16-bit sources to 8 bits per channel rather than keep their depth
+    pub reduce_to_8bit: bool,
+    /// Which images of a HEIF container are exported
+    pub heif_images: HeifImages,
+    /// Per-format encoder tuning
+    pub encoder: EncoderOptions,
+    /// Which formats compete when the `auto-best` format is requested
+    pub auto_best: AutoBestOptions,
+    /// Generate a responsive set instead of a single output
+    pub responsive: Option<ResponsiveOptions>,
 }
 
 /// Everything a successful compression produces: the summary returned to the
-/// frontend and the stat to persist. Building both here keeps the Tauri command
-/// a thin adapter.
+/// frontend and a stat to persist for each file written. Building both here
+/// keeps the Tauri command a thin adapter.
 pub struct CompressionOutcome {
     pub summary: CompressionSummary,
-    pub stat: CompressionStat,
+    pub stats: Vec<CompressionStat>,
 }
 
 /// Orchestrates a single compression: resolve the output format and path,
 /// compress, then decide whether the compressed file is worth keeping. The input
 /// is assumed already validated (see `validate_image_file`), so this returns a
-/// `CompressionError` only for genuine processing failures.
+/// `CompressionError` only for genuine processing failures. A HEIF container
+/// of several images is exported image by image under `HeifImages::All`,
+/// `auto-best` picks the format of each output afresh, and a responsive set
+/// takes its formats from its own options: requesting a format as well is an
+/// error.
 pub fn run_compression(
     file_path: &Path,
     metadata: &FileMetadata,
     requested_format: Option<&str>,
     quality: Option<u8>,
     level: CompressionLevel,
+    options: &CompressionOptions,
 ) -> Result<CompressionOutcome, CompressionError> {
-    let output_format = resolve_output_format(requested_format, metadata);
+    if options.responsive.is_some() && requested_format.is_some() {
+        return Err(CompressionError::InvalidSettings(
+            "A responsive set takes its formats from its own options, not from format".to_string(),
+        ));
+    }
+    let choice = resolve_output_format(requested_format, metadata, &options.auto_best)?;
     let quality = quality.unwrap_or(DEFAULT_QUALITY);
-    let settings = CompressionSettings::new(quality, output_format);
+    let settings = CompressionSettings {
+        metadata_policy: options.metadata_policy,
+        convert_to_srgb: options.convert_to_srgb,
+        resize: options.resize,
+        target_size: options.target_size,
+        perceptual_target: options.perceptual_target,
+        decode_limits: options.decode_limits,
+        raw_mode: options.raw_mode,
+        reduce_to_8bit: options.reduce_to_8bit,
+        encoder: options.encoder,
+        ..CompressionSettings::new(quality, choice.initial())
+    };
+    if let Some(responsive) = &options.responsive {
+        return compress_responsive_set(file_path, metadata, level, &settings, responsive);
+    }
 
-    let output_path = resolve_output_path(file_path, level, output_format.extension());
+    let is_heif = matches!(metadata.format(), Some("heic" | "heif"));
+    let image_count = match options.heif_images {
+        HeifImages::All if is_heif => {
+            heic_top_level_image_count(file_path, &settings.decode_limits)?
+        }
+        _ => 1,
+    };
+    if image_count > 1 {
+        return compress_heif_images(file_path, metadata, level, &settings, &choice, image_count);
+    }
 
-    // The output path is derived here and written by the engine through std::fs,
-    // so it never passes through get_file_info. Validate it before writing.
-    validate_safe_path(&output_path)
-        .map_err(|e| CompressionError::ProcessingError(format!("Invalid output path: {e}")))?;
+    let output_path =
+        |format: OutputFormat| resolve_output_path(file_path, level, format.extension());
+    let (output_path, output, stat) =
+        compress_to(file_path, output_path, metadata, &settings, &choice)?;
 
+    let (keep_original, summary) = resolve_final_summary(&output, file_path, &output_path);
+    if keep_original {
+        // Best-effort cleanup of the non-improving output; log rather than
+        // discard the error, mirroring the stat-save best-effort in the command.
+        if let Err(e) = std::fs::remove_file(&output_path) {
+            log::warn!("Failed to remove non-improving compressed file: {e}");
+        }
+    }
+
+    Ok(CompressionOutcome {
+        summary,
+        stats: vec![stat],
+    })
+}
+
+/// Export each of the `image_count` top-level images of a HEIF container to
+/// its own indexed output. Every file is kept: the container is no substitute
+/// for any one of its images. The summary describes the first.
+fn compress_heif_images(
+    file_path: &Path,
+    metadata: &FileMetadata,
+    level: CompressionLevel,
+    settings: &CompressionSettings,
+    choice: &FormatChoice,
+    image_count: usize,
+) -> Result<CompressionOutcome, CompressionError> {
+    let mut outputs = Vec::with_capacity(image_count);
+    let mut stats = Vec::with_capacity(image_count);
+    for index in 0..image_count {
+        let output_path = |format: OutputFormat| {
+            resolve_indexed_output_path(file_path, level, index + 1, format.extension())
+        };
+        let image_settings = CompressionSettings {
+            heif_image: Some(index),
+            ..settings.clone()
+        };
+        let (output_path, output, stat) =
+            compress_to(file_path, output_path, metadata, &image_settings, choice)?;
+        outputs.push((output_path, output));
+        stats.push(stat);
+    }
+
+    let mut outputs = outputs.into_iter();
+    let Some((output_path, first)) = outputs.next() else {
+        return Err(CompressionError::ProcessingError(
+            "The HEIC file has no image".to_string(),
+        ));
+    };
+    let summary = CompressionSummary {
+        original_size: first.original_size,
+        compressed_size: first.compressed_size,
+        savings_percent: first.savings_percent,
+        output_path: output_path.to_string_lossy().to_string(),
+        quality_search: first.quality_search,
+        metrics: first.metrics,
+        metrics_unavailable: first.unmeasured,
+        extra_outputs: outputs
+            .map(|(output_path, output)| ExtraOutput {
+                output_path: output_path.to_string_lossy().to_string(),
+                compressed_size: output.compressed_size,
+                dimensions: output.dimensions,
+            })
+            .collect(),
+        responsive: None,
+        excluded_formats: first.excluded_formats,
+    };
+    Ok(CompressionOutcome { summary, stats })
+}
+
+/// Encode a responsive set: every width in every format, each variant
+/// resized to at most that width. The source is decoded once and every variant
+/// encoded from it; an animation contributes its first frame. The source is
+/// never upscaled, so the first width past the source's makes a variant of the
+/// source's own width and the ones after it none, and each file is written
+/// once, named after the width it has. Every file is kept, even one larger than
+/// the source. The summary describes the `<img>` fallback, the largest variant
+/// of the last format; the others are its extra outputs.
+fn compress_responsive_set(
+    file_path: &Path,
+    metadata: &FileMetadata,
+    level: CompressionLevel,
+    settings: &CompressionSettings,
+    responsive: &ResponsiveOptions,
+) -> Result<CompressionOutcome, CompressionError> {
+    let formats = parse_formats(&responsive.formats)?;
+    let mut widths = responsive.widths.clone();
+    widths.sort_unstable();
+    widths.dedup();
+    if formats.is_empty() || widths.is_empty() || widths.contains(&0) {
+        return Err(CompressionError::InvalidSettings(
+            "A responsive set needs at least one format and widths above zero".to_string(),
+        ));
+    }
+
+    let input_format = metadata.format().unwrap_or_default();
+    let source = decode_still(&read_input(file_path)?, input_format, settings)?;
+    let pixel_count = pixel_count_from_path(file_path);
+    let (source_width, _) = source.dimensions();
+    let mut widths: Vec<u32> = widths
+        .into_iter()
+        .map(|width| width.min(source_width))
+        .collect();
+    widths.dedup();
+
+    let mut variants = Vec::with_capacity(formats.len() * widths.len());
+    let mut outputs = Vec::with_capacity(variants.capacity());
+    let mut stats = Vec::with_capacity(variants.capacity());
+    for &format in &formats {
+        for &width in &widths {
+            // Decoding applied `settings.resize` already
+            let variant_settings = CompressionSettings {
+                format,
+                resize: ResizeOptions {
+                    max_width: Some(width),
+                    ..ResizeOptions::default()
+                },
+                ..settings.clone()
+            };
+            let started = Instant::now();
+            let (data, output) = compress_decoded(&source, &variant_settings)?;
+            let processing_time = started.elapsed().as_millis() as u64;
+            let Some((actual_width, actual_height)) = output.dimensions else {
+                return Err(CompressionError::ProcessingError(
+                    "The dimensions of a responsive variant are unknown".to_string(),
+                ));
+            };
+
+            let output_path = validated_output_path(resolve_variant_output_path(
+                file_path,
+                level,
+                actual_width,
+                format.extension(),
+            ))?;
+            write_output(&output_path, &data)?;
+            let output = CompressionOutput {
+                output_path: Some(output_path.clone()),
+                ..output
+            };
+            let stat = create_stat_with_time(
+                input_format.to_string(),
+                &output,
+                settings.quality,
+                processing_time,
+                pixel_count,
+            );
+            let output_path = output_path.to_string_lossy().to_string();
+            variants.push(ResponsiveVariant {
+                output_path: output_path.clone(),
+                format,
+                width: actual_width,
+                height: actual_height,
+                compressed_size: output.compressed_size,
+            });
+            outputs.push((output_path, output));
+            stats.push(stat);
+        }
+    }
+
+    // Formats in order, widths ascending: the fallback is the last variant
+    let Some((output_path, fallback)) = outputs.pop() else {
+        return Err(CompressionError::ProcessingError(
+            "The responsive set is empty".to_string(),
+        ));
+    };
+    let summary = CompressionSummary {
+        original_size: fallback.original_size,
+        compressed_size: fallback.compressed_size,
+        savings_percent: fallback.savings_percent,
+        output_path,
+        quality_search: fallback.quality_search,
+        metrics: fallback.metrics,
+        metrics_unavailable: fallback.unmeasured,
+        extra_outputs: outputs
+            .into_iter()
+            .map(|(output_path, output)| ExtraOutput {
+                output_path,
+                compressed_size: output.compressed_size,
+                dimensions: output.dimensions,
+            })
+            .collect(),
+        responsive: Some(ResponsiveManifest::new(variants, &responsive.sizes)),
+        excluded_formats: Vec::new(),
+    };
+    Ok(CompressionOutcome { summary, stats })
+}
+
+/// Compress `file_path` to `output_path(format)`, `format` being the one the
+/// choice settles on, and build the stat of that compression
+fn compress_to(
+    file_path: &Path,
+    output_path: impl Fn(OutputFormat) -> PathBuf,
+    metadata: &FileMetadata,
+    settings: &CompressionSettings,
+    choice: &FormatChoice,
+) -> Result<(PathBuf, CompressionOutput, CompressionStat), CompressionError> {
     let pixel_count = pixel_count_from_path(file_path);
 
     let started = Instant::now();
-    let output = compress_file_to_file(file_path, output_path.as_path(), &settings)?;
+    let (output_path, output) = match choice {
+        FormatChoice::Fixed(format) => {
+            let output_path = validated_output_path(output_path(*format))?;
+            let output = compress_file_to_file(file_path, &output_path, settings)?;
+            (output_path, output)
+        }
+        FormatChoice::Smallest {
+            candidates,
+            keep_transparency,
+        } => {
+            let input = read_input(file_path)?;
+            let (data, output) = compress_bytes_to_smallest(
+                &input,
+                metadata.format().unwrap_or_default(),
+                settings,
+                candidates,
+                *keep_transparency,
+            )?;
+            let output_path = validated_output_path(output_path(output.format))?;
+            write_output(&output_path, &data)?;
+            let output = CompressionOutput {
+                output_path: Some(output_path.clone()),
+                ..output
+            };
+            (output_path, output)
+        }
+    };
     let processing_time = started.elapsed().as_millis() as u64;
 
-    let input_format = metadata
-        .extension
-        .clone()
-        .unwrap_or_else(|| "unknown".to_string());
     let stat = create_stat_with_time(
-        input_format,
-        output_format.extension().to_string(),
-        output.original_size,
-        output.compressed_size,
+        metadata.format().unwrap_or("unknown").to_string(),
+        &output,
+        settings.quality,
         processing_time,
         pixel_count,
-        &settings,
     );
 
-    let (keep_original, summary) = resolve_final_summary(&output, file_path);
-    if keep_original {
-        // Best-effort cleanup of the non-improving output; log rather than
-        // discard the error, mirroring the stat-save best-effort in the command.
-        if let Err(e) = std::fs::remove_file(&output.output_path) {
-            log::warn!("Failed to remove non-improving compressed file: {e}");
+    Ok((output_path, output, stat))
+}
+
+/// An output path is derived here and written through std::fs, so it never
+/// passes through get_file_info: validate it before writing
+fn validated_output_path(path: PathBuf) -> Result<PathBuf, CompressionError> {
+    validate_safe_path(&path)
+        .map(|()| path)
+        .map_err(|e| CompressionError::ProcessingError(format!("Invalid output path: {e}")))
+}
+
+fn read_input(file_path: &Path) -> Result<Vec<u8>, CompressionError> {
+    std::fs::read(file_path)
+        .map_err(|e| CompressionError::IoError(format!("Failed to read input file: {e}")))
+}
+
+fn write_output(output_path: &Path, data: &[u8]) -> Result<(), CompressionError> {
+    std::fs::write(output_path, data)
+        .map_err(|e| CompressionError::IoError(format!("Failed to write output file: {e}")))
+}
+
+/// The output format a request resolves to
+#[derive(Debug, PartialEq)]
+enum FormatChoice {
+    Fixed(OutputFormat),
+    /// Every candidate is encoded and only the smallest output is kept
+    Smallest {
+        candidates: Vec<OutputFormat>,
+        keep_transparency: bool,
+    },
+}
+
+impl FormatChoice {
+    /// The format the settings start from; each candidate replaces it
+    fn initial(&self) -> OutputFormat {
+        match self {
+            FormatChoice::Fixed(format) => *format,
+            FormatChoice::Smallest { candidates, .. } => {
+                candidates.first().copied().unwrap_or(OutputFormat::WebP)
+            }
         }
     }
-
-    Ok(CompressionOutcome { summary, stat })
 }
 
-fn resolve_output_format(requested_format: Option<&str>, metadata: &FileMetadata) -> OutputFormat {
-    match requested_format {
+fn resolve_output_format(
+    requested_format: Option<&str>,
+    metadata: &FileMetadata,
+    auto_best: &AutoBestOptions,
+) -> Result<FormatChoice, CompressionError> {
+    let format = match requested_format {
         Some("webp") => OutputFormat::WebP,
         Some("png") => OutputFormat::Png,
         Some("jpg" | "jpeg") => OutputFormat::Jpeg,
+        Some("avif") => OutputFormat::Avif,
+        Some("jxl") => OutputFormat::Jxl,
         Some("auto") => {
-            let ext = metadata
-                .extension
-                .clone()
-                .unwrap_or_else(|| "webp".to_string());
-            match ext.as_str() {
+            let ext = metadata.format().unwrap_or("webp");
+            match ext {
                 "heic" | "heif" => OutputFormat::Jpeg,
-                _ => CompressionSettings::preserve_input_format(&ext),
+                _ => CompressionSettings::preserve_input_format(ext),
+            }
+        }
+        Some("auto-best") => {
+            let mut candidates = parse_formats(&auto_best.formats)?;
+            if candidates.is_empty() {
+                candidates = OutputFormat::ALL.to_vec();
             }
+            return Ok(FormatChoice::Smallest {
+                candidates,
+                keep_transparency: auto_best.keep_transparency,
+            });
         }
         _ => {
-            let ext = metadata
-                .extension
-                .clone()
-                .unwrap_or_else(|| "webp".to_string());
-            CompressionSettings::optimal_format_for_input(&ext)
+            let ext = metadata.format().unwrap_or("webp");
+            CompressionSettings::optimal_format_for_input(ext)
+        }
+    };
+    Ok(FormatChoice::Fixed(format))
+}
+
+/// Parse output formats named by extension, in order and without repeats
+fn parse_formats(names: &[String]) -> Result<Vec<OutputFormat>, CompressionError> {
+    let mut formats = Vec::with_capacity(names.len());
+    for name in names {
+        let format = OutputFormat::from_string(name).ok_or_else(|| {
+            CompressionError::InvalidSettings(format!("Unknown output format: {name}"))
+        })?;
+        if !formats.contains(&format) {
+            formats.push(format);
         }
     }
+    Ok(formats)
 }
 
 /// When compression did not shrink the file, keep the original and report zero
@@ -119,6 +509,7 @@ fn resolve_output_format(requested_format: Option<&str>, metadata: &FileMetadata
 fn resolve_final_summary(
     output: &CompressionOutput,
     input_path: &Path,
+    output_path: &Path,
 ) -> (bool, CompressionSummary) {
     if output.compressed_size >= output.original_size {
         (
@@ -128,6 +519,13 @@ fn resolve_final_summary(
                 compressed_size: output.original_size,
                 savings_percent: 0.0,
                 output_path: input_path.to_string_lossy().to_string(),
+                quality_search: output.quality_search,
+                // The file handed back is the source itself
+                metrics: Some(QualityMetrics::IDENTICAL),
+                metrics_unavailable: None,
+                extra_outputs: Vec::new(),
+                responsive: None,
+                excluded_formats: output.excluded_formats.clone(),
             },
         )
     } else {
@@ -137,7 +535,13 @@ fn resolve_final_summary(
                 original_size: output.original_size,
                 compressed_size: output.compressed_size,
                 savings_percent: output.savings_percent,
-                output_path: output.output_path.to_string_lossy().to_string(),
+                output_path: output_path.to_string_lossy().to_string(),
+                quality_search: output.quality_search,
+                metrics: output.metrics,
+                metrics_unavailable: output.unmeasured,
+                extra_outputs: Vec::new(),
+                responsive: None,
+                excluded_formats: output.excluded_formats.clone(),
             },
         )
     }
@@ -151,20 +555,31 @@ mod tests {
     use super::*;
     use std::path::PathBuf;
 
+    const OUTPUT: &str = "/tmp/photo_balanced.webp";
+
     fn output(original: u64, compressed: u64, savings: f64) -> CompressionOutput {
         CompressionOutput {
-            output_path: PathBuf::from("/tmp/photo_balanced.webp"),
+            output_path: Some(PathBuf::from(OUTPUT)),
             original_size: original,
             compressed_size: compressed,
             format: OutputFormat::WebP,
             savings_percent: savings,
+            dimensions: None,
+            quality_search: None,
+            metrics: None,
+            unmeasured: None,
+            lossy: true,
+            excluded_formats: Vec::new(),
         }
     }
 
     #[test]
     fn keeps_the_original_when_compression_does_not_shrink() {
-        let (keep, summary) =
-            resolve_final_summary(&output(1000, 1200, -20.0), Path::new("/tmp/photo.png"));
+        let (keep, summary) = resolve_final_summary(
+            &output(1000, 1200, -20.0),
+            Path::new("/tmp/photo.png"),
+            Path::new(OUTPUT),
+        );
 
         assert!(keep);
         assert_eq!(summary.compressed_size, 1000);
@@ -174,12 +589,157 @@ mod tests {
 
     #[test]
     fn keeps_the_compressed_file_when_it_shrinks() {
-        let (keep, summary) =
-            resolve_final_summary(&output(1000, 400, 60.0), Path::new("/tmp/photo.png"));
+        let (keep, summary) = resolve_final_summary(
+            &output(1000, 400, 60.0),
+            Path::new("/tmp/photo.png"),
+            Path::new(OUTPUT),
+        );
 
         assert!(!keep);
         assert_eq!(summary.compressed_size, 400);
         assert_eq!(summary.savings_percent, 60.0);
-        assert_eq!(summary.output_path, "/tmp/photo_balanced.webp");
+        assert_eq!(summary.output_path, OUTPUT);
+    }
+
+    fn auto_best(formats: &[&str]) -> AutoBestOptions {
+        AutoBestOptions {
+            formats: formats.iter().map(ToString::to_string).collect(),
+            keep_transparency: true,
+        }
+    }
+
+    fn png_metadata(path: &Path, size: u64) -> FileMetadata {
+        FileMetadata {
+            path: path.to_string_lossy().to_string(),
+            name: path.file_name().unwrap().to_string_lossy().to_string(),
+            extension: Some("png".to_string()),
+            size,
+            is_image: true,
+            detected_format: Some("png".to_string()),
+        }
+    }
+
+    #[test]
+    fn auto_best_lets_every_format_compete_unless_restricted() {
+        let metadata = png_metadata(Path::new("/tmp/logo.png"), 1000);
+
+        assert_eq!(
+            resolve_output_format(Some("auto-best"), &metadata, &auto_best(&[])).unwrap(),
+            FormatChoice::Smallest {
+                candidates: OutputFormat::ALL.to_vec(),
+                keep_transparency: true,
+            }
+        );
+        assert_eq!(
+            resolve_output_format(
+                Some("auto-best"),
+                &metadata,
+                &auto_best(&["png", "jpg", "jpeg"])
+            )
+            .unwrap(),
+            FormatChoice::Smallest {
+                candidates: vec![OutputFormat::Png, OutputFormat::Jpeg],
+                keep_transparency: true,
+            }
+        );
+        assert!(matches!(
+            resolve_output_format(Some("auto-best"), &metadata, &auto_best(&["gif"])),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+        assert!(
+            serde_json::from_str::<AutoBestOptions>("{}")
+                .unwrap()
+                .keep_transparency
+        );
+        // The whitelist only concerns auto-best
+        assert_eq!(
+            resolve_output_format(Some("auto"), &metadata, &auto_best(&["webp"])).unwrap(),
+            FormatChoice::Fixed(OutputFormat::Png)
+        );
+    }
+
+    #[test]
+    fn responsive_set_writes_every_width_and_format_without_upscaling() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let input = dir.path().join("hero.png");
+        image::RgbImage::from_fn(800, 400, |x, y| {
+            image::Rgb([(x % 256) as u8, (y % 256) as u8, 128])
+        })
+        .save(&input)
+        .unwrap();
+        let metadata = png_metadata(&input, std::fs::metadata(&input).unwrap().len());
+        // Named like the 1280 variant the source is too narrow for
+        let unrelated = dir.path().join("hero_balanced_1280w.jpg");
+        std::fs::write(&unrelated, b"not a variant").unwrap();
+        let options = CompressionOptions {
+            responsive: Some(ResponsiveOptions {
+                widths: vec![1280, 320, 640, 320],
+                formats: vec!["webp".to_string(), "jpg".to_string()],
+                ..ResponsiveOptions::default()
+            }),
+            ..CompressionOptions::default()
+        };
+
+        // The set names its own formats
+        assert!(matches!(
+            run_compression(
+                &input,
+                &metadata,
+                Some("png"),
+                Some(80),
+                CompressionLevel::Balanced,
+                &options,
+            ),
+            Err(CompressionError::InvalidSettings(_))
+        ));
+        let outcome = run_compression(
+            &input,
+            &metadata,
+            None,
+            Some(80),
+            CompressionLevel::Balanced,
+            &options,
+        )
+        .unwrap();
+
+        let manifest = outcome.summary.responsive.unwrap();
+        let names: Vec<_> = manifest
+            .variants
+            .iter()
+            .map(|variant| {
+                assert!(Path::new(&variant.output_path).exists());
+                assert_eq!(variant.height, variant.width / 2);
+                Path::new(&variant.output_path)
+                    .file_name()
+                    .unwrap()
+                    .to_owned()
+            })
+            .collect();
+        // 1280 is past the source, so its variant is the 800-pixel one
+        assert_eq!(
+            names,
+            [
+                "hero_balanced_320w.webp",
+                "hero_balanced_640w.webp",
+                "hero_balanced_800w.webp",
+                "hero_balanced_320w.jpg",
+                "hero_balanced_640w.jpg",
+                "hero_balanced_800w.jpg",
+            ]
+        );
+        assert!(!dir.path().join("hero_balanced_1280w.webp").exists());
+        assert_eq!(std::fs::read(&unrelated).unwrap(), b"not a variant");
+        assert_eq!(outcome.stats.len(), 6);
+        assert_eq!(
+            outcome.summary.output_path,
+            manifest.variants[5].output_path
+        );
+        assert_eq!(outcome.summary.extra_outputs.len(), 5);
+        assert!(manifest.picture.contains("<source type=\"image/webp\""));
+        assert!(
+            manifest
+                .picture
+                .contains("<img src=\"hero_balanced_800w.jpg\"")
+        );
     }
 }
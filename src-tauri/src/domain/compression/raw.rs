@@ -0,0 +1,306 @@
+//! Camera RAW and DNG inputs, decoded by rawler.
+//!
+//! A RAW holds the sensor's mosaic of single-colour samples, and most also
+//! embed JPEG renderings of it made by the camera. `RawDecodeMode::Fast` takes
+//! the largest of those; `RawDecodeMode::Full` demosaics the mosaic, balances
+//! it with the white recorded at capture and develops it to sRGB through the
+//! camera's colour matrix. Either way the pixels come back upright: the
+//! orientation lives in the RAW's own TIFF tags, not in an EXIF block the
+//! engine could read.
+
+use std::panic::{AssertUnwindSafe, catch_unwind};
+
+use image::DynamicImage;
+use image::metadata::Orientation;
+use rawler::decoders::{Decoder, RawDecodeParams, WellKnownIFD};
+use rawler::formats::tiff::IFD;
+use rawler::imgop::develop::RawDevelop;
+use rawler::rawsource::RawSource;
+use rawler::tags::TiffCommonTag;
+
+use crate::domain::compression::{
+    error::{CompressionError, CompressionResult},
+    settings::{DecodeLimits, RawDecodeMode},
+};
+
+/// Decoded bytes per pixel of an embedded preview, 8-bit RGB
+const PREVIEW_BYTES_PER_PIXEL: u64 = 3;
+
+/// Bytes per pixel of the 32-bit float RGB image demosaicing works in
+const DEVELOP_BYTES_PER_PIXEL: u64 = 12;
+
+/// Decode a camera RAW or DNG into upright pixels. The sensor dimensions are
+/// checked against `limits` before any pixel is decoded; an embedded preview
+/// is never larger than the sensor.
+pub fn decode_raw(
+    input_data: &[u8],
+    mode: RawDecodeMode,
+    limits: &DecodeLimits,
+) -> CompressionResult<DynamicImage> {
+    // rawler still panics on some layouts it does not handle; a bad file must
+    // fail its own compression, not the worker running the batch
+    catch_unwind(AssertUnwindSafe(|| decode(input_data, mode, limits))).unwrap_or_else(|_| {
+        Err(CompressionError::ProcessingError(
+            "The camera RAW decoder failed on this file".to_string(),
+        ))
+    })
+}
+
+fn decode(
+    input_data: &[u8],
+    mode: RawDecodeMode,
+    limits: &DecodeLimits,
+) -> CompressionResult<DynamicImage> {
+    let source = RawSource::new_from_slice(input_data);
+    let decoder = rawler::get_decoder(&source).map_err(|e| raw_error(&e))?;
+    let params = RawDecodeParams::default();
+    let (width, height, orientation) = probe(decoder.as_ref(), &source, &params)?;
+    let pixels = u64::from(width) * u64::from(height);
+
+    let preview = match mode {
+        RawDecodeMode::Fast => {
+            limits.check(width, height, pixels * PREVIEW_BYTES_PER_PIXEL)?;
+            largest_preview(decoder.as_ref(), &source, &params)
+        }
+        RawDecodeMode::Full => None,
+    };
+    let mut img = if let Some(img) = preview {
+        img
+    } else {
+        limits.check(width, height, pixels * DEVELOP_BYTES_PER_PIXEL)?;
+        develop(decoder.as_ref(), &source, &params)?
+    };
+    if let Some(orientation) = orientation {
+        img.apply_orientation(orientation);
+    }
+    Ok(img)
+}
+
+/// Sensor width, height and orientation, read without decoding the sensor
+/// data. The DNG decoder reads that data even for a dummy decode, so its raw
+/// IFD is read directly; the other decoders skip the pixels when asked to.
+fn probe(
+    decoder: &dyn Decoder,
+    source: &RawSource,
+    params: &RawDecodeParams,
+) -> CompressionResult<(u32, u32, Option<Orientation>)> {
+    let read = |ifd: &IFD, tag| ifd.get_entry(tag).map(|entry| entry.force_u32(0));
+    if let Ok(Some(raw)) = decoder.ifd(WellKnownIFD::Raw)
+        && let Some(width) = read(&raw, TiffCommonTag::ImageWidth)
+        && let Some(height) = read(&raw, TiffCommonTag::ImageLength)
+    {
+        let orientation = decoder
+            .ifd(WellKnownIFD::Root)
+            .ok()
+            .flatten()
+            .and_then(|root| read(&root, TiffCommonTag::Orientation));
+        return Ok((width, height, orientation.and_then(exif_orientation)));
+    }
+
+    let sensor = decoder
+        .raw_image(source, params, true)
+        .map_err(|e| raw_error(&e))?;
+    let side = |side: usize| {
+        u32::try_from(side).map_err(|_| {
+            CompressionError::DecodeLimitExceeded(format!("A {side}-pixel side is out of range"))
+        })
+    };
+    Ok((
+        side(sensor.width)?,
+        side(sensor.height)?,
+        exif_orientation(u32::from(sensor.orientation.to_u16())),
+    ))
+}
+
+/// The orientation an EXIF/TIFF tag value names; `None` for 0 (unknown) and
+/// out-of-range values
+fn exif_orientation(value: u32) -> Option<Orientation> {
+    Orientation::from_exif(u8::try_from(value).ok()?)
+}
+
+/// The largest JPEG rendering the camera embedded, among those that decode
+fn largest_preview(
+    decoder: &dyn Decoder,
+    source: &RawSource,
+    params: &RawDecodeParams,
+) -> Option<DynamicImage> {
+    [
+        decoder.full_image(source, params),
+        decoder.preview_image(source, params),
+        decoder.thumbnail_image(source, params),
+    ]
+    .into_iter()
+    .filter_map(|decoded| {
+        decoded
+            .inspect_err(|e| log::warn!("Skipping an embedded RAW preview: {e}"))
+            .ok()
+            .flatten()
+    })
+    .max_by_key(|img| u64::from(img.width()) * u64::from(img.height()))
+}
+
+/// Demosaic the sensor data and develop it to 16-bit sRGB, white-balanced as
+/// shot
+fn develop(
+    decoder: &dyn Decoder,
+    source: &RawSource,
+    params: &RawDecodeParams,
+) -> CompressionResult<DynamicImage> {
+    let sensor = decoder
+        .raw_image(source, params, false)
+        .map_err(|e| raw_error(&e))?;
+    RawDevelop::default()
+        .develop_intermediate(&sensor)
+        .map_err(|e| raw_error(&e))?
+        .to_dynamic_image()
+        .ok_or_else(|| {
+            CompressionError::ProcessingError("The camera RAW developed to no image".to_string())
+        })
+}
+
+fn raw_error(e: &rawler::RawlerError) -> CompressionError {
+    CompressionError::ProcessingError(format!("Camera RAW decoding failed: {e}"))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rawler::dng::{DNG_VERSION_V1_4, writer::DngWriter};
+    use rawler::formats::tiff::{Rational, SRational};
+    use rawler::imgop::xyz::Illuminant;
+
+    /// XYZ (D65) to linear sRGB, scaled by 10 000: a sensor that sees sRGB
+    const SRGB_COLOR_MATRIX: [i32; 9] =
+        [32406, -15372, -4986, -9689, 18758, 415, 557, -2040, 10570];
+
+    /// A 16×12 DNG of an even grey, shot under a light that halves what the
+    /// red photosites record (`AsShotNeutral` 0.5, 1, 1), with an optional
+    /// 40×30 pure red JPEG preview
+    pub(crate) fn sample_dng(preview: bool, orientation: u16) -> Vec<u8> {
+        let (width, height) = (16_u32, 12_u32);
+        let mosaic: Vec<u16> = (0..height)
+            .flat_map(|y| (0..width).map(move |x| (x, y)))
+            .map(|(x, y)| if x % 2 == 0 && y % 2 == 0 { 1000 } else { 2000 })
+            .collect();
+
+        let mut buffer = std::io::Cursor::new(Vec::new());
+        let mut dng = DngWriter::new(&mut buffer, DNG_VERSION_V1_4).unwrap();
+        let offset = dng.dng.write_data_u16_le(&mosaic).unwrap();
+        let root = dng.root_ifd_mut();
+        root.add_tag(TiffCommonTag::NewSubFileType, 0_u32);
+        root.add_tag(TiffCommonTag::ImageWidth, width);
+        root.add_tag(TiffCommonTag::ImageLength, height);
+        root.add_tag(TiffCommonTag::BitsPerSample, 16_u16);
+        root.add_tag(TiffCommonTag::Compression, 1_u16);
+        root.add_tag(TiffCommonTag::PhotometricInt, 32803_u16); // CFA
+        root.add_tag(TiffCommonTag::StripOffsets, offset);
+        root.add_tag(TiffCommonTag::SamplesPerPixel, 1_u16);
+        root.add_tag(TiffCommonTag::RowsPerStrip, height);
+        root.add_tag(TiffCommonTag::StripByteCounts, width * height * 2);
+        root.add_tag(TiffCommonTag::CFARepeatPatternDim, [2_u16, 2]);
+        root.add_tag(TiffCommonTag::CFAPattern, [0_u8, 1, 1, 2]); // RGGB
+        root.add_tag(TiffCommonTag::Orientation, orientation);
+        dng.color_matrix(
+            1,
+            Illuminant::D65,
+            SRGB_COLOR_MATRIX.map(|value| SRational::new(value, 10_000)),
+        );
+        dng.as_shot_neutral([
+            Rational::new(1, 2),
+            Rational::new(1, 1),
+            Rational::new(1, 1),
+        ]);
+        if preview {
+            let red = image::RgbImage::from_pixel(40, 30, image::Rgb([255, 0, 0]));
+            let mut subframe = dng.subframe(1);
+            subframe
+                .preview(&DynamicImage::ImageRgb8(red), 0.9)
+                .unwrap();
+            subframe.finalize().unwrap();
+        }
+        dng.close().unwrap();
+        buffer.into_inner()
+    }
+
+    #[test]
+    fn test_fast_mode_takes_the_embedded_preview_upright() {
+        let img = decode_raw(
+            &sample_dng(true, 6),
+            RawDecodeMode::Fast,
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!((img.width(), img.height()), (30, 40)); // 90° clockwise
+        let [r, g, b] = img.to_rgb8().get_pixel(15, 20).0;
+        assert!(r > 240 && g < 16 && b < 16, "{r} {g} {b}");
+    }
+
+    #[test]
+    fn test_full_mode_demosaics_and_balances_the_sensor_data() {
+        let img = decode_raw(
+            &sample_dng(true, 1),
+            RawDecodeMode::Full,
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!((img.width(), img.height()), (16, 12));
+        assert!(matches!(img, DynamicImage::ImageRgb16(_)));
+        // The grey comes out grey once the weak red channel is balanced
+        let [r, g, b] = img.to_rgb16().get_pixel(8, 6).0;
+        assert!(r.abs_diff(g) < 256 && g.abs_diff(b) < 256, "{r} {g} {b}");
+        assert!(g > 4096, "{g}");
+    }
+
+    #[test]
+    fn test_fast_mode_demosaics_a_raw_without_preview() {
+        let img = decode_raw(
+            &sample_dng(false, 8),
+            RawDecodeMode::Fast,
+            &DecodeLimits::default(),
+        )
+        .unwrap();
+
+        assert_eq!((img.width(), img.height()), (12, 16)); // 90° counter-clockwise
+        assert!(matches!(img, DynamicImage::ImageRgb16(_)));
+    }
+
+    #[test]
+    fn test_sensor_dimensions_are_checked_before_decoding() {
+        let dng = sample_dng(true, 1);
+        let tight = DecodeLimits {
+            max_pixels: 100,
+            ..DecodeLimits::default()
+        };
+        for mode in [RawDecodeMode::Fast, RawDecodeMode::Full] {
+            assert!(matches!(
+                decode_raw(&dng, mode, &tight),
+                Err(CompressionError::DecodeLimitExceeded(_))
+            ));
+        }
+
+        // Enough for the preview, not for the float buffer demosaicing needs
+        let preview_only = DecodeLimits {
+            max_alloc_bytes: 16 * 12 * PREVIEW_BYTES_PER_PIXEL,
+            ..DecodeLimits::default()
+        };
+        assert!(decode_raw(&dng, RawDecodeMode::Fast, &preview_only).is_ok());
+        assert!(matches!(
+            decode_raw(&dng, RawDecodeMode::Full, &preview_only),
+            Err(CompressionError::DecodeLimitExceeded(_))
+        ));
+    }
+
+    #[test]
+    fn test_unreadable_raws_fail_with_an_error() {
+        let mut truncated = sample_dng(false, 1);
+        truncated.truncate(64);
+        for data in [&b"II*\x00\x08\x00\x00\x00"[..], &truncated] {
+            assert!(matches!(
+                decode_raw(data, RawDecodeMode::Full, &DecodeLimits::default()),
+                Err(CompressionError::ProcessingError(_))
+            ));
+        }
+    }
+}
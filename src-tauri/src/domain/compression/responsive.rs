@@ -0,0 +1,207 @@
+use std::fmt::Write as _;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::domain::compression::formats::OutputFormat;
+
+/// Widths a responsive set is generated at when the request names none
+pub const DEFAULT_WIDTHS: [u32; 4] = [320, 640, 1280, 1920];
+
+/// A responsive image set: the same input encoded at several widths in several
+/// formats, the last format being the `<img>` fallback
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResponsiveOptions {
+    /// Maximum widths of the variants, in pixels; the source is never upscaled
+    pub widths: Vec<u32>,
+    /// Output formats by extension, most preferred first
+    pub formats: Vec<String>,
+    /// The `sizes` attribute of the `<picture>` snippet
+    pub sizes: String,
+}
+
+impl Default for ResponsiveOptions {
+    fn default() -> Self {
+        Self {
+            widths: DEFAULT_WIDTHS.to_vec(),
+            formats: vec!["avif".to_string(), "webp".to_string(), "jpg".to_string()],
+            sizes: "100vw".to_string(),
+        }
+    }
+}
+
+/// One file of a responsive set
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponsiveVariant {
+    pub output_path: String,
+    pub format: OutputFormat,
+    pub width: u32,
+    pub height: u32,
+    pub compressed_size: u64,
+}
+
+/// Every file of a responsive set and the `<picture>` element that serves them
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResponsiveManifest {
+    /// Grouped by format in the requested order, each group by ascending width
+    pub variants: Vec<ResponsiveVariant>,
+    /// Ready-to-paste HTML; the files are referenced by name, relative to the page
+    pub picture: String,
+}
+
+impl ResponsiveManifest {
+    pub fn new(variants: Vec<ResponsiveVariant>, sizes: &str) -> Self {
+        let picture = picture_snippet(&variants, sizes);
+        Self { variants, picture }
+    }
+}
+
+/// A `<source>` per format and an `<img>` for the last one, each listing its
+/// variants with their width descriptors
+fn picture_snippet(variants: &[ResponsiveVariant], sizes: &str) -> String {
+    let mut formats: Vec<OutputFormat> = Vec::new();
+    for variant in variants {
+        if !formats.contains(&variant.format) {
+            formats.push(variant.format);
+        }
+    }
+    let Some((&fallback_format, source_formats)) = formats.split_last() else {
+        return String::new();
+    };
+
+    let sizes = escape_attribute(sizes);
+    let srcset = |format: OutputFormat| {
+        variants
+            .iter()
+            .filter(|variant| variant.format == format)
+            .map(|variant| {
+                format!(
+                    "{} {}w",
+                    file_reference(&variant.output_path),
+                    variant.width
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut html = String::from("<picture>\n");
+    for &format in source_formats {
+        let _ = writeln!(
+            html,
+            "  <source type=\"{}\" srcset=\"{}\" sizes=\"{sizes}\">",
+            format.mime_type(),
+            srcset(format)
+        );
+    }
+    if let Some(img) = variants
+        .iter()
+        .filter(|variant| variant.format == fallback_format)
+        .max_by_key(|variant| variant.width)
+    {
+        let _ = writeln!(
+            html,
+            "  <img src=\"{}\" srcset=\"{}\" sizes=\"{sizes}\" width=\"{}\" height=\"{}\" alt=\"\">",
+            file_reference(&img.output_path),
+            srcset(fallback_format),
+            img.width,
+            img.height
+        );
+    }
+    html.push_str("</picture>");
+    html
+}
+
+/// The file name of `path`, percent-encoded so it is a valid URL even with
+/// spaces, commas (the srcset separator) or non-ASCII characters
+fn file_reference(path: &str) -> String {
+    let name = Path::new(path)
+        .file_name()
+        .map_or_else(|| path.into(), |name| name.to_string_lossy());
+    let mut encoded = String::with_capacity(name.len());
+    for byte in name.bytes() {
+        if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'.' | b'_' | b'~') {
+            encoded.push(char::from(byte));
+        } else {
+            let _ = write!(encoded, "%{byte:02X}");
+        }
+    }
+    encoded
+}
+
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(name: &str, format: OutputFormat, width: u32) -> ResponsiveVariant {
+        ResponsiveVariant {
+            output_path: format!("/home/user/Pictures/{name}"),
+            format,
+            width,
+            height: width / 2,
+            compressed_size: u64::from(width) * 10,
+        }
+    }
+
+    #[test]
+    fn test_picture_has_a_source_per_format_and_the_last_as_img() {
+        let manifest = ResponsiveManifest::new(
+            vec![
+                variant("hero_balanced_320w.webp", OutputFormat::WebP, 320),
+                variant("hero_balanced_640w.webp", OutputFormat::WebP, 640),
+                variant("hero_balanced_320w.jpg", OutputFormat::Jpeg, 320),
+                variant("hero_balanced_640w.jpg", OutputFormat::Jpeg, 640),
+            ],
+            "(max-width: 640px) 100vw, 640px",
+        );
+
+        assert_eq!(
+            manifest.picture,
+            "<picture>\n  \
+             <source type=\"image/webp\" srcset=\"hero_balanced_320w.webp 320w, \
+             hero_balanced_640w.webp 640w\" sizes=\"(max-width: 640px) 100vw, 640px\">\n  \
+             <img src=\"hero_balanced_640w.jpg\" srcset=\"hero_balanced_320w.jpg 320w, \
+             hero_balanced_640w.jpg 640w\" sizes=\"(max-width: 640px) 100vw, 640px\" \
+             width=\"640\" height=\"320\" alt=\"\">\n\
+             </picture>"
+        );
+    }
+
+    #[test]
+    fn test_file_names_and_sizes_are_escaped_for_html() {
+        let manifest = ResponsiveManifest::new(
+            vec![variant("été, 2024_light_320w.jpg", OutputFormat::Jpeg, 320)],
+            "\"><script>",
+        );
+
+        assert!(
+            manifest
+                .picture
+                .contains("src=\"%C3%A9t%C3%A9%2C%202024_light_320w.jpg\"")
+        );
+        assert!(
+            manifest
+                .picture
+                .contains("sizes=\"&quot;&gt;&lt;script&gt;\"")
+        );
+        assert!(!manifest.picture.contains("<script>"));
+    }
+
+    #[test]
+    fn test_options_default_to_avif_webp_and_a_jpeg_fallback() {
+        let options: ResponsiveOptions = serde_json::from_str("{\"widths\":[480]}").unwrap();
+
+        assert_eq!(options.widths, vec![480]);
+        assert_eq!(options.formats, ["avif", "webp", "jpg"]);
+        assert_eq!(options.sizes, "100vw");
+    }
+}
@@ -1,14 +1,378 @@
+use crate::domain::compression::error::{CompressionError, CompressionResult};
 use crate::domain::compression::formats::OutputFormat;
 use serde::{Deserialize, Serialize};
 
 /// Quality applied when the frontend does not specify one.
 pub const DEFAULT_QUALITY: u8 = 80;
 
+/// Full Floyd-Steinberg dithering, pngquant's default: smooth gradients survive
+/// the palette reduction at the cost of a little noise in flat areas.
+pub const DEFAULT_DITHERING: u8 = 100;
+
+/// Which metadata survives compression. Whatever the policy, only the ICC
+/// profile, EXIF and XMP can reach the output; comments, IPTC and vendor blocks
+/// are always dropped by a re-encode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetadataPolicy {
+    /// ICC profile, EXIF and XMP are carried over untouched
+    #[default]
+    KeepAll,
+    /// As `KeepAll`, minus the GPS IFD and any XMP packet naming a location
+    KeepAllExceptLocation,
+    /// Only the ICC profile, which is needed to render colours correctly
+    IccOnly,
+    /// Nothing, not even the ICC profile
+    StripAll,
+}
+
+impl MetadataPolicy {
+    pub fn keeps_icc(self) -> bool {
+        self != MetadataPolicy::StripAll
+    }
+}
+
+/// Luma SSIM at which a compressed image is taken as visually lossless: above
+/// it, differences are hard to spot even side by side with the source.
+pub const VISUALLY_LOSSLESS_SSIM: f64 = 0.99;
+
+/// A perceptual quality the output must reach, in place of a fixed quality
+/// number: the smallest output that meets it is kept.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PerceptualTarget {
+    /// Indistinguishable from the source at a normal viewing distance
+    VisuallyLossless,
+    /// Minimum SSIM against the source, in (0, 1]
+    MinSsim(f64),
+}
+
+impl PerceptualTarget {
+    pub fn min_ssim(self) -> f64 {
+        match self {
+            PerceptualTarget::VisuallyLossless => VISUALLY_LOSSLESS_SSIM,
+            PerceptualTarget::MinSsim(ssim) => ssim,
+        }
+    }
+}
+
+/// Downscaling applied between decode and encode. Every bound that is set must
+/// hold, so the most restrictive one wins; the aspect ratio is kept and the image
+/// is never enlarged. The default changes nothing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ResizeOptions {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Bound on the longer side, whichever way the image is oriented
+    pub longest_edge: Option<u32>,
+    /// Scale relative to the source, in (0, 100]
+    pub scale_percent: Option<f32>,
+}
+
+impl ResizeOptions {
+    pub fn is_valid(&self) -> bool {
+        [self.max_width, self.max_height, self.longest_edge]
+            .into_iter()
+            .flatten()
+            .all(|bound| bound > 0)
+            && self
+                .scale_percent
+                .is_none_or(|percent| percent > 0.0 && percent <= 100.0)
+    }
+
+    /// Output dimensions for an upright `width` × `height` image
+    // The factor is at most 1, so the scaled sides fit back in a u32.
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    pub fn target_dimensions(&self, width: u32, height: u32) -> (u32, u32) {
+        let factor = [
+            self.scale_percent.map(|percent| f64::from(percent) / 100.0),
+            self.max_width.map(|max| f64::from(max) / f64::from(width)),
+            self.max_height
+                .map(|max| f64::from(max) / f64::from(height)),
+            self.longest_edge
+                .map(|max| f64::from(max) / f64::from(width.max(height))),
+        ]
+        .into_iter()
+        .flatten()
+        .fold(1.0, f64::min);
+
+        if factor >= 1.0 {
+            return (width, height);
+        }
+        let scale = |side: u32| ((f64::from(side) * factor).round() as u32).max(1);
+        (scale(width), scale(height))
+    }
+}
+
+/// Pixel count accepted by default: a 100 MP camera, or a 12000×8000 scan
+pub const DEFAULT_MAX_PIXELS: u64 = 100_000_000;
+
+/// Decoded buffer accepted by default, enough for 100 MP of 16-bit RGB
+pub const DEFAULT_MAX_ALLOC_BYTES: u64 = 1 << 30;
+
+/// Ceilings on what decoding an input may allocate. A few megabytes of PNG or
+/// HEIC can declare a 60000×60000 image, so the dimensions in the headers are
+/// checked against these before any pixel is decoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecodeLimits {
+    /// Largest width × height accepted
+    pub max_pixels: u64,
+    /// Largest decoded pixel buffer accepted, in bytes
+    pub max_alloc_bytes: u64,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        Self {
+            max_pixels: DEFAULT_MAX_PIXELS,
+            max_alloc_bytes: DEFAULT_MAX_ALLOC_BYTES,
+        }
+    }
+}
+
+impl DecodeLimits {
+    pub fn is_valid(&self) -> bool {
+        self.max_pixels > 0 && self.max_alloc_bytes > 0
+    }
+
+    /// Refuse a `width` × `height` image whose decoded pixels take
+    /// `decoded_bytes`, when either exceeds a limit
+    pub fn check(&self, width: u32, height: u32, decoded_bytes: u64) -> CompressionResult<()> {
+        let pixels = u64::from(width) * u64::from(height);
+        if pixels > self.max_pixels {
+            return Err(CompressionError::DecodeLimitExceeded(format!(
+                "{width}x{height} is {pixels} pixels, the limit is {}",
+                self.max_pixels
+            )));
+        }
+        if decoded_bytes > self.max_alloc_bytes {
+            return Err(CompressionError::DecodeLimitExceeded(format!(
+                "{width}x{height} decodes to {decoded_bytes} bytes, the limit is {}",
+                self.max_alloc_bytes
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// How a camera RAW becomes pixels
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RawDecodeMode {
+    /// The largest JPEG preview embedded by the camera, rendered as it showed
+    /// it; falls back to `Full` when the file has none
+    #[default]
+    Fast,
+    /// Demosaic the sensor data, balanced with the white the camera recorded
+    Full,
+}
+
+/// Which images of a HEIF container become outputs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum HeifImages {
+    /// The primary image alone, as a viewer shows the file
+    #[default]
+    Primary,
+    /// Every top-level image (burst shots, the frames of a live photo), each
+    /// to its own indexed output
+    All,
+}
+
+/// libwebp's tuning presets, which set the filtering and spatial noise shaping
+/// for a kind of content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebpPreset {
+    Photo,
+    Drawing,
+    Icon,
+}
+
+/// How WebP output is coded. Only `Lossy` reads the quality.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WebpMode {
+    /// VP8 at `CompressionSettings::quality`
+    #[default]
+    Lossy,
+    /// Lossless coding of pixels that libwebp first nudges where that saves
+    /// the most, as far as `WebpOptions::near_lossless` allows
+    NearLossless,
+    /// Every pixel kept exactly
+    Lossless,
+}
+
+/// WebP encoder options. The default is what Plume always used: method 4 and
+/// an alpha plane kept at full quality.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebpOptions {
+    pub mode: WebpMode,
+    /// Effort, from 0 (fastest) to 6 (smallest output)
+    pub method: u8,
+    /// `None` keeps libwebp's default tuning
+    pub preset: Option<WebpPreset>,
+    /// Near-lossless preprocessing, from 0 (strongest) to 100 (none)
+    pub near_lossless: u8,
+    /// Lossless compression effort, from 0 (fastest) to 100 (smallest output)
+    pub lossless_effort: u8,
+    /// Quality of the alpha plane in lossy output, from 0 to 100
+    pub alpha_quality: u8,
+}
+
+impl Default for WebpOptions {
+    fn default() -> Self {
+        Self {
+            mode: WebpMode::default(),
+            method: 4,
+            preset: None,
+            near_lossless: 60,
+            lossless_effort: 75,
+            alpha_quality: 100,
+        }
+    }
+}
+
+/// How JPEG chroma is sampled against luma
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ChromaSubsampling {
+    /// Chroma at half the resolution both ways, the smallest output
+    #[default]
+    #[serde(rename = "4:2:0")]
+    Yuv420,
+    /// Chroma at half the horizontal resolution
+    #[serde(rename = "4:2:2")]
+    Yuv422,
+    /// Full-resolution chroma, for text and sharp coloured edges
+    #[serde(rename = "4:4:4")]
+    Yuv444,
+}
+
+impl ChromaSubsampling {
+    /// Horizontal and vertical sampling factors of the luma component
+    pub fn luma_factors(self) -> (i32, i32) {
+        match self {
+            ChromaSubsampling::Yuv420 => (2, 2),
+            ChromaSubsampling::Yuv422 => (2, 1),
+            ChromaSubsampling::Yuv444 => (1, 1),
+        }
+    }
+}
+
+/// Highest mozjpeg quantization table preset
+pub const MAX_JPEG_QUANT_TABLE: u8 = 8;
+
+/// mozjpeg encoder options. The default is mozjpeg's own: progressive, 4:2:0,
+/// trellis quantization and the table tuned for MS-SSIM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct JpegOptions {
+    /// Progressive scans; off writes a baseline JPEG
+    pub progressive: bool,
+    pub chroma_subsampling: ChromaSubsampling,
+    /// Trellis quantization of the AC and DC coefficients
+    pub trellis: bool,
+    /// mozjpeg quantization table preset, from 0 (the JPEG standard's) to 8
+    pub quant_table: u8,
+    /// Rewrite a JPEG source at the coefficient level, as quality 100 does;
+    /// refused for any other source, and when the pixels must change
+    pub lossless: bool,
+}
+
+impl Default for JpegOptions {
+    fn default() -> Self {
+        Self {
+            progressive: true,
+            chroma_subsampling: ChromaSubsampling::default(),
+            trellis: true,
+            quant_table: 3,
+            lossless: false,
+        }
+    }
+}
+
+/// Highest oxipng optimization level
+pub const MAX_PNG_LEVEL: u8 = 6;
+
+/// oxipng options for lossless and palette PNG output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PngOptions {
+    /// oxipng optimization level, from 0 (fastest) to 6
+    pub level: u8,
+    /// Deflate with Zopfli: a few percent smaller, many times slower
+    pub zopfli: bool,
+    /// Adam7 interlacing, which renders progressively but compresses worse
+    pub interlace: bool,
+    /// Reduce a quality below 100 to a ≤256-colour palette, the quality being
+    /// the floor it must reach; off keeps every PNG lossless
+    pub quantize: bool,
+    /// Floyd-Steinberg dithering of the palette, from 0 (none) to 100
+    pub dithering: u8,
+}
+
+impl Default for PngOptions {
+    fn default() -> Self {
+        Self {
+            level: 3,
+            zopfli: false,
+            interlace: false,
+            quantize: false,
+            dithering: DEFAULT_DITHERING,
+        }
+    }
+}
+
+/// Encoder options per output format; only those of the output format apply
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncoderOptions {
+    pub webp: WebpOptions,
+    pub jpeg: JpegOptions,
+    pub png: PngOptions,
+}
+
+impl EncoderOptions {
+    pub fn is_valid(&self) -> bool {
+        self.webp.method <= 6
+            && self.webp.near_lossless <= 100
+            && self.webp.lossless_effort <= 100
+            && self.webp.alpha_quality <= 100
+            && self.jpeg.quant_table <= MAX_JPEG_QUANT_TABLE
+            && self.png.level <= MAX_PNG_LEVEL
+            && self.png.dithering <= 100
+    }
+}
+
 /// Configuration settings for image compression operations
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct CompressionSettings {
     pub quality: u8,
     pub format: OutputFormat,
+    pub metadata_policy: MetadataPolicy,
+    /// Convert pixels from their embedded ICC profile to sRGB and drop the profile
+    pub convert_to_srgb: bool,
+    pub resize: ResizeOptions,
+    /// Upper bound on the output size in bytes. `quality` becomes a ceiling: the
+    /// highest quality in `1..=quality` that fits is searched for, never one above
+    /// it, so a target the ceiling already meets is not used to raise the quality.
+    /// Set `quality` to 100 to search the whole range.
+    pub target_size: Option<u64>,
+    /// Search for the lowest quality whose output meets this target; `quality`
+    /// is then ignored
+    pub perceptual_target: Option<PerceptualTarget>,
+    pub decode_limits: DecodeLimits,
+    pub raw_mode: RawDecodeMode,
+    /// Reduce 16-bit and float sources to 8 bits per channel. Off, their depth is
+    /// kept wherever the output format can hold it (PNG)
+    pub reduce_to_8bit: bool,
+    /// Top-level image of a HEIF container to read, by position; `None` reads
+    /// the primary image
+    pub heif_image: Option<usize>,
+    pub encoder: EncoderOptions,
 }
 
 impl CompressionSettings {
@@ -17,12 +381,31 @@ impl CompressionSettings {
         Self {
             quality: quality.clamp(1, 100),
             format,
+            metadata_policy: MetadataPolicy::default(),
+            convert_to_srgb: false,
+            resize: ResizeOptions::default(),
+            target_size: None,
+            perceptual_target: None,
+            decode_limits: DecodeLimits::default(),
+            raw_mode: RawDecodeMode::default(),
+            reduce_to_8bit: false,
+            heif_image: None,
+            encoder: EncoderOptions::default(),
         }
     }
 
     /// Validates the settings
     pub fn is_valid(&self) -> bool {
         (1..=100).contains(&self.quality)
+            && self.resize.is_valid()
+            && self.decode_limits.is_valid()
+            && self.encoder.is_valid()
+            && self.target_size != Some(0)
+            && self
+                .perceptual_target
+                .is_none_or(|target| target.min_ssim() > 0.0 && target.min_ssim() <= 1.0)
+            // Each target drives the quality on its own
+            && !(self.target_size.is_some() && self.perceptual_target.is_some())
     }
 
     /// Determines the optimal output format for the given input format
@@ -40,7 +423,7 @@ impl CompressionSettings {
     }
 
     /// Returns the same format as input (for preserving original format)
-    // WebP, HEIC and the fallback all resolve to WebP; the arms stay explicit so
+    // WebP and every input-only format resolve to WebP; the arms stay explicit so
     // the preserve-vs-transcode intent per input format is readable.
     #[allow(clippy::match_same_arms)]
     pub fn preserve_input_format(input_format: &str) -> OutputFormat {
@@ -48,7 +431,11 @@ impl CompressionSettings {
             "png" => OutputFormat::Png,
             "jpg" | "jpeg" => OutputFormat::Jpeg,
             "webp" => OutputFormat::WebP,
+            "jxl" => OutputFormat::Jxl,
             "heic" | "heif" => OutputFormat::WebP, // HEIC cannot be preserved, default to WebP
+            "gif" => OutputFormat::WebP, // GIF cannot be written; animated WebP keeps the frames
+            "tiff" | "tif" | "bmp" => OutputFormat::WebP, // Input-only formats
+            "dng" | "cr2" | "cr3" | "nef" | "arw" => OutputFormat::WebP, // Camera RAW
             _ => OutputFormat::WebP,
         }
     }
@@ -66,4 +453,168 @@ mod tests {
         let settings = CompressionSettings::new(0, OutputFormat::WebP);
         assert_eq!(settings.quality, 1);
     }
+
+    #[test]
+    fn test_dithering_travels_with_the_png_options() {
+        let options: EncoderOptions =
+            serde_json::from_str(r#"{"png": {"quantize": true, "dithering": 50}}"#).unwrap();
+        assert!(options.png.quantize);
+        assert_eq!(options.png.dithering, 50);
+
+        let mut settings = CompressionSettings::new(80, OutputFormat::Png);
+        assert_eq!(settings.encoder.png.dithering, DEFAULT_DITHERING);
+        settings.encoder.png.dithering = 101;
+        assert!(!settings.is_valid());
+    }
+
+    #[test]
+    fn test_metadata_policy_serializes_as_kebab_case() {
+        assert_eq!(
+            serde_json::from_str::<MetadataPolicy>("\"keep-all-except-location\"").unwrap(),
+            MetadataPolicy::KeepAllExceptLocation
+        );
+        assert_eq!(
+            CompressionSettings::new(80, OutputFormat::WebP).metadata_policy,
+            MetadataPolicy::KeepAll
+        );
+    }
+
+    #[test]
+    fn test_encoder_options_fill_unset_fields_with_the_defaults() {
+        let options: EncoderOptions = serde_json::from_str(
+            r#"{"webp": {"preset": "drawing"}, "jpeg": {"chroma_subsampling": "4:4:4"}}"#,
+        )
+        .unwrap();
+
+        assert_eq!(options.webp.preset, Some(WebpPreset::Drawing));
+        assert_eq!(options.webp.method, WebpOptions::default().method);
+        assert_eq!(options.webp.mode, WebpMode::Lossy);
+        assert_eq!(options.jpeg.chroma_subsampling, ChromaSubsampling::Yuv444);
+        assert!(options.jpeg.progressive);
+        assert_eq!(options.png, PngOptions::default());
+    }
+
+    #[test]
+    fn test_encoder_options_out_of_range_are_invalid() {
+        let mut settings = CompressionSettings::new(80, OutputFormat::WebP);
+        settings.encoder.webp.method = 7;
+        assert!(!settings.is_valid());
+
+        let mut settings = CompressionSettings::new(80, OutputFormat::Jpeg);
+        settings.encoder.jpeg.quant_table = MAX_JPEG_QUANT_TABLE + 1;
+        assert!(!settings.is_valid());
+
+        let mut settings = CompressionSettings::new(80, OutputFormat::Png);
+        settings.encoder.png.level = MAX_PNG_LEVEL;
+        assert!(settings.is_valid());
+    }
+
+    #[test]
+    fn test_heif_images_default_to_the_primary_image() {
+        assert_eq!(
+            serde_json::from_str::<HeifImages>("\"all\"").unwrap(),
+            HeifImages::All
+        );
+        assert_eq!(HeifImages::default(), HeifImages::Primary);
+    }
+
+    #[test]
+    fn test_raw_mode_defaults_to_the_embedded_preview() {
+        assert_eq!(
+            serde_json::from_str::<RawDecodeMode>("\"full\"").unwrap(),
+            RawDecodeMode::Full
+        );
+        assert_eq!(
+            CompressionSettings::new(80, OutputFormat::Jpeg).raw_mode,
+            RawDecodeMode::Fast
+        );
+    }
+
+    #[test]
+    fn test_resize_keeps_the_aspect_ratio_under_the_tightest_bound() {
+        let resize = ResizeOptions {
+            max_width: Some(2000),
+            max_height: Some(600),
+            ..ResizeOptions::default()
+        };
+        assert_eq!(resize.target_dimensions(4000, 3000), (800, 600));
+
+        let resize = ResizeOptions {
+            longest_edge: Some(1000),
+            scale_percent: Some(50.0),
+            ..ResizeOptions::default()
+        };
+        // 50% gives 1500 on the long side, over the 1000 edge
+        assert_eq!(resize.target_dimensions(3000, 2000), (1000, 667));
+        assert_eq!(resize.target_dimensions(1200, 1600), (600, 800));
+    }
+
+    #[test]
+    fn test_resize_never_enlarges() {
+        let resize = ResizeOptions {
+            max_width: Some(4000),
+            longest_edge: Some(5000),
+            ..ResizeOptions::default()
+        };
+        assert_eq!(resize.target_dimensions(640, 480), (640, 480));
+        assert_eq!(ResizeOptions::default().target_dimensions(1, 1), (1, 1));
+    }
+
+    #[test]
+    fn test_perceptual_target_deserializes_and_excludes_a_target_size() {
+        assert_eq!(
+            serde_json::from_str::<PerceptualTarget>("\"visually-lossless\"").unwrap(),
+            PerceptualTarget::VisuallyLossless
+        );
+        let target = serde_json::from_str::<PerceptualTarget>("{\"min-ssim\":0.98}").unwrap();
+        assert_eq!(target, PerceptualTarget::MinSsim(0.98));
+
+        let mut settings = CompressionSettings {
+            perceptual_target: Some(target),
+            ..CompressionSettings::new(80, OutputFormat::WebP)
+        };
+        assert!(settings.is_valid());
+        settings.target_size = Some(200_000);
+        assert!(!settings.is_valid());
+        settings.target_size = None;
+        settings.perceptual_target = Some(PerceptualTarget::MinSsim(1.5));
+        assert!(!settings.is_valid());
+    }
+
+    #[test]
+    fn test_resize_rejects_empty_bounds() {
+        let mut settings = CompressionSettings::new(80, OutputFormat::WebP);
+        settings.resize.max_height = Some(0);
+        assert!(!settings.is_valid());
+
+        settings.resize = ResizeOptions {
+            scale_percent: Some(150.0),
+            ..ResizeOptions::default()
+        };
+        assert!(!settings.is_valid());
+    }
+
+    #[test]
+    fn test_decode_limits_bound_pixels_and_bytes() {
+        let limits = DecodeLimits {
+            max_pixels: 1_000_000,
+            max_alloc_bytes: 3_000_000,
+        };
+        assert!(limits.check(1000, 1000, 3_000_000).is_ok());
+        assert!(matches!(
+            limits.check(1001, 1000, 3_003_000),
+            Err(CompressionError::DecodeLimitExceeded(_))
+        ));
+        // Within the pixel count, but RGBA needs 4 MB
+        assert!(matches!(
+            limits.check(1000, 1000, 4_000_000),
+            Err(CompressionError::DecodeLimitExceeded(_))
+        ));
+        // No overflow on the largest dimensions a header can declare
+        assert!(limits.check(u32::MAX, u32::MAX, u64::MAX).is_err());
+
+        let mut settings = CompressionSettings::new(80, OutputFormat::WebP);
+        settings.decode_limits.max_pixels = 0;
+        assert!(!settings.is_valid());
+    }
 }
@@ -2,7 +2,7 @@
 // real file sizes. Scoped deviation — see docs/conventions.md (pedantic-cast).
 #![allow(clippy::cast_precision_loss)]
 
-use crate::domain::compression::formats::OutputFormat;
+use crate::domain::compression::engine::CompressionOutput;
 use crate::domain::compression::settings::CompressionSettings;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
@@ -21,6 +21,16 @@ pub struct CompressionStat {
     pub pixel_count: Option<u64>,
     pub compression_time_ms: Option<u64>,
     pub timestamp: String,
+    /// Dimensions of the written image; they differ from the source's when it
+    /// was resized, which makes the size reduction incomparable
+    pub output_width: Option<u32>,
+    pub output_height: Option<u32>,
+    /// Fidelity of the output to the source, when it was measured; see
+    /// `metrics::QualityMetrics` (a PSNR of `None` with a max error of 0 means
+    /// a pixel-identical output)
+    pub psnr: Option<f64>,
+    pub ssim: Option<f64>,
+    pub max_error: Option<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -41,10 +51,14 @@ pub struct EstimationResult {
 }
 
 /// Total pixels (width × height) of an image, read from its header without
-/// decoding the whole file. Returns `None` when the dimensions cannot be read,
-/// so callers fall back to size-range matching rather than failing.
+/// decoding the whole file. The format is guessed from the content, so a
+/// mislabelled file is still measured. Returns `None` when the dimensions cannot
+/// be read, so callers fall back to size-range matching rather than failing.
 pub fn pixel_count_from_path<P: AsRef<Path>>(path: P) -> Option<u64> {
-    image::image_dimensions(path)
+    image::ImageReader::open(path)
+        .and_then(image::ImageReader::with_guessed_format)
+        .ok()?
+        .into_dimensions()
         .map(|(width, height)| u64::from(width) * u64::from(height))
         .ok()
 }
@@ -57,16 +71,6 @@ pub fn get_size_range(size_bytes: u64) -> String {
     }
 }
 
-/// Determines if the compression is lossy based on the output format and settings.
-/// PNG output is always lossless. WebP at quality 100 is lossless.
-fn is_lossy(output_format: OutputFormat, quality: u8) -> bool {
-    match output_format {
-        OutputFormat::Png => false,
-        OutputFormat::WebP => quality < 100,
-        OutputFormat::Jpeg => true,
-    }
-}
-
 pub fn estimate_compression(
     input_format: &str,
     output_format: &str,
@@ -87,12 +91,28 @@ pub fn estimate_compression(
             }
         }
         ("jpg" | "jpeg", "webp") => (8.0, 0.5, 0.15),
-        ("png", "png") => (15.0, 0.9, 0.0),
+        ("png", "png") => {
+            if settings.encoder.png.quantize && quality < 100.0 {
+                (70.0, 0.6, 0.5) // palette quantization
+            } else {
+                (15.0, 0.9, 0.0) // oxipng only
+            }
+        }
         ("jpg" | "jpeg", "jpg" | "jpeg") => (20.0, 0.8, 0.3),
         ("webp", "webp") => (10.0, 0.6, 0.2),
         ("heic" | "heif", "webp") => (70.0, 0.7, 0.5),
         ("heic" | "heif", "jpg" | "jpeg") => (50.0, 0.7, 0.4),
         ("heic" | "heif", "png") => (10.0, 0.5, 0.0),
+        ("png", "avif") => (90.0, 0.6, 0.5),
+        ("jpg" | "jpeg", "avif") => (35.0, 0.5, 0.4),
+        ("webp", "avif") => (25.0, 0.5, 0.3),
+        ("heic" | "heif", "avif") => (60.0, 0.5, 0.5),
+        ("png", "jxl") => (80.0, 0.5, 0.5),
+        // At quality 100 this is the lossless recompression, which saves ~20%
+        ("jpg" | "jpeg", "jxl") => (25.0, 0.6, 0.25),
+        ("webp", "jxl") => (15.0, 0.4, 0.3),
+        ("heic" | "heif", "jxl") => (55.0, 0.5, 0.5),
+        ("jxl", "jxl") => (10.0, 0.4, 0.2),
         _ => (5.0, 0.3, 0.1),
     };
 
@@ -131,7 +151,8 @@ fn create_stat(
     output_format: String,
     original_size: u64,
     compressed_size: u64,
-    settings: &CompressionSettings,
+    quality_setting: u8,
+    lossy_mode: bool,
 ) -> CompressionStat {
     let size_reduction_percent = if original_size > 0 && original_size >= compressed_size {
         ((original_size - compressed_size) as f64 / original_size as f64) * 100.0
@@ -143,38 +164,51 @@ fn create_stat(
 
     CompressionStat {
         id: None,
+        lossy_mode,
         input_format,
         output_format,
         input_size_range: get_size_range(original_size),
-        quality_setting: settings.quality,
-        lossy_mode: is_lossy(settings.format, settings.quality),
+        quality_setting,
         size_reduction_percent,
         original_size,
         compressed_size,
         pixel_count: None,
         compression_time_ms: None,
         timestamp: chrono::Utc::now().to_rfc3339(),
+        output_width: None,
+        output_height: None,
+        psnr: None,
+        ssim: None,
+        max_error: None,
     }
 }
 
+/// The stat of a compression that wrote `output`. The quality recorded is the
+/// one the output was encoded at (a searched quality replaces the requested
+/// one), and the lossy flag the one measured on what was written.
 pub fn create_stat_with_time(
     input_format: String,
-    output_format: String,
-    original_size: u64,
-    compressed_size: u64,
+    output: &CompressionOutput,
+    requested_quality: u8,
     compression_time_ms: u64,
     pixel_count: Option<u64>,
-    settings: &CompressionSettings,
 ) -> CompressionStat {
     let mut stat = create_stat(
         input_format,
-        output_format,
-        original_size,
-        compressed_size,
-        settings,
+        output.format.extension().to_string(),
+        output.original_size,
+        output.compressed_size,
+        output
+            .quality_search
+            .map_or(requested_quality, |search| search.quality),
+        output.lossy,
     );
     stat.compression_time_ms = Some(compression_time_ms);
     stat.pixel_count = pixel_count;
+    (stat.output_width, stat.output_height) = output.dimensions.unzip();
+    stat.psnr = output.metrics.and_then(|metrics| metrics.psnr);
+    stat.ssim = output.metrics.map(|metrics| metrics.ssim);
+    stat.max_error = output.metrics.map(|metrics| metrics.max_error);
     stat
 }
 
@@ -184,6 +218,9 @@ pub fn create_stat_with_time(
 #[allow(clippy::float_cmp)]
 mod tests {
     use super::*;
+    use crate::domain::compression::engine::QualitySearch;
+    use crate::domain::compression::formats::OutputFormat;
+    use crate::domain::compression::metrics::QualityMetrics;
 
     #[test]
     fn test_pixel_count_from_path_returns_none_for_non_image() {
@@ -229,6 +266,29 @@ mod tests {
         assert!(high_result.percent >= 0.0);
     }
 
+    #[test]
+    fn test_estimate_png_to_png_counts_on_a_palette_only_when_quantizing() {
+        let mut settings = CompressionSettings::new(80, OutputFormat::Png);
+        assert_eq!(
+            estimate_compression("png", "png", 1_000_000, &settings).percent,
+            15.0
+        );
+
+        settings.encoder.png.quantize = true;
+        assert_eq!(
+            estimate_compression("png", "png", 1_000_000, &settings).percent,
+            70.0
+        );
+    }
+
+    #[test]
+    fn test_estimate_jpeg_to_jxl_lossless_recompression() {
+        // q100: base=25%, delta=20, sensitivity=0.25 → percent=20.0
+        let settings = CompressionSettings::new(100, OutputFormat::Jxl);
+        let result = estimate_compression("jpg", "jxl", 1_000_000, &settings);
+        assert_eq!(result.percent, 20.0);
+    }
+
     #[test]
     fn test_calculate_confidence_variance() {
         let no_variance = calculate_confidence(100, 0.0);
@@ -241,24 +301,45 @@ mod tests {
 
     #[test]
     fn test_create_stat_compressed_larger_than_original() {
-        let settings = CompressionSettings::new(80, OutputFormat::WebP);
-        let stat = create_stat("jpeg".into(), "webp".into(), 500, 600, &settings);
+        let stat = create_stat("jpeg".into(), "webp".into(), 500, 600, 80, true);
         assert!(stat.size_reduction_percent < 0.0); // Negative = file grew
     }
 
     #[test]
     fn test_create_stat_zero_original() {
-        let settings = CompressionSettings::new(80, OutputFormat::WebP);
-        let stat = create_stat("jpeg".into(), "webp".into(), 0, 0, &settings);
+        let stat = create_stat("jpeg".into(), "webp".into(), 0, 0, 80, true);
         assert_eq!(stat.size_reduction_percent, 0.0); // No division by zero
     }
 
     #[test]
-    fn test_lossy_mode() {
-        assert!(!is_lossy(OutputFormat::Png, 80));
-        assert!(!is_lossy(OutputFormat::Png, 60));
-        assert!(is_lossy(OutputFormat::Jpeg, 80));
-        assert!(is_lossy(OutputFormat::WebP, 80));
-        assert!(!is_lossy(OutputFormat::WebP, 100));
+    fn test_stat_records_what_the_output_reports() {
+        let output = CompressionOutput {
+            quality_search: Some(QualitySearch {
+                quality: 64,
+                passes: 4,
+                ssim: None,
+            }),
+            lossy: true,
+            ..CompressionOutput::new(None, 1000, 400, OutputFormat::Jxl, Some((30, 20)))
+        };
+
+        let stat = create_stat_with_time("png".into(), &output, 90, 12, Some(600));
+
+        assert_eq!(stat.output_format, "jxl");
+        assert_eq!(stat.quality_setting, 64);
+        assert!(stat.lossy_mode);
+        assert_eq!(
+            (stat.output_width, stat.output_height),
+            (Some(30), Some(20))
+        );
+
+        let passthrough = CompressionOutput {
+            metrics: Some(QualityMetrics::IDENTICAL),
+            ..CompressionOutput::new(None, 1000, 900, OutputFormat::Jpeg, None)
+        };
+        let stat = create_stat_with_time("jpg".into(), &passthrough, 100, 12, None);
+        assert_eq!(stat.quality_setting, 100);
+        assert!(!stat.lossy_mode);
+        assert_eq!(stat.max_error, Some(0));
     }
 }
@@ -1,5 +1,6 @@
 use crate::domain::file::error::{FileError, FileResult};
 use crate::domain::file::path::validate_safe_path;
+use crate::domain::file::signature::{resolve_format, same_format, sniff_file_format};
 use serde::{Deserialize, Serialize};
 use std::path::Path;
 
@@ -8,7 +9,10 @@ use std::path::Path;
 /// derive from this list, so they can never drift apart. (The frontend keeps its
 /// own typed SSOT in `domain/constants.ts`; without shared codegen the two sides
 /// are synced by hand — this is the single Rust-side point.)
-pub const SUPPORTED_IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "webp", "heic", "heif"];
+pub const SUPPORTED_IMAGE_EXTENSIONS: &[&str] = &[
+    "png", "jpg", "jpeg", "webp", "gif", "tif", "tiff", "bmp", "heic", "heif", "jxl", "dng", "cr2",
+    "cr3", "nef", "arw",
+];
 
 /// Whether a bare, lowercased extension is one Plume can compress.
 pub fn is_supported_extension(extension: &str) -> bool {
@@ -41,6 +45,9 @@ pub struct FileMetadata {
     pub extension: Option<String>,
     pub size: u64,
     pub is_image: bool,
+    /// Format identified from the file's signature, as a canonical extension;
+    /// `None` when the content is not a recognised image
+    pub detected_format: Option<String>,
 }
 
 impl FileMetadata {
@@ -60,29 +67,65 @@ impl FileMetadata {
             .and_then(|ext| ext.to_str())
             .map(str::to_lowercase);
 
-        let is_image = extension.as_ref().is_some_and(|ext| {
-            matches!(
-                ext.as_str(),
-                "jpg" | "jpeg" | "png" | "webp" | "gif" | "bmp" | "tiff" | "heic" | "heif"
-            )
-        });
+        let detected_format = sniff_file_format(path_ref).map(str::to_string);
 
-        Ok(FileMetadata {
+        let mut file_metadata = FileMetadata {
             path: path_ref.to_string_lossy().to_string(),
             name,
             extension,
             size: metadata.len(),
-            is_image,
-        })
+            is_image: false,
+            detected_format,
+        };
+        file_metadata.is_image = file_metadata.format().is_some_and(|format| {
+            matches!(
+                format,
+                "jpg"
+                    | "jpeg"
+                    | "png"
+                    | "webp"
+                    | "gif"
+                    | "bmp"
+                    | "tif"
+                    | "tiff"
+                    | "heic"
+                    | "heif"
+                    | "jxl"
+                    | "dng"
+                    | "cr2"
+                    | "cr3"
+                    | "nef"
+                    | "arw"
+            )
+        });
+        Ok(file_metadata)
+    }
+
+    /// Format the file is read as: the sniffed one, or the extension when the
+    /// content was not recognised (the decoder then has the final word) or is
+    /// a TIFF that the extension names a camera RAW
+    pub fn format(&self) -> Option<&str> {
+        match (self.detected_format.as_deref(), self.extension.as_deref()) {
+            (Some(detected), Some(extension)) => Some(resolve_format(detected, extension)),
+            (detected, extension) => detected.or(extension),
+        }
+    }
+
+    /// Whether the extension names another format than the content holds, e.g.
+    /// a PNG saved as `.jpg` or a HEIC exported as `.jpeg`
+    pub fn has_mismatched_extension(&self) -> bool {
+        match (&self.detected_format, &self.extension) {
+            (Some(detected), Some(extension)) => {
+                !same_format(resolve_format(detected, extension), extension)
+            }
+            // An extension-less image is not mislabelled
+            _ => false,
+        }
     }
 
     /// Check if file is a supported image format
     pub fn is_supported_image(&self) -> bool {
-        self.is_image
-            && self
-                .extension
-                .as_ref()
-                .is_some_and(|ext| is_supported_extension(ext))
+        self.is_image && self.format().is_some_and(is_supported_extension)
     }
 }
 
@@ -97,7 +140,7 @@ mod tests {
         for ext in SUPPORTED_IMAGE_EXTENSIONS {
             assert!(is_supported_extension(ext), "{ext} should be supported");
         }
-        assert!(!is_supported_extension("gif"));
+        assert!(!is_supported_extension("avif"));
         assert!(!is_supported_extension("txt"));
         assert!(!is_supported_extension("PNG")); // callers pass a lowercased ext
     }
@@ -115,6 +158,54 @@ mod tests {
         assert!(metadata.is_supported_image());
     }
 
+    #[test]
+    fn test_content_wins_over_a_wrong_or_missing_extension() {
+        let temp_dir = TempDir::new().unwrap();
+        let png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR";
+
+        let mislabelled = temp_dir.path().join("photo.jpg");
+        fs::write(&mislabelled, png).unwrap();
+        let metadata = get_file_info(&mislabelled).unwrap();
+        assert_eq!(metadata.format(), Some("png"));
+        assert!(metadata.has_mismatched_extension());
+        assert!(metadata.is_supported_image());
+
+        let bare = temp_dir.path().join("IMG_0001");
+        fs::write(&bare, png).unwrap();
+        let metadata = get_file_info(&bare).unwrap();
+        assert_eq!(metadata.extension, None);
+        assert!(!metadata.has_mismatched_extension());
+        assert!(metadata.is_supported_image());
+
+        // Recognised but unsupported content is rejected whatever its name
+        let avif = temp_dir.path().join("photo.png");
+        fs::write(&avif, b"\x00\x00\x00\x18ftypavif\x00\x00\x00\x00mif1miaf").unwrap();
+        let metadata = get_file_info(&avif).unwrap();
+        assert!(metadata.has_mismatched_extension());
+        assert!(!metadata.is_supported_image());
+    }
+
+    #[test]
+    fn test_a_tiff_named_as_a_raw_is_read_as_that_raw() {
+        let temp_dir = TempDir::new().unwrap();
+        let tiff_header = b"II*\x00\x08\x00\x00\x00";
+
+        let nef = temp_dir.path().join("DSC_0001.NEF");
+        fs::write(&nef, tiff_header).unwrap();
+        let metadata = get_file_info(&nef).unwrap();
+        assert_eq!(metadata.detected_format.as_deref(), Some("tiff"));
+        assert_eq!(metadata.format(), Some("nef"));
+        assert!(!metadata.has_mismatched_extension());
+        assert!(metadata.is_supported_image());
+
+        // Other formats keep their own signature whatever the extension says
+        let png = temp_dir.path().join("photo.dng");
+        fs::write(&png, b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR").unwrap();
+        let metadata = get_file_info(&png).unwrap();
+        assert_eq!(metadata.format(), Some("png"));
+        assert!(metadata.has_mismatched_extension());
+    }
+
     #[test]
     fn test_unsupported_extension_is_not_an_image() {
         let temp_dir = TempDir::new().unwrap();
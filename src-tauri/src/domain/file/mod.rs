@@ -7,6 +7,7 @@ pub mod error;
 pub mod metadata;
 pub mod path;
 pub mod scan;
+pub mod signature;
 
 // Re-export core types and functions for easy access
 pub use error::{FileError, FileResult};
@@ -15,6 +16,9 @@ pub use metadata::{
 };
 pub use path::{get_file_stem, validate_safe_path};
 pub use scan::{ScanOutcome, collect_image_paths};
+pub use signature::{
+    is_camera_raw, resolve_format, same_format, sniff_file_format, sniff_image_format,
+};
 
 /// Largest input accepted for compression
 const MAX_FILE_SIZE: u64 = 100 * 1024 * 1024;
@@ -26,10 +30,20 @@ pub fn validate_image_file<P: AsRef<std::path::Path>>(path: P) -> FileResult<Fil
     if !metadata.is_supported_image() {
         return Err(FileError::UnsupportedFormat(format!(
             "Unsupported image format: {:?}",
-            metadata.extension
+            metadata.format()
         )));
     }
 
+    if metadata.has_mismatched_extension() {
+        // Not an error: the content decides how the file is decoded
+        log::warn!(
+            "{} has a .{} extension but holds {} data",
+            metadata.name,
+            metadata.extension.as_deref().unwrap_or_default(),
+            metadata.format().unwrap_or_default(),
+        );
+    }
+
     if metadata.size > MAX_FILE_SIZE {
         return Err(FileError::InvalidPath(format!(
             "File too large: {} bytes (max: {} bytes)",
@@ -1,5 +1,6 @@
 use crate::domain::file::metadata::is_supported_extension;
 use crate::domain::file::path::validate_safe_path;
+use crate::domain::file::signature::sniff_file_format;
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
 
@@ -123,7 +124,7 @@ fn collect_into(path: &Path, depth: usize, found: &mut Vec<PathBuf>) {
             }
             collect_into(&child, depth + 1, found);
         }
-    } else if file_type.is_file() && has_supported_extension(path) {
+    } else if file_type.is_file() && is_supported_image(path) {
         found.push(path.to_path_buf());
     }
 }
@@ -134,11 +135,19 @@ fn is_hidden(path: &Path) -> bool {
         .is_some_and(|name| name.starts_with('.'))
 }
 
-fn has_supported_extension(path: &Path) -> bool {
-    path.extension()
-        .and_then(|ext| ext.to_str())
-        .map(str::to_lowercase)
-        .is_some_and(|ext| is_supported_extension(&ext))
+/// Whether a file holds an image Plume can compress, judged by its signature
+/// so that mislabelled and extension-less images are found too. Content that
+/// is not a recognised image falls back to the extension, as `FileMetadata`
+/// does.
+fn is_supported_image(path: &Path) -> bool {
+    match sniff_file_format(path) {
+        Some(format) => is_supported_extension(format),
+        None => path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(str::to_lowercase)
+            .is_some_and(|ext| is_supported_extension(&ext)),
+    }
 }
 
 #[cfg(test)]
@@ -199,6 +208,54 @@ mod tests {
         assert_eq!(result.images, vec![image.to_string_lossy().to_string()]);
     }
 
+    #[test]
+    fn judges_files_by_their_content_before_their_extension() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::write(root.join("IMG_0001"), b"\xff\xd8\xff\xe1\x00\x10Exif").unwrap();
+        fs::write(root.join("export.dat"), b"\x89PNG\r\n\x1a\n\x00").unwrap();
+        fs::write(
+            root.join("photo.png"),
+            b"\x00\x00\x00\x18ftypavif\x00\x00\x00\x00mif1miaf",
+        )
+        .unwrap();
+
+        let result = collect_image_paths(&[root.to_string_lossy().to_string()]);
+
+        let names: Vec<&str> = result
+            .images
+            .iter()
+            .map(|p| Path::new(p).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["IMG_0001", "export.dat"]);
+    }
+
+    #[test]
+    fn collects_the_camera_raws_of_a_card_dump() {
+        let dir = TempDir::new().unwrap();
+        let root = dir.path();
+        fs::write(root.join("DSC_0001.NEF"), b"MM\x00*\x00\x00\x00\x08").unwrap();
+        fs::write(
+            root.join("IMG_0002.CR2"),
+            b"II*\x00\x10\x00\x00\x00CR\x02\x00",
+        )
+        .unwrap();
+        fs::write(
+            root.join("IMG_0003.CR3"),
+            b"\x00\x00\x00\x18ftypcrx \x00\x00\x00\x01crx isom",
+        )
+        .unwrap();
+
+        let result = collect_image_paths(&[root.to_string_lossy().to_string()]);
+
+        let names: Vec<&str> = result
+            .images
+            .iter()
+            .map(|p| Path::new(p).file_name().unwrap().to_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["DSC_0001.NEF", "IMG_0002.CR2", "IMG_0003.CR3"]);
+    }
+
     #[test]
     fn does_not_descend_into_macos_package_bundles() {
         let dir = TempDir::new().unwrap();
@@ -0,0 +1,239 @@
+use std::io::Read;
+use std::path::Path;
+
+/// Bytes read from the start of a file to identify it. An ISO-BMFF `ftyp` box
+/// lists its compatible brands after the major one; 64 bytes covers the dozen
+/// a HEIC or AVIF file carries in practice.
+const SIGNATURE_LENGTH: usize = 64;
+
+/// JPEG XL container signature box (ISO/IEC 18181-2)
+const JXL_CONTAINER: &[u8] = b"\x00\x00\x00\x0cJXL \x0d\x0a\x87\x0a";
+
+/// `ftyp` brands of HEVC-coded HEIF images and sequences
+const HEIC_BRANDS: &[&[u8]] = &[
+    b"heic", b"heix", b"heim", b"heis", b"hevc", b"hevx", b"hevm",
+];
+
+/// `ftyp` brands of AV1-coded HEIF images and sequences
+const AVIF_BRANDS: &[&[u8]] = &[b"avif", b"avis"];
+
+/// `ftyp` brands of a HEIF whose codec the brand does not name
+const HEIF_BRANDS: &[&[u8]] = &[b"mif1", b"msf1"];
+
+/// `ftyp` major brand of a Canon CR3
+const CR3_BRAND: &[u8] = b"crx ";
+
+/// Camera RAW formats, by canonical extension
+const CAMERA_RAW_EXTENSIONS: &[&str] = &["dng", "cr2", "cr3", "nef", "arw"];
+
+/// Camera RAW formats whose files open with a plain TIFF header, which only
+/// their extension tells apart from an ordinary TIFF
+const TIFF_BASED_RAW_EXTENSIONS: &[&str] = &["dng", "nef", "arw"];
+
+/// Identify an image from the signature at the start of its bytes, returning
+/// the canonical extension of its format (`"jpg"` rather than `"jpeg"`), or
+/// `None` when the content is not a recognised image. Formats Plume cannot
+/// read yet are identified too, so that a mislabelled one is reported as what
+/// it really is.
+pub fn sniff_image_format(data: &[u8]) -> Option<&'static str> {
+    let format = match data {
+        [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a, ..] => "png",
+        // SOI then any marker: JFIF (APP0), EXIF (APP1), or a bare frame
+        [0xff, 0xd8, 0xff, ..] => "jpg",
+        [
+            b'R',
+            b'I',
+            b'F',
+            b'F',
+            _,
+            _,
+            _,
+            _,
+            b'W',
+            b'E',
+            b'B',
+            b'P',
+            ..,
+        ] => "webp",
+        [0xff, 0x0a, ..] => "jxl",
+        _ if data.starts_with(JXL_CONTAINER) => "jxl",
+        [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => "gif",
+        // A TIFF header followed by Canon's `CR` marker and major version 2
+        [b'I', b'I', 0x2a, 0x00, _, _, _, _, b'C', b'R', 0x02, ..] => "cr2",
+        [b'I', b'I', 0x2a, 0x00, ..] | [b'M', b'M', 0x00, 0x2a, ..] => "tiff",
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => return sniff_ftyp(data),
+        [b'B', b'M', ..] => "bmp",
+        _ => return None,
+    };
+    Some(format)
+}
+
+/// Classify an ISO-BMFF file by the brands of its leading `ftyp` box. The
+/// major brand decides when it names a codec or a CR3; otherwise the
+/// compatible brands do, and a bare `mif1`/`msf1` HEIF counts as HEIC, its
+/// usual codec.
+fn sniff_ftyp(data: &[u8]) -> Option<&'static str> {
+    let declared = u32::from_be_bytes(data.get(..4)?.try_into().ok()?) as usize;
+    let end = declared.min(data.len());
+    let major = data.get(8..12)?;
+    // Major brand, minor version, then the compatible brands
+    let compatible = data.get(16..end).unwrap_or_default().chunks_exact(4);
+    if major == CR3_BRAND {
+        return Some("cr3");
+    }
+
+    let classify = |brand: &[u8]| {
+        if AVIF_BRANDS.contains(&brand) {
+            Some("avif")
+        } else if HEIC_BRANDS.contains(&brand) {
+            Some("heic")
+        } else {
+            None
+        }
+    };
+    classify(major)
+        .or_else(|| compatible.clone().find_map(classify))
+        .or_else(|| {
+            std::iter::once(major)
+                .chain(compatible)
+                .any(|brand| HEIF_BRANDS.contains(&brand))
+                .then_some("heic")
+        })
+}
+
+/// The format to read a file as, from the one its signature names and its
+/// extension: a plain TIFF signature defers to a TIFF-based camera RAW
+/// extension, whatever its letter case
+pub fn resolve_format<'a>(sniffed: &'a str, extension: &'a str) -> &'a str {
+    let raw_extension = TIFF_BASED_RAW_EXTENSIONS
+        .iter()
+        .any(|raw| extension.eq_ignore_ascii_case(raw));
+    if sniffed == "tiff" && raw_extension {
+        extension
+    } else {
+        sniffed
+    }
+}
+
+/// Whether a lowercased format names a camera RAW
+pub fn is_camera_raw(format: &str) -> bool {
+    CAMERA_RAW_EXTENSIONS.contains(&format)
+}
+
+/// Sniff the format of a file from its first bytes. Unreadable files yield
+/// `None`, like unrecognised ones.
+pub fn sniff_file_format<P: AsRef<Path>>(path: P) -> Option<&'static str> {
+    let mut header = Vec::with_capacity(SIGNATURE_LENGTH);
+    std::fs::File::open(path)
+        .ok()?
+        .take(SIGNATURE_LENGTH as u64)
+        .read_to_end(&mut header)
+        .ok()?;
+    sniff_image_format(&header)
+}
+
+/// Whether two lowercased extensions name the same format (`jpg`/`jpeg`,
+/// `heic`/`heif`, `tif`/`tiff`)
+pub fn same_format(a: &str, b: &str) -> bool {
+    fn canonical(ext: &str) -> &str {
+        match ext {
+            "jpeg" => "jpg",
+            "heif" => "heic",
+            "tif" => "tiff",
+            other => other,
+        }
+    }
+    canonical(a) == canonical(b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ftyp(major: &[u8], compatible: &[&[u8]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len();
+        let mut data = u32::try_from(size).unwrap().to_be_bytes().to_vec();
+        data.extend_from_slice(b"ftyp");
+        data.extend_from_slice(major);
+        data.extend_from_slice(&[0; 4]);
+        for brand in compatible {
+            data.extend_from_slice(brand);
+        }
+        data.extend_from_slice(b"\x00\x00\x00\x08meta");
+        data
+    }
+
+    #[test]
+    fn test_recognises_the_still_image_signatures() {
+        let cases: &[(&[u8], &str)] = &[
+            (b"\x89PNG\r\n\x1a\n\x00\x00\x00\rIHDR", "png"),
+            (b"\xff\xd8\xff\xe0\x00\x10JFIF\x00", "jpg"),
+            (b"\xff\xd8\xff\xe1\x12\x34Exif\x00\x00", "jpg"),
+            (b"RIFF\x24\x00\x00\x00WEBPVP8 ", "webp"),
+            (b"\xff\x0a\xfa\x7f", "jxl"),
+            (b"\x00\x00\x00\x0cJXL \r\n\x87\n\x00\x00", "jxl"),
+            (b"GIF89a\x01\x00", "gif"),
+            (b"II*\x00\x08\x00\x00\x00", "tiff"),
+            (b"MM\x00*\x00\x00\x00\x08", "tiff"),
+            (b"II*\x00\x10\x00\x00\x00CR\x02\x00", "cr2"),
+            (b"BM\x36\x00\x00\x00", "bmp"),
+        ];
+        for (data, expected) in cases {
+            assert_eq!(sniff_image_format(data), Some(*expected), "{expected}");
+        }
+    }
+
+    #[test]
+    fn test_tells_heic_from_avif_by_ftyp_brand() {
+        assert_eq!(
+            sniff_image_format(&ftyp(b"heic", &[b"mif1", b"heic"])),
+            Some("heic")
+        );
+        assert_eq!(
+            sniff_image_format(&ftyp(b"avif", &[b"mif1", b"miaf"])),
+            Some("avif")
+        );
+        // Generic major brand: the compatible brands name the codec
+        assert_eq!(
+            sniff_image_format(&ftyp(b"mif1", &[b"avif", b"miaf"])),
+            Some("avif")
+        );
+        assert_eq!(sniff_image_format(&ftyp(b"mif1", &[b"miaf"])), Some("heic"));
+        assert_eq!(
+            sniff_image_format(&ftyp(b"crx ", &[b"crx ", b"isom"])),
+            Some("cr3")
+        );
+        // An MP4 video is not an image
+        assert_eq!(
+            sniff_image_format(&ftyp(b"isom", &[b"isom", b"mp41"])),
+            None
+        );
+    }
+
+    #[test]
+    fn test_rejects_unknown_and_truncated_content() {
+        assert_eq!(sniff_image_format(b""), None);
+        assert_eq!(sniff_image_format(b"\x89PN"), None);
+        assert_eq!(sniff_image_format(b"RIFF\x24\x00\x00\x00WAVEfmt "), None);
+        assert_eq!(sniff_image_format(b"%PDF-1.7"), None);
+        assert_eq!(sniff_image_format(b"\x00\x00\x00\x18ftyp"), None);
+    }
+
+    #[test]
+    fn test_a_tiff_signature_defers_to_a_tiff_based_raw_extension() {
+        assert_eq!(resolve_format("tiff", "nef"), "nef");
+        assert_eq!(resolve_format("tiff", "DNG"), "DNG");
+        assert_eq!(resolve_format("tiff", "tif"), "tiff");
+        // Only a TIFF header can hide a RAW: the signature wins otherwise
+        assert_eq!(resolve_format("png", "arw"), "png");
+        assert_eq!(resolve_format("tiff", "cr2"), "tiff");
+    }
+
+    #[test]
+    fn test_same_format_folds_extension_aliases() {
+        assert!(same_format("jpeg", "jpg"));
+        assert!(same_format("heif", "heic"));
+        assert!(same_format("tif", "tiff"));
+        assert!(!same_format("png", "jpg"));
+    }
+}
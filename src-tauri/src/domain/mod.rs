@@ -6,8 +6,9 @@ pub mod file;
 
 // Compression domain exports
 pub use compression::{
-    CompressionLevel, CompressionSettings, EstimationQuery, EstimationResult, OutputFormat,
-    resolve_output_path,
+    CompressionLevel, CompressionSettings, DecodeLimits, EncoderOptions, EstimationQuery,
+    EstimationResult, HeifImages, MetadataPolicy, OutputFormat, PerceptualTarget, RawDecodeMode,
+    ResizeOptions, resolve_output_path,
 };
 
 // File domain exports
@@ -41,11 +41,22 @@ describe('resolveCompressionParams', () => {
   });
 
   describe('explicit png output', () => {
-    it('ignores the level entirely — oxipng is always lossless', () => {
-      const expected = { quality: 100, format: 'png', lossy: false };
-      expect(resolveCompressionParams('png', 'light', 'JPEG')).toEqual(expected);
-      expect(resolveCompressionParams('png', 'balanced', 'JPEG')).toEqual(expected);
-      expect(resolveCompressionParams('png', 'aggressive', 'JPEG')).toEqual(expected);
+    it('stays lossless at light and quantizes to a palette at the other levels', () => {
+      expect(resolveCompressionParams('png', 'light', 'JPEG')).toEqual({
+        quality: 100,
+        format: 'png',
+        lossy: false,
+      });
+      expect(resolveCompressionParams('png', 'balanced', 'JPEG')).toEqual({
+        quality: 80,
+        format: 'png',
+        lossy: true,
+      });
+      expect(resolveCompressionParams('png', 'aggressive', 'JPEG')).toEqual({
+        quality: 60,
+        format: 'png',
+        lossy: true,
+      });
     });
   });
 
@@ -58,6 +69,14 @@ describe('resolveCompressionParams', () => {
       });
     });
 
+    it('redirects a camera RAW to jpeg, like HEIC', () => {
+      expect(resolveCompressionParams('keep', 'light', 'RAW')).toEqual({
+        quality: 92,
+        format: 'jpeg',
+        lossy: true,
+      });
+    });
+
     it('mirrors the webp rules when the source is webp', () => {
       expect(resolveCompressionParams('keep', 'light', 'WEBP')).toEqual({
         quality: 100,
@@ -71,6 +90,14 @@ describe('resolveCompressionParams', () => {
       });
     });
 
+    it('mirrors the webp rules when the source is gif, kept as animated webp', () => {
+      expect(resolveCompressionParams('keep', 'balanced', 'GIF')).toEqual({
+        quality: 80,
+        format: 'auto',
+        lossy: true,
+      });
+    });
+
     it('mirrors the jpeg rules when the source is jpeg', () => {
       expect(resolveCompressionParams('keep', 'light', 'JPEG')).toEqual({
         quality: 92,
@@ -79,8 +106,21 @@ describe('resolveCompressionParams', () => {
       });
     });
 
-    it('falls back to lossless for png sources whatever the level', () => {
+    it('mirrors the png rules when the source is png', () => {
+      expect(resolveCompressionParams('keep', 'light', 'PNG')).toEqual({
+        quality: 100,
+        format: 'auto',
+        lossy: false,
+      });
       expect(resolveCompressionParams('keep', 'aggressive', 'PNG')).toEqual({
+        quality: 60,
+        format: 'auto',
+        lossy: true,
+      });
+    });
+
+    it('keeps other sources lossless whatever the level', () => {
+      expect(resolveCompressionParams('keep', 'aggressive', 'TIFF')).toEqual({
         quality: 100,
         format: 'auto',
         lossy: false,
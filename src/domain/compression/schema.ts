@@ -16,6 +16,51 @@ export const CompressionSummarySchema = z.object({
   compressed_size: z.number().nonnegative(),
   savings_percent: z.number(),
   output_path: z.string(),
+  quality_search: z
+    .object({
+      quality: z.number().int().min(1).max(100),
+      passes: z.number().int().positive(),
+      ssim: z.number().min(0).max(1).nullish(),
+    })
+    .nullish(),
+  metrics: z
+    .object({
+      psnr: z.number().nullable(), // null: pixel-identical output
+      ssim: z.number().min(0).max(1),
+      max_error: z.number().int().min(0).max(255),
+    })
+    .nullish(),
+  // Why metrics is null
+  metrics_unavailable: z.enum(['avif-output', 'animation', 'failed']).nullish(),
+  // Further files written for the same input (each image of a HEIF burst, or
+  // the other variants of a responsive set)
+  extra_outputs: z
+    .array(
+      z.object({
+        output_path: z.string(),
+        compressed_size: z.number().nonnegative(),
+        dimensions: z.tuple([z.number().int(), z.number().int()]).nullable(),
+      })
+    )
+    .nullish(),
+  // Every file of a responsive set and the <picture> element serving them
+  responsive: z
+    .object({
+      variants: z.array(
+        z.object({
+          output_path: z.string(),
+          format: z.enum(['Png', 'Jpeg', 'WebP', 'Avif', 'Jxl']),
+          width: z.number().int().positive(),
+          height: z.number().int().positive(),
+          compressed_size: z.number().nonnegative(),
+        })
+      ),
+      picture: z.string(),
+    })
+    .nullish(),
+  // Allowed formats auto-best left out of the contest (AVIF under a fidelity
+  // floor, JPEG for a transparent source, all but WebP for an animation)
+  excluded_formats: z.array(z.enum(['Png', 'Jpeg', 'WebP', 'Avif', 'Jxl'])).nullish(),
 });
 
 export type CompressionSummaryType = z.infer<typeof CompressionSummarySchema>;
@@ -47,12 +92,13 @@ export function resolveCompressionParams(
   level: CompressionLevelType,
   imageFormat: ImageFormatDisplay
 ): ResolvedCompressionParams {
-  const isHeic = imageFormat === 'HEIC';
+  // Neither a HEIC nor a camera RAW can be written back: keep makes a JPEG
+  const isCameraPhoto = imageFormat === 'HEIC' || imageFormat === 'RAW';
 
   // Determine effective format
   let effectiveFormat: 'webp' | 'jpeg' | 'png' | 'auto';
   if (outputFormat === 'keep') {
-    effectiveFormat = isHeic ? 'jpeg' : 'auto';
+    effectiveFormat = isCameraPhoto ? 'jpeg' : 'auto';
   } else {
     effectiveFormat = outputFormat;
   }
@@ -73,15 +119,19 @@ export function resolveCompressionParams(
         format: 'jpeg',
         lossy: true,
       };
-    case 'png':
+    case 'png': {
+      // Below 100 the backend reduces the PNG to a palette of at least that quality
+      const isLossless = level === 'light';
       return {
-        quality: 100,
+        quality: QUALITY_MAP[level],
         format: 'png',
-        lossy: false,
+        lossy: !isLossless,
       };
+    }
     case 'auto': {
-      // Keep original format — adapt quality to source format
-      if (imageFormat === 'WEBP') {
+      // Keep original format — adapt quality to source format. A GIF is kept
+      // as an animated WebP, so it follows the WebP levels.
+      if (imageFormat === 'WEBP' || imageFormat === 'GIF') {
         const isLossless = level === 'light';
         return {
           quality: QUALITY_MAP[level],
@@ -96,7 +146,15 @@ export function resolveCompressionParams(
           lossy: true,
         };
       }
-      // PNG or other — oxipng is always lossless, so the level has no effect
+      if (imageFormat === 'PNG') {
+        const isLossless = level === 'light';
+        return {
+          quality: QUALITY_MAP[level],
+          format: 'auto',
+          lossy: !isLossless,
+        };
+      }
+      // Other sources are kept losslessly, so the level has no effect
       return {
         quality: 100,
         format: 'auto',
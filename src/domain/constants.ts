@@ -6,11 +6,30 @@ import { z } from 'zod';
 
 // Zod is the SSOT for the format enum: the union type and the value list both
 // derive from this schema, so they can never drift apart.
-export const ImageFormatSchema = z.enum(['png', 'jpeg', 'webp', 'heic']);
+export const ImageFormatSchema = z.enum([
+  'png',
+  'jpeg',
+  'webp',
+  'gif',
+  'tiff',
+  'bmp',
+  'heic',
+  'jxl',
+  'raw',
+]);
 export type ImageFormat = z.infer<typeof ImageFormatSchema>;
 export const IMAGE_FORMATS = ImageFormatSchema.options;
 
-export type ImageFormatDisplay = 'PNG' | 'JPEG' | 'WEBP' | 'HEIC';
+export type ImageFormatDisplay =
+  | 'PNG'
+  | 'JPEG'
+  | 'WEBP'
+  | 'GIF'
+  | 'TIFF'
+  | 'BMP'
+  | 'HEIC'
+  | 'JXL'
+  | 'RAW';
 
 export const SUPPORTED_FORMATS_DISPLAY = IMAGE_FORMATS.map(format => format.toUpperCase()).join(
   ', '
@@ -21,8 +40,19 @@ const EXTENSION_TO_FORMAT: Record<string, ImageFormatDisplay> = {
   jpg: 'JPEG',
   jpeg: 'JPEG',
   webp: 'WEBP',
+  gif: 'GIF',
+  tif: 'TIFF',
+  tiff: 'TIFF',
+  bmp: 'BMP',
   heic: 'HEIC',
   heif: 'HEIC',
+  jxl: 'JXL',
+  // Camera RAW and DNG
+  dng: 'RAW',
+  cr2: 'RAW',
+  cr3: 'RAW',
+  nef: 'RAW',
+  arw: 'RAW',
 };
 
 const FALLBACK_FORMAT: ImageFormatDisplay = 'JPEG';
@@ -22,13 +22,15 @@ export const ImageSchema = z.object({
 });
 
 // IPC contract — mirrors FileInfo in src-tauri/src/commands/file.rs.
-// extension is Option<String> on the Rust side, hence nullish.
+// extension and detected_format are Option<String> on the Rust side, hence nullish.
 export const FileInfoSchema = z.object({
   path: z.string(),
   name: z.string(),
   size: z.number().nonnegative(),
   extension: z.string().nullish(),
   is_image: z.boolean(),
+  detected_format: z.string().nullish(),
+  extension_mismatch: z.boolean().optional(),
 });
 
 export const SelectedFilesSchema = z.array(z.string());
@@ -51,6 +51,49 @@ interface CompressImageRequest {
   quality?: number;
   format?: string;
   level?: CompressionLevelType;
+  metadata_policy?: 'keep-all' | 'keep-all-except-location' | 'icc-only' | 'strip-all';
+  convert_to_srgb?: boolean;
+  resize?: {
+    max_width?: number;
+    max_height?: number;
+    longest_edge?: number;
+    scale_percent?: number;
+  };
+  target_size?: number;
+  perceptual_target?: 'visually-lossless' | { 'min-ssim': number };
+  decode_limits?: { max_pixels?: number; max_alloc_bytes?: number };
+  raw_mode?: 'fast' | 'full';
+  reduce_to_8bit?: boolean;
+  heif_images?: 'primary' | 'all';
+  encoder_options?: {
+    webp?: {
+      mode?: 'lossy' | 'near-lossless' | 'lossless';
+      method?: number;
+      preset?: 'photo' | 'drawing' | 'icon' | null;
+      near_lossless?: number;
+      lossless_effort?: number;
+      alpha_quality?: number;
+    };
+    jpeg?: {
+      progressive?: boolean;
+      chroma_subsampling?: '4:2:0' | '4:2:2' | '4:4:4';
+      trellis?: boolean;
+      quant_table?: number;
+      lossless?: boolean;
+    };
+    png?: {
+      level?: number;
+      zopfli?: boolean;
+      interlace?: boolean;
+      quantize?: boolean;
+      dithering?: number;
+    };
+  };
+  // Only read when format is 'auto-best'; keep_transparency defaults to true
+  auto_best?: { formats?: string[]; keep_transparency?: boolean };
+  // Every width in every format, with a <picture> manifest; leave format unset,
+  // the set names its own formats
+  responsive?: { widths?: number[]; formats?: string[]; sizes?: string };
 }
 
 // ====== DATABASE ======
@@ -82,7 +82,7 @@ async function runImageCompression(
     }));
 
     // Resolve compression params for this image
-    const { quality, format: outputFormatForImage } = resolveCompressionParams(
+    const { quality, format: outputFormatForImage, lossy } = resolveCompressionParams(
       compressionSettings.outputFormat,
       compressionSettings.compressionLevel,
       image.format
@@ -156,6 +156,12 @@ async function runImageCompression(
       quality,
       format: outputFormatForImage,
       level: compressionSettings.compressionLevel,
+      // Lossless WebP is a mode of its own and a lossy PNG is a palette; other
+      // formats ignore both
+      encoder_options: {
+        webp: { mode: lossy ? 'lossy' : 'lossless' },
+        png: { quantize: lossy },
+      },
     });
 
     // Signal completion to the adaptive manager -> triggers the 85->100 animation
@@ -212,7 +218,13 @@ async function buildPendingImage(
     const fileInfo = await getFileInformation(filePath);
     fileName = fileInfo.name;
     fileSize = fileInfo.size;
-    format = imageFormatFromExtension(fileInfo.extension);
+    // The backend sniffs the content; a mislabelled file shows its real format
+    format = imageFormatFromExtension(fileInfo.detected_format ?? fileInfo.extension);
+    if (fileInfo.extension_mismatch) {
+      console.warn(
+        `addImages: ${fileInfo.name} holds ${fileInfo.detected_format} data despite its extension`
+      );
+    }
   } catch (error) {
     // Non-blocking — file info is best-effort; fall back to the path.
     console.error('addImages: file info unavailable, using path fallback:', error);